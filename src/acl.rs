@@ -0,0 +1,164 @@
+use crate::users::Accounts;
+use std::io;
+use std::path::Path;
+
+//GNU tar and star store ACLs as text in these PAX records
+pub const PAX_ACCESS: &str = "SCHILY.acl.access";
+pub const PAX_DEFAULT: &str = "SCHILY.acl.default";
+
+//the attributes the kernel keeps ACLs in, in its own binary format
+pub const XATTR_ACCESS: &str = "system.posix_acl_access";
+pub const XATTR_DEFAULT: &str = "system.posix_acl_default";
+
+const VERSION: u32 = 2;
+const UNDEFINED_ID: u32 = u32::MAX;
+
+//entry tags, in the order the kernel wants them
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+//the PAX records for a file's access ACL and a directory's default ACL
+//files whose ACL only repeats their mode have no attribute and get no records
+pub fn read(path: &Path) -> Vec<(&'static str, String)> {
+    [(PAX_ACCESS, XATTR_ACCESS), (PAX_DEFAULT, XATTR_DEFAULT)]
+        .iter()
+        .filter_map(|(record, attribute)| {
+            let value = xattr::get(path, attribute).ok()??;
+            Some((*record, to_text(&value)?))
+        })
+        .collect()
+}
+
+//the ACL records among an entry's PAX records, as (attribute, text)
+pub fn from_pax<'a>(records: impl Iterator<Item = io::Result<tar::PaxExtension<'a>>>) -> Vec<(&'static str, String)> {
+    records
+        .flatten()
+        .filter_map(|record| {
+            let attribute = match record.key().ok()? {
+                PAX_ACCESS => XATTR_ACCESS,
+                PAX_DEFAULT => XATTR_DEFAULT,
+                _ => return None,
+            };
+            Some((attribute, record.value().ok()?.to_string()))
+        })
+        .collect()
+}
+
+//set an ACL read back from the archive, names are looked up on this system
+pub fn apply(path: &Path, attribute: &str, text: &str, accounts: &Accounts) -> io::Result<()> {
+    let value = from_text(text, accounts).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    xattr::set(path, attribute, &value)
+}
+
+//"user::rw-,user:1000:r--,group::r--,mask::r--,other::r--", numeric ids keep it independent of name lookups
+fn to_text(value: &[u8]) -> Option<String> {
+    if value.len() < 4 || !(value.len() - 4).is_multiple_of(8) || u32::from_le_bytes(value[..4].try_into().ok()?) != VERSION {
+        return None;
+    }
+
+    let entries: Option<Vec<String>> = value[4..]
+        .chunks_exact(8)
+        .map(|entry| {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let perm = u16::from_le_bytes([entry[2], entry[3]]);
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            let qualifier = if matches!(tag, USER | GROUP) { id.to_string() } else { String::new() };
+            let name = match tag {
+                USER_OBJ | USER => "user",
+                GROUP_OBJ | GROUP => "group",
+                MASK => "mask",
+                OTHER => "other",
+                _ => return None,
+            };
+            Some(format!("{}:{}:{}", name, qualifier, permissions(perm)))
+        })
+        .collect();
+    Some(entries?.join(","))
+}
+
+//parses what to_text writes and what GNU tar writes: abbreviated tags, names and trailing ids are accepted
+fn from_text(text: &str, accounts: &Accounts) -> Result<Vec<u8>, String> {
+    let mut entries = Vec::new();
+    for entry in text.split([',', '\n']).map(str::trim).filter(|entry| !entry.is_empty() && !entry.starts_with('#')) {
+        let fields: Vec<&str> = entry.split(':').collect();
+        if fields.len() < 3 {
+            return Err(format!("invalid ACL entry '{}'", entry));
+        }
+        let (tag_name, qualifier, perms) = (fields[0], fields[1], fields[2]);
+
+        let named = !qualifier.is_empty();
+        let (tag, id) = match (tag_name, named) {
+            ("user" | "u", false) => (USER_OBJ, UNDEFINED_ID),
+            ("user" | "u", true) => (USER, resolve(qualifier, fields.get(3), |name| accounts.uid_by_name(name))?),
+            ("group" | "g", false) => (GROUP_OBJ, UNDEFINED_ID),
+            ("group" | "g", true) => (GROUP, resolve(qualifier, fields.get(3), |name| accounts.gid_by_name(name))?),
+            ("mask" | "m", _) => (MASK, UNDEFINED_ID),
+            ("other" | "o", _) => (OTHER, UNDEFINED_ID),
+            _ => return Err(format!("invalid ACL entry '{}'", entry)),
+        };
+
+        let mut perm = 0;
+        for (bit, letter) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+            if perms.contains(letter) {
+                perm |= bit;
+            }
+        }
+        entries.push((tag, id, perm));
+    }
+
+    //the kernel rejects entries out of order
+    entries.sort_unstable_by_key(|(tag, id, _)| (*tag, *id));
+    let mut value = VERSION.to_le_bytes().to_vec();
+    for (tag, id, perm) in entries {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&(perm as u16).to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    Ok(value)
+}
+
+//a numeric qualifier as is, a name through this system's accounts or star's trailing id
+fn resolve(qualifier: &str, trailing_id: Option<&&str>, lookup: impl Fn(&str) -> Option<u32>) -> Result<u32, String> {
+    qualifier
+        .parse()
+        .ok()
+        .or_else(|| lookup(qualifier))
+        .or_else(|| trailing_id.and_then(|id| id.parse().ok()))
+        .ok_or_else(|| format!("unknown user or group '{}'", qualifier))
+}
+
+fn permissions(perm: u16) -> String {
+    [(4, 'r'), (2, 'w'), (1, 'x')]
+        .iter()
+        .map(|(bit, letter)| if perm & bit != 0 { *letter } else { '-' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acl_text_round_trips_through_the_kernel_format() {
+        let text = "user::rw-,user:1000:r--,group::r--,group:100:rw-,mask::rw-,other::---";
+        let value = from_text(text, &Accounts::System).unwrap();
+        assert_eq!(value.len(), 4 + 6 * 8);
+        assert_eq!(to_text(&value).unwrap(), text);
+
+        //GNU tar's abbreviated form, out of order, with a name
+        let gnu = from_text("o::r--,u::rwx,g::r-x,u:root:rwx,m::rwx", &Accounts::System).unwrap();
+        assert_eq!(to_text(&gnu).unwrap(), "user::rwx,user:0:rwx,group::r-x,mask::rwx,other::r--");
+
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("shared");
+        std::fs::write(&path, "").unwrap();
+        //tmpfs without ACL support and the like, nothing to read back there
+        if apply(&path, XATTR_ACCESS, text, &Accounts::System).is_ok() {
+            assert_eq!(read(&path), vec![(PAX_ACCESS, text.to_string())]);
+        }
+    }
+}
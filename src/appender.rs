@@ -0,0 +1,568 @@
+use crate::acl;
+use crate::emitted::{Emission, EmittedPaths};
+use crate::manifest::{FileListing, PartialFile};
+use crate::sqlite::{self, DatabaseSnapshots};
+use crate::users::Accounts;
+use crate::xattrs;
+use colored::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use tar::{Builder, EntryType, Header, HeaderMode};
+
+//a file whose content could only be read up to a point
+pub struct PartialRead {
+    pub path: PathBuf,
+    pub read: u64,
+    pub size: u64,
+    pub error: String,
+}
+
+//an entry that failed with part of it in the stream already, a PAX or long name header there would be read as part of the next entry
+#[derive(Debug)]
+pub struct EntryCutShort(io::Error);
+
+impl std::fmt::Display for EntryCutShort {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}, the archive can't be continued after a half written entry", self.0)
+    }
+}
+
+impl std::error::Error for EntryCutShort {}
+
+fn cut_short(e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), EntryCutShort(e))
+}
+
+//true for errors that leave the archive unusable past this point, skipping the entry isn't enough then
+pub fn is_cut_short(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<EntryCutShort>())
+}
+
+//the checks tar makes on a name, done before anything of the entry is written so a refused name leaves nothing behind
+fn check_name(name: &Path) -> io::Result<()> {
+    if name.components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir)) {
+        return Err(io::Error::other(format!("{} is not a relative path inside the archive", name.display())));
+    }
+    //longer names go into a GNU long name entry, which takes anything that passed the check above
+    if name.as_os_str().len() < 100 {
+        Header::new_gnu().set_path(name)?;
+    }
+    Ok(())
+}
+
+//streams files into the archive so a read error mid-file doesn't abort the whole backup
+pub struct FileAppender {
+    mode: HeaderMode,
+    pub partial: Vec<PartialRead>,
+    //uid and gid to name lookups, a backup sees the same few owners over and over
+    user_names: HashMap<u32, Option<String>>,
+    group_names: HashMap<u32, Option<String>>,
+    accounts: Accounts,
+    //names written so far, a name is only ever written once per archive
+    emitted: EmittedPaths,
+    //store extended attributes and file capabilities as PAX records
+    xattrs: bool,
+    //store POSIX ACLs as PAX records
+    acls: bool,
+    //SQLite databases found so far, archived from a snapshot where possible
+    pub databases: DatabaseSnapshots,
+    //(dev, inode) of files with more than one link, and the name and checksum their content was written under
+    hard_links: HashMap<(u64, u64), StoredContent>,
+    pub hard_links_stored: u64,
+    //names and sizes of what was written, for the archive's manifest
+    pub listing: Option<FileListing>,
+    //the checksum of the file just written, hashed on its way into the archive when there is a listing
+    last_sum: Option<(PathBuf, String)>,
+    //the file just written, when it could only be read partway
+    last_partial: Option<(PathBuf, PartialFile)>,
+}
+
+//the name a file's content was first written under, its checksum and how far it was read
+type StoredContent = (PathBuf, Option<String>, Option<PartialFile>);
+
+impl FileAppender {
+    pub fn new(reproducible: bool) -> Self {
+        FileAppender {
+            mode: if reproducible { HeaderMode::Deterministic } else { HeaderMode::Complete },
+            partial: Vec::new(),
+            user_names: HashMap::new(),
+            group_names: HashMap::new(),
+            accounts: Accounts::System,
+            emitted: EmittedPaths::default(),
+            xattrs: false,
+            acls: false,
+            databases: DatabaseSnapshots::default(),
+            hard_links: HashMap::new(),
+            hard_links_stored: 0,
+            listing: None,
+            last_sum: None,
+            last_partial: None,
+        }
+    }
+
+    pub fn with_listing(mut self, listing: FileListing) -> Self {
+        self.listing = Some(listing);
+        self
+    }
+
+    //an entry made it into the archive
+    pub fn list(&mut self, name: &Path, size: u64) -> io::Result<()> {
+        let sum = self.last_sum.take().filter(|(hashed, _)| hashed == name).map(|(_, sum)| sum);
+        let partial = self.last_partial.take().filter(|(read, _)| read == name).map(|(_, partial)| partial);
+        match &mut self.listing {
+            Some(listing) => listing.push(name, size, sum.as_deref(), partial),
+            None => Ok(()),
+        }
+    }
+
+    //reading attributes costs a few syscalls per entry, so it can be left out on large trees
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    pub fn with_acls(mut self, acls: bool) -> Self {
+        self.acls = acls;
+        self
+    }
+
+    //look owner names up somewhere other than this system, for backups of a mounted root
+    pub fn with_accounts(mut self, accounts: Accounts) -> Self {
+        self.accounts = accounts;
+        self
+    }
+
+    //owner names let restores map ownership by name on machines with different ids
+    //reproducible archives store uid 0, so they don't get names either
+    fn set_owner_names(&mut self, header: &mut Header, metadata: &fs::Metadata) {
+        if self.mode == HeaderMode::Deterministic {
+            return;
+        }
+
+        let accounts = &self.accounts;
+        let user = self.user_names.entry(metadata.uid()).or_insert_with(|| accounts.user_name(metadata.uid()));
+        if let Some(name) = user {
+            //names longer than the header field are simply left out
+            let _ = header.set_username(name);
+        }
+        let group = self.group_names.entry(metadata.gid()).or_insert_with(|| accounts.group_name(metadata.gid()));
+        if let Some(name) = group {
+            let _ = header.set_groupname(name);
+        }
+    }
+
+    //a PAX header in front of the entry, the way GNU tar --xattrs --acls stores them
+    fn append_pax_records<W: Write>(&self, archive: &mut Builder<W>, path: &Path) -> io::Result<()> {
+        let attrs = if self.xattrs { xattrs::read(path) } else { Vec::new() };
+        let acls = if self.acls { acl::read(path) } else { Vec::new() };
+        if attrs.is_empty() && acls.is_empty() {
+            return Ok(());
+        }
+
+        let mut records = xattrs::pax_records(&attrs);
+        records.extend(acls.iter().map(|(key, text)| (key.to_string(), text.as_bytes())));
+        archive.append_pax_extensions(records.iter().map(|(key, value)| (key.as_str(), *value)))
+    }
+
+    //false when the name is in the archive already, a different file under it is warned about
+    //the name is checked first, after this only writing is left and a failure there is fatal
+    fn first_emission(&mut self, name: &Path, path: &Path, metadata: &fs::Metadata) -> io::Result<bool> {
+        check_name(name)?;
+        match self.emitted.record(name, path, metadata)? {
+            Emission::New => Ok(true),
+            Emission::Duplicate => Ok(false),
+            Emission::Collision { first } => {
+                println!(
+                    "{}",
+                    format!("Warning: {} and {} would both be stored as {}, only the first is kept", first.display(), path.display(), name.display()).yellow()
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    //an entry that was left out after all, so a later file may still be stored under its name
+    pub fn forget(&mut self, name: &Path) {
+        self.emitted.forget(name);
+    }
+
+    //both return false for entries left out because their name was written already
+    pub fn append_dir<W: Write>(&mut self, archive: &mut Builder<W>, name: &Path, path: &Path) -> io::Result<bool> {
+        let metadata = fs::metadata(path)?;
+        if !self.first_emission(name, path, &metadata)? {
+            return Ok(false);
+        }
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, self.mode);
+        self.set_owner_names(&mut header, &metadata);
+
+        self.append_pax_records(archive, path).map_err(cut_short)?;
+        archive.append_data(&mut header, name, io::empty()).map_err(cut_short)?;
+        Ok(true)
+    }
+
+    //the link itself with its target, whether or not the target exists
+    pub fn append_symlink<W: Write>(&mut self, archive: &mut Builder<W>, name: &Path, path: &Path) -> io::Result<bool> {
+        let metadata = fs::symlink_metadata(path)?;
+        let target = fs::read_link(path)?;
+        if !self.first_emission(name, path, &metadata)? {
+            return Ok(false);
+        }
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, self.mode);
+        self.set_owner_names(&mut header, &metadata);
+
+        self.append_pax_records(archive, path).map_err(cut_short)?;
+        archive.append_link(&mut header, name, &target).map_err(cut_short)?;
+        Ok(true)
+    }
+
+    //a FIFO or device node as the header alone, opening a FIFO would block until something writes to it
+    pub fn append_special<W: Write>(&mut self, archive: &mut Builder<W>, name: &Path, path: &Path) -> io::Result<bool> {
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        let entry_type = if file_type.is_fifo() {
+            EntryType::Fifo
+        } else if file_type.is_char_device() {
+            EntryType::Char
+        } else if file_type.is_block_device() {
+            EntryType::Block
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a FIFO or device node"));
+        };
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, self.mode);
+        header.set_entry_type(entry_type);
+        header.set_size(0);
+        header.set_device_major(libc::major(metadata.rdev()))?;
+        header.set_device_minor(libc::minor(metadata.rdev()))?;
+        if !self.first_emission(name, path, &metadata)? {
+            return Ok(false);
+        }
+        self.set_owner_names(&mut header, &metadata);
+
+        self.append_pax_records(archive, path).map_err(cut_short)?;
+        archive.append_data(&mut header, name, io::empty()).map_err(cut_short)?;
+        Ok(true)
+    }
+
+    //like Builder::append_file, but unreadable bytes are stored as zeros and recorded
+    //SQLite databases are read through a snapshot, a raw copy of a busy one may be mid-transaction
+    pub fn append_file<W: Write>(&mut self, archive: &mut Builder<W>, name: &Path, path: &Path, file: &mut File) -> io::Result<bool> {
+        let metadata = file.metadata()?;
+        if let Some((first, sum, partial)) = self.hard_links.get(&(metadata.dev(), metadata.ino())).cloned() {
+            let appended = self.append_hard_link(archive, name, path, &metadata, &first)?;
+            self.last_sum = sum.map(|sum| (name.to_path_buf(), sum));
+            self.last_partial = partial.map(|partial| (name.to_path_buf(), partial));
+            return Ok(appended);
+        }
+        if let Some((database, database_name)) = sqlite::sidecar_of(path, name) {
+            if self.database_covers_sidecar(archive, &database_name, &database)? {
+                self.databases.sidecars_skipped += 1;
+                return Ok(false);
+            }
+        }
+        if !sqlite::is_database(file, metadata.len()) {
+            return self.append_contents(archive, name, path, &metadata, file);
+        }
+
+        let snapshot_path = sqlite::snapshot_path();
+        sqlite::remove_snapshot(&snapshot_path);
+        let result = match sqlite::snapshot(path, &snapshot_path) {
+            Ok(()) => {
+                let appended = self.append_contents(archive, name, path, &metadata, &mut File::open(&snapshot_path)?);
+                if appended.as_ref().is_ok_and(|appended| *appended) {
+                    self.databases.snapshotted.push(path.to_path_buf());
+                }
+                appended
+            }
+            Err(reason) => {
+                let appended = self.append_contents(archive, name, path, &metadata, file);
+                if appended.as_ref().is_ok_and(|appended| *appended) {
+                    self.databases.raw_copies.push((path.to_path_buf(), reason));
+                }
+                appended
+            }
+        };
+        sqlite::remove_snapshot(&snapshot_path);
+        result
+    }
+
+    //a further name of a file whose content is in the archive already, restored as a hard link to it
+    fn append_hard_link<W: Write>(&mut self, archive: &mut Builder<W>, name: &Path, path: &Path, metadata: &fs::Metadata, first: &Path) -> io::Result<bool> {
+        if !self.first_emission(name, path, metadata)? {
+            return Ok(false);
+        }
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(metadata, self.mode);
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        self.set_owner_names(&mut header, metadata);
+
+        archive.append_link(&mut header, name, first).map_err(cut_short)?;
+        self.hard_links_stored += 1;
+        Ok(true)
+    }
+
+    //an old WAL restored next to a snapshot would be replayed over it, so a snapshotted database's sidecars are left out
+    //commits in WAL mode only touch the -wal, so an incremental can meet it without the database, which is snapshotted then
+    fn database_covers_sidecar<W: Write>(&mut self, archive: &mut Builder<W>, name: &Path, database: &Path) -> io::Result<bool> {
+        if let Some(snapshotted) = self.databases.snapshotted(database) {
+            return Ok(snapshotted);
+        }
+        let mut file = match File::open(database) {
+            Ok(file) => file,
+            Err(_) => return Ok(false),
+        };
+        if !sqlite::is_database(&file, file.metadata()?.len()) {
+            return Ok(false);
+        }
+        self.append_file(archive, name, database, &mut file)?;
+        Ok(self.databases.snapshotted(database) == Some(true))
+    }
+
+    //the entry gets the original file's name, owner and times, its size is whatever `content` holds
+    fn append_contents<W: Write>(&mut self, archive: &mut Builder<W>, name: &Path, path: &Path, metadata: &fs::Metadata, content: &mut File) -> io::Result<bool> {
+        let size = content.metadata()?.len();
+        if !self.first_emission(name, path, metadata)? {
+            return Ok(false);
+        }
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(metadata, self.mode);
+        header.set_size(size);
+        self.set_owner_names(&mut header, metadata);
+
+        self.append_pax_records(archive, path).map_err(cut_short)?;
+        let mut reader = HashingReader {
+            inner: TolerantReader::new(content, size),
+            hasher: self.listing.as_ref().map(|_| Sha256::new()),
+        };
+        archive.append_data(&mut header, name, &mut reader).map_err(cut_short)?;
+        let sum = reader.hasher.map(|hasher| format!("{:x}", hasher.finalize()));
+        let reader = reader.inner;
+        let partial = reader.error.as_ref().map(|_| PartialFile { read: reader.read, error_offset: reader.read });
+        if metadata.nlink() > 1 {
+            self.hard_links.insert((metadata.dev(), metadata.ino()), (name.to_path_buf(), sum.clone(), partial.clone()));
+        }
+        self.last_sum = sum.map(|sum| (name.to_path_buf(), sum));
+        self.last_partial = partial.map(|partial| (name.to_path_buf(), partial));
+
+        if let Some(error) = reader.error {
+            println!("{}", format!("Warning: {} was only partially read: {}", path.display(), error).yellow());
+            self.partial.push(PartialRead {
+                path: path.to_path_buf(),
+                read: reader.read,
+                size,
+                error,
+            });
+        }
+
+        Ok(true)
+    }
+
+    pub fn report(&self) {
+        if self.hard_links_stored > 0 {
+            println!("Hard links stored as links to the first name instead of another copy: {}", self.hard_links_stored);
+        }
+        self.databases.report();
+        if self.emitted.duplicates > 0 {
+            println!("Entries skipped because overlapping sources reached them twice: {}", self.emitted.duplicates);
+        }
+        if !self.emitted.collisions.is_empty() {
+            println!("{}", format!("{} names were reached with different content, the first file was kept:", self.emitted.collisions.len()).yellow());
+            for collision in &self.emitted.collisions {
+                println!("  {}: {} (kept), {}", collision.name.display(), collision.first.display(), collision.second.display());
+            }
+        }
+        if self.partial.is_empty() {
+            return;
+        }
+
+        println!("{}", format!("{} files were only partially read, the missing bytes are stored as zeros:", self.partial.len()).yellow());
+        for partial in &self.partial {
+            println!(
+                "  {}: partially read - {} of {} bytes, I/O error at offset {} ({})",
+                partial.path.display(), partial.read, partial.size, partial.read, partial.error
+            );
+        }
+    }
+}
+
+//yields exactly `size` bytes: the file content up to the first error or early EOF, then zeros
+//this keeps the entry length in line with its header, which the tar stream depends on
+struct TolerantReader<R> {
+    inner: R,
+    remaining: u64,
+    read: u64,
+    error: Option<String>,
+}
+
+impl<R: Read> TolerantReader<R> {
+    fn new(inner: R, size: u64) -> Self {
+        TolerantReader { inner, remaining: size, read: 0, error: None }
+    }
+}
+
+impl<R: Read> Read for TolerantReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let limit = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        if limit == 0 {
+            return Ok(0);
+        }
+
+        if self.error.is_none() {
+            match self.inner.read(&mut buf[..limit]) {
+                Ok(0) => self.error = Some("file shrank while it was being read".to_string()),
+                Ok(n) => {
+                    self.read += n as u64;
+                    self.remaining -= n as u64;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+                Err(e) => self.error = Some(e.to_string()),
+            }
+        }
+
+        buf[..limit].fill(0);
+        self.remaining -= limit as u64;
+        Ok(limit)
+    }
+}
+
+//hashes what goes into the archive, so a file is read once for both, zeros stored for unreadable bytes included
+struct HashingReader<R> {
+    inner: R,
+    hasher: Option<Sha256>,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //reads some bytes, then fails like a bad sector would
+    struct FailingReader {
+        data: Vec<u8>,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::Error::other("Input/output error"));
+            }
+            let n = buf.len().min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn read_errors_are_padded_to_the_declared_size() {
+        let mut reader = TolerantReader::new(FailingReader { data: vec![7; 1000] }, 4096);
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+
+        assert_eq!(content.len(), 4096);
+        assert!(content[..1000].iter().all(|&b| b == 7));
+        assert!(content[1000..].iter().all(|&b| b == 0));
+        assert_eq!(reader.read, 1000);
+        assert!(reader.error.unwrap().contains("Input/output error"));
+    }
+
+    #[test]
+    fn entries_after_a_partial_file_stay_readable() {
+        let mut archive = Builder::new(Vec::new());
+
+        let mut header = Header::new_gnu();
+        header.set_size(4096);
+        header.set_mode(0o644);
+        let mut reader = TolerantReader::new(FailingReader { data: vec![1; 100] }, 4096);
+        archive.append_data(&mut header, "bad.bin", &mut reader).unwrap();
+
+        let mut header = Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        archive.append_data(&mut header, "next.txt", &b"hello"[..]).unwrap();
+
+        let data = archive.into_inner().unwrap();
+        let mut archive = tar::Archive::new(&data[..]);
+        let mut entries = archive.entries().unwrap();
+        assert_eq!(entries.next().unwrap().unwrap().size(), 4096);
+
+        let mut next = entries.next().unwrap().unwrap();
+        let mut content = String::new();
+        next.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello");
+    }
+
+    //takes a few bytes, then reports a full disk
+    struct FullWriter {
+        room: usize,
+    }
+
+    impl Write for FullWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::Error::other("No space left on device"));
+            }
+            let n = buf.len().min(self.room);
+            self.room -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_refused_name_leaves_nothing_in_the_stream() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("file.txt");
+        fs::write(&path, "content").unwrap();
+        //an attribute puts a PAX header in front of the entry, on filesystems that have them
+        let _ = xattr::set(&path, "user.comment", b"kept");
+
+        let mut appender = FileAppender::new(true).with_xattrs(true);
+        let mut archive = Builder::new(Vec::new());
+        assert!(appender.append_file(&mut archive, Path::new("../file.txt"), &path, &mut File::open(&path).unwrap()).is_err());
+        assert!(appender.append_dir(&mut archive, Path::new("/abs"), temp.path()).is_err());
+        assert!(archive.get_ref().is_empty());
+
+        //the refused name never counted as written, so the file still goes in under a good one
+        assert!(appender.append_file(&mut archive, Path::new("file.txt"), &path, &mut File::open(&path).unwrap()).unwrap());
+        let data = archive.into_inner().unwrap();
+        let names: Vec<_> = tar::Archive::new(&data[..]).entries().unwrap().map(|e| e.unwrap().path().unwrap().into_owned()).collect();
+        assert_eq!(names, vec![PathBuf::from("file.txt")]);
+    }
+
+    #[test]
+    fn an_entry_cut_off_halfway_is_fatal() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("file.txt");
+        fs::write(&path, vec![1u8; 4096]).unwrap();
+
+        let mut appender = FileAppender::new(true);
+        let mut archive = Builder::new(FullWriter { room: 600 });
+        let error = appender.append_file(&mut archive, Path::new("file.txt"), &path, &mut File::open(&path).unwrap()).unwrap_err();
+        assert!(is_cut_short(&error));
+
+        //one that fails before writing is only skipped
+        let error = appender.append_file(&mut archive, Path::new("../file.txt"), &path, &mut File::open(&path).unwrap()).unwrap_err();
+        assert!(!is_cut_short(&error));
+    }
+}
@@ -1,174 +1,245 @@
-use crate::utils::{self, BackupMetadata, BackupType};
+use crate::appender::{self, FileAppender};
+use crate::catalog::{self, CatalogEntry, RunStatus};
+use crate::check;
+use crate::compressibility;
+use crate::compression::{ArchiveCompression, ArchiveEncoder, CompressionFormat};
+use crate::config::{self, Config, Profile};
+use crate::disks::DiskLayout;
+use crate::encryption::{self, ArchiveOutput, Encryption};
+use crate::entry_index;
+use crate::estimate;
+use crate::filter::FileFilter;
+use crate::hangup;
+use crate::lock;
+use crate::manifest::{FileListing, Manifest};
+use crate::paths;
+use crate::pause::PauseControl;
+use crate::portability::{self, Portability};
+use crate::presets::{self, Preset};
+use crate::prompt::{self, Confirm, Input, MultiSelect, Select};
+use crate::restore_script::{self, ScriptOptions};
+use crate::selector::{EntryKind, FileSelector};
+use crate::services::{self, ServiceRecord, Strategy};
+use crate::sidecars;
+use crate::space::{self, LowSpacePolicy, SpaceMonitor};
+use crate::status;
+use crate::sysinfo::{self, SystemInfo};
+use crate::users::{self, Accounts, UserAccount};
+use crate::utils::{self, BackupMetadata, BackupType, ChangeDetection, FileChange, MetadataStore};
 use colored::*;
-use dialoguer::{theme::ColorfulTheme, Input, MultiSelect, Select};
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use glob::glob;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::fs::{self, File};
-use std::io;
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::time::{Instant, SystemTime};
-use tar::Builder;
+use std::time::{Duration, Instant, SystemTime};
+use tar::{Builder, HeaderMode};
 use walkdir::WalkDir;
 
-pub fn backup_selected_directories() -> Result<(), Box<dyn std::error::Error>> {
+//bytes compressed to estimate the archive size when the destination caps file sizes
+const SIZE_CHECK_SAMPLE: u64 = 16 * 1024 * 1024;
+
+//how to back up, independent of what is backed up and where it goes
+pub struct BackupSettings {
+    pub backup_type: BackupType,
+    pub compression: ArchiveCompression,
+    //reproducible archives are byte-identical for identical content
+    pub reproducible: bool,
+    //skip files not modified within this many seconds, with the text the user entered
+    pub max_age: Option<(u64, String)>,
+    //what a run does when the destination runs low on space, None asks in interactive runs and aborts otherwise
+    pub low_space: Option<LowSpacePolicy>,
+    //skip files larger than this many bytes, with the text the user entered
+    pub max_size: Option<(u64, String)>,
+    //write the paths of the files skipped for their size here
+    pub list_skipped: Option<PathBuf>,
+    //exclusion presets whose patterns are among the exclusions, for the summary
+    pub presets: Vec<&'static Preset>,
+    //decide what changed by blake3 content hash instead of timestamps
+    pub hash_check: bool,
+    pub encryption: Option<Encryption>,
+    //store extended attributes and file capabilities
+    pub xattrs: bool,
+    //store POSIX ACLs
+    pub acls: bool,
+    //archive what symlinks point to instead of the links themselves
+    pub follow_symlinks: bool,
+    //leave out the contents of directories with a .nobackup file
+    pub nobackup_markers: bool,
+    //leave out the contents of cache directories tagged with a CACHEDIR.TAG
+    pub cache_dirs: bool,
+    //don't cross into filesystems mounted below a source
+    pub one_file_system: bool,
+    //queue behind a running backup of the same chain for this long, None fails right away
+    pub wait_for_lock: Option<Duration>,
+    //free space the archive must leave on the destination, with the text the user entered
+    pub min_free_after: Option<(u64, String)>,
+    //whether features plain tar can't extract are allowed, from config.toml
+    pub portability: Portability,
+    //read the finished archive back from start to end, --verify on the command line, the menu asks instead
+    pub check_archive: bool,
+    //write backup.tar.gz.index, where every entry starts, gzip and uncompressed archives only
+    pub entry_index: bool,
+    //keep going when the terminal closes, runs that never ask anything do by default
+    pub survive_hangup: bool,
+}
+
+impl BackupSettings {
+    //.tar.gz and friends, with .age or .gpg appended for encrypted archives
+    pub fn archive_extension(&self) -> String {
+        match &self.encryption {
+            Some(encryption) => format!("{}{}", self.compression.format.extension(), encryption.extension()),
+            None => self.compression.format.extension().to_string(),
+        }
+    }
+
+    //None for the built-in encryption format, no shell tool can decrypt it
+    pub fn script_options(&self) -> Option<ScriptOptions> {
+        let options = ScriptOptions::for_format(self.compression.format);
+        match self.encryption {
+            Some(Encryption::Builtin(_)) => None,
+            Some(Encryption::Gpg(_)) => Some(options.gpg_encrypted()),
+            Some(_) => Some(options.encrypted()),
+            None => Some(options),
+        }
+    }
+}
+
+//everything a backup run needs, gathered from the prompts or the command line
+pub struct BackupJob {
+    pub sources: Vec<PathBuf>,
+    pub exclusions: Vec<PathBuf>,
+    pub settings: BackupSettings,
+    pub output_path: PathBuf,
+    pub existing_output: ExistingOutput,
+    //false for command line runs, every question is answered with its default
+    pub interactive: bool,
+    //drop the archive without asking when no files were archived
+    pub skip_empty: bool,
+    //the profile the job comes from, its chain is kept under the profile's name instead of the directories
+    pub profile: Option<String>,
+    //sources that did not exist and were left out with the user's agreement, noted in the catalog
+    pub skipped_sources: Vec<PathBuf>,
+    //a system mounted here is backed up, sources and exclusions already point below it
+    //entries are stored as if it was mounted at /
+    pub root: Option<PathBuf>,
+    //running database services below the sources, dumped or stopped around the walk
+    pub services: Vec<services::Quiesce>,
+    //save partition tables, LUKS headers, RAID and LVM metadata for a bare-metal restore, root only
+    pub disk_layout: bool,
+}
+
+//directories the system backup covers, the last two typically need root access
+pub fn system_sources(is_root: bool) -> Vec<PathBuf> {
+    let mut system_dirs = vec![PathBuf::from("/home"), PathBuf::from("/etc"), PathBuf::from("/usr/local")];
+    if is_root {
+        system_dirs.extend([PathBuf::from("/var"), PathBuf::from("/opt")]);
+    }
+    system_dirs
+}
+
+//directories to exclude from full backup
+pub const SYSTEM_EXCLUSIONS: [&str; 13] = [
+    "/proc", "/sys", "/tmp", "/run", "/mnt", "/media",
+    "/lost+found", "/dev", "/var/log", "/var/cache",
+    "/var/tmp", "/root", "/home/*/.cache",
+];
+
+pub fn backup_selected_directories() -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
     println!("{}", "\n---- Backup Selected Directories ----".blue().bold());
 
-    //list of common home directories
-    let home_dir = dirs::home_dir().expect("Could not find home directory");
-    let mut options = vec![
-        format!("{}", home_dir.join("Documents").display()),
-        format!("{}", home_dir.join("Pictures").display()),
-        format!("{}", home_dir.join("Videos").display()),
-        format!("{}", home_dir.join("Music").display()),
-        format!("{}", home_dir.join("Downloads").display()),
-        format!("{}", home_dir.join(".config").display()),
-        format!("{}", home_dir.join(".local/share").display()),
-        "/etc".to_string(),
-    ];
+    //quick-pick directories from config.toml, XDG user-dirs or the defaults
+    let config = config::load_config()?;
+    let mut options = config::quick_dirs(&config);
     
     //add "Custom directory" option
     options.push("Enter a custom directory path".to_string());
     
-    println!("{}", "IMPORTANT: Use SPACEBAR to select directories, then press ENTER to confirm".yellow().bold());
+    if !prompt::is_plain() {
+        println!("{}", "IMPORTANT: Use SPACEBAR to select directories, then press ENTER to confirm".yellow().bold());
+    }
     
     //ask user to select directories with clear instructions
-    let selection = MultiSelect::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select directories to backup (SPACEBAR to select, ENTER to confirm)")
+    let selection = MultiSelect::new()
+        .with_prompt("Select directories to backup")
         .items(&options)
         .interact()?;
     
-    let mut selected_dirs: Vec<String> = Vec::new();
+    let mut selected_dirs: Vec<PathBuf> = Vec::new();
     
     //process selected directories
     for i in selection {
         if i == options.len() - 1 {
             //custom directory was selected
-            let custom_path: String = Input::with_theme(&ColorfulTheme::default())
+            let custom_path: String = Input::new()
                 .with_prompt("Enter custom directory path")
                 .interact_text()?;
                 
+            let custom_path = utils::expand_tilde(custom_path.trim());
             if !custom_path.is_empty() {
                 if Path::new(&custom_path).exists() {
-                    selected_dirs.push(custom_path);
+                    offer_quick_dir_promotion(&config, &custom_path)?;
+                    selected_dirs.push(PathBuf::from(custom_path));
                 } else {
                     println!("{}", format!("Warning: Path does not exist: {}", custom_path).yellow());
                 }
             }
         } else {
-            selected_dirs.push(options[i].clone());
+            selected_dirs.push(PathBuf::from(&options[i]));
         }
     }
 
     if selected_dirs.is_empty() {
         println!("{}", "No directories selected, returning to main menu.".yellow());
-        return Ok(());
+        return Ok(None);
     }
 
-    println!("Selected directories: {:?}", selected_dirs);
+    //drop duplicates and directories already covered by another selection
+    let selected_dirs = utils::normalize_sources(&selected_dirs);
 
-    //ask for backup type
-    let backup_types = vec!["Full", "Incremental", "Differential"];
-    let selected_type = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select backup type")
-        .default(0)
-        .items(&backup_types)
-        .interact()?;
-        
-    let backup_type = match selected_type {
-        0 => BackupType::Full,
-        1 => BackupType::Incremental,
-        2 => BackupType::Differential,
-        _ => BackupType::Full,
+    let (selected_dirs, skipped_sources) = match confirm_missing_sources(selected_dirs)? {
+        Some(dirs) => dirs,
+        None => return Ok(None),
     };
 
-    //ask for compression level
-    let compression_levels = vec!["Fast (1)", "Default (6)", "Best (9)"];
-    let selected_level = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select compression level")
-        .default(1)
-        .items(&compression_levels)
-        .interact()?;
+    let names: Vec<String> = selected_dirs.iter().map(|dir| dir.display().to_string()).collect();
+    println!("Selected directories: {}", names.join(", "));
+    announce_symlinked_sources(&selected_dirs);
+    warn_long_paths(&selected_dirs, config.max_path_length.unwrap_or(config::DEFAULT_MAX_PATH_LENGTH), false);
 
-    let compression = match selected_level {
-        0 => Compression::fast(),
-        1 => Compression::default(),
-        2 => Compression::best(),
-        _ => Compression::default(),
-    };
+    //optionally trim the largest subdirectories before committing to the backup
+    let mut exclusions = review_largest_directories(&selected_dirs)?;
+    let (patterns, presets) = prompt_exclusion_patterns()?;
+    exclusions.extend(patterns);
 
-    //ask for output file location
-    let default_name = format!("backup_{}.tar.gz", chrono::Local::now().format("%Y%m%d_%H%M%S"));
-    let output: String = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("Enter output file name")
-        .default(default_name)
-        .interact_text()?;
+    let mut settings = prompt_settings()?;
+    settings.presets = presets;
+    check_compressibility(&selected_dirs, &exclusions, &mut settings, ApplyRecommendation::Ask)?;
 
-    //create absolute path for output
-    let output_path = if Path::new(&output).is_absolute() {
-        PathBuf::from(output)
-    } else {
-        std::env::current_dir()?.join(output)
+    //ask for output file location
+    let default_name = format!("backup_{}{}", chrono::Local::now().format("%Y%m%d_%H%M%S"), settings.archive_extension());
+    let (output_path, existing_output) = match prompt_output_path(default_name)? {
+        Some(output) => output,
+        None => return Ok(None),
     };
-    
-    //ensure parent directory exists
-    if let Some(parent) = output_path.parent() {
-        fs::create_dir_all(parent)?;
-    }
-    
-    //create output file
-    let file = File::create(&output_path)?;
-    let encoder = GzEncoder::new(file, compression);
-    let mut archive = Builder::new(encoder);
-
-    //load or create backup metadata
-    let metadata_path = home_dir.join(utils::METADATA_DIR);
-    fs::create_dir_all(&metadata_path)?;
-    
-    let mut metadata = utils::load_backup_metadata(&metadata_path)?;
-
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_secs();
-    
-    if metadata.original_backup_time.is_none() {
-        metadata.original_backup_time = Some(current_time);
-    }
-
-    let start_time = Instant::now();
-    
-    //process each selected directory based on backup type
-    for dir in selected_dirs {
-        match backup_type {
-            BackupType::Full => {
-                backup_directory(&mut archive, &dir)?;
-            },
-            BackupType::Incremental => {
-                incremental_backup(&mut archive, &dir, &metadata, current_time)?;
-            },
-            BackupType::Differential => {
-                differential_backup(&mut archive, &dir, &metadata, current_time)?;
-            }
-        }
-    }
-
-    //finish the archive
-    archive.finish()?;
-    
-    //update metadata
-    metadata.last_backup_time = Some(current_time);
-    utils::save_backup_metadata(&metadata_path, &metadata)?;
-    
-    let duration = start_time.elapsed();
-    println!("\n{}", "Backup completed!".green().bold());
-    println!("Time taken: {:.2} seconds", duration.as_secs_f64());
-    println!("Backup saved to: {}", output_path.display().to_string().green());
 
-    Ok(())
+    run_backup(BackupJob {
+        sources: selected_dirs,
+        exclusions,
+        settings,
+        output_path,
+        existing_output,
+        interactive: true,
+        skip_empty: false,
+        profile: None,
+        skipped_sources,
+        root: None,
+        services: Vec::new(),
+        disk_layout: false,
+    })
 }
 
-pub fn backup_system() -> Result<(), Box<dyn std::error::Error>> {
+pub fn backup_system() -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
     println!("{}", "\n---- Backup System ----".blue().bold());
     
     //check if running as root
@@ -179,86 +250,262 @@ pub fn backup_system() -> Result<(), Box<dyn std::error::Error>> {
         println!("For a complete system backup, consider running the program with sudo.");
         
         let options = vec!["Continue without root", "Return to main menu"];
-        let selection = Select::with_theme(&ColorfulTheme::default())
+        let selection = Select::new()
             .with_prompt("What would you like to do?")
             .default(0)
             .items(&options)
             .interact()?;
             
         if selection == 1 {
-            return Ok(());
+            return Ok(None);
         }
     }
 
-    //ask for backup type
-    let backup_types = vec!["Full", "Incremental", "Differential"];
-    let selected_type = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select backup type")
-        .default(0)
-        .items(&backup_types)
-        .interact()?;
-        
-    let backup_type = match selected_type {
-        0 => BackupType::Full,
-        1 => BackupType::Incremental,
-        2 => BackupType::Differential,
-        _ => BackupType::Full,
-    };
+    //root can back up every user into their own archive instead of one combined one
+    if is_root {
+        let scopes = vec!["Whole system (one archive)", "Each user separately (one archive per user)"];
+        let scope = Select::new()
+            .with_prompt("What should be backed up?")
+            .default(0)
+            .items(&scopes)
+            .interact()?;
 
-    //ask for compression level
-    let compression_levels = vec!["Fast (1)", "Default (6)", "Best (9)"];
-    let selected_level = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select compression level")
-        .default(1)
-        .items(&compression_levels)
-        .interact()?;
+        if scope == 1 {
+            return backup_each_user().map(|_| None);
+        }
+    }
 
-    let compression = match selected_level {
-        0 => Compression::fast(),
-        1 => Compression::default(),
-        2 => Compression::best(),
-        _ => Compression::default(),
+    let (system_dirs, skipped_sources) = match confirm_missing_sources(system_sources(is_root))? {
+        Some(dirs) => dirs,
+        None => return Ok(None),
     };
 
+    let config = config::load_config()?;
+    warn_long_paths(&system_dirs, config.max_path_length.unwrap_or(config::DEFAULT_MAX_PATH_LENGTH), true);
+    let services = services::plan(&system_dirs, &config.services.clone().unwrap_or_default(), true)?;
+    let disk_layout = is_root
+        && Confirm::new()
+            .with_prompt("Save the disk layout (partition tables, LUKS headers, RAID and LVM metadata) for a bare-metal restore?")
+            .default(false)
+            .interact()?;
+
+    let exclusions: Vec<PathBuf> = SYSTEM_EXCLUSIONS.iter().map(PathBuf::from).collect();
+    let mut settings = prompt_settings()?;
+    settings.one_file_system = true;
+    check_compressibility(&system_dirs, &exclusions, &mut settings, ApplyRecommendation::Ask)?;
+
     //ask for output file location
-    let default_name = format!("system_backup_{}.tar.gz", chrono::Local::now().format("%Y%m%d_%H%M%S"));
-    let output: String = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("Enter output file name")
-        .default(default_name)
-        .interact_text()?;
+    let default_name = format!("system_backup_{}{}", chrono::Local::now().format("%Y%m%d_%H%M%S"), settings.archive_extension());
+    let (output_path, existing_output) = match prompt_output_path(default_name)? {
+        Some(output) => output,
+        None => return Ok(None),
+    };
 
-    //create absolute path for output
-    let output_path = if Path::new(&output).is_absolute() {
-        PathBuf::from(output)
-    } else {
-        std::env::current_dir()?.join(output)
+    run_backup(BackupJob {
+        sources: system_dirs,
+        exclusions,
+        settings,
+        output_path,
+        existing_output,
+        interactive: true,
+        skip_empty: false,
+        profile: None,
+        skipped_sources,
+        root: None,
+        services,
+        disk_layout,
+    })
+}
+
+//a named profile from config.toml, the whole backup runs without a question
+pub fn run_profile(name: &str) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    println!("{}", format!("\n---- Profile {} ----", name).blue().bold());
+    let config = config::load_config()?;
+    let job = ProfileJob::resolve(Some(name), &config.profile(name)?, &config)?;
+    warn_long_paths(&job.sources, config.max_path_length.unwrap_or(config::DEFAULT_MAX_PATH_LENGTH), job.settings.one_file_system);
+    job.run(false)
+}
+
+//a profile resolved against this machine, ready to look at or to run
+//None for directories backed up with the defaults of a profile
+pub struct ProfileJob {
+    pub profile: Option<String>,
+    pub sources: Vec<PathBuf>,
+    pub exclusions: Vec<PathBuf>,
+    pub settings: BackupSettings,
+    pub output_dir: PathBuf,
+    //unattended runs with nothing to back up leave no archive unless the profile says otherwise
+    pub skip_empty: bool,
+}
+
+impl ProfileJob {
+    pub fn resolve(name: Option<&str>, profile: &Profile, config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let dirs: Vec<PathBuf> = profile.dirs.iter().map(|dir| utils::absolute_path(Path::new(dir))).collect();
+        let sources = require_sources(utils::normalize_sources(&dirs))?;
+        let settings = profile_settings(profile, config)?;
+        let mut exclusions = presets::patterns(&settings.presets);
+        exclusions.extend(utils::exclusion_patterns(&profile.excludes.iter().map(PathBuf::from).collect::<Vec<_>>()));
+        let output_dir = match &profile.output_dir {
+            Some(dir) => utils::absolute_path(Path::new(dir)),
+            None => std::env::current_dir()?,
+        };
+        Ok(ProfileJob { profile: name.map(str::to_string), sources, exclusions, settings, output_dir, skip_empty: profile.skip_empty.unwrap_or(true) })
+    }
+
+    //the archive is named after the profile and the time, in the profile's output directory
+    pub fn run(self, interactive: bool) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+        fs::create_dir_all(&self.output_dir)?;
+        let name = format!("{}_{}{}", self.profile.as_deref().unwrap_or("backup"), chrono::Local::now().format("%Y%m%d_%H%M%S"), self.settings.archive_extension());
+        let (output_path, existing_output) = resolve_output_path(&self.output_dir.join(name).display().to_string(), None)?;
+
+        run_backup(BackupJob {
+            sources: self.sources,
+            exclusions: self.exclusions,
+            settings: self.settings,
+            output_path,
+            existing_output,
+            interactive,
+            skip_empty: self.skip_empty,
+            profile: self.profile,
+            skipped_sources: Vec::new(),
+            root: None,
+            services: Vec::new(),
+            disk_layout: false,
+        })
+    }
+}
+
+//the settings of a merged profile, anything it leaves out as a selective backup from the command line would
+pub fn profile_settings(profile: &Profile, config: &Config) -> Result<BackupSettings, Box<dyn std::error::Error>> {
+    let backup_type = match profile.backup_type.as_deref().unwrap_or("full") {
+        "full" => BackupType::Full,
+        "incremental" => BackupType::Incremental,
+        "differential" => BackupType::Differential,
+        other => return Err(format!("Unknown backup_type {} in the profile, use full, incremental or differential", other).into()),
     };
-    
-    //ensure parent directory exists
-    if let Some(parent) = output_path.parent() {
-        fs::create_dir_all(parent)?;
+    let format = match profile.compression.as_deref().unwrap_or("gzip") {
+        "gzip" => CompressionFormat::Gzip,
+        "zstd" => CompressionFormat::Zstd,
+        "xz" => CompressionFormat::Xz,
+        "none" => CompressionFormat::Store,
+        other => return Err(format!("Unknown compression {} in the profile, use gzip, zstd, xz or none", other).into()),
+    };
+    let mut compression = ArchiveCompression::new(format);
+    if let Some(level) = profile.compression_level {
+        compression = compression.with_level(level)?;
     }
 
-    //create output file
-    let file = File::create(&output_path)?;
-    let encoder = GzEncoder::new(file, compression);
-    let mut archive = Builder::new(encoder);
+    Ok(BackupSettings {
+        backup_type,
+        compression,
+        reproducible: false,
+        max_age: None,
+        max_size: None,
+        list_skipped: None,
+        presets: profile.presets.iter().map(|preset| presets::parse(preset)).collect::<Result<_, _>>()?,
+        hash_check: false,
+        encryption: None,
+        xattrs: true,
+        acls: false,
+        follow_symlinks: false,
+        nobackup_markers: true,
+        cache_dirs: true,
+        one_file_system: false,
+        wait_for_lock: lock::configured_wait(None, config)?,
+        min_free_after: profile.min_free_after.clone().map(|size| utils::parse_size(&size).map(|bytes| (bytes, size))).transpose()?,
+        portability: config.portability.unwrap_or_default(),
+        check_archive: false,
+        entry_index: false,
+        survive_hangup: true,
+        low_space: None,
+    })
+}
 
-    let start_time = Instant::now();
-    
-    //directories to exclude from full backup
-    let exclude_dirs = vec![
-        "/proc", "/sys", "/tmp", "/run", "/mnt", "/media", 
-        "/lost+found", "/dev", "/var/log", "/var/cache",
-        "/var/tmp", "/root", "/home/*/.cache",
-    ];
-    
-    //home directory gets different backup types
-    let home_dir = dirs::home_dir().expect("Could not find home directory");
-    let metadata_path = home_dir.join(utils::METADATA_DIR);
+//--list-skipped, a list that can't be written doesn't fail a finished backup
+pub fn list_skipped(selector: &FileSelector, settings: &BackupSettings, append: bool) {
+    if let Some(list) = &settings.list_skipped {
+        match selector.write_oversized(list, append) {
+            Ok(()) => println!("Files skipped for their size listed in {}", list.display()),
+            Err(e) => println!("{}", format!("Warning: Could not write the skipped files to {}: {}", list.display(), e).yellow()),
+        }
+    }
+}
+
+//build the archive for a job, this is where the menu and the command line meet
+//returns the archive written, None when the run ended without one
+pub fn run_backup(job: BackupJob) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    //load or create backup metadata, an offline system keeps its chain in its own root's state directory
+    let metadata_path = match &job.root {
+        Some(root) => paths::offline_state_dir(root),
+        None => paths::state_dir(),
+    };
     fs::create_dir_all(&metadata_path)?;
-    
-    let mut metadata = utils::load_backup_metadata(&metadata_path)?;
+
+    //the outcome shows in the status command until the next run
+    let result = write_backup(job, &metadata_path);
+    match &result {
+        Ok(Some(_)) => status::clear_failure(&metadata_path),
+        Ok(None) => {}
+        Err(e) => status::record_failure(&metadata_path, &e.to_string()),
+    }
+    result
+}
+
+fn write_backup(mut job: BackupJob, metadata_path: &Path) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    let settings = &job.settings;
+
+    //one backup per chain at a time, a queued run may find its work done by the one it waited for
+    let lock = lock::acquire(metadata_path, lock::operation(&settings.backup_type), settings.wait_for_lock)?;
+    let mut store = utils::load_metadata_store(metadata_path)?;
+    let key = utils::job_key(job.profile.as_deref(), &job.sources);
+    if !store.jobs.contains_key(&key) && store.jobs.contains_key(utils::LEGACY_JOB) {
+        println!("{}", "Continuing the backup chain recorded before chains were kept per set of directories, other jobs start their own.".yellow());
+    }
+    let mut metadata = store.job(&key);
+    if lock.superseded(&settings.backup_type, metadata.last_backup_time) {
+        println!("{}", "The backup that just finished covers this run, skipping it. Later changes go into the next backup.".yellow());
+        return Ok(None);
+    }
+
+    if !check_file_size_limit(&job.sources, &job.exclusions, settings, &job.output_path, job.interactive)? {
+        return Ok(None);
+    }
+    if !check_free_space_floor(&job.sources, &mut job.exclusions, settings, &job.output_path, &metadata, job.interactive)? {
+        return Ok(None);
+    }
+
+    //write to a separate file so an existing backup survives a failed run
+    let partial_path = sidecars::partial(&job.output_path);
+    let output = ArchiveOutput::new(File::create(&partial_path)?, settings.encryption.as_ref())?;
+    let mut archive = create_archive(output, settings.compression, settings.reproducible)?;
+
+    //record where this backup came from so restores can warn about mismatches
+    let root = job.root.as_deref().unwrap_or(Path::new("/"));
+    let origin = match &job.root {
+        Some(root) => {
+            let sources: Vec<PathBuf> = job.sources.iter().map(|s| archived_path(root, s)).collect();
+            SystemInfo::of_root(root, &sources)
+        }
+        None => SystemInfo::current(&job.sources),
+    };
+    let disk_layout = if job.disk_layout { Some(DiskLayout::capture()?) } else { None };
+    let mut origin = SystemInfo {
+        compression: Some(settings.compression.label()),
+        disk_layout: disk_layout.as_ref().map(|layout| layout.files.clone()).unwrap_or_default(),
+        portability: Some(settings.portability),
+        manifest: true,
+        ..origin
+    };
+    if settings.reproducible {
+        origin = origin.without_free_space();
+    }
+    sysinfo::append_to_archive(&mut archive, &origin, utils::entry_mtime(settings.reproducible))?;
+    //right after the descriptor, so --show-disk-layout doesn't have to read the whole archive
+    if let Some(layout) = &disk_layout {
+        layout.append_to_archive(&mut archive, utils::entry_mtime(settings.reproducible))?;
+    }
+    portability::append_readme(&mut archive, settings, utils::entry_mtime(settings.reproducible))?;
+
     let current_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)?
         .as_secs();
@@ -266,523 +513,2172 @@ pub fn backup_system() -> Result<(), Box<dyn std::error::Error>> {
     if metadata.original_backup_time.is_none() {
         metadata.original_backup_time = Some(current_time);
     }
+
+    //a last backup time in the future makes every incremental come out empty
+    if metadata.last_backup_time.is_some_and(|t| t > current_time) {
+        println!("{}", "Warning: The last backup time is in the future, check the system clock. Incremental backups will miss changes until it has passed.".yellow());
+    }
+
+    //the change detection policy is fixed for the whole chain once chosen
+    if !matches!(settings.backup_type, BackupType::Full) && metadata.change_detection.is_none() {
+        metadata.change_detection = Some(choose_change_detection(job.interactive)?);
+    }
+
+    let start_time = Instant::now();
+    let mut pause = PauseControl::listen();
+    if settings.survive_hangup {
+        hangup::survive();
+    }
+    if job.interactive {
+        println!("Send SIGUSR1 to process {} to pause the backup, SIGUSR2 to resume it.", std::process::id());
+    }
+
+    //dumps go in before the walk, the stopped services are handled by the selector as it gets to them
+    let dumped: Vec<ServiceRecord> = job.services.iter().filter(|plan| plan.strategy == Strategy::Dump).map(|plan| services::dump(plan, &mut archive, utils::entry_mtime(settings.reproducible))).collect();
+    let stopped = job.services.iter().filter(|plan| plan.strategy == Strategy::Stop).map(|plan| (PathBuf::from(plan.service.data_dir), plan.unit.clone())).collect();
+
+    let mut total_files = 0;
+    let mut selector = file_selector(&job.exclusions, settings, &metadata, current_time).stop_services(stopped);
+    let mut appender = FileAppender::new(settings.reproducible).with_xattrs(settings.xattrs).with_acls(settings.acls).with_listing(FileListing::new()?);
+    if let Some(root) = &job.root {
+        appender = appender.with_accounts(Accounts::of_root(root));
+    }
+
+    let mut monitor = space_monitor(job.output_path.parent().unwrap_or(Path::new("/")), settings, job.interactive)?;
     
-    match backup_type {
-        BackupType::Full => {
-            //backup home directory with exclusions
-            backup_with_exclusions(&mut archive, "/home", &exclude_dirs)?;
-            
-            //backup important system directories
-            backup_with_exclusions(&mut archive, "/etc", &exclude_dirs)?;
-            backup_with_exclusions(&mut archive, "/usr/local", &exclude_dirs)?;
-            
-            if is_root {
-                //these directories typically need root access
-                backup_with_exclusions(&mut archive, "/var", &exclude_dirs)?;
-                backup_with_exclusions(&mut archive, "/opt", &exclude_dirs)?;
-            }
-        },
-        BackupType::Incremental => {
-            //incremental backup for home with exclusions
-            incremental_backup_with_exclusions(&mut archive, "/home", &exclude_dirs, &metadata, current_time)?;
-            
-            //important system directories
-            incremental_backup_with_exclusions(&mut archive, "/etc", &exclude_dirs, &metadata, current_time)?;
-            incremental_backup_with_exclusions(&mut archive, "/usr/local", &exclude_dirs, &metadata, current_time)?;
-            
-            if is_root {
-                incremental_backup_with_exclusions(&mut archive, "/var", &exclude_dirs, &metadata, current_time)?;
-                incremental_backup_with_exclusions(&mut archive, "/opt", &exclude_dirs, &metadata, current_time)?;
-            }
-        },
-        BackupType::Differential => {
-            //differential backup for home with exclusions
-            differential_backup_with_exclusions(&mut archive, "/home", &exclude_dirs, &metadata, current_time)?;
-            
-            //important system directories
-            differential_backup_with_exclusions(&mut archive, "/etc", &exclude_dirs, &metadata, current_time)?;
-            differential_backup_with_exclusions(&mut archive, "/usr/local", &exclude_dirs, &metadata, current_time)?;
-            
-            if is_root {
-                differential_backup_with_exclusions(&mut archive, "/var", &exclude_dirs, &metadata, current_time)?;
-                differential_backup_with_exclusions(&mut archive, "/opt", &exclude_dirs, &metadata, current_time)?;
-            }
+    //process each source directory based on backup type
+    for dir in &job.sources {
+        if monitor.aborted {
+            break;
+        }
+
+        match settings.backup_type {
+            BackupType::Full => println!("Backing up directory: {}", dir.display()),
+            BackupType::Incremental => println!("Performing incremental backup of: {}", dir.display()),
+            BackupType::Differential => println!("Performing differential backup of: {}", dir.display()),
         }
+        total_files += archive_source(&mut archive, dir, root, &mut selector, &mut appender, &mut monitor, &mut pause)?;
     }
-    
+
+    //an aborted run must not advance the metadata, not even the copy inside the archive
+    //the snapshot changes on every run, so reproducible archives leave it out
+    //the deletions are part of the archive's content, so reproducible archives keep them
+    let mut deleted = 0;
+    let manifest = manifest_for(settings, &job.sources.iter().map(|source| archived_path(root, source)).collect::<Vec<_>>(), &job.exclusions, &metadata, current_time);
+    if !monitor.aborted {
+        metadata.last_backup_time = Some(current_time);
+        deleted = record_deletions(&mut archive, &job.sources, root, settings, &selector)?;
+        update_history(&mut metadata, settings, &mut selector);
+        if !settings.reproducible {
+            utils::append_state_to_archive(&mut archive, &metadata)?;
+        }
+    }
+    appender.databases.append_to_archive(&mut archive, utils::entry_mtime(settings.reproducible))?;
+    let records = services::records_for(&job.services, dumped, &selector.take_stops());
+    services::append_to_archive(&mut archive, &records, utils::entry_mtime(settings.reproducible))?;
+    //last, so an archive that was cut short is told apart by its missing manifest
+    let mut listing = appender.listing.take().filter(|_| !monitor.aborted);
+    if let Some(listing) = &mut listing {
+        listing.append_to_archive(&mut archive, manifest, utils::entry_mtime(settings.reproducible))?;
+    }
+
     //finish the archive
-    archive.finish()?;
-    
-    //update metadata
-    metadata.last_backup_time = Some(current_time);
-    utils::save_backup_metadata(&metadata_path, &metadata)?;
-    
+    archive.into_inner()?.finish()?.finish()?;
+
+    if monitor.aborted {
+        report_low_space_abort(&partial_path, &monitor);
+        return Err(format!("The destination ran low on free space, the backup stopped early and is incomplete: {}", partial_path.display()).into());
+    }
+    monitor.report_skipped();
+
+    //the archive goes into place first, failing to record the run afterwards can't cost the user the archive
+    let empty = total_files == 0 && deleted == 0;
+    let discarded = empty && discard_empty_backup(&partial_path, &job)?;
+    if !discarded {
+        let moved = finalize_archive(&partial_path, &job.output_path, &job.existing_output)?;
+        if let Some((_, rotated)) = moved.last() {
+            println!("Previous backup kept as: {}", rotated.display());
+            catalog::archives_moved(metadata_path, &moved);
+        }
+    }
+    store.record(&key, metadata);
+    record_metadata(metadata_path, &store, &key, Some(job.output_path.as_path()).filter(|_| !discarded));
+    if !discarded {
+        if let Some(listing) = &mut listing {
+            save_sums(listing, &job.output_path);
+        }
+        save_entry_index(settings, &job.output_path);
+    }
+
+    //a run that wrote nothing is listed too, a schedule that keeps finding nothing shows up in the history
     let duration = start_time.elapsed();
-    println!("\n{}", "Backup completed!".green().bold());
-    println!("Time taken: {:.2} seconds", duration.as_secs_f64());
-    println!("Backup saved to: {}", output_path.display().to_string().green());
+    catalog::record(metadata_path, CatalogEntry {
+        time: current_time,
+        archive: utils::absolute_path(&job.output_path),
+        size: if discarded { 0 } else { fs::metadata(&job.output_path).map_or(0, |metadata| metadata.len()) },
+        backup_type: settings.backup_type.clone(),
+        sources: job.sources.clone(),
+        skipped_sources: job.skipped_sources.clone(),
+        files: total_files,
+        duration: duration.as_secs_f64(),
+        job: key,
+        status: if empty { RunStatus::Empty } else { RunStatus::Completed },
+    });
+    if discarded {
+        return Ok(None);
+    }
+    if empty {
+        println!("\n{}", "Backup completed, but no files were archived.".yellow().bold());
+    } else {
+        println!("\n{}", "Backup completed!".green().bold());
+    }
+    println!("Files archived: {}", total_files);
+    if deleted > 0 {
+        println!("Deleted files recorded: {} (restore --apply-deletions removes them again)", deleted);
+    }
+    selector.report();
+    list_skipped(&selector, settings, false);
+    appender.report();
+    print_duration(duration, pause.paused_for);
+    println!("Backup saved to: {}", job.output_path.display().to_string().green());
+
+    if wants_check(settings, job.interactive)? {
+        check::check_written(&job.output_path, settings.encryption.as_ref())?;
+    }
+    if job.interactive {
+        if let Some(options) = settings.script_options() {
+            offer_restore_script(&job.output_path, options)?;
+        }
+    }
+    hangup::report_finished();
+
+    Ok(Some(job.output_path))
+}
+
+//the path a file below `root` is stored under, as if the root was mounted at /
+fn archived_path(root: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix(root) {
+        Ok(relative) => Path::new("/").join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+//print which stage of the selection includes or leaves out a path, without archiving anything
+pub fn explain_selection(sources: &[PathBuf], exclusions: &[PathBuf], settings: &BackupSettings, root: Option<&Path>, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata_dir = match root {
+        Some(root) => paths::offline_state_dir(root),
+        None => paths::state_dir(),
+    };
+    let metadata = utils::load_metadata_store(&metadata_dir)?.job(&utils::job_key(None, sources));
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    //with a root, /etc/fstab means the offline system's /etc/fstab
+    let path = match root {
+        Some(root) if !path.starts_with(root) => utils::under_root(root, path),
+        _ => utils::absolute_path(path),
+    };
+    let mut selector = file_selector(exclusions, settings, &metadata, current_time);
+    let trace = selector.trace(sources, &path);
+
+    println!("{}", format!("\n---- Why: {} ----", path.display()).blue().bold());
+    for (stage, message) in &trace.steps {
+        println!("  {:<12} {}", stage, message);
+    }
 
+    if trace.included {
+        println!("{}", "Included in the backup.".green().bold());
+    } else {
+        println!("{}", "Not included in the backup.".red().bold());
+    }
     Ok(())
 }
 
-fn backup_directory(archive: &mut Builder<GzEncoder<File>>, dir_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Backing up directory: {}", dir_path);
-    
-    let path = Path::new(dir_path);
-    if !path.exists() {
-        println!("{}", format!("Warning: Path does not exist: {}", dir_path).yellow());
+//one archive and one metadata file per regular user, so their backups stay separate
+fn backup_each_user() -> Result<(), Box<dyn std::error::Error>> {
+    let users = users::regular_users();
+    if users.is_empty() {
+        println!("{}", "No regular users with a home directory were found.".yellow());
         return Ok(());
     }
-    
-    let total_files = WalkDir::new(dir_path).into_iter().count();
-    let progress = ProgressBar::new(total_files as u64);
-    progress.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
-        .unwrap()
-        .progress_chars("#>-"));
-    
-    for entry in WalkDir::new(dir_path) {
-        match entry {
-            Ok(entry) => {
-                let path = entry.path();
-                let name = path.strip_prefix("/").unwrap_or(path);
-                
-                if path.is_file() {
-                    match File::open(path) {
-                        Ok(mut file) => {
-                            archive.append_file(name, &mut file)?;
-                        }
-                        Err(e) => {
-                            println!("{}", format!("Warning: Could not open file {}: {}", path.display(), e).yellow());
-                        }
-                    }
-                } else if path.is_dir() && entry.depth() > 0 {
-                    archive.append_dir(name, path)?;
-                }
-                
-                progress.inc(1);
-            }
+
+    let names: Vec<&str> = users.iter().map(|u| u.name.as_str()).collect();
+    println!("Users to back up: {}", names.join(", "));
+
+    let mut settings = prompt_settings()?;
+    settings.one_file_system = true;
+
+    let output: String = Input::new()
+        .with_prompt("Enter output directory")
+        .default(std::env::current_dir()?.display().to_string())
+        .interact_text()?;
+    let output_dir = PathBuf::from(utils::expand_tilde(&output));
+
+    run_per_user_backup(&users, &settings, &output_dir, true)
+}
+
+//back up each user in turn, one failing user does not stop the others
+pub fn run_per_user_backup(users: &[UserAccount], settings: &BackupSettings, output_dir: &Path, interactive: bool) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(output_dir)?;
+
+    let start_time = Instant::now();
+    let mut change_detection = None;
+    let mut monitor = space_monitor(output_dir, settings, interactive)?;
+    let mut pause = PauseControl::listen();
+    if settings.survive_hangup {
+        hangup::survive();
+    }
+    let mut results = Vec::new();
+    //every user's skipped files are appended to one list
+    if let Some(list) = &settings.list_skipped {
+        File::create(list)?;
+    }
+
+    for user in users {
+        println!("\n{}", format!("Backing up user {}", user.name).blue().bold());
+
+        let result = backup_user(user, settings, output_dir, interactive, &mut change_detection, &mut monitor, &mut pause);
+        match &result {
+            Ok(_) => status::clear_failure(&paths::user_state_dir(user)),
             Err(e) => {
-                println!("{}", format!("Warning: Error accessing entry: {}", e).yellow());
+                println!("{}", format!("Backup of {} failed: {}", user.name, e).red());
+                status::record_failure(&paths::user_state_dir(user), &e.to_string());
             }
         }
+        results.push((user, result.map_err(|e| e.to_string())));
+
+        //a full destination affects every user that is still left
+        if monitor.aborted {
+            break;
+        }
     }
-    
-    progress.finish();
-    Ok(())
-}
 
-fn incremental_backup(
-    archive: &mut Builder<GzEncoder<File>>, 
-    dir_path: &str,
-    metadata: &BackupMetadata,
-    current_time: u64
-) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Performing incremental backup of: {}", dir_path);
-    
-    let path = Path::new(dir_path);
-    if !path.exists() {
-        println!("{}", format!("Warning: Path does not exist: {}", dir_path).yellow());
-        return Ok(());
+    println!("\n{}", "Per-user backup summary:".bold());
+    let mut succeeded = Vec::new();
+    for (user, result) in &results {
+        match result {
+            Ok((path, files)) => {
+                println!("  {}: {} files -> {}", user.name.green(), files, path.display());
+                succeeded.push(path.clone());
+            }
+            Err(e) => println!("  {}: {}", user.name.red(), e),
+        }
     }
-    
-    let last_backup_time = metadata.last_backup_time.unwrap_or(0);
-    let progress = ProgressBar::new_spinner();
-    progress.set_style(ProgressStyle::default_spinner()
-        .template("{spinner:.green} {msg}")
-        .unwrap());
-    
-    progress.set_message(format!("Checking for changes in {} since last backup", dir_path));
-    
-    let mut files_backed_up = 0;
-    
-    for entry in WalkDir::new(dir_path) {
-        match entry {
-            Ok(entry) => {
-                let path = entry.path();
-                
-                if path.is_file() {
-                    //check if file was modified since last backup
-                    if let Ok(metadata) = path.metadata() {
-                        if let Ok(modified) = metadata.modified() {
-                            if let Ok(modified_secs) = modified.duration_since(SystemTime::UNIX_EPOCH) {
-                                if modified_secs.as_secs() > last_backup_time {
-                                    let name = path.strip_prefix("/").unwrap_or(path);
-                                    progress.set_message(format!("Adding {}", path.display()));
-                                    
-                                    match File::open(path) {
-                                        Ok(mut file) => {
-                                            archive.append_file(name, &mut file)?;
-                                            files_backed_up += 1;
-                                        }
-                                        Err(e) => {
-                                            println!("{}", format!("Warning: Could not open file {}: {}", path.display(), e).yellow());
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                } else if path.is_dir() && entry.depth() > 0 {
-                    //only add directories that are new
-                    if let Ok(dir_metadata) = path.metadata() {
-                        if let Ok(created) = dir_metadata.created() {
-                            if let Ok(created_secs) = created.duration_since(SystemTime::UNIX_EPOCH) {
-                                if created_secs.as_secs() > last_backup_time {
-                                    let name = path.strip_prefix("/").unwrap_or(path);
-                                    archive.append_dir(name, path)?;
-                                }
-                            }
-                        }
-                    }
+    for user in users.iter().skip(results.len()) {
+        println!("  {}: not started, the destination ran low on space", user.name.yellow());
+    }
+
+    println!("{} of {} users backed up", succeeded.len(), users.len());
+    print_duration(start_time.elapsed(), pause.paused_for);
+
+    let mut unreadable = 0;
+    if !succeeded.is_empty() && wants_check(settings, interactive)? {
+        for path in &succeeded {
+            if let Err(e) = check::check_written(path, settings.encryption.as_ref()) {
+                println!("{}", e.to_string().red());
+                unreadable += 1;
+            }
+        }
+    }
+
+    let script_options = settings.script_options();
+    if let (true, Some(options)) = (interactive && !succeeded.is_empty(), &script_options) {
+        let generate = Confirm::new()
+            .with_prompt("Generate restore scripts next to the archives?")
+            .default(true)
+            .interact()?;
+
+        if generate {
+            for path in &succeeded {
+                if let Err(e) = restore_script::write_restore_script(path, options) {
+                    println!("{}", format!("Warning: Could not write restore script for {}: {}", path.display(), e).yellow());
                 }
             }
-            Err(e) => {
-                println!("{}", format!("Warning: Error accessing entry: {}", e).yellow());
+        }
+    }
+    hangup::report_finished();
+
+    if succeeded.len() < users.len() {
+        return Err(format!("{} of {} user backups did not complete", users.len() - succeeded.len(), users.len()).into());
+    }
+    if unreadable > 0 {
+        return Err(format!("{} of {} user archives did not read back completely", unreadable, users.len()).into());
+    }
+
+    Ok(())
+}
+
+//back up a single home directory into {hostname}_{user}_{date}.tar.gz (or .tar.zst/.tar.xz), metadata stays in that home
+fn backup_user(
+    user: &UserAccount,
+    settings: &BackupSettings,
+    output_dir: &Path,
+    interactive: bool,
+    change_detection: &mut Option<ChangeDetection>,
+    monitor: &mut SpaceMonitor,
+    pause: &mut PauseControl
+) -> Result<(PathBuf, u64), Box<dyn std::error::Error>> {
+    let home = user.home.clone();
+    let start_time = Instant::now();
+    let output_path = output_dir.join(format!(
+        "{}_{}_{}{}",
+        sysinfo::hostname(),
+        user.name,
+        chrono::Local::now().format("%Y%m%d_%H%M%S"),
+        settings.archive_extension()
+    ));
+
+    let mut exclusions = vec![home.join(".cache")];
+    exclusions.extend(presets::patterns(&settings.presets));
+    if !check_file_size_limit(std::slice::from_ref(&home), &exclusions, settings, &output_path, interactive)? {
+        return Err("the archive would exceed the destination's file size limit".into());
+    }
+
+    let partial_path = sidecars::partial(&output_path);
+    let output = ArchiveOutput::new(File::create(&partial_path)?, settings.encryption.as_ref())?;
+    let mut archive = create_archive(output, settings.compression, settings.reproducible)?;
+    let mut origin = SystemInfo { compression: Some(settings.compression.label()), portability: Some(settings.portability), manifest: true, ..SystemInfo::current(std::slice::from_ref(&home)) };
+    if settings.reproducible {
+        origin = origin.without_free_space();
+    }
+    sysinfo::append_to_archive(&mut archive, &origin, utils::entry_mtime(settings.reproducible))?;
+    portability::append_readme(&mut archive, settings, utils::entry_mtime(settings.reproducible))?;
+
+    let metadata_path = paths::user_state_dir(user);
+    fs::create_dir_all(&metadata_path)?;
+    let _lock = lock::acquire(&metadata_path, lock::operation(&settings.backup_type), settings.wait_for_lock)?;
+
+    let mut store = utils::load_metadata_store(&metadata_path)?;
+    let key = utils::job_key(None, std::slice::from_ref(&home));
+    let mut metadata = store.job(&key);
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    if metadata.original_backup_time.is_none() {
+        metadata.original_backup_time = Some(current_time);
+    }
+
+    if metadata.last_backup_time.is_some_and(|t| t > current_time) {
+        println!("{}", format!("Warning: The last backup time for {} is in the future, check the system clock.", user.name).yellow());
+    }
+
+    //asked at most once per run, users that already have a policy keep theirs
+    if !matches!(settings.backup_type, BackupType::Full) && metadata.change_detection.is_none() {
+        if change_detection.is_none() {
+            *change_detection = Some(choose_change_detection(interactive)?);
+        }
+        metadata.change_detection = change_detection.clone();
+    }
+
+    let mut selector = file_selector(&exclusions, settings, &metadata, current_time);
+    let mut appender = FileAppender::new(settings.reproducible).with_xattrs(settings.xattrs).with_acls(settings.acls).with_listing(FileListing::new()?);
+
+    let total_files = archive_source(&mut archive, &home, Path::new("/"), &mut selector, &mut appender, monitor, pause)?;
+
+    let manifest = manifest_for(settings, std::slice::from_ref(&home), &exclusions, &metadata, current_time);
+    if !monitor.aborted {
+        metadata.last_backup_time = Some(current_time);
+        record_deletions(&mut archive, std::slice::from_ref(&home), Path::new("/"), settings, &selector)?;
+        update_history(&mut metadata, settings, &mut selector);
+        if !settings.reproducible {
+            utils::append_state_to_archive(&mut archive, &metadata)?;
+        }
+    }
+    appender.databases.append_to_archive(&mut archive, utils::entry_mtime(settings.reproducible))?;
+    let mut listing = appender.listing.take().filter(|_| !monitor.aborted);
+    if let Some(listing) = &mut listing {
+        listing.append_to_archive(&mut archive, manifest, utils::entry_mtime(settings.reproducible))?;
+    }
+
+    archive.into_inner()?.finish()?.finish()?;
+
+    if monitor.aborted {
+        report_low_space_abort(&partial_path, monitor);
+        return Err("destination is low on free space, archive is incomplete".into());
+    }
+    monitor.report_skipped();
+    selector.report();
+    list_skipped(&selector, settings, true);
+    appender.report();
+    finalize_archive(&partial_path, &output_path, &ExistingOutput::Replace)?;
+    if let Some(listing) = &mut listing {
+        save_sums(listing, &output_path);
+    }
+    save_entry_index(settings, &output_path);
+
+    //the user owns their metadata and archive, so they can run their own restores
+    store.record(&key, metadata);
+    let stub = record_metadata(&metadata_path, &store, &key, Some(&output_path));
+    catalog::record(&metadata_path, CatalogEntry {
+        time: current_time,
+        archive: output_path.clone(),
+        size: fs::metadata(&output_path).map_or(0, |metadata| metadata.len()),
+        backup_type: settings.backup_type.clone(),
+        sources: vec![home.clone()],
+        skipped_sources: Vec::new(),
+        files: total_files,
+        duration: start_time.elapsed().as_secs_f64(),
+        job: key,
+        status: if total_files == 0 { RunStatus::Empty } else { RunStatus::Completed },
+    });
+    //directories below the home may have just been created by root, so they are included too
+    let handed_over = metadata_path
+        .ancestors()
+        .take_while(|dir| *dir != user.home)
+        .map(Path::to_path_buf)
+        .chain([metadata_path.join(utils::METADATA_FILE), output_path.clone()])
+        .chain(Some(metadata_path.join(utils::PREVIOUS_METADATA_FILE)).filter(|path| path.exists()))
+        .chain(Some(metadata_path.join(catalog::CATALOG_FILE)).filter(|path| path.exists()))
+        .chain(Some(sidecars::checksum(&output_path)).filter(|path| path.exists()))
+        .chain(Some(sidecars::entry_index(&output_path)).filter(|path| path.exists()))
+        .chain(stub)
+        .filter(|path| path.exists());
+    for path in handed_over {
+        if let Err(e) = users::chown(&path, user) {
+            println!("{}", format!("Warning: Could not hand {} over to {}: {}", path.display(), user.name, e).yellow());
+        }
+    }
+
+    Ok((output_path, total_files))
+}
+
+//incremental runs compare against the last backup, differential ones against the first
+pub fn file_selector(exclusions: &[PathBuf], settings: &BackupSettings, metadata: &BackupMetadata, current_time: u64) -> FileSelector {
+    let mut selector = FileSelector::new(exclusions, FileFilter::new(settings.max_age.clone(), current_time).with_max_size(settings.max_size.clone())).follow_symlinks(settings.follow_symlinks).nobackup_markers(settings.nobackup_markers).cache_dirs(settings.cache_dirs).one_file_system(settings.one_file_system).presets(&settings.presets);
+    selector = match settings.hash_check {
+        true => selector.verify_hashes(metadata.backup_history.clone()),
+        false => selector.previous_history(metadata.backup_history.clone()),
+    };
+    let policy = metadata.change_detection.clone().unwrap_or_default();
+
+    match settings.backup_type {
+        BackupType::Full => selector,
+        BackupType::Incremental => selector.changed_since(metadata.last_backup_time.unwrap_or(0), policy),
+        BackupType::Differential => selector.changed_since(metadata.original_backup_time.unwrap_or(0), policy),
+    }
+}
+
+//differential runs keep comparing against the files of the backup they build on
+//every other run replaces the history, so a full backup drops the files that are gone
+fn update_history(metadata: &mut BackupMetadata, settings: &BackupSettings, selector: &mut FileSelector) {
+    let current = selector.take_history();
+    if !matches!(settings.backup_type, BackupType::Differential) || metadata.backup_history.is_empty() {
+        metadata.backup_history = current;
+    }
+}
+
+//the manifest's fields, taken before the run advances the chain, the listing adds the counts
+fn manifest_for(settings: &BackupSettings, sources: &[PathBuf], exclusions: &[PathBuf], metadata: &BackupMetadata, current_time: u64) -> Manifest {
+    Manifest {
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        backup_type: settings.backup_type.clone(),
+        created: Some(current_time).filter(|_| !settings.reproducible),
+        changes_since: match settings.backup_type {
+            BackupType::Full => None,
+            BackupType::Incremental => metadata.last_backup_time,
+            BackupType::Differential => metadata.original_backup_time,
+        },
+        sources: sources.iter().map(|source| source.display().to_string()).collect(),
+        exclusions: exclusions.iter().map(|exclusion| exclusion.display().to_string()).collect(),
+        file_count: 0,
+        total_size: 0,
+    }
+}
+
+//files gone since the backup this one builds on, listed in the archive by their archived names
+//a full backup stands on its own and lists nothing
+fn record_deletions<W: Write>(archive: &mut Builder<W>, sources: &[PathBuf], root: &Path, settings: &BackupSettings, selector: &FileSelector) -> io::Result<usize> {
+    if matches!(settings.backup_type, BackupType::Full) {
+        return Ok(0);
+    }
+    let deleted: Vec<PathBuf> = selector.deleted(sources).iter().map(|path| path.strip_prefix(root).unwrap_or(path).to_path_buf()).collect();
+    if !deleted.is_empty() {
+        utils::append_deleted_files(archive, &deleted, utils::entry_mtime(settings.reproducible))?;
+    }
+    Ok(deleted.len())
+}
+
+//backup type, advanced options, compression format and level
+fn prompt_settings() -> Result<BackupSettings, Box<dyn std::error::Error>> {
+    //ask for backup type
+    let backup_types = vec!["Full", "Incremental", "Differential"];
+    let selected_type = Select::new()
+        .with_prompt("Select backup type")
+        .default(0)
+        .items(&backup_types)
+        .interact()?;
+        
+    let backup_type = match selected_type {
+        0 => BackupType::Full,
+        1 => BackupType::Incremental,
+        2 => BackupType::Differential,
+        _ => BackupType::Full,
+    };
+
+    let advanced = prompt_advanced_options()?;
+    let compression = prompt_compression(advanced.reproducible)?;
+    let portability = config::load_config()?.portability.unwrap_or_default();
+    let encryption = prompt_encryption(portability)?;
+
+    Ok(BackupSettings {
+        backup_type,
+        compression,
+        reproducible: advanced.reproducible,
+        max_age: advanced.max_age,
+        max_size: advanced.max_size,
+        list_skipped: None,
+        presets: Vec::new(),
+        low_space: None,
+        hash_check: advanced.hash_check,
+        encryption,
+        xattrs: !advanced.skip_xattrs,
+        acls: advanced.acls,
+        follow_symlinks: advanced.follow_symlinks,
+        nobackup_markers: !advanced.ignore_nobackup,
+        cache_dirs: !advanced.include_caches,
+        //system backups turn this on, a selected directory is taken with whatever is mounted in it
+        one_file_system: false,
+        //scheduled runs queue with --wait-for-lock, the menu only waits when the config says so
+        wait_for_lock: lock::configured_wait(None, &config::load_config()?)?,
+        min_free_after: None,
+        portability,
+        check_archive: false,
+        entry_index: advanced.entry_index,
+        survive_hangup: false,
+    })
+}
+
+//ask for compression format and level, reproducible archives always use the default level
+fn prompt_compression(reproducible: bool) -> Result<ArchiveCompression, Box<dyn std::error::Error>> {
+    let formats = vec![
+        "gzip (.tar.gz, works everywhere)",
+        "zstd (.tar.zst, faster and smaller)",
+        "xz (.tar.xz, smallest, slowest)",
+        "None / store only (.tar, no compression)",
+    ];
+    let selected_format = Select::new()
+        .with_prompt("Select compression format")
+        .default(0)
+        .items(&formats)
+        .interact()?;
+
+    let format = match selected_format {
+        1 => CompressionFormat::Zstd,
+        2 => CompressionFormat::Xz,
+        3 => CompressionFormat::Store,
+        _ => CompressionFormat::Gzip,
+    };
+
+    //store only has no levels to choose from
+    if reproducible || format.levels().len() == 1 {
+        return Ok(ArchiveCompression::new(format));
+    }
+
+    //the labels show the level in the chosen format's own range
+    let levels = format.levels();
+    let mut labels: Vec<&str> = levels.iter().map(|(label, _)| *label).collect();
+    labels.push("Custom…");
+    let selected_level = Select::new()
+        .with_prompt("Select compression level")
+        .default(1)
+        .items(&labels)
+        .interact()?;
+
+    if selected_level == levels.len() {
+        return prompt_custom_level(format);
+    }
+    Ok(ArchiveCompression { level: levels[selected_level].1, ..ArchiveCompression::new(format) })
+}
+
+//any level the format accepts, asked again until it is in range
+fn prompt_custom_level(format: CompressionFormat) -> Result<ArchiveCompression, Box<dyn std::error::Error>> {
+    let range = format.level_range();
+    loop {
+        let level: i32 = Input::new()
+            .with_prompt(format!("Compression level ({} to {})", range.start(), range.end()))
+            .default(format.default_level())
+            .interact_text()?;
+
+        match ArchiveCompression::new(format).with_level(level) {
+            Ok(compression) => return Ok(compression),
+            Err(e) => println!("{}", e.to_string().red()),
+        }
+    }
+}
+
+//archives with keys or other secrets in them shouldn't sit around in plain text
+fn prompt_encryption(portability: Portability) -> Result<Option<Encryption>, Box<dyn std::error::Error>> {
+    let options = vec![
+        "No encryption",
+        "Encrypt with a passphrase",
+        "Encrypt to an age public key",
+        "Encrypt with GPG",
+        "Encrypt with a passphrase, built-in format (restores need neither age nor gpg)",
+    ];
+    let selection = Select::new()
+        .with_prompt("Encrypt the archive?")
+        .default(0)
+        .items(&options)
+        .interact()?;
+
+    match selection {
+        3 => prompt_gpg_recipients(),
+        4 => {
+            portability.require_tar("The built-in encryption format", "Encrypt with a passphrase through age instead")?;
+            Ok(Some(Encryption::Builtin(encryption::prompt_passphrase(true)?)))
+        }
+        1 => Ok(Some(Encryption::Passphrase(encryption::prompt_passphrase(true)?))),
+        2 => loop {
+            let recipient: String = Input::new()
+                .with_prompt("age public key (age1...)")
+                .interact_text()?;
+
+            match encryption::parse_recipient(&recipient) {
+                Ok(recipient) => break Ok(Some(Encryption::Recipients(vec![recipient]))),
+                Err(e) => println!("{}", e.to_string().red()),
             }
+        },
+        _ => Ok(None),
+    }
+}
+
+//pick recipients from the public keys in the keyring, any of them can restore
+fn prompt_gpg_recipients() -> Result<Option<Encryption>, Box<dyn std::error::Error>> {
+    let keys = encryption::gpg_public_keys()?;
+    if keys.is_empty() {
+        println!("{}", "No GPG public keys found, import them with gpg --import first. The archive will not be encrypted.".yellow());
+        return Ok(None);
+    }
+
+    let labels: Vec<String> = keys
+        .iter()
+        .map(|(fingerprint, user)| format!("{} ({})", user, &fingerprint[fingerprint.len().saturating_sub(16)..]))
+        .collect();
+
+    loop {
+        let selection = MultiSelect::new()
+            .with_prompt("Encrypt to these keys")
+            .items(&labels)
+            .interact()?;
+
+        if selection.is_empty() {
+            println!("{}", "Select at least one key.".yellow());
+            continue;
         }
+        return Ok(Some(Encryption::Gpg(selection.into_iter().map(|i| keys[i].0.clone()).collect())));
+    }
+}
+
+//what to do when compressing the sources looks pointless
+pub enum ApplyRecommendation {
+    Ask,
+    //--auto-compress, switch without asking
+    Apply,
+}
+
+//sample the sources and switch to store only or a faster level when most data is compressed already
+//runs before the output name is chosen, so the default name gets the right extension
+pub fn check_compressibility(
+    sources: &[PathBuf],
+    exclusions: &[PathBuf],
+    settings: &mut BackupSettings,
+    apply: ApplyRecommendation
+) -> Result<(), Box<dyn std::error::Error>> {
+    if settings.compression.format == CompressionFormat::Store {
+        return Ok(());
+    }
+
+    let result = compressibility::analyze(sources, exclusions, settings.one_file_system);
+    println!("Compression check: {}", compressibility::describe(&result));
+
+    let recommended = match result.recommendation(settings.compression) {
+        Some(recommended) => recommended,
+        None => return Ok(()),
+    };
+    let label = recommended.label();
+    println!("{}", format!("Recommended: {}, most of this data is compressed already.", label).yellow());
+
+    let accept = match apply {
+        ApplyRecommendation::Ask => Confirm::new()
+            .with_prompt(format!("Use {} instead?", label))
+            .default(true)
+            .interact()?,
+        ApplyRecommendation::Apply => {
+            println!("Using {}.", label);
+            true
+        }
+    };
+
+    if accept {
+        settings.compression = recommended;
     }
-    
-    progress.finish_with_message(format!("Incremental backup of {} completed. {} files backed up.", dir_path, files_backed_up));
-    
     Ok(())
 }
 
-fn differential_backup(
-    archive: &mut Builder<GzEncoder<File>>, 
-    dir_path: &str,
+//options most runs don't need, kept behind a single question
+#[derive(Default)]
+struct AdvancedOptions {
+    reproducible: bool,
+    //skip files not modified within this many seconds, with the text the user entered
+    max_age: Option<(u64, String)>,
+    //skip files larger than this many bytes, with the text the user entered
+    max_size: Option<(u64, String)>,
+    hash_check: bool,
+    skip_xattrs: bool,
+    acls: bool,
+    follow_symlinks: bool,
+    ignore_nobackup: bool,
+    include_caches: bool,
+    entry_index: bool,
+}
+
+fn prompt_advanced_options() -> Result<AdvancedOptions, Box<dyn std::error::Error>> {
+    let configure = Confirm::new()
+        .with_prompt("Configure advanced options?")
+        .default(false)
+        .interact()?;
+
+    if !configure {
+        return Ok(AdvancedOptions::default());
+    }
+
+    //reproducible archives are byte-identical for identical content
+    let reproducible = Confirm::new()
+        .with_prompt("Create a reproducible archive? (file times, ownership and modes are normalized)")
+        .default(false)
+        .interact()?;
+
+    let max_age = loop {
+        let input: String = Input::new()
+            .with_prompt("Skip files not modified within (e.g. 90d, 12w, 1y, empty for no limit)")
+            .allow_empty(true)
+            .interact_text()?;
+
+        if input.trim().is_empty() {
+            break None;
+        }
+
+        match utils::parse_duration(&input) {
+            Ok(seconds) => break Some((seconds, input.trim().to_string())),
+            Err(e) => println!("{}", e.red()),
+        }
+    };
+
+    let max_size = loop {
+        let input: String = Input::new()
+            .with_prompt("Skip files larger than (e.g. 500M, 2G, empty for no limit)")
+            .allow_empty(true)
+            .interact_text()?;
+
+        if input.trim().is_empty() {
+            break None;
+        }
+
+        match utils::parse_size(&input) {
+            Ok(bytes) => break Some((bytes, input.trim().to_string())),
+            Err(e) => println!("{}", e.red()),
+        }
+    };
+
+    let hash_check = Confirm::new()
+        .with_prompt("Verify changes by content hash? (reads changed-looking files, catches restored old timestamps)")
+        .default(false)
+        .interact()?;
+
+    let xattrs = Confirm::new()
+        .with_prompt("Store extended attributes and file capabilities? (SELinux labels, setcap binaries, a bit slower on large trees)")
+        .default(true)
+        .interact()?;
+
+    let acls = Confirm::new()
+        .with_prompt("Store POSIX ACLs? (only needed for directories managed with setfacl)")
+        .default(false)
+        .interact()?;
+
+    let follow_symlinks = Confirm::new()
+        .with_prompt("Follow symlinks? (stores what they point to instead of the links, linked directories are backed up too)")
+        .default(false)
+        .interact()?;
+
+    let nobackup_markers = Confirm::new()
+        .with_prompt("Skip the contents of directories marked with a .nobackup file?")
+        .default(true)
+        .interact()?;
+
+    let cache_dirs = Confirm::new()
+        .with_prompt("Skip the contents of cache directories tagged with a CACHEDIR.TAG? (cargo, ccache, borg and others tag theirs)")
+        .default(true)
+        .interact()?;
+
+    let entry_index = Confirm::new()
+        .with_prompt("Write an entry index next to the archive? (single files come back without reading the whole archive, gzip and uncompressed only)")
+        .default(false)
+        .interact()?;
+
+    Ok(AdvancedOptions { reproducible, max_age, max_size, hash_check, skip_xattrs: !xattrs, acls, follow_symlinks, ignore_nobackup: !nobackup_markers, include_caches: !cache_dirs, entry_index })
+}
+
+//unattended runs use the recommended policy instead of asking
+fn choose_change_detection(interactive: bool) -> Result<ChangeDetection, Box<dyn std::error::Error>> {
+    if interactive {
+        prompt_change_detection()
+    } else {
+        Ok(ChangeDetection::default())
+    }
+}
+
+fn prompt_change_detection() -> Result<ChangeDetection, Box<dyn std::error::Error>> {
+    println!("Note: ctime also changes on chmod/chown, xattr edits and hard link count changes,");
+    println!("so ctime-based detection may back up files whose content did not change.");
+
+    let options = vec![
+        "Content or metadata changed (mtime or ctime, recommended)",
+        "Content changed (mtime only)",
+        "Metadata changed (ctime only)",
+    ];
+    let selection = Select::new()
+        .with_prompt("How should changed files be detected?")
+        .default(0)
+        .items(&options)
+        .interact()?;
+
+    Ok(match selection {
+        1 => ChangeDetection::Mtime,
+        2 => ChangeDetection::Ctime,
+        _ => ChangeDetection::Either,
+    })
+}
+
+//runs without anyone to ask fail on a missing source, naming every one at once
+pub fn require_sources(sources: Vec<PathBuf>) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let missing = utils::missing_sources(&sources);
+    if !missing.is_empty() {
+        let missing: Vec<String> = missing.iter().map(|dir| dir.display().to_string()).collect();
+        return Err(format!("These directories do not exist: {}", missing.join(", ")).into());
+    }
+    if sources.is_empty() {
+        return Err("No directories to back up".into());
+    }
+    Ok(sources)
+}
+
+//the sources to back up and the missing ones that were left out
+type ConfirmedSources = (Vec<PathBuf>, Vec<PathBuf>);
+
+//list every missing source at once and ask whether to continue without them
+fn confirm_missing_sources(sources: Vec<PathBuf>) -> Result<Option<ConfirmedSources>, Box<dyn std::error::Error>> {
+    let missing = utils::missing_sources(&sources);
+    if missing.is_empty() {
+        return Ok(Some((sources, Vec::new())));
+    }
+
+    println!("{}", "Warning: The following paths do not exist:".yellow());
+    for path in &missing {
+        println!("  {}", path.display());
+    }
+
+    let remaining: Vec<PathBuf> = sources.into_iter().filter(|s| !missing.contains(s)).collect();
+    if remaining.is_empty() {
+        println!("{}", "Nothing left to back up.".yellow());
+        return Ok(None);
+    }
+
+    let proceed = Confirm::new()
+        .with_prompt("Continue without the missing paths?")
+        .default(true)
+        .interact()?;
+
+    Ok(if proceed { Some((remaining, missing)) } else { None })
+}
+
+//what happens to a file already at the output path once the new archive is complete
+pub enum ExistingOutput {
+    Replace,
+    Rotate,
+}
+
+//ask for the output file name, and what to do with a file that is already there
+fn prompt_output_path(default_name: String) -> Result<Option<(PathBuf, ExistingOutput)>, Box<dyn std::error::Error>> {
+    loop {
+        let output: String = Input::new()
+            .with_prompt("Enter output file name")
+            .default(default_name.clone())
+            .interact_text()?;
+
+        //create absolute path for output
+        let output_path = if Path::new(&output).is_absolute() {
+            PathBuf::from(output)
+        } else {
+            std::env::current_dir()?.join(output)
+        };
+
+        if let Err(e) = sidecars::check_output_name(&output_path) {
+            println!("{}", e.red());
+            continue;
+        }
+
+        //ensure parent directory exists
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let conflicts = sidecars::conflicts(&output_path);
+        if !conflicts.is_empty() {
+            println!("{}", "These files belong to another backup and would be written over:".yellow());
+            for conflict in &conflicts {
+                println!("  {}", conflict.display());
+            }
+            let replace = Confirm::new()
+                .with_prompt("Write over them?")
+                .default(false)
+                .interact()?;
+            if !replace {
+                continue;
+            }
+        }
+
+        if !output_path.exists() {
+            return Ok(Some((output_path, ExistingOutput::Replace)));
+        }
+
+        let existing = fs::metadata(&output_path)?;
+        let modified = chrono::DateTime::<chrono::Local>::from(existing.modified()?).format("%Y-%m-%d %H:%M");
+        println!("{}", format!("{} already exists ({}, {})", output_path.display(), modified, utils::format_size(existing.len())).yellow());
+
+        let options = vec![
+            "Replace it once the new backup is complete".to_string(),
+            format!("Keep it as {}", sidecars::rotated(&output_path, 1).display()),
+            "Choose a different name".to_string(),
+            "Cancel".to_string(),
+        ];
+        let selection = Select::new()
+            .with_prompt("What would you like to do?")
+            .default(1)
+            .items(&options)
+            .interact()?;
+
+        match selection {
+            0 => return Ok(Some((output_path, ExistingOutput::Replace))),
+            1 => return Ok(Some((output_path, ExistingOutput::Rotate))),
+            2 => continue,
+            _ => return Ok(None),
+        }
+    }
+}
+
+//command line runs refuse to replace an existing file unless told to, --overwrite replaces it and --rotate keeps it as .1
+pub fn resolve_output_path(output: &str, on_existing: Option<ExistingOutput>) -> Result<(PathBuf, ExistingOutput), Box<dyn std::error::Error>> {
+    let output_path = if Path::new(output).is_absolute() {
+        PathBuf::from(output)
+    } else {
+        std::env::current_dir()?.join(output)
+    };
+
+    sidecars::check_output_name(&output_path)?;
+
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let Some(on_existing) = on_existing else {
+        if output_path.exists() {
+            return Err(format!("{} already exists, pass --overwrite to replace it or --rotate to keep it as {}", output_path.display(), sidecars::rotated(&output_path, 1).display()).into());
+        }
+        if let Some(conflict) = sidecars::conflicts(&output_path).first() {
+            return Err(format!("{} belongs to another backup and would be written over, pass --overwrite to replace it", conflict.display()).into());
+        }
+        return Ok((output_path, ExistingOutput::Replace));
+    };
+
+    Ok((output_path, on_existing))
+}
+
+//move the finished archive into place, returns where rotated previous backups went, oldest first
+fn finalize_archive(partial_path: &Path, output_path: &Path, existing: &ExistingOutput) -> std::io::Result<Vec<(PathBuf, PathBuf)>> {
+    let moved = match existing {
+        ExistingOutput::Rotate if output_path.exists() => rotate_existing(output_path)?,
+        _ => Vec::new(),
+    };
+
+    fs::rename(partial_path, output_path)?;
+    Ok(moved)
+}
+
+//shift backup.tar.gz.1 to .2 and so on, then move the current file to .1
+//the checksums, entry index and metadata copy go along, the new archive would write over them otherwise
+fn rotate_existing(output_path: &Path) -> std::io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut last = 1;
+    while sidecars::rotated(output_path, last).exists() {
+        last += 1;
+    }
+
+    let mut moved = Vec::new();
+    for n in (0..last).rev() {
+        let from = if n == 0 { output_path.to_path_buf() } else { sidecars::rotated(output_path, n) };
+        let to = sidecars::rotated(output_path, n + 1);
+        for sidecar in [sidecars::checksum, sidecars::entry_index, sidecars::metadata_stub] {
+            if sidecar(&from).exists() {
+                fs::rename(sidecar(&from), sidecar(&to))?;
+            } else {
+                let _ = fs::remove_file(sidecar(&to));
+            }
+        }
+        fs::rename(&from, &to)?;
+        moved.push((from, to));
+    }
+    Ok(moved)
+}
+
+//any writer works, runs write to the .partial file and tests to memory
+pub fn create_archive<W: Write>(writer: W, compression: ArchiveCompression, reproducible: bool) -> Result<Builder<ArchiveEncoder<W>>, Box<dyn std::error::Error>> {
+    let encoder = ArchiveEncoder::new(writer, compression)?;
+    let mut archive = Builder::new(encoder);
+
+    if reproducible {
+        archive.mode(HeaderMode::Deterministic);
+    }
+
+    Ok(archive)
+}
+
+//report an empty run distinctly and drop the archive unless the user wants to keep it
+fn discard_empty_backup(output_path: &Path, job: &BackupJob) -> Result<bool, Box<dyn std::error::Error>> {
+    let message = match job.settings.backup_type {
+        BackupType::Full => "No files were found to back up.",
+        _ => "No files have changed since the last backup.",
+    };
+    println!("\n{}", message.yellow().bold());
+
+    let keep = if job.skip_empty {
+        false
+    } else if job.interactive {
+        Confirm::new()
+            .with_prompt("Keep the empty archive anyway?")
+            .default(false)
+            .interact()?
+    } else {
+        true
+    };
+
+    if keep {
+        return Ok(false);
+    }
+
+    fs::remove_file(output_path)?;
+    println!("Empty archive removed, nothing was written.");
+    Ok(true)
+}
+
+//reading the archive back takes about as long as decompressing it, the menu asks and the command line needs --verify
+fn wants_check(settings: &BackupSettings, interactive: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    if !interactive {
+        return Ok(settings.check_archive);
+    }
+    Ok(Confirm::new()
+        .with_prompt("Read the archive back to check it is complete?")
+        .default(true)
+        .interact()?)
+}
+
+//optionally write a standalone restore script next to the archive
+fn offer_restore_script(output_path: &Path, options: ScriptOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let generate = Confirm::new()
+        .with_prompt("Generate a restore script next to the archive?")
+        .default(true)
+        .interact()?;
+
+    if generate {
+        match restore_script::write_restore_script(output_path, &options) {
+            Ok(script_path) => println!("Restore script saved to: {}", script_path.display()),
+            Err(e) => println!("{}", format!("Warning: Could not write restore script: {}", e).yellow()),
+        }
+    }
+
+    Ok(())
+}
+
+//watch free space on the destination while archiving, interactive runs ask and unattended ones go by --on-low-space
+fn space_monitor(destination: &Path, settings: &BackupSettings, interactive: bool) -> Result<SpaceMonitor, Box<dyn std::error::Error>> {
+    let policy = settings.low_space.unwrap_or(if interactive { LowSpacePolicy::Prompt } else { LowSpacePolicy::Abort });
+    let mark = config::load_config()?.low_water_mark.map(|size| utils::parse_size(&size)).transpose()?;
+    Ok(SpaceMonitor::new(destination, policy).with_low_water_mark(mark).with_floor(settings.min_free_after.as_ref()))
+}
+
+//time spent paused is reported separately from the time actually spent backing up
+fn print_duration(total: Duration, paused: Duration) {
+    if paused.is_zero() {
+        println!("Time taken: {:.2} seconds", total.as_secs_f64());
+    } else {
+        println!("Time taken: {:.2} seconds active, {:.2} seconds paused", (total - paused).as_secs_f64(), paused.as_secs_f64());
+    }
+}
+
+//FAT32 sticks and some shares cap file sizes, better to stop now than after an hour of work with EFBIG
+//incrementals are left alone, they are usually far smaller than the sources
+fn check_file_size_limit(sources: &[PathBuf], exclusions: &[PathBuf], settings: &BackupSettings, output_path: &Path, interactive: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    if !matches!(settings.backup_type, BackupType::Full) {
+        return Ok(true);
+    }
+    let limit = match space::file_size_limit(output_path.parent().unwrap_or(Path::new("/"))) {
+        Some(limit) => limit,
+        None => return Ok(true),
+    };
+
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let selector = file_selector(exclusions, settings, &BackupMetadata::default(), current_time);
+    let estimate = estimate::estimate(sources, selector, settings.compression, SIZE_CHECK_SAMPLE)?;
+    if estimate.estimated_compressed_bytes <= limit.max_file_size {
+        return Ok(true);
+    }
+
+    let problem = format!(
+        "the destination is on {}, which can't hold files over {}, and this backup is estimated at ~{}",
+        limit.filesystem,
+        utils::format_size(limit.max_file_size),
+        utils::format_size(estimate.estimated_compressed_bytes)
+    );
+    if !interactive {
+        return Err(format!("{}; write it to another filesystem or back up less at once", problem).into());
+    }
+
+    println!("{}", format!("Warning: {}.", problem).yellow().bold());
+    println!("The backup would fail once the archive reaches the limit. Write it to another filesystem, exclude some data or choose stronger compression.");
+    Ok(Confirm::new()
+        .with_prompt("Start the backup anyway?")
+        .default(false)
+        .interact()?)
+}
+
+//min_free_after, the archive has to leave that much free on the destination
+//sized from what the selection takes now, full backups scaled by the compression estimate, the others uncompressed
+fn check_free_space_floor(
+    sources: &[PathBuf],
+    exclusions: &mut Vec<PathBuf>,
+    settings: &BackupSettings,
+    output_path: &Path,
     metadata: &BackupMetadata,
-    current_time: u64
-) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Performing differential backup of: {}", dir_path);
-    
-    let path = Path::new(dir_path);
-    if !path.exists() {
-        println!("{}", format!("Warning: Path does not exist: {}", dir_path).yellow());
+    interactive: bool
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let (floor, floor_text) = match &settings.min_free_after {
+        Some(floor) => floor,
+        None => return Ok(true),
+    };
+    let destination = output_path.parent().unwrap_or(Path::new("/"));
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let ratio = match settings.backup_type {
+        BackupType::Full if settings.compression.format != CompressionFormat::Store => {
+            let selector = file_selector(exclusions, settings, metadata, current_time);
+            let estimate = estimate::estimate(sources, selector, settings.compression, SIZE_CHECK_SAMPLE)?;
+            estimate.estimated_compressed_bytes as f64 / estimate.bytes.max(1) as f64
+        }
+        _ => 1.0,
+    };
+
+    loop {
+        let free = match space::free_space(destination) {
+            Some(free) => free,
+            None => return Ok(true),
+        };
+        let mut selector = file_selector(exclusions, settings, metadata, current_time);
+        let mut selected = 0;
+        for source in sources {
+            selected += selector.select(source).filter(|entry| entry.kind == EntryKind::File).map(|entry| entry.size).sum::<u64>();
+        }
+        let needed = (selected as f64 * ratio) as u64;
+        if free.saturating_sub(needed) >= *floor {
+            return Ok(true);
+        }
+
+        let problem = format!(
+            "this backup is estimated at ~{} and would leave {} free on {}, less than min_free_after ({})",
+            utils::format_size(needed),
+            utils::format_size(free.saturating_sub(needed)),
+            destination.display(),
+            floor_text
+        );
+        if !interactive {
+            return Err(format!("{}; free up space on the destination or back up less at once", problem).into());
+        }
+
+        println!("{}", format!("Warning: {}.", problem).yellow().bold());
+        let options = vec!["Leave out some of the largest directories", "Start anyway, the run stops once the destination reaches the limit", "Cancel"];
+        let selection = Select::new()
+            .with_prompt("What would you like to do?")
+            .default(0)
+            .items(&options)
+            .interact()?;
+        match selection {
+            0 => {
+                let chosen = choose_largest_directories(sources)?;
+                if chosen.is_empty() {
+                    println!("{}", "Nothing left out.".yellow());
+                }
+                exclusions.extend(chosen);
+            }
+            1 => return Ok(true),
+            _ => return Ok(false),
+        }
+    }
+}
+
+//the backup itself is complete by now, a metadata save that ultimately fails only costs the record of it
+//the metadata is then written next to the archive, returns where when it was
+fn record_metadata(metadata_path: &Path, store: &MetadataStore, key: &str, archive: Option<&Path>) -> Option<PathBuf> {
+    let error = utils::persist_metadata_store(metadata_path, store).err()?;
+    let metadata = store.job(key);
+
+    println!("{}", format!("Warning: The backup is complete, but its metadata could not be saved to {}: {}", metadata_path.display(), error).yellow().bold());
+    let recorded = metadata.last_backup_time.map(|time| chrono::DateTime::from_timestamp(time as i64, 0).unwrap_or_default().with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string());
+    println!(
+        "Not recorded: this run's time ({}) and {} file hashes. Until it is, the next incremental backup compares against the previous run and archives its changes again.",
+        recorded.unwrap_or_default(),
+        metadata.backup_history.len()
+    );
+
+    let stub = sidecars::metadata_stub(archive?);
+    match fs::write(&stub, serde_json::to_vec_pretty(store).unwrap_or_default()) {
+        Ok(()) => {
+            println!("The metadata was written to {} instead.", stub.display());
+            println!("To record the run, copy it to {} once the filesystem responds again.", metadata_path.join(utils::METADATA_FILE).display());
+            Some(stub)
+        }
+        Err(e) => {
+            println!("{}", format!("Warning: Could not write it next to the archive either: {}", e).yellow());
+            None
+        }
+    }
+}
+
+//the checksums in the archive are what verify goes by, the copy next to it is a convenience
+fn save_sums(listing: &mut FileListing, output_path: &Path) {
+    let sums_path = sidecars::checksum(output_path);
+    if let Err(e) = listing.save_sums(&sums_path) {
+        println!("{}", format!("Warning: Could not write the file checksums to {}: {}", sums_path.display(), e).yellow());
+    }
+}
+
+//an earlier archive's index at the same path would describe the one just replaced
+fn save_entry_index(settings: &BackupSettings, output_path: &Path) {
+    if !settings.entry_index {
+        entry_index::remove_stale(output_path);
+        return;
+    }
+    match entry_index::write_index(output_path) {
+        Ok(entries) => println!("Entry index: {} entries in {}", entries, sidecars::entry_index(output_path).display()),
+        Err(e) => println!("{}", format!("Warning: Could not write the entry index of {}: {}", output_path.display(), e).yellow()),
+    }
+}
+
+//the archive was closed early, so it is valid but does not hold everything
+fn report_low_space_abort(output_path: &Path, monitor: &SpaceMonitor) {
+    println!("\n{}", "Backup aborted: destination is low on free space.".red().bold());
+    println!("The archive was closed cleanly but only contains files archived before the abort, it was kept under its unfinished name:");
+    println!("  {}", output_path.display().to_string().yellow());
+    monitor.report_skipped();
+    println!("{}", "Backup metadata was not updated, the next incremental backup will pick up the missing files.".yellow());
+}
+
+//custom directories used more than once are offered for the quick-pick list
+fn offer_quick_dir_promotion(config: &Config, custom_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let uses = config::record_custom_dir(&paths::state_dir(), custom_path)?;
+    let mut quick_dirs = config::configured_quick_dirs(config);
+
+    if uses < 2 || quick_dirs.iter().any(|dir| utils::expand_tilde(dir) == custom_path) {
         return Ok(());
     }
-    
-    let original_backup_time = metadata.original_backup_time.unwrap_or(0);
+
+    let promote = Confirm::new()
+        .with_prompt(format!("You have backed up {} {} times. Add it to the quick-pick list?", custom_path, uses))
+        .default(true)
+        .interact()?;
+
+    if promote {
+        quick_dirs.push(custom_path.to_string());
+        let mut config = config::load_config()?;
+        config.quick_dirs = Some(quick_dirs);
+        config::save_config(&config)?;
+        println!("Added {} to the quick-pick list.", custom_path);
+    }
+
+    Ok(())
+}
+
+//a source that is itself a symlink is walked through its target, but entries
+//keep the selected path so restores land where the user expects
+//the archive's descriptor records the same translation
+pub fn announce_symlinked_sources(selected_dirs: &[PathBuf]) {
+    for source in sysinfo::symlinked_sources(selected_dirs) {
+        println!("Note: {} is a symlink to {}, its contents will be stored under {}", source.path, source.target, source.path);
+
+        if utils::is_network_filesystem(Path::new(&source.target)) {
+            println!("{}", format!("Warning: {} resolves to a network filesystem ({}), the backup may be slow or incomplete", source.path, source.target).yellow());
+        }
+    }
+}
+
+//entries with very long stored paths may not fit under PATH_MAX once joined with a restore target
+pub fn warn_long_paths(sources: &[PathBuf], limit: usize, one_file_system: bool) {
     let progress = ProgressBar::new_spinner();
     progress.set_style(ProgressStyle::default_spinner()
         .template("{spinner:.green} {msg}")
         .unwrap());
-    
-    progress.set_message(format!("Checking for changes in {} since original backup", dir_path));
-    
-    let mut files_backed_up = 0;
-    
-    for entry in WalkDir::new(dir_path) {
-        match entry {
-            Ok(entry) => {
-                let path = entry.path();
-                
-                if path.is_file() {
-                    //check if file was modified since original backup
-                    if let Ok(metadata) = path.metadata() {
-                        if let Ok(modified) = metadata.modified() {
-                            if let Ok(modified_secs) = modified.duration_since(SystemTime::UNIX_EPOCH) {
-                                if modified_secs.as_secs() > original_backup_time {
-                                    let name = path.strip_prefix("/").unwrap_or(path);
-                                    progress.set_message(format!("Adding {}", path.display()));
-                                    
-                                    match File::open(path) {
-                                        Ok(mut file) => {
-                                            archive.append_file(name, &mut file)?;
-                                            files_backed_up += 1;
-                                        }
-                                        Err(e) => {
-                                            println!("{}", format!("Warning: Could not open file {}: {}", path.display(), e).yellow());
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                } else if path.is_dir() && entry.depth() > 0 {
-                    //only add directories that are new since original backup
-                    if let Ok(dir_metadata) = path.metadata() {
-                        if let Ok(created) = dir_metadata.created() {
-                            if let Ok(created_secs) = created.duration_since(SystemTime::UNIX_EPOCH) {
-                                if created_secs.as_secs() > original_backup_time {
-                                    let name = path.strip_prefix("/").unwrap_or(path);
-                                    archive.append_dir(name, path)?;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                println!("{}", format!("Warning: Error accessing entry: {}", e).yellow());
+    progress.set_message("Checking path lengths...");
+
+    let long_paths = long_paths(sources, limit, one_file_system);
+    progress.finish_and_clear();
+
+    if long_paths.is_empty() {
+        return;
+    }
+
+    println!("{}", format!("Warning: {} entries have paths longer than {} bytes and may fail to restore into a deep target directory:", long_paths.len(), limit).yellow());
+    for path in long_paths.iter().take(5) {
+        println!("  {} ({} bytes)", path.display(), path.as_os_str().len());
+    }
+    if long_paths.len() > 5 {
+        println!("  ...and {} more", long_paths.len() - 5);
+    }
+}
+
+//every path below the sources longer than limit bytes, staying on each source's filesystem if asked
+fn long_paths(sources: &[PathBuf], limit: usize, one_file_system: bool) -> Vec<PathBuf> {
+    sources
+        .iter()
+        .flat_map(|dir| {
+            let device = if one_file_system { dir.metadata().ok().map(|metadata| metadata.dev()) } else { None };
+            WalkDir::new(dir).into_iter().filter_entry(move |e| device.is_none_or(|device| e.metadata().map_or(true, |metadata| metadata.dev() == device))).filter_map(|e| e.ok())
+        })
+        .map(|e| e.into_path())
+        .filter(|path| path.as_os_str().len() > limit)
+        .collect()
+}
+
+//total size in bytes of all files below a path, and the part of it in each immediate subdirectory
+//one walk for all of them, sizing every subdirectory on its own would read each tree twice
+fn subdirectory_sizes(path: &Path) -> io::Result<(u64, Vec<(PathBuf, u64)>)> {
+    let mut total = 0;
+    let mut subdirs: Vec<(PathBuf, u64)> = Vec::new();
+    for entry in WalkDir::new(path) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if e.depth() == 0 => return Err(e.into()),
+            Err(_) => continue,
+        };
+        //the walk is depth first, everything below a subdirectory comes right after it
+        if entry.depth() == 1 && entry.file_type().is_dir() {
+            subdirs.push((entry.path().to_path_buf(), 0));
+            continue;
+        }
+        let size = match entry.metadata() {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => continue,
+        };
+        total += size;
+        if entry.depth() > 1 {
+            if let Some((_, subdir_size)) = subdirs.last_mut() {
+                *subdir_size += size;
             }
         }
     }
-    
-    progress.finish_with_message(format!("Differential backup of {} completed. {} files backed up.", dir_path, files_backed_up));
-    
-    Ok(())
+    Ok((total, subdirs))
 }
 
-fn backup_with_exclusions(archive: &mut Builder<GzEncoder<File>>, dir_path: &str, exclusions: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Backing up directory with exclusions: {}", dir_path);
-    
-    let path = Path::new(dir_path);
-    if !path.exists() {
-        println!("{}", format!("Warning: Path does not exist: {}", dir_path).yellow());
-        return Ok(());
+//show the largest immediate subdirectories of each source and let the user exclude some of them
+//the patterns to leave out, and the presets they include
+type ChosenExclusions = (Vec<PathBuf>, Vec<&'static Preset>);
+
+//presets first, then names like target or node_modules/ at any depth, paths and globs like ~/projects/*/build
+fn prompt_exclusion_patterns() -> Result<ChosenExclusions, Box<dyn std::error::Error>> {
+    let add = Confirm::new()
+        .with_prompt("Add exclusion patterns?")
+        .default(false)
+        .interact()?;
+    if !add {
+        return Ok((Vec::new(), Vec::new()));
     }
-    
+
+    let labels: Vec<&str> = presets::PRESETS.iter().map(|preset| preset.label).collect();
+    let selection = MultiSelect::new()
+        .with_prompt("Exclusion presets to use")
+        .items(&labels)
+        .interact()?;
+    let chosen: Vec<&'static Preset> = selection.iter().map(|&i| &presets::PRESETS[i]).collect();
+
+    let patterns: String = Input::new()
+        .with_prompt("Patterns to leave out, comma-separated (e.g. target, node_modules, *.log, ~/projects/*/build)")
+        .allow_empty(true)
+        .interact_text()?;
+    let patterns: Vec<PathBuf> = patterns.split(',').map(|pattern| PathBuf::from(pattern.trim())).collect();
+    let mut exclusions = presets::patterns(&chosen);
+    exclusions.extend(utils::exclusion_patterns(&patterns));
+    Ok((exclusions, chosen))
+}
+
+fn review_largest_directories(selected_dirs: &[PathBuf]) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let review = Confirm::new()
+        .with_prompt("Review the largest directories before starting?")
+        .default(false)
+        .interact()?;
+
+    if !review {
+        return Ok(Vec::new());
+    }
+    let exclusions = choose_largest_directories(selected_dirs)?;
+    if !exclusions.is_empty() {
+        offer_saving_exclusions(selected_dirs, &exclusions)?;
+    }
+    Ok(exclusions)
+}
+
+//the 15 largest subdirectories of each source, picked from by size
+fn choose_largest_directories(selected_dirs: &[PathBuf]) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
     let progress = ProgressBar::new_spinner();
     progress.set_style(ProgressStyle::default_spinner()
         .template("{spinner:.green} {msg}")
         .unwrap());
-    progress.set_message(format!("Processing {}", dir_path));
-    
-    for entry in WalkDir::new(dir_path).into_iter().filter_entry(|e| {
-        let path = e.path().to_string_lossy();
-        !exclusions.iter().any(|ex| {
-            if ex.contains('*') {
-                //handle glob patterns
-                if let Ok(pattern) = glob(ex) {
-                    pattern.into_iter().any(|p| {
-                        if let Ok(p) = p {
-                            path.starts_with(p.to_string_lossy().as_ref())
-                        } else {
-                            false
-                        }
-                    })
-                } else {
-                    false
-                }
-            } else {
-                //simple prefix matching
-                path.starts_with(ex)
-            }
-        })
-    }) {
-        match entry {
-            Ok(entry) => {
-                let path = entry.path();
-                let name = path.strip_prefix("/").unwrap_or(path);
-                
-                progress.set_message(format!("Adding {}", path.display()));
-                
-                if path.is_file() {
-                    match File::open(path) {
-                        Ok(mut file) => {
-                            archive.append_file(name, &mut file)?;
-                        }
-                        Err(e) => {
-                            println!("{}", format!("Warning: Could not open file {}: {}", path.display(), e).yellow());
-                        }
-                    }
-                } else if path.is_dir() && entry.depth() > 0 {
-                    archive.append_dir(name, path)?;
-                }
+
+    let mut total_size = 0;
+    let mut candidates: Vec<(PathBuf, u64)> = Vec::new();
+
+    for dir in selected_dirs {
+        progress.set_message(format!("Scanning {}", dir.display()));
+        let mut subdirs = match subdirectory_sizes(dir) {
+            Ok((size, subdirs)) => {
+                total_size += size;
+                subdirs
             }
             Err(e) => {
-                println!("{}", format!("Warning: Error accessing entry: {}", e).yellow());
+                println!("{}", format!("Warning: Could not read directory {}: {}", dir.display(), e).yellow());
+                continue;
             }
-        }
+        };
+
+        //keep the 15 largest subdirectories of each source
+        subdirs.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+        subdirs.truncate(15);
+        candidates.extend(subdirs);
     }
-    
-    progress.finish_with_message(format!("Completed {}", dir_path));
+
+    progress.finish_and_clear();
+
+    println!("Estimated total size: {}", utils::format_size(total_size));
+
+    if candidates.is_empty() {
+        println!("{}", "No subdirectories found to review.".yellow());
+        return Ok(Vec::new());
+    }
+
+    let items: Vec<String> = candidates
+        .iter()
+        .map(|(path, size)| format!("{:>10}  {}", utils::format_size(*size), path.display()))
+        .collect();
+
+    let selection = MultiSelect::new()
+        .with_prompt("Select directories to EXCLUDE from this backup")
+        .items(&items)
+        .interact()?;
+
+    let excluded_size: u64 = selection.iter().map(|&i| candidates[i].1).sum();
+    let exclusions: Vec<PathBuf> = selection.iter().map(|&i| candidates[i].0.clone()).collect();
+
+    if !exclusions.is_empty() {
+        println!("Excluding {} directories ({})", exclusions.len(), utils::format_size(excluded_size));
+        println!("Adjusted estimated size: {}", utils::format_size(total_size.saturating_sub(excluded_size)).green());
+    }
+
+    Ok(exclusions)
+}
+
+//a profile that has them leaves the same directories out next time without the review
+fn offer_saving_exclusions(selected_dirs: &[PathBuf], exclusions: &[PathBuf]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = config::load_config()?;
+    let names: Vec<String> = config.profiles.keys().cloned().collect();
+    let mut options: Vec<String> = names.iter().map(|name| format!("Add them to profile {}", name)).collect();
+    options.push("Save them in a new profile for these directories".to_string());
+    options.push("Only leave them out of this backup".to_string());
+
+    let selection = Select::new()
+        .with_prompt("Save these exclusions for later backups?")
+        .default(options.len() - 1)
+        .items(&options)
+        .interact()?;
+    let name = if selection < names.len() {
+        names[selection].clone()
+    } else if selection == names.len() {
+        let name: String = Input::new()
+            .with_prompt("Name of the new profile")
+            .interact_text()?;
+        name.trim().to_string()
+    } else {
+        return Ok(());
+    };
+    if name.is_empty() {
+        println!("{}", "No profile name given, the exclusions are only used for this backup.".yellow());
+        return Ok(());
+    }
+
+    let dirs: Vec<String> = selected_dirs.iter().map(|dir| dir.display().to_string()).collect();
+    let excludes: Vec<String> = exclusions.iter().map(|exclusion| exclusion.display().to_string()).collect();
+    config.save_exclusions(&name, &dirs, &excludes);
+    config::save_config(&config)?;
+    println!("{}", format!("Saved {} exclusions to profile {}, run it with --profile {} or Run Profile in the menu.", excludes.len(), name, name).green());
     Ok(())
 }
 
-fn incremental_backup_with_exclusions(
-    archive: &mut Builder<GzEncoder<File>>, 
-    dir_path: &str, 
-    exclusions: &[&str],
-    metadata: &BackupMetadata,
-    current_time: u64
-) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Performing incremental backup with exclusions: {}", dir_path);
-    
-    let path = Path::new(dir_path);
+//archive everything the selector picks below one source, returns the number of files written
+fn archive_source<W: Write>(
+    archive: &mut Builder<W>,
+    path: &Path,
+    root: &Path,
+    selector: &mut FileSelector,
+    appender: &mut FileAppender,
+    monitor: &mut SpaceMonitor,
+    pause: &mut PauseControl
+) -> Result<u64, Box<dyn std::error::Error>> {
     if !path.exists() {
-        println!("{}", format!("Warning: Path does not exist: {}", dir_path).yellow());
-        return Ok(());
+        println!("{}", format!("Warning: Path does not exist: {}", path.display()).yellow());
+        return Ok(0);
     }
-    
-    let last_backup_time = metadata.last_backup_time.unwrap_or(0);
+
     let progress = ProgressBar::new_spinner();
     progress.set_style(ProgressStyle::default_spinner()
         .template("{spinner:.green} {msg}")
         .unwrap());
-    
-    progress.set_message(format!("Checking for changes in {} since last backup", dir_path));
-    
+    progress.set_message(format!("Processing {}", path.display()));
+
     let mut files_backed_up = 0;
-    
-    for entry in WalkDir::new(dir_path).into_iter().filter_entry(|e| {
-        let path = e.path().to_string_lossy();
-        !exclusions.iter().any(|ex| {
-            if ex.contains('*') {
-                //handle glob patterns
-                if let Ok(pattern) = glob(ex) {
-                    pattern.into_iter().any(|p| {
-                        if let Ok(p) = p {
-                            path.starts_with(p.to_string_lossy().as_ref())
-                        } else {
-                            false
-                        }
-                    })
-                } else {
-                    false
+    let mut metadata_only = 0;
+
+    for entry in selector.select(path) {
+        if pause.is_paused() {
+            //get everything compressed so far onto disk before sitting idle
+            archive.get_mut().flush()?;
+            pause.wait_while_paused(&progress);
+        }
+
+        let name = entry.path.strip_prefix(root).unwrap_or(&entry.path);
+
+        match entry.kind {
+            EntryKind::File => {
+                if !monitor.allow(&entry.path, entry.size)? {
+                    if monitor.aborted {
+                        break;
+                    }
+                    continue;
                 }
-            } else {
-                //simple prefix matching
-                path.starts_with(ex)
-            }
-        })
-    }) {
-        match entry {
-            Ok(entry) => {
-                let path = entry.path();
-                
-                if path.is_file() {
-                    //check if file was modified since last backup
-                    if let Ok(metadata) = path.metadata() {
-                        if let Ok(modified) = metadata.modified() {
-                            if let Ok(modified_secs) = modified.duration_since(SystemTime::UNIX_EPOCH) {
-                                if modified_secs.as_secs() > last_backup_time {
-                                    let name = path.strip_prefix("/").unwrap_or(path);
-                                    progress.set_message(format!("Adding {}", path.display()));
-                                    
-                                    match File::open(path) {
-                                        Ok(mut file) => {
-                                            archive.append_file(name, &mut file)?;
-                                            files_backed_up += 1;
-                                        }
-                                        Err(e) => {
-                                            println!("{}", format!("Warning: Could not open file {}: {}", path.display(), e).yellow());
-                                        }
-                                    }
-                                }
+
+                progress.set_message(format!("Adding {}", entry.path.display()));
+
+                match File::open(&entry.path) {
+                    Ok(mut file) => match appender.append_file(archive, name, &entry.path, &mut file) {
+                        Ok(true) => {
+                            appender.list(name, entry.size)?;
+                            files_backed_up += 1;
+                            if entry.change == FileChange::MetadataOnly {
+                                metadata_only += 1;
                             }
                         }
+                        Ok(false) => {}
+                        Err(e) => skip_failed_entry(appender, name, &entry.path, e)?,
+                    },
+                    Err(e) => {
+                        println!("{}", format!("Warning: Could not open file {}: {}", entry.path.display(), e).yellow());
                     }
-                } else if path.is_dir() && entry.depth() > 0 {
-                    //only add directories that are new
-                    if let Ok(dir_metadata) = path.metadata() {
-                        if let Ok(created) = dir_metadata.created() {
-                            if let Ok(created_secs) = created.duration_since(SystemTime::UNIX_EPOCH) {
-                                if created_secs.as_secs() > last_backup_time {
-                                    let name = path.strip_prefix("/").unwrap_or(path);
-                                    archive.append_dir(name, path)?;
-                                }
-                            }
-                        }
+                }
+            }
+            EntryKind::Directory => {
+                if let Err(e) = appender.append_dir(archive, name, &entry.path) {
+                    skip_failed_entry(appender, name, &entry.path, e)?;
+                }
+            }
+            EntryKind::Symlink => {
+                match appender.append_symlink(archive, name, &entry.path) {
+                    Ok(true) => {
+                        appender.list(name, 0)?;
+                        files_backed_up += 1;
                     }
+                    Ok(false) => {}
+                    Err(e) => println!("{}", format!("Warning: Could not read symlink {}: {}", entry.path.display(), e).yellow()),
                 }
             }
-            Err(e) => {
-                println!("{}", format!("Warning: Error accessing entry: {}", e).yellow());
+            EntryKind::Special => {
+                match appender.append_special(archive, name, &entry.path) {
+                    Ok(true) => {
+                        appender.list(name, 0)?;
+                        files_backed_up += 1;
+                    }
+                    Ok(false) => {}
+                    Err(e) => println!("{}", format!("Warning: Could not archive {}: {}", entry.path.display(), e).yellow()),
+                }
             }
         }
     }
-    
-    progress.finish_with_message(format!("Incremental backup of {} completed. {} files backed up.", dir_path, files_backed_up));
-    
+
+    if metadata_only > 0 {
+        progress.finish_with_message(format!("Completed {}. {} files backed up ({} metadata-only changes).", path.display(), files_backed_up, metadata_only));
+    } else {
+        progress.finish_with_message(format!("Completed {}. {} files backed up.", path.display(), files_backed_up));
+    }
+
+    Ok(files_backed_up)
+}
+
+//one entry the archive can't take is reported and left out, a multi-hour backup shouldn't die on a single odd name
+//a destination that stops accepting data fails every entry after it, so that still ends the run, as does an entry cut off halfway
+fn skip_failed_entry(appender: &mut FileAppender, name: &Path, path: &Path, e: io::Error) -> io::Result<()> {
+    if appender::is_cut_short(&e) || matches!(e.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded | io::ErrorKind::WriteZero | io::ErrorKind::BrokenPipe) {
+        return Err(e);
+    }
+    appender.forget(name);
+    println!("{}", format!("Warning: Could not archive {}, leaving it out: {}", path.display(), e).yellow());
     Ok(())
 }
 
-fn differential_backup_with_exclusions(
-    archive: &mut Builder<GzEncoder<File>>, 
-    dir_path: &str, 
-    exclusions: &[&str],
-    metadata: &BackupMetadata,
-    current_time: u64
-) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Performing differential backup with exclusions: {}", dir_path);
-    
-    let path = Path::new(dir_path);
-    if !path.exists() {
-        println!("{}", format!("Warning: Path does not exist: {}", dir_path).yellow());
-        return Ok(());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restore_script::sha256_file;
+
+    fn backup_fixture(source: &Path, output_path: &Path) {
+        let mut archive = create_archive(File::create(output_path).unwrap(), ArchiveCompression::default(), true).unwrap();
+        let sources = vec![source.to_path_buf()];
+        sysinfo::append_to_archive(&mut archive, &SystemInfo::current(&sources).without_free_space(), utils::entry_mtime(true)).unwrap();
+
+        let mut monitor = SpaceMonitor::new(output_path.parent().unwrap(), LowSpacePolicy::Continue);
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+        assert_eq!(archive_source(&mut archive, &sources[0], Path::new("/"), &mut selector, &mut FileAppender::new(true), &mut monitor, &mut PauseControl::default()).unwrap(), 3);
+        archive.into_inner().unwrap().finish().unwrap();
     }
-    
-    let original_backup_time = metadata.original_backup_time.unwrap_or(0);
-    let progress = ProgressBar::new_spinner();
-    progress.set_style(ProgressStyle::default_spinner()
-        .template("{spinner:.green} {msg}")
-        .unwrap());
-    
-    progress.set_message(format!("Checking for changes in {} since original backup", dir_path));
-    
-    let mut files_backed_up = 0;
-    
-    for entry in WalkDir::new(dir_path).into_iter().filter_entry(|e| {
-        let path = e.path().to_string_lossy();
-        !exclusions.iter().any(|ex| {
-            if ex.contains('*') {
-                //handle glob patterns
-                if let Ok(pattern) = glob(ex) {
-                    pattern.into_iter().any(|p| {
-                        if let Ok(p) = p {
-                            path.starts_with(p.to_string_lossy().as_ref())
-                        } else {
-                            false
-                        }
-                    })
-                } else {
-                    false
-                }
-            } else {
-                //simple prefix matching
-                path.starts_with(ex)
+
+    #[test]
+    fn sources_can_be_archived_into_memory() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("nested")).unwrap();
+        fs::write(source.join("a.txt"), "alpha").unwrap();
+        fs::write(source.join("nested/b.txt"), "beta").unwrap();
+
+        let mut archive = Builder::new(Vec::new());
+        let mut monitor = SpaceMonitor::new(temp.path(), LowSpacePolicy::Continue);
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+        let files = archive_source(&mut archive, &source, Path::new("/"), &mut selector, &mut FileAppender::new(true), &mut monitor, &mut PauseControl::default()).unwrap();
+        assert_eq!(files, 2);
+
+        let data = archive.into_inner().unwrap();
+        let mut reader = tar::Archive::new(data.as_slice());
+        let prefix = source.strip_prefix("/").unwrap();
+        let entries: Vec<(PathBuf, String)> = reader
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut contents = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut contents).unwrap();
+                (entry.path().unwrap().strip_prefix(prefix).unwrap().to_path_buf(), contents)
+            })
+            .collect();
+
+        assert_eq!(
+            entries,
+            vec![
+                (PathBuf::from("a.txt"), "alpha".to_string()),
+                (PathBuf::from("nested"), String::new()),
+                (PathBuf::from("nested/b.txt"), "beta".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn symlinks_are_archived_as_links() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("dir")).unwrap();
+        fs::write(source.join("dir/a.txt"), "alpha").unwrap();
+        std::os::unix::fs::symlink("dir/a.txt", source.join("file-link")).unwrap();
+        std::os::unix::fs::symlink("dir", source.join("dir-link")).unwrap();
+        std::os::unix::fs::symlink("/nonexistent/target", source.join("dangling")).unwrap();
+
+        let mut archive = Builder::new(Vec::new());
+        let mut monitor = SpaceMonitor::new(temp.path(), LowSpacePolicy::Continue);
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+        let files = archive_source(&mut archive, &source, Path::new("/"), &mut selector, &mut FileAppender::new(true), &mut monitor, &mut PauseControl::default()).unwrap();
+        assert_eq!(files, 4);
+
+        let data = archive.into_inner().unwrap();
+        let mut reader = tar::Archive::new(data.as_slice());
+        let prefix = source.strip_prefix("/").unwrap();
+        let links: Vec<(PathBuf, PathBuf)> = reader
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.header().entry_type().is_symlink())
+            .map(|entry| (entry.path().unwrap().strip_prefix(prefix).unwrap().to_path_buf(), entry.link_name().unwrap().unwrap().to_path_buf()))
+            .collect();
+
+        assert_eq!(
+            links,
+            vec![
+                (PathBuf::from("dangling"), PathBuf::from("/nonexistent/target")),
+                (PathBuf::from("dir-link"), PathBuf::from("dir")),
+                (PathBuf::from("file-link"), PathBuf::from("dir/a.txt")),
+            ]
+        );
+    }
+
+    #[test]
+    fn non_utf8_names_are_archived_and_restored_byte_for_byte() {
+        use crate::restore::{restore_backup, RestoreOptions};
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        let song = OsStr::from_bytes(b"song-\xff.mp3");
+        let album = source.join(OsStr::from_bytes(b"album-\xff"));
+        fs::create_dir_all(&album).unwrap();
+        fs::write(source.join(song), "la la").unwrap();
+        fs::write(album.join("cover.jpg"), "jpeg").unwrap();
+        //the same name with a different invalid byte, a lossy comparison would exclude album-\xff too
+        let excluded = source.join(OsStr::from_bytes(b"album-\xfe"));
+        fs::create_dir_all(&excluded).unwrap();
+        fs::write(excluded.join("cover.jpg"), "jpeg").unwrap();
+
+        let archive_path = temp.path().join("backup.tar.gz");
+        let mut archive = create_archive(File::create(&archive_path).unwrap(), ArchiveCompression::default(), false).unwrap();
+        let mut monitor = SpaceMonitor::new(temp.path(), LowSpacePolicy::Continue);
+        let mut selector = FileSelector::new(std::slice::from_ref(&excluded), FileFilter::default());
+        let files = archive_source(&mut archive, &source, Path::new("/"), &mut selector, &mut FileAppender::new(false), &mut monitor, &mut PauseControl::default()).unwrap();
+        assert_eq!(files, 2);
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let target = temp.path().join("target");
+        restore_backup(archive_path.to_str().unwrap(), target.to_str().unwrap(), &RestoreOptions { ignore_origin: true, ..RestoreOptions::default() }).unwrap();
+
+        let restored = target.join(source.strip_prefix("/").unwrap());
+        let mut names: Vec<Vec<u8>> = fs::read_dir(&restored).unwrap().map(|entry| entry.unwrap().file_name().as_bytes().to_vec()).collect();
+        names.sort();
+        assert_eq!(names, vec![b"album-\xff".to_vec(), b"song-\xff.mp3".to_vec()]);
+        assert_eq!(fs::read_to_string(restored.join(song)).unwrap(), "la la");
+        assert_eq!(fs::read_to_string(restored.join(OsStr::from_bytes(b"album-\xff/cover.jpg"))).unwrap(), "jpeg");
+    }
+
+    #[test]
+    fn paths_longer_than_a_tar_header_round_trip() {
+        use crate::restore::{restore_backup, RestoreOptions};
+
+        //node_modules nesting, well past the 100 bytes a plain tar header holds
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        let deep = source.join(["node_modules/@scope/package"; 5].join("/"));
+        let name = format!("{}.js", "a".repeat(200));
+        fs::create_dir_all(&deep).unwrap();
+        fs::write(deep.join(&name), "module.exports = 1").unwrap();
+        std::os::unix::fs::symlink(deep.join(&name), source.join("link")).unwrap();
+        assert!(deep.join(&name).strip_prefix(temp.path()).unwrap().as_os_str().len() > 300);
+
+        let archive_path = temp.path().join("backup.tar.gz");
+        let mut archive = create_archive(File::create(&archive_path).unwrap(), ArchiveCompression::default(), false).unwrap();
+        let mut monitor = SpaceMonitor::new(temp.path(), LowSpacePolicy::Continue);
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+        let files = archive_source(&mut archive, &source, Path::new("/"), &mut selector, &mut FileAppender::new(false), &mut monitor, &mut PauseControl::default()).unwrap();
+        assert_eq!(files, 2);
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let target = temp.path().join("target");
+        restore_backup(archive_path.to_str().unwrap(), target.to_str().unwrap(), &RestoreOptions { ignore_origin: true, ..RestoreOptions::default() }).unwrap();
+
+        let restored = target.join(deep.strip_prefix("/").unwrap()).join(&name);
+        assert_eq!(fs::read_to_string(&restored).unwrap(), "module.exports = 1");
+        assert_eq!(fs::read_link(target.join(source.strip_prefix("/").unwrap()).join("link")).unwrap(), deep.join(&name));
+    }
+
+    #[test]
+    fn unsaved_metadata_is_written_next_to_the_archive() {
+        let temp = tempfile::tempdir().unwrap();
+        let output = temp.path().join("backup.tar.gz");
+        fs::write(&output, "archive").unwrap();
+        //a file where the metadata directory should be fails every attempt
+        let metadata_path = temp.path().join("state");
+        fs::write(&metadata_path, "").unwrap();
+
+        let mut store = MetadataStore::default();
+        store.record("dirs:test", BackupMetadata { last_backup_time: Some(1_700_000_000), ..BackupMetadata::default() });
+        let stub = record_metadata(&metadata_path, &store, "dirs:test", Some(&output)).unwrap();
+        assert_eq!(stub, temp.path().join("backup.tar.gz.metadata.json"));
+        assert_eq!(utils::load_metadata_store(temp.path()).unwrap().last_backup_time(), None);
+        let recovered: MetadataStore = serde_json::from_slice(&fs::read(&stub).unwrap()).unwrap();
+        assert_eq!(recovered.job("dirs:test").last_backup_time, Some(1_700_000_000));
+        assert_eq!(fs::read_to_string(&output).unwrap(), "archive");
+
+        assert!(record_metadata(temp.path(), &store, "dirs:test", Some(&output)).is_none());
+    }
+
+    fn plain_job(source: &Path, output_path: PathBuf, backup_type: BackupType) -> BackupJob {
+        BackupJob {
+            sources: vec![source.to_path_buf()],
+            exclusions: Vec::new(),
+            settings: BackupSettings {
+                backup_type,
+                compression: ArchiveCompression::default(),
+                reproducible: false,
+                max_age: None,
+                max_size: None,
+                list_skipped: None,
+                presets: Vec::new(),
+                hash_check: false,
+                encryption: None,
+                xattrs: false,
+                acls: false,
+                follow_symlinks: false,
+                nobackup_markers: true,
+                cache_dirs: true,
+                one_file_system: false,
+                wait_for_lock: None,
+                min_free_after: None,
+                portability: Default::default(),
+                check_archive: false,
+                entry_index: false,
+                survive_hangup: false,
+                low_space: None,
+            },
+            output_path,
+            existing_output: ExistingOutput::Replace,
+            interactive: false,
+            skip_empty: false,
+            profile: None,
+            skipped_sources: Vec::new(),
+            root: None,
+            services: Vec::new(),
+            disk_layout: false,
+        }
+    }
+
+    //the names of the files below the source, without the tool's own entries
+    fn archived_files(archive: &Path, source: &Path) -> Vec<String> {
+        let mut reader = tar::Archive::new(flate2::read::GzDecoder::new(File::open(archive).unwrap()));
+        let names: Vec<PathBuf> = reader.entries().unwrap().map(|entry| entry.unwrap().path().unwrap().to_path_buf()).collect();
+        let prefix = source.strip_prefix("/").unwrap();
+        names.iter().filter_map(|name| name.strip_prefix(prefix).ok()).filter(|name| !name.as_os_str().is_empty()).map(|name| name.display().to_string()).collect()
+    }
+
+    #[test]
+    fn interleaved_jobs_keep_their_own_chains() {
+        let temp = tempfile::tempdir().unwrap();
+        let state = temp.path().join("state");
+        let (documents, etc) = (temp.path().join("documents"), temp.path().join("etc"));
+        fs::create_dir_all(&documents).unwrap();
+        fs::create_dir_all(&etc).unwrap();
+        let now = SystemTime::now();
+        let ago = |seconds| now - std::time::Duration::from_secs(seconds);
+        let write = |path: PathBuf, modified: SystemTime| {
+            fs::write(&path, "content").unwrap();
+            File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        };
+
+        //etc was backed up a while ago, a file changed there since, then documents gets its first backup
+        //the fixture was only just written, so the etc chain goes by mtime alone
+        write(etc.join("untouched.txt"), ago(2000));
+        write(etc.join("changed.txt"), ago(500));
+        write(documents.join("report.txt"), ago(500));
+        let mut store = MetadataStore::default();
+        let since = now.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() - 1000;
+        store.record(&utils::job_key(None, std::slice::from_ref(&etc)), BackupMetadata { last_backup_time: Some(since), original_backup_time: Some(since), change_detection: Some(ChangeDetection::Mtime), ..BackupMetadata::default() });
+        fs::create_dir_all(&state).unwrap();
+        utils::persist_metadata_store(&state, &store).unwrap();
+
+        write_backup(plain_job(&documents, temp.path().join("documents-full.tar.gz"), BackupType::Full), &state).unwrap();
+        assert_eq!(archived_files(&temp.path().join("documents-full.tar.gz"), &documents), vec!["report.txt"]);
+
+        //a shared chain would measure etc against the documents backup that just ran and miss the change
+        write_backup(plain_job(&etc, temp.path().join("etc-incr.tar.gz"), BackupType::Incremental), &state).unwrap();
+        assert_eq!(archived_files(&temp.path().join("etc-incr.tar.gz"), &etc), vec!["changed.txt"]);
+
+        write(documents.join("new.txt"), now + std::time::Duration::from_secs(60));
+        write_backup(plain_job(&documents, temp.path().join("documents-incr.tar.gz"), BackupType::Incremental), &state).unwrap();
+        assert_eq!(archived_files(&temp.path().join("documents-incr.tar.gz"), &documents), vec!["new.txt"]);
+
+        assert_eq!(utils::load_metadata_store(&state).unwrap().jobs.len(), 2);
+    }
+
+    #[test]
+    fn runs_without_changes_are_listed_as_empty() {
+        let temp = tempfile::tempdir().unwrap();
+        let state = temp.path().join("state");
+        let source = temp.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&state).unwrap();
+        fs::write(source.join("notes.txt"), "notes").unwrap();
+
+        //a last backup just ahead of everything in the source, nothing counts as changed
+        let since = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() + 60;
+        let mut store = MetadataStore::default();
+        store.record(&utils::job_key(None, std::slice::from_ref(&source)), BackupMetadata { last_backup_time: Some(since), original_backup_time: Some(since), change_detection: Some(ChangeDetection::Either), ..BackupMetadata::default() });
+        utils::persist_metadata_store(&state, &store).unwrap();
+
+        //skip_empty leaves nothing behind, not even the .partial, but the run is still in the history
+        let skipped = temp.path().join("skipped.tar.gz");
+        let job = BackupJob { skip_empty: true, ..plain_job(&source, skipped.clone(), BackupType::Incremental) };
+        assert_eq!(write_backup(job, &state).unwrap(), None);
+        assert!(!skipped.exists());
+        assert!(!sidecars::partial(&skipped).exists());
+
+        //kept, it is listed with no files either way
+        let kept = temp.path().join("kept.tar.gz");
+        write_backup(plain_job(&source, kept.clone(), BackupType::Incremental), &state).unwrap();
+        assert!(kept.exists());
+        let catalog = catalog::load_catalog(&state).unwrap();
+        assert_eq!(catalog.iter().map(|entry| (entry.files, entry.status.clone())).collect::<Vec<_>>(), vec![(0, RunStatus::Empty), (0, RunStatus::Empty)]);
+        assert_eq!(catalog[0].size, 0);
+
+        //a file changes and the next run is a normal one again
+        fs::write(source.join("later.txt"), "later").unwrap();
+        File::options().write(true).open(source.join("later.txt")).unwrap().set_modified(SystemTime::now() + std::time::Duration::from_secs(120)).unwrap();
+        write_backup(plain_job(&source, temp.path().join("changed.tar.gz"), BackupType::Incremental), &state).unwrap();
+        assert_eq!(catalog::load_catalog(&state).unwrap()[2].status, RunStatus::Completed);
+    }
+
+    #[test]
+    fn incrementals_record_deletions_and_restores_apply_them() {
+        use crate::restore::{restore_backup, DeletionPolicy, RestoreOptions};
+        use std::io::Read;
+
+        let temp = tempfile::tempdir().unwrap();
+        let state = temp.path().join("state");
+        let source = temp.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&state).unwrap();
+        fs::write(source.join("kept.txt"), "kept").unwrap();
+        fs::write(source.join("gone.txt"), "gone").unwrap();
+        let (full, incremental) = (temp.path().join("full.tar.gz"), temp.path().join("incr.tar.gz"));
+
+        write_backup(plain_job(&source, full.clone(), BackupType::Full), &state).unwrap();
+        fs::remove_file(source.join("gone.txt")).unwrap();
+        //nothing changed, the deletion alone keeps the archive
+        write_backup(plain_job(&source, incremental.clone(), BackupType::Incremental), &state).unwrap();
+
+        let mut reader = tar::Archive::new(flate2::read::GzDecoder::new(File::open(&incremental).unwrap()));
+        let (mut manifest, mut last) = (String::new(), PathBuf::new());
+        for entry in reader.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            last = entry.path().unwrap().to_path_buf();
+            if last == Path::new(utils::STATE_DIR).join(utils::DELETED_FILES) {
+                entry.read_to_string(&mut manifest).unwrap();
             }
-        })
-    }) {
-        match entry {
-            Ok(entry) => {
-                let path = entry.path();
-                
-                if path.is_file() {
-                    //check if file was modified since original backup
-                    if let Ok(metadata) = path.metadata() {
-                        if let Ok(modified) = metadata.modified() {
-                            if let Ok(modified_secs) = modified.duration_since(SystemTime::UNIX_EPOCH) {
-                                if modified_secs.as_secs() > original_backup_time {
-                                    let name = path.strip_prefix("/").unwrap_or(path);
-                                    progress.set_message(format!("Adding {}", path.display()));
-                                    
-                                    match File::open(path) {
-                                        Ok(mut file) => {
-                                            archive.append_file(name, &mut file)?;
-                                            files_backed_up += 1;
-                                        }
-                                        Err(e) => {
-                                            println!("{}", format!("Warning: Could not open file {}: {}", path.display(), e).yellow());
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                } else if path.is_dir() && entry.depth() > 0 {
-                    //only add directories that are new since original backup
-                    if let Ok(dir_metadata) = path.metadata() {
-                        if let Ok(created) = dir_metadata.created() {
-                            if let Ok(created_secs) = created.duration_since(SystemTime::UNIX_EPOCH) {
-                                if created_secs.as_secs() > original_backup_time {
-                                    let name = path.strip_prefix("/").unwrap_or(path);
-                                    archive.append_dir(name, path)?;
-                                }
-                            }
-                        }
-                    }
-                }
+        }
+        //the archive's own manifest closes it
+        assert_eq!(last, Path::new(utils::STATE_DIR).join(crate::manifest::MANIFEST_FILE));
+        assert_eq!(manifest, format!("{}\n", source.join("gone.txt").strip_prefix("/").unwrap().display()));
+
+        //the history lost the file, so the next incremental doesn't list it again
+        let history = utils::load_metadata_store(&state).unwrap().job(&utils::job_key(None, std::slice::from_ref(&source))).backup_history;
+        assert_eq!(history.keys().collect::<Vec<_>>(), vec![&source.join("kept.txt").display().to_string()]);
+
+        let target = temp.path().join("target");
+        let restored = target.join(source.strip_prefix("/").unwrap());
+        for (archive, deletions) in [(&full, DeletionPolicy::Keep), (&incremental, DeletionPolicy::Remove)] {
+            restore_backup(archive.to_str().unwrap(), target.to_str().unwrap(), &RestoreOptions { ignore_origin: true, deletions, ..RestoreOptions::default() }).unwrap();
+        }
+        assert!(restored.join("kept.txt").exists());
+        assert!(!restored.join("gone.txt").exists());
+    }
+
+    #[test]
+    fn backups_that_would_eat_into_min_free_after_are_refused() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.txt"), "alpha").unwrap();
+        let output = temp.path().join("backup.tar.gz");
+        let free = space::free_space(temp.path()).unwrap();
+
+        let mut job = plain_job(&source, output.clone(), BackupType::Full);
+        job.settings.min_free_after = Some((free / 2, "half".to_string()));
+        assert!(check_free_space_floor(&job.sources, &mut job.exclusions, &job.settings, &output, &BackupMetadata::default(), false).unwrap());
+
+        //more than is free can never be left over
+        job.settings.min_free_after = Some((free + (1 << 40), "a lot".to_string()));
+        let error = check_free_space_floor(&job.sources, &mut job.exclusions, &job.settings, &output, &BackupMetadata::default(), false).unwrap_err();
+        assert!(error.to_string().contains("less than min_free_after (a lot)"), "{}", error);
+        assert_eq!(SpaceMonitor::new(temp.path(), LowSpacePolicy::Abort).with_floor(job.settings.min_free_after.as_ref()).low_water_mark, free + (1 << 40));
+    }
+
+    #[test]
+    fn overlapping_sources_write_every_path_once() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("nested")).unwrap();
+        fs::write(source.join("a.txt"), "alpha").unwrap();
+        fs::write(source.join("nested/b.txt"), "beta").unwrap();
+
+        //the second source lies inside the first one
+        let sources = [source.clone(), source.join("nested")];
+        let mut archive = Builder::new(Vec::new());
+        let mut monitor = SpaceMonitor::new(temp.path(), LowSpacePolicy::Continue);
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+        let mut appender = FileAppender::new(true);
+        let files: u64 = sources
+            .iter()
+            .map(|dir| archive_source(&mut archive, dir, Path::new("/"), &mut selector, &mut appender, &mut monitor, &mut PauseControl::default()).unwrap())
+            .sum();
+        assert_eq!(files, 2);
+
+        let data = archive.into_inner().unwrap();
+        let mut reader = tar::Archive::new(data.as_slice());
+        let names: Vec<PathBuf> = reader.entries().unwrap().map(|entry| entry.unwrap().path().unwrap().to_path_buf()).collect();
+        let prefix = source.strip_prefix("/").unwrap();
+        assert_eq!(names, vec![prefix.join("a.txt"), prefix.join("nested"), prefix.join("nested/b.txt")]);
+    }
+
+    #[test]
+    fn finished_archives_rotate_previous_backups() {
+        let temp = tempfile::tempdir().unwrap();
+        let output = temp.path().join("backup.tar.gz");
+
+        for content in ["first", "second", "third"] {
+            let partial = sidecars::partial(&output);
+            fs::write(&partial, content).unwrap();
+            let moved = finalize_archive(&partial, &output, &ExistingOutput::Rotate).unwrap();
+            //as record_metadata and save_sums would, except for the second archive
+            if content != "second" {
+                fs::write(sidecars::metadata_stub(&output), format!("metadata of {}", content)).unwrap();
+                fs::write(sidecars::checksum(&output), format!("sums of {}", content)).unwrap();
             }
-            Err(e) => {
-                println!("{}", format!("Warning: Error accessing entry: {}", e).yellow());
+            if content == "third" {
+                assert_eq!(moved, vec![(sidecars::rotated(&output, 1), sidecars::rotated(&output, 2)), (output.clone(), sidecars::rotated(&output, 1))]);
             }
         }
+
+        assert_eq!(fs::read_to_string(&output).unwrap(), "third");
+        assert_eq!(fs::read_to_string(sidecars::rotated(&output, 1)).unwrap(), "second");
+        assert_eq!(fs::read_to_string(sidecars::rotated(&output, 2)).unwrap(), "first");
+        assert!(!sidecars::partial(&output).exists());
+        //each archive keeps its own metadata copy and checksums, the second never had any
+        assert_eq!(fs::read_to_string(sidecars::metadata_stub(&output)).unwrap(), "metadata of third");
+        assert!(!sidecars::metadata_stub(&sidecars::rotated(&output, 1)).exists());
+        assert_eq!(fs::read_to_string(sidecars::metadata_stub(&sidecars::rotated(&output, 2))).unwrap(), "metadata of first");
+        assert_eq!(fs::read_to_string(sidecars::checksum(&output)).unwrap(), "sums of third");
+        assert!(!sidecars::checksum(&sidecars::rotated(&output, 1)).exists());
+        assert_eq!(fs::read_to_string(sidecars::checksum(&sidecars::rotated(&output, 2))).unwrap(), "sums of first");
     }
-    
-    progress.finish_with_message(format!("Differential backup of {} completed. {} files backed up.", dir_path, files_backed_up));
-    
-    Ok(())
-} 
\ No newline at end of file
+
+    #[test]
+    fn reproducible_backups_of_identical_content_are_identical() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("b/nested")).unwrap();
+        fs::create_dir_all(source.join("a")).unwrap();
+        fs::write(source.join("b/nested/file.txt"), "nested").unwrap();
+        fs::write(source.join("a/one.txt"), "one").unwrap();
+        fs::write(source.join("top.txt"), "top").unwrap();
+
+        let first = temp.path().join("first.tar.gz");
+        backup_fixture(&source, &first);
+
+        //touching files must not change the archive
+        let later = SystemTime::now() + std::time::Duration::from_secs(3600);
+        File::options().write(true).open(source.join("top.txt")).unwrap().set_modified(later).unwrap();
+
+        let second = temp.path().join("second.tar.gz");
+        backup_fixture(&source, &second);
+
+        assert_eq!(sha256_file(&first).unwrap(), sha256_file(&second).unwrap());
+    }
+
+    #[test]
+    fn subdirectory_sizes_come_from_one_walk() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path();
+        fs::create_dir_all(source.join("videos/2024/raw")).unwrap();
+        fs::create_dir_all(source.join("notes")).unwrap();
+        fs::create_dir_all(source.join("empty")).unwrap();
+        fs::write(source.join("videos/2024/raw/a.mov"), vec![0u8; 3000]).unwrap();
+        fs::write(source.join("videos/b.mov"), vec![0u8; 1000]).unwrap();
+        fs::write(source.join("notes/todo.txt"), "milk").unwrap();
+        fs::write(source.join("top.txt"), "top").unwrap();
+        std::os::unix::fs::symlink(source.join("videos"), source.join("videos-link")).unwrap();
+
+        let (total, mut subdirs) = subdirectory_sizes(source).unwrap();
+        assert_eq!(total, 4007);
+        subdirs.sort();
+        assert_eq!(subdirs, vec![(source.join("empty"), 0), (source.join("notes"), 4), (source.join("videos"), 4000)]);
+        assert!(subdirectory_sizes(&source.join("missing")).is_err());
+    }
+
+    #[test]
+    fn symlinked_sources_are_stored_and_restored_under_the_link() {
+        use crate::restore::{restore_backup, RestoreOptions};
+
+        //~/Documents pointing at /data/docs
+        let temp = tempfile::tempdir().unwrap();
+        let data = temp.path().join("data/docs");
+        fs::create_dir_all(data.join("taxes")).unwrap();
+        fs::write(data.join("taxes/2025.pdf"), "receipts").unwrap();
+        let documents = temp.path().join("home/Documents");
+        fs::create_dir_all(documents.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(&data, &documents).unwrap();
+
+        let archive_path = temp.path().join("backup.tar.gz");
+        let mut archive = create_archive(File::create(&archive_path).unwrap(), ArchiveCompression::default(), false).unwrap();
+        let sources = vec![documents.clone()];
+        sysinfo::append_to_archive(&mut archive, &SystemInfo::current(&sources), utils::entry_mtime(false)).unwrap();
+        let mut monitor = SpaceMonitor::new(temp.path(), LowSpacePolicy::Continue);
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+        archive_source(&mut archive, &documents, Path::new("/"), &mut selector, &mut FileAppender::new(false), &mut monitor, &mut PauseControl::default()).unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let info = sysinfo::read_from_archive(&archive_path, None).unwrap();
+        assert_eq!(info.symlinked_sources.len(), 1);
+        assert_eq!(info.symlinked_sources[0].path, documents.display().to_string());
+        assert_eq!(info.symlinked_sources[0].target, data.canonicalize().unwrap().display().to_string());
+
+        let target = temp.path().join("target");
+        restore_backup(archive_path.to_str().unwrap(), target.to_str().unwrap(), &RestoreOptions { ignore_origin: true, ..RestoreOptions::default() }).unwrap();
+        let restored = target.join(documents.strip_prefix("/").unwrap());
+        assert!(fs::symlink_metadata(&restored).unwrap().is_dir());
+        assert_eq!(fs::read_to_string(restored.join("taxes/2025.pdf")).unwrap(), "receipts");
+        assert!(!target.join(data.strip_prefix("/").unwrap()).exists());
+    }
+
+    #[test]
+    fn runs_without_changes_archive_no_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("notes.txt"), "notes").unwrap();
+        //a last backup just ahead of everything in the source, nothing counts as changed
+        let since = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() + 60;
+        let metadata = BackupMetadata { last_backup_time: Some(since), original_backup_time: Some(since), change_detection: Some(ChangeDetection::Either), ..BackupMetadata::default() };
+
+        let mut archive = create_archive(Vec::new(), ArchiveCompression::default(), false).unwrap();
+        let mut monitor = SpaceMonitor::new(temp.path(), LowSpacePolicy::Continue);
+        for backup_type in [BackupType::Incremental, BackupType::Differential, BackupType::Full] {
+            let settings = BackupSettings { backup_type, compression: ArchiveCompression::default(), reproducible: false, max_age: None, low_space: None, hash_check: false, encryption: None, xattrs: false, acls: false, follow_symlinks: false, wait_for_lock: None, nobackup_markers: false, cache_dirs: false, one_file_system: false, portability: Default::default(), list_skipped: None, max_size: None, presets: Vec::new(), min_free_after: None, check_archive: false, entry_index: false, survive_hangup: false };
+            let mut selector = file_selector(&[], &settings, &metadata, since - 60);
+            let archived = archive_source(&mut archive, &source, Path::new("/"), &mut selector, &mut FileAppender::new(false), &mut monitor, &mut PauseControl::default()).unwrap();
+            //the count is what tells an empty run from one with data
+            assert_eq!(archived, if matches!(settings.backup_type, BackupType::Full) { 1 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn paths_over_the_configured_length_are_found() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        let deep = source.join(["nested"; 10].join("/"));
+        fs::create_dir_all(&deep).unwrap();
+        fs::write(deep.join("file"), "").unwrap();
+        fs::write(source.join("short"), "").unwrap();
+
+        let sources = vec![source.clone()];
+        let limit = source.as_os_str().len() + 40;
+        let found = long_paths(&sources, limit, false);
+        assert!(!found.is_empty());
+        assert!(found.iter().all(|path| path.as_os_str().len() > limit));
+        assert!(found.contains(&deep.join("file")));
+        assert!(!found.contains(&source.join("short")));
+
+        assert!(long_paths(&sources, 4096, false).is_empty());
+    }
+}
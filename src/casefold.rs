@@ -0,0 +1,213 @@
+use colored::*;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+//what happens to archived names that differ only in case from an earlier entry, on a target that ignores case
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CaseCollisionPolicy {
+    //restore them next to the first one as "Name (case 2).txt"
+    #[default]
+    Rename,
+    //keep the first entry and leave the later ones out
+    Skip,
+    //list them and restore nothing
+    Abort,
+}
+
+//where an archived entry goes on a case-insensitive target
+#[derive(Debug, PartialEq)]
+pub enum Destination {
+    Unchanged,
+    Renamed(PathBuf),
+    Skip,
+}
+
+//an archived name the target would have merged with an earlier one
+#[derive(Debug, PartialEq)]
+pub struct Collision {
+    pub name: PathBuf,
+    //the earlier entry that keeps its name
+    pub kept: PathBuf,
+    //the new name, None when the entry was left out
+    pub renamed: Option<PathBuf>,
+}
+
+//the collisions of one restore, found before anything is extracted and looked up while extracting
+#[derive(Default)]
+pub struct CasePlan {
+    pub collisions: Vec<Collision>,
+    renamed: HashMap<PathBuf, PathBuf>,
+    skipped: HashSet<PathBuf>,
+}
+
+impl CasePlan {
+    //entries are (archived name, is a directory) in archive order, the first of each name wins
+    //two directories whose names differ in case are merged by the target, only what's inside them can collide
+    pub fn new(entries: &[(PathBuf, bool)], policy: CaseCollisionPolicy, target_dir: &Path) -> Self {
+        let mut plan = CasePlan::default();
+        let archived: HashSet<OsString> = entries.iter().map(|(name, _)| fold(name)).collect();
+        let mut seen: HashMap<OsString, (PathBuf, bool)> = HashMap::new();
+
+        for (name, is_dir) in entries {
+            //below a renamed or skipped directory already
+            let effective = match plan.destination(name) {
+                Destination::Skip => continue,
+                Destination::Renamed(renamed) => renamed,
+                Destination::Unchanged => name.clone(),
+            };
+
+            let (kept, kept_is_dir) = match seen.get(&fold(&effective)) {
+                None => {
+                    seen.insert(fold(&effective), (effective, *is_dir));
+                    continue;
+                }
+                Some(first) => first.clone(),
+            };
+            if kept == effective || (kept_is_dir && *is_dir) {
+                continue;
+            }
+
+            let renamed = match policy {
+                CaseCollisionPolicy::Rename => {
+                    let renamed = free_name(&effective, &archived, &seen, target_dir);
+                    seen.insert(fold(&renamed), (renamed.clone(), *is_dir));
+                    plan.renamed.insert(name.clone(), renamed.clone());
+                    Some(renamed)
+                }
+                CaseCollisionPolicy::Skip | CaseCollisionPolicy::Abort => {
+                    plan.skipped.insert(name.clone());
+                    None
+                }
+            };
+            plan.collisions.push(Collision { name: name.clone(), kept, renamed });
+        }
+
+        plan
+    }
+
+    //an entry's own decision, or the one made for the directory it is in
+    pub fn destination(&self, name: &Path) -> Destination {
+        for ancestor in name.ancestors() {
+            if self.skipped.contains(ancestor) {
+                return Destination::Skip;
+            }
+            if let Some(renamed) = self.renamed.get(ancestor) {
+                let rest = name.strip_prefix(ancestor).unwrap_or(Path::new(""));
+                return Destination::Renamed(if rest.as_os_str().is_empty() { renamed.clone() } else { renamed.join(rest) });
+            }
+        }
+        Destination::Unchanged
+    }
+
+    //the name an archived path is restored under, for hard link targets
+    pub fn rename(&self, name: &Path) -> PathBuf {
+        match self.destination(name) {
+            Destination::Renamed(renamed) => renamed,
+            _ => name.to_path_buf(),
+        }
+    }
+
+    pub fn report(&self) {
+        if self.collisions.is_empty() {
+            return;
+        }
+
+        println!("{}", format!("{} entries differ only in case from an earlier one, the target ignores case:", self.collisions.len()).yellow());
+        for collision in &self.collisions {
+            match &collision.renamed {
+                Some(renamed) => println!("  {}: restored as {} (collides with {})", collision.name.display(), renamed.display(), collision.kept.display()),
+                None => println!("  {}: left out (collides with {})", collision.name.display(), collision.kept.display()),
+            }
+        }
+    }
+}
+
+//create a name and the same name in capitals, the second one failing means the directory ignores case
+//this catches case-folding ext4 and ciopfs directories, which a filesystem type alone doesn't tell
+pub fn is_case_insensitive(dir: &Path) -> io::Result<bool> {
+    let lower = dir.join(format!(".linux_backup-case-probe-{}", std::process::id()));
+    let upper = dir.join(format!(".LINUX_BACKUP-CASE-PROBE-{}", std::process::id()));
+
+    File::options().write(true).create_new(true).open(&lower)?;
+    let result = match File::options().write(true).create_new(true).open(&upper) {
+        Ok(_) => {
+            let _ = fs::remove_file(&upper);
+            Ok(false)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(true),
+        Err(e) => Err(e),
+    };
+    let _ = fs::remove_file(&lower);
+    result
+}
+
+//close to what case-insensitive filesystems compare, full Unicode case folding aside
+//names that aren't valid UTF-8 only fold their ASCII letters, so two of them never look alike by accident
+fn fold(name: &Path) -> OsString {
+    match name.to_str() {
+        Some(name) => OsString::from(name.to_lowercase()),
+        None => OsString::from_vec(name.as_os_str().as_bytes().to_ascii_lowercase()),
+    }
+}
+
+//"Notes (case 2).txt", numbered past every name in the archive and the target
+fn free_name(name: &Path, archived: &HashSet<OsString>, seen: &HashMap<OsString, (PathBuf, bool)>, target_dir: &Path) -> PathBuf {
+    let mut n = 2;
+    loop {
+        let mut file_name = name.file_stem().unwrap_or_default().to_os_string();
+        file_name.push(format!(" (case {})", n));
+        if let Some(extension) = name.extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+        let candidate = name.with_file_name(file_name);
+        let key = fold(&candidate);
+        if !archived.contains(&key) && !seen.contains_key(&key) && fs::symlink_metadata(target_dir.join(&candidate)).is_err() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_differing_in_case_are_renamed_or_skipped() {
+        let temp = tempfile::tempdir().unwrap();
+        assert!(!is_case_insensitive(temp.path()).unwrap());
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 0);
+
+        let entries: Vec<(PathBuf, bool)> = [
+            ("docs", true),
+            ("docs/Notes.txt", false),
+            ("docs/notes.txt", false),
+            ("DOCS", true),
+            ("DOCS/todo.txt", false),
+            ("Photos", false),
+            ("photos", true),
+            ("photos/cat.jpg", false),
+        ]
+        .iter()
+        .map(|(name, is_dir)| (PathBuf::from(name), *is_dir))
+        .collect();
+
+        //merged directories aren't collisions, a file and a directory are
+        let plan = CasePlan::new(&entries, CaseCollisionPolicy::Rename, temp.path());
+        assert_eq!(plan.collisions.len(), 2);
+        assert_eq!(plan.collisions[0].kept, PathBuf::from("docs/Notes.txt"));
+        assert_eq!(plan.destination(Path::new("docs/notes.txt")), Destination::Renamed(PathBuf::from("docs/notes (case 2).txt")));
+        assert_eq!(plan.destination(Path::new("photos/cat.jpg")), Destination::Renamed(PathBuf::from("photos (case 2)/cat.jpg")));
+        assert_eq!(plan.destination(Path::new("DOCS/todo.txt")), Destination::Unchanged);
+
+        let plan = CasePlan::new(&entries, CaseCollisionPolicy::Skip, temp.path());
+        assert_eq!(plan.destination(Path::new("photos/cat.jpg")), Destination::Skip);
+        assert_eq!(plan.rename(Path::new("docs/Notes.txt")), PathBuf::from("docs/Notes.txt"));
+        assert!(plan.collisions.iter().all(|collision| collision.renamed.is_none()));
+    }
+}
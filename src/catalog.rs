@@ -0,0 +1,264 @@
+use crate::paths;
+use crate::status;
+use crate::users;
+use crate::utils::{self, BackupType};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+//every completed backup of the state directory's chains, oldest first
+pub const CATALOG_FILE: &str = "catalog.json";
+//where a catalog that no longer parses is moved, so the next backup can start a new one
+const CORRUPT_CATALOG_FILE: &str = "catalog.json.corrupt";
+
+//one backup, recorded once its archive is in place
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CatalogEntry {
+    pub time: u64,
+    pub archive: PathBuf,
+    pub size: u64,
+    pub backup_type: BackupType,
+    pub sources: Vec<PathBuf>,
+    //sources that did not exist when the backup ran and were left out, the backup covers less than the chain usually does
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped_sources: Vec<PathBuf>,
+    pub files: u64,
+    //seconds from the start of the walk to the finished archive, pauses included
+    pub duration: f64,
+    //the metadata chain the backup belongs to, see utils::job_key
+    pub job: String,
+    //catalogs from before the field only hold backups with data
+    #[serde(default)]
+    pub status: RunStatus,
+}
+
+//what a run left behind, monitoring tells a schedule that finds nothing apart from one that backs up
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    #[default]
+    Completed,
+    //no file had changed, the archive was kept with nothing in it or not written at all with skip_empty
+    Empty,
+}
+
+//no catalog yet is an empty one
+pub fn load_catalog(dir: &Path) -> Result<Vec<CatalogEntry>, Box<dyn std::error::Error>> {
+    match fs::read(dir.join(CATALOG_FILE)) {
+        Ok(data) => Ok(serde_json::from_slice(&data).map_err(|e| format!("{} is damaged: {}", dir.join(CATALOG_FILE).display(), e))?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+//the backup is done by now, a catalog that can't be written only costs its line in the list
+pub fn record(dir: &Path, entry: CatalogEntry) {
+    let mut entries = match load_catalog(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            let moved = dir.join(CORRUPT_CATALOG_FILE);
+            println!("{}", format!("Warning: {}, it was moved to {} and a new catalog started.", e, moved.display()).yellow());
+            let _ = fs::rename(dir.join(CATALOG_FILE), &moved);
+            Vec::new()
+        }
+    };
+    entries.push(entry);
+    if let Err(e) = write_catalog(dir, &entries) {
+        println!("{}", format!("Warning: Could not add the backup to {}: {}", dir.join(CATALOG_FILE).display(), e).yellow());
+    }
+}
+
+//rotation renamed archives, their entries follow them so the history doesn't point the old backups at the new file
+pub fn archives_moved(dir: &Path, moved: &[(PathBuf, PathBuf)]) {
+    let Ok(mut entries) = load_catalog(dir) else {
+        return;
+    };
+    let mut changed = false;
+    for entry in &mut entries {
+        if let Some((_, to)) = moved.iter().find(|(from, _)| utils::absolute_path(from) == entry.archive) {
+            entry.archive = utils::absolute_path(to);
+            changed = true;
+        }
+    }
+    if changed {
+        if let Err(e) = write_catalog(dir, &entries) {
+            println!("{}", format!("Warning: Could not update the rotated backups in {}: {}", dir.join(CATALOG_FILE).display(), e).yellow());
+        }
+    }
+}
+
+fn write_catalog(dir: &Path, entries: &[CatalogEntry]) -> io::Result<()> {
+    let temp_file = dir.join(format!("{}.tmp", CATALOG_FILE));
+    let mut file = File::create(&temp_file)?;
+    file.write_all(&serde_json::to_vec_pretty(entries)?)?;
+    file.sync_all()?;
+    fs::rename(&temp_file, dir.join(CATALOG_FILE))
+}
+
+//the full backup an incremental or differential one builds on, the latest earlier full of its chain
+pub fn base_of(entries: &[CatalogEntry], index: usize) -> Option<usize> {
+    let entry = &entries[index];
+    if matches!(entry.backup_type, BackupType::Full) {
+        return None;
+    }
+    entries[..index].iter().rposition(|earlier| earlier.job == entry.job && matches!(earlier.backup_type, BackupType::Full) && earlier.status == RunStatus::Completed)
+}
+
+//the list subcommand and the menu's history, one table per chain, or only the chain of `only`
+//entries keep their numbers from the whole catalog either way
+pub fn print_catalog(title: &str, entries: &[CatalogEntry], only: Option<&str>) {
+    println!("{}", format!("\n---- {} ----", title).blue().bold());
+    if !entries.iter().any(|entry| only.is_none_or(|job| entry.job == job)) {
+        println!("No backups have been recorded yet.");
+        return;
+    }
+
+    let mut jobs: Vec<&str> = Vec::new();
+    for entry in entries.iter().filter(|entry| only.is_none_or(|job| entry.job == job)) {
+        if !jobs.contains(&entry.job.as_str()) {
+            jobs.push(&entry.job);
+        }
+    }
+
+    let mut missing = 0;
+    for job in jobs {
+        let chain: Vec<usize> = (0..entries.len()).filter(|&index| entries[index].job == job).collect();
+        let latest = &entries[chain[chain.len() - 1]];
+        let name = job.strip_prefix("profile:").map(|profile| format!("profile {}", profile)).unwrap_or_else(|| "directories".to_string());
+        println!("\n{}: {}", name.bold(), latest.sources.iter().map(|source| source.display().to_string()).collect::<Vec<_>>().join(", "));
+        println!("{:>4}  {:<16}  {:<12}  {:>8}  {:>10}  {:>8}  Archive", "#", "Date", "Type", "Files", "Size", "Took");
+
+        for index in chain {
+            let entry = &entries[index];
+            let mut archive = entry.archive.display().to_string();
+            if entry.status == RunStatus::Empty && !entry.archive.exists() {
+                archive = "(nothing written)".to_string();
+            } else if !entry.archive.exists() {
+                missing += 1;
+                archive = format!("{} {}", archive, "(missing)".red());
+            }
+            let based_on = match base_of(entries, index) {
+                Some(base) if !entries[base].archive.exists() => format!(", needs #{} {}", base + 1, "(missing)".red()),
+                Some(base) => format!(", needs #{}", base + 1),
+                None if !matches!(entry.backup_type, BackupType::Full) => format!(", {}", "no full backup recorded".yellow()),
+                None => String::new(),
+            };
+            let empty = match entry.status {
+                RunStatus::Empty => format!(", {}", "empty".yellow()),
+                RunStatus::Completed => String::new(),
+            };
+            let skipped = match entry.skipped_sources.is_empty() {
+                true => String::new(),
+                false => format!(", {}", format!("left out missing {}", entry.skipped_sources.iter().map(|source| source.display().to_string()).collect::<Vec<_>>().join(", ")).yellow()),
+            };
+            println!(
+                "{:>4}  {:<16}  {:<12}  {:>8}  {:>10}  {:>7.0}s  {}{}{}{}",
+                index + 1,
+                status::local_time(entry.time),
+                type_label(&entry.backup_type),
+                entry.files,
+                utils::format_size(entry.size),
+                entry.duration,
+                archive,
+                based_on,
+                empty,
+                skipped
+            );
+        }
+    }
+
+    if missing > 0 {
+        println!("{}", format!("\nArchives no longer where they were written: {} (restoring them needs the moved copies)", missing).yellow());
+    }
+}
+
+//the invoking user's catalog, and for root the catalogs of the per-user backups too
+pub fn show_catalogs() -> Result<(), Box<dyn std::error::Error>> {
+    print_catalog("Backup History", &load_catalog(&paths::state_dir())?, None);
+    if unsafe { libc::geteuid() == 0 } {
+        for user in users::regular_users() {
+            let entries = load_catalog(&paths::user_state_dir_lookup(&user))?;
+            if !entries.is_empty() {
+                print_catalog(&format!("Backup History of {}", user.name), &entries, None);
+            }
+        }
+    }
+    Ok(())
+}
+
+//the menu's history right after running a profile, just that profile's chain
+pub fn show_profile_catalog(profile: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let job = utils::job_key(Some(profile), &[]);
+    print_catalog(&format!("Backup History of profile {}", profile), &load_catalog(&paths::state_dir())?, Some(&job));
+    Ok(())
+}
+
+fn type_label(backup_type: &BackupType) -> &'static str {
+    match backup_type {
+        BackupType::Full => "full",
+        BackupType::Incremental => "incremental",
+        BackupType::Differential => "differential",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(time: u64, job: &str, backup_type: BackupType) -> CatalogEntry {
+        CatalogEntry { time, archive: PathBuf::from(format!("/backups/{}.tar.gz", time)), size: 0, backup_type, sources: Vec::new(), skipped_sources: Vec::new(), files: 0, duration: 0.0, job: job.to_string(), status: RunStatus::Completed }
+    }
+
+    #[test]
+    fn entries_are_appended_and_linked_to_the_full_of_their_chain() {
+        let temp = tempfile::tempdir().unwrap();
+        assert!(load_catalog(temp.path()).unwrap().is_empty());
+
+        for entry in [
+            entry(1, "profile:home", BackupType::Full),
+            entry(2, "profile:etc", BackupType::Full),
+            entry(3, "profile:home", BackupType::Incremental),
+            entry(4, "profile:home", BackupType::Full),
+            entry(5, "profile:home", BackupType::Differential),
+            entry(6, "profile:etc", BackupType::Incremental),
+            entry(7, "profile:docs", BackupType::Incremental),
+        ] {
+            record(temp.path(), entry);
+        }
+        let entries = load_catalog(temp.path()).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.time).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!((0..entries.len()).map(|index| base_of(&entries, index)).collect::<Vec<_>>(), vec![None, None, Some(0), None, Some(3), Some(1), None]);
+
+        //an empty full run wrote nothing to build on, the chain still needs the full before it
+        let empty = CatalogEntry { status: RunStatus::Empty, ..entry(9, "profile:home", BackupType::Full) };
+        let with_empty = [entries.clone(), vec![empty, entry(10, "profile:home", BackupType::Incremental)]].concat();
+        assert_eq!(base_of(&with_empty, 8), Some(3));
+
+        //a damaged catalog is set aside rather than overwritten
+        fs::write(temp.path().join(CATALOG_FILE), "[{").unwrap();
+        record(temp.path(), entry(8, "profile:home", BackupType::Full));
+        assert_eq!(load_catalog(temp.path()).unwrap().len(), 1);
+        assert_eq!(fs::read_to_string(temp.path().join(CORRUPT_CATALOG_FILE)).unwrap(), "[{");
+    }
+
+    #[test]
+    fn rotated_archives_keep_their_entries() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut older = entry(1, "home", BackupType::Full);
+        older.archive = PathBuf::from("/backups/home.tar.gz.1");
+        let mut newer = entry(2, "home", BackupType::Full);
+        newer.archive = PathBuf::from("/backups/home.tar.gz");
+        record(temp.path(), older);
+        record(temp.path(), newer);
+
+        //.1 moves on first, each entry moves once
+        archives_moved(temp.path(), &[
+            (PathBuf::from("/backups/home.tar.gz.1"), PathBuf::from("/backups/home.tar.gz.2")),
+            (PathBuf::from("/backups/home.tar.gz"), PathBuf::from("/backups/home.tar.gz.1")),
+        ]);
+        let archives: Vec<PathBuf> = load_catalog(temp.path()).unwrap().into_iter().map(|entry| entry.archive).collect();
+        assert_eq!(archives, vec![PathBuf::from("/backups/home.tar.gz.2"), PathBuf::from("/backups/home.tar.gz.1")]);
+    }
+}
@@ -0,0 +1,286 @@
+use crate::backup::{self, ProfileJob};
+use crate::config::{self, Profile};
+use crate::lock;
+use crate::paths;
+use crate::prompt::{Input, MultiSelect, Select};
+use crate::selector::EntryKind;
+use crate::status;
+use crate::utils::{self, BackupMetadata, BackupType};
+use colored::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+//files listed per directory before the rest is only counted
+const FILES_SHOWN: usize = 50;
+
+//what the next backup of a job would take, the files grouped by the directory right below each source
+#[derive(Default)]
+pub struct Changes {
+    pub groups: BTreeMap<PathBuf, Vec<(PathBuf, u64)>>,
+    //files the last backup saw that are gone now
+    pub deleted: Vec<PathBuf>,
+    //deletions only show up against the files an earlier backup recorded
+    pub tracks_deletions: bool,
+}
+
+impl Changes {
+    pub fn files(&self) -> usize {
+        self.groups.values().map(Vec::len).sum()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.groups.values().flatten().map(|(_, size)| size).sum()
+    }
+}
+
+//a dry run of the selection against the stored metadata, nothing is archived and the metadata isn't touched
+pub fn find_changes(job: &ProfileJob, metadata: &BackupMetadata, current_time: u64) -> Changes {
+    let mut selector = backup::file_selector(&job.exclusions, &job.settings, metadata, current_time);
+    //a full profile takes everything, what changed is still measured against the last backup
+    if matches!(job.settings.backup_type, BackupType::Full) {
+        selector = selector.changed_since(metadata.last_backup_time.unwrap_or(0), metadata.change_detection.clone().unwrap_or_default());
+    }
+
+    let mut changes = Changes { tracks_deletions: !metadata.backup_history.is_empty(), ..Changes::default() };
+    for source in &job.sources {
+        for entry in selector.select(source).filter(|entry| entry.kind != EntryKind::Directory) {
+            changes.groups.entry(group_of(source, &entry.path)).or_default().push((entry.path, entry.size));
+        }
+    }
+
+    changes.deleted = selector.deleted(&job.sources);
+    changes
+}
+
+//the directory right below the source a path is in, files directly in the source count towards the source
+fn group_of(source: &Path, path: &Path) -> PathBuf {
+    let mut components = path.strip_prefix(source).unwrap_or(path).components();
+    match (components.next(), components.next()) {
+        (Some(first), Some(_)) => source.join(first),
+        _ => source.to_path_buf(),
+    }
+}
+
+fn group_line(group: &Path, files: &[(PathBuf, u64)]) -> String {
+    let size: u64 = files.iter().map(|(_, size)| size).sum();
+    format!("{:>12} {:>10}  {}", count_files(files.len()), utils::format_size(size), group.display())
+}
+
+fn count_files(count: usize) -> String {
+    format!("{} {}", count, if count == 1 { "file" } else { "files" })
+}
+
+pub fn print_changes(changes: &Changes, last_backup: Option<u64>, current_time: u64) {
+    match last_backup {
+        Some(time) => println!("{}", format!("\n---- Changes since the last backup ({} ago) ----", status::format_age(current_time.saturating_sub(time))).blue().bold()),
+        None => {
+            println!("{}", "\n---- Changes ----".blue().bold());
+            println!("{}", "These directories haven't been backed up yet, the next backup takes every file.".yellow());
+        }
+    }
+
+    if changes.groups.is_empty() {
+        println!("{}", "Nothing changed.".green());
+    } else {
+        for (group, files) in &changes.groups {
+            println!("{}", group_line(group, files));
+        }
+        println!("Total: {} changed, {}", count_files(changes.files()), utils::format_size(changes.size()));
+    }
+
+    if !changes.tracks_deletions {
+        println!("Deleted files are noticed once a backup has recorded the files it saw.");
+    } else if !changes.deleted.is_empty() {
+        println!("{}", format!("{} deleted since the last backup.", count_files(changes.deleted.len())).yellow());
+    }
+}
+
+//a profile, or some directories backed up incrementally with the defaults of config.toml
+pub fn resolve_job(profile: Option<&str>, dirs: &[PathBuf]) -> Result<ProfileJob, Box<dyn std::error::Error>> {
+    let config = config::load_config()?;
+    match profile {
+        Some(name) => ProfileJob::resolve(Some(name), &config.profile(name)?, &config),
+        None => {
+            let dirs = dirs.iter().map(|dir| dir.display().to_string()).collect();
+            ProfileJob::resolve(None, &Profile { dirs, backup_type: Some("incremental".to_string()), ..Profile::default() }, &config)
+        }
+    }
+}
+
+//the changes subcommand, the digest and the deleted files without a question
+pub fn show_changes(job: &ProfileJob) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = chain(job)?;
+    let current_time = now()?;
+    let changes = find_changes(job, &metadata, current_time);
+    print_changes(&changes, metadata.last_backup_time, current_time);
+    print_files(&changes.deleted.iter().map(|path| (path.clone(), None)).collect::<Vec<_>>());
+    Ok(())
+}
+
+//the menu's digest, from where a directory can be looked into or left out before backing up
+pub fn changes_menu() -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    let mut job = match choose_job()? {
+        Some(job) => job,
+        None => return Ok(None),
+    };
+    let metadata = chain(&job)?;
+    if let (Some(name), BackupType::Full) = (&job.profile, &job.settings.backup_type) {
+        println!("{}", format!("The {} profile makes full backups, it takes every file and not only these.", name).yellow());
+    }
+
+    loop {
+        let current_time = now()?;
+        let changes = find_changes(&job, &metadata, current_time);
+        print_changes(&changes, metadata.last_backup_time, current_time);
+        let groups: Vec<&PathBuf> = changes.groups.keys().collect();
+        let labels: Vec<String> = changes.groups.iter().map(|(group, files)| group_line(group, files)).collect();
+
+        let backup_now = format!("Back up now ({})", lock::operation(&job.settings.backup_type));
+        let mut actions = vec![backup_now.as_str()];
+        if !groups.is_empty() {
+            actions.extend(["Show the files of a directory", "Exclude a directory or pattern"]);
+        }
+        if !changes.deleted.is_empty() {
+            actions.push("Show the deleted files");
+        }
+        actions.push("Back to the main menu");
+
+        let action = Select::new()
+            .with_prompt("What next?")
+            .default(0)
+            .items(&actions)
+            .interact()?;
+
+        match actions[action] {
+            "Show the files of a directory" => {
+                let group = Select::new()
+                    .with_prompt("Which directory?")
+                    .default(0)
+                    .items(&labels)
+                    .interact()?;
+                print_files(&changes.groups[groups[group]].iter().map(|(path, size)| (path.clone(), Some(*size))).collect::<Vec<_>>());
+            }
+            "Exclude a directory or pattern" => {
+                let mut options = labels.clone();
+                options.push("Enter a pattern".to_string());
+                let choice = Select::new()
+                    .with_prompt("Leave out what? It only applies to this backup")
+                    .default(0)
+                    .items(&options)
+                    .interact()?;
+                let exclusion = match groups.get(choice) {
+                    Some(group) => group.to_path_buf(),
+                    None => {
+                        let pattern: String = Input::new()
+                            .with_prompt("Path or pattern to exclude (like *.iso)")
+                            .interact_text()?;
+                        PathBuf::from(pattern.trim())
+                    }
+                };
+                if !exclusion.as_os_str().is_empty() {
+                    job.exclusions.extend(utils::exclusion_patterns(&[exclusion]));
+                }
+            }
+            "Show the deleted files" => print_files(&changes.deleted.iter().map(|path| (path.clone(), None)).collect::<Vec<_>>()),
+            "Back to the main menu" => return Ok(None),
+            _ => return job.run(true),
+        }
+    }
+}
+
+//a profile when config.toml has any, otherwise the quick-pick directories
+fn choose_job() -> Result<Option<ProfileJob>, Box<dyn std::error::Error>> {
+    println!("{}", "\n---- What Changed ----".blue().bold());
+    let config = config::load_config()?;
+    let mut options: Vec<String> = config.profiles.keys().map(|name| format!("Profile {}", name)).collect();
+    if !options.is_empty() {
+        options.push("Quick-pick directories".to_string());
+        let choice = Select::new()
+            .with_prompt("Changes of what?")
+            .default(0)
+            .items(&options)
+            .interact()?;
+        if let Some(name) = config.profiles.keys().nth(choice) {
+            return resolve_job(Some(name), &[]).map(Some);
+        }
+    }
+
+    let quick_dirs = config::quick_dirs(&config);
+    if quick_dirs.is_empty() {
+        println!("{}", "None of the quick-pick directories exist, add some to quick_dirs in config.toml.".yellow());
+        return Ok(None);
+    }
+    let chosen = MultiSelect::new()
+        .with_prompt("Which directories?")
+        .items(&quick_dirs)
+        .defaults(&vec![true; quick_dirs.len()])
+        .interact()?;
+    if chosen.is_empty() {
+        println!("{}", "No directories selected, returning to main menu.".yellow());
+        return Ok(None);
+    }
+    let dirs: Vec<PathBuf> = chosen.iter().map(|&i| PathBuf::from(&quick_dirs[i])).collect();
+    resolve_job(None, &dirs).map(Some)
+}
+
+//the job's own chain, the one its next backup continues
+fn chain(job: &ProfileJob) -> Result<BackupMetadata, Box<dyn std::error::Error>> {
+    Ok(utils::load_metadata_store(&paths::state_dir())?.job(&utils::job_key(job.profile.as_deref(), &job.sources)))
+}
+
+fn print_files(files: &[(PathBuf, Option<u64>)]) {
+    for (path, size) in files.iter().take(FILES_SHOWN) {
+        match size {
+            Some(size) => println!("  {:>10}  {}", utils::format_size(*size), path.display()),
+            None => println!("  {}", path.display()),
+        }
+    }
+    if files.len() > FILES_SHOWN {
+        println!("  ...and {} more", files.len() - FILES_SHOWN);
+    }
+}
+
+fn now() -> Result<u64, Box<dyn std::error::Error>> {
+    Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::utils::FileHash;
+    use std::fs;
+
+    #[test]
+    fn changes_are_grouped_below_the_source_and_deletions_found() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("home");
+        fs::create_dir_all(source.join("Documents/taxes")).unwrap();
+        fs::create_dir_all(source.join("Pictures")).unwrap();
+        fs::write(source.join("Documents/taxes/2025.pdf"), "12345").unwrap();
+        fs::write(source.join("Documents/notes.txt"), "abc").unwrap();
+        fs::write(source.join("Pictures/cat.jpg"), "a").unwrap();
+        fs::write(source.join("todo.txt"), "ab").unwrap();
+
+        let profile = Profile { dirs: vec![source.display().to_string()], backup_type: Some("incremental".to_string()), ..Profile::default() };
+        let mut job = ProfileJob::resolve(None, &profile, &Config::default()).unwrap();
+        job.exclusions = vec![source.join("Pictures")];
+        let gone = FileHash { size: 1, mtime: 0, blake3: String::new() };
+        let metadata = BackupMetadata {
+            backup_history: [source.join("Documents/old.txt"), source.join("Pictures/dog.jpg"), temp.path().join("elsewhere.txt"), source.join("todo.txt")]
+                .iter()
+                .map(|path| (path.display().to_string(), gone.clone()))
+                .collect(),
+            ..BackupMetadata::default()
+        };
+
+        let changes = find_changes(&job, &metadata, now().unwrap());
+        let groups: Vec<(&PathBuf, usize)> = changes.groups.iter().map(|(group, files)| (group, files.len())).collect();
+        assert_eq!(groups, vec![(&source, 1), (&source.join("Documents"), 2)]);
+        assert_eq!(changes.size(), 10);
+        //excluded, outside the sources and still there don't count as deleted
+        assert_eq!(changes.deleted, vec![source.join("Documents/old.txt")]);
+        assert!(changes.tracks_deletions);
+    }
+}
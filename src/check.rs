@@ -0,0 +1,268 @@
+use crate::compression::{self, CompressionFormat};
+use crate::encryption::{self, ArchiveKey, Encryption};
+use crate::restore::{self, RestoreOptions};
+use crate::utils;
+use colored::*;
+use std::io::{self, Read};
+use std::path::Path;
+use tar::{EntryType, Header};
+
+const BLOCK: u64 = 512;
+
+//what reading an archive from end to end found, nothing is extracted or written
+#[derive(Debug)]
+pub struct CheckReport {
+    pub format: CompressionFormat,
+    pub entries: u64,
+    //bytes of the decompressed tar stream, headers and padding included
+    pub uncompressed: u64,
+}
+
+impl CheckReport {
+    //the check the decoder made of the stream as a whole once it reached the end
+    fn trailer(&self) -> &'static str {
+        match self.format {
+            CompressionFormat::Gzip => "gzip trailer CRC: OK",
+            CompressionFormat::Zstd => "zstd frames: OK",
+            CompressionFormat::Xz => "xz check: OK",
+            CompressionFormat::Store => "gzip trailer CRC: n/a, the archive is not compressed",
+        }
+    }
+}
+
+//counts what was read so errors can say where the stream broke off
+struct Counted<R> {
+    inner: R,
+    read: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+//Ok(false) when the stream ended right where the block would start, an error when it ended inside it
+fn read_block<R: Read>(reader: &mut Counted<R>, block: &mut [u8; BLOCK as usize]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match reader.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("the stream ends {} bytes into a tar header", filled))),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+//the sum of the header's bytes with its own checksum field counted as spaces, as tar computes it
+fn header_checksum_matches(block: &[u8; BLOCK as usize]) -> bool {
+    let field = &block[148..156];
+    let text = String::from_utf8_lossy(field);
+    let Ok(stored) = u32::from_str_radix(text.trim_matches(|c: char| c == '\0' || c == ' '), 8) else {
+        return false;
+    };
+    let sum: u32 = block.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { b' ' as u32 } else { b as u32 }).sum();
+    sum == stored
+}
+
+//walk every tar header and skip over the data, then read the decoder to its end so the compression trailer is checked too
+pub fn check_stream<R: Read>(format: CompressionFormat, reader: R) -> Result<CheckReport, String> {
+    let mut reader = Counted { inner: reader, read: 0 };
+    let mut block = [0u8; BLOCK as usize];
+    let mut entries = 0;
+    //GNU long names come in an entry of their own before the one they name
+    let mut long_name: Option<String> = None;
+    let broken = |reader: &Counted<R>, entries: u64, e: io::Error| format!("The archive is damaged after {} entries, {} bytes into the tar stream: {}", entries, reader.read, e);
+    let cut_inside = |name: &str, entries: u64, e: io::Error| format!("The archive breaks off in the middle of {} (entry {}): {}", name, entries + 1, e);
+
+    loop {
+        let at = reader.read;
+        if !read_block(&mut reader, &mut block).map_err(|e| broken(&reader, entries, e))? {
+            return Err(format!("The archive ends after {} entries without tar's end-of-archive blocks, it was cut short", entries));
+        }
+        if block.iter().all(|&b| b == 0) {
+            break;
+        }
+        if !header_checksum_matches(&block) {
+            return Err(format!("The tar header at byte {} of the stream (after {} entries) is damaged, its checksum doesn't match", at, entries));
+        }
+
+        let header = Header::from_byte_slice(&block);
+        let size = header.entry_size().map_err(|e| broken(&reader, entries, e))?;
+        let name = long_name.take().unwrap_or_else(|| String::from_utf8_lossy(&header.path_bytes()).to_string());
+        let kind = header.entry_type();
+        let padded = size.div_ceil(BLOCK) * BLOCK;
+
+        let data = if kind == EntryType::GNULongName {
+            let mut data = Vec::new();
+            let result = (&mut reader).take(padded).read_to_end(&mut data);
+            let read = result.map_err(|e| cut_inside(&name, entries, e))? as u64;
+            data.truncate(size as usize);
+            long_name = Some(String::from_utf8_lossy(&data).trim_end_matches('\0').to_string());
+            read
+        } else {
+            let result = io::copy(&mut (&mut reader).take(padded), &mut io::sink());
+            result.map_err(|e| cut_inside(&name, entries, e))?
+        };
+        if data < padded {
+            return Err(format!("The archive ends in the middle of {} (entry {}), {} of its {} bytes are there", name, entries + 1, data.min(size), size));
+        }
+
+        if !matches!(kind, EntryType::GNULongName | EntryType::GNULongLink | EntryType::XHeader | EntryType::XGlobalHeader) {
+            entries += 1;
+        }
+    }
+
+    //the second zero block and the record padding, then the compressed stream's own trailer
+    io::copy(&mut reader, &mut io::sink()).map_err(|e| format!("The archive's contents are intact but its {} stream is damaged past them: {}", format_name(format), e))?;
+    Ok(CheckReport { format, entries, uncompressed: reader.read })
+}
+
+fn format_name(format: CompressionFormat) -> &'static str {
+    match format {
+        CompressionFormat::Gzip => "gzip",
+        CompressionFormat::Zstd => "zstd",
+        CompressionFormat::Xz => "xz",
+        CompressionFormat::Store => "tar",
+    }
+}
+
+pub fn check_archive(path: &Path, key: Option<&ArchiveKey>) -> Result<CheckReport, String> {
+    let (format, decoder) = compression::open_detected(path, key).map_err(|e| format!("{} can't be read: {}", path.display(), e))?;
+    check_stream(format, decoder)
+}
+
+fn print_report(report: &CheckReport) {
+    println!("Entries read: {}", report.entries);
+    println!("Uncompressed size: {}", utils::format_size(report.uncompressed));
+    println!("{}", report.trailer());
+    println!("{}", "The archive is complete.".green());
+}
+
+//the check subcommand, asks for the key of an encrypted archive
+pub fn run_check(backup_file: &str, options: &RestoreOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(backup_file);
+    if !path.exists() {
+        return Err(format!("Backup file does not exist: {}", backup_file).into());
+    }
+    let key = if encryption::is_encrypted(path) {
+        match restore::unlock_archive(path, options)? {
+            Some(key) => Some(key),
+            None => return Ok(()),
+        }
+    } else {
+        None
+    };
+
+    println!("{}", "\n---- Check Archive ----".blue().bold());
+    println!("Reading {}", backup_file);
+    let report = check_archive(path, key.as_ref())?;
+    print_report(&report);
+    Ok(())
+}
+
+//right after a backup, the key is the one it was written with
+//archives encrypted to age or gpg public keys can only be read with the secret key, which the backup doesn't have
+pub fn check_written(path: &Path, encryption: Option<&Encryption>) -> Result<(), Box<dyn std::error::Error>> {
+    let key = match encryption {
+        None => None,
+        Some(Encryption::Passphrase(passphrase)) | Some(Encryption::Builtin(passphrase)) => Some(ArchiveKey::Passphrase(passphrase.clone())),
+        Some(_) => {
+            println!("{}", "The archive is encrypted to a public key, check it with linux_backup check where the secret key is.".yellow());
+            return Ok(());
+        }
+    };
+
+    println!("{}", "\n---- Check Archive ----".blue().bold());
+    let report = check_archive(path, key.as_ref()).map_err(|e| format!("The backup was written but {}: {}", path.display(), e))?;
+    print_report(&report);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+    use tar::Builder;
+
+    fn sample_tar() -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for (name, size) in [("a.txt", 700usize), (&"long/".repeat(30) as &str, 20), ("c.bin", 50000)] {
+            let mut header = Header::new_gnu();
+            header.set_size(size as u64);
+            header.set_mode(0o644);
+            //doesn't compress, so a cut in the gzip stream is a cut at the same place in the tar stream
+            let mut state = 0x9e3779b97f4a7c15u64;
+            let data: Vec<u8> = (0..size)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            builder.append_data(&mut header, format!("{}x", name), data.as_slice()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn complete_archives_pass_and_truncated_ones_fail() {
+        let temp = tempfile::tempdir().unwrap();
+        let tar = sample_tar();
+        let archive = temp.path().join("backup.tar.gz");
+        fs::write(&archive, gzip(&tar)).unwrap();
+
+        let report = check_archive(&archive, None).unwrap();
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.uncompressed, tar.len() as u64);
+        assert_eq!(report.trailer(), "gzip trailer CRC: OK");
+
+        //cut inside the compressed data
+        let compressed = gzip(&tar);
+        fs::write(&archive, &compressed[..compressed.len() / 2]).unwrap();
+        let cut = check_archive(&archive, None).unwrap_err();
+        assert!(cut.contains("breaks off in the middle of c.binx (entry 3)"), "{}", cut);
+
+        //the tar stream is whole but the gzip trailer is missing
+        fs::write(&archive, &compressed[..compressed.len() - 4]).unwrap();
+        assert!(check_archive(&archive, None).is_err());
+
+        //a damaged CRC fails even though every entry reads
+        let mut bad_crc = compressed.clone();
+        let crc = bad_crc.len() - 8;
+        bad_crc[crc] ^= 0xff;
+        fs::write(&archive, &bad_crc).unwrap();
+        assert!(check_archive(&archive, None).unwrap_err().contains("gzip stream is damaged"));
+    }
+
+    #[test]
+    fn plain_tar_cut_mid_entry_or_at_an_entry_boundary_fails() {
+        let tar = sample_tar();
+        //inside c.bin, the last entry, before its padding and the two end blocks
+        let cut = check_stream(CompressionFormat::Store, &tar[..tar.len() - 1024 - 2000]).unwrap_err();
+        assert!(cut.contains("in the middle of c.binx (entry 3)"), "{}", cut);
+
+        //after a.txt, the archive looks fine up to where it stops
+        let boundary = check_stream(CompressionFormat::Store, &tar[..1536]).unwrap_err();
+        assert!(boundary.contains("after 1 entries without tar's end-of-archive blocks"), "{}", boundary);
+
+        let mut damaged = tar.clone();
+        damaged[0] ^= 1;
+        assert!(check_stream(CompressionFormat::Store, damaged.as_slice()).unwrap_err().contains("checksum doesn't match"));
+    }
+}
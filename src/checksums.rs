@@ -0,0 +1,270 @@
+use crate::compression;
+use crate::encryption::{self, ArchiveKey};
+use crate::manifest;
+use crate::restore::{self, RestoreOptions};
+use crate::restore_script::sha256_file;
+use crate::utils;
+use colored::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tar::{Archive, EntryType};
+
+//the SHA-256 of every archived file, in the format sha256sum -c reads, written just before the manifest
+pub const SUMS_FILE: &str = "SHA256SUMS";
+
+//archived names with the hex SHA-256 of their content
+pub type Sums = Vec<(PathBuf, String)>;
+
+//"<hash>  <name>", names holding a backslash or a newline are escaped and the line starts with a backslash, like sha256sum does
+pub fn sums_line(sum: &str, name: &Path) -> Vec<u8> {
+    let name = name.as_os_str().as_bytes();
+    let mut line = Vec::with_capacity(sum.len() + name.len() + 4);
+    if name.iter().any(|&b| b == b'\\' || b == b'\n' || b == b'\r') {
+        line.push(b'\\');
+        line.extend_from_slice(sum.as_bytes());
+        line.extend_from_slice(b"  ");
+        for &b in name {
+            match b {
+                b'\\' => line.extend_from_slice(b"\\\\"),
+                b'\n' => line.extend_from_slice(b"\\n"),
+                b'\r' => line.extend_from_slice(b"\\r"),
+                _ => line.push(b),
+            }
+        }
+    } else {
+        line.extend_from_slice(sum.as_bytes());
+        line.extend_from_slice(b"  ");
+        line.extend_from_slice(name);
+    }
+    line.push(b'\n');
+    line
+}
+
+//lines that don't look like "<64 hex digits>  <name>" are skipped
+pub fn parse_sums(data: &[u8]) -> Sums {
+    let mut sums = Vec::new();
+    for line in data.split(|&b| b == b'\n') {
+        let (escaped, line) = match line.strip_prefix(b"\\") {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        if line.len() < 66 || !line[..64].iter().all(u8::is_ascii_hexdigit) || !(line[64..].starts_with(b"  ") || line[64..].starts_with(b" *")) {
+            continue;
+        }
+        let name = &line[66..];
+        let name = if escaped { unescape(name) } else { name.to_vec() };
+        sums.push((PathBuf::from(OsStr::from_bytes(&name)), String::from_utf8_lossy(&line[..64]).to_lowercase()));
+    }
+    sums
+}
+
+fn unescape(name: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'\\' {
+            unescaped.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => unescaped.push(b'\n'),
+            Some(b'r') => unescaped.push(b'\r'),
+            Some(&other) => unescaped.push(other),
+            None => unescaped.push(b'\\'),
+        }
+    }
+    unescaped
+}
+
+fn sums_entry() -> PathBuf {
+    Path::new(utils::STATE_DIR).join(SUMS_FILE)
+}
+
+//the key of an encrypted archive, None when the user gave up on the passphrase
+fn archive_key(path: &Path, options: &RestoreOptions) -> Result<Option<Option<ArchiveKey>>, Box<dyn std::error::Error>> {
+    if !encryption::is_encrypted(path) {
+        return Ok(Some(None));
+    }
+    Ok(restore::unlock_archive(path, options)?.map(Some))
+}
+
+//read the whole archive, hash every file entry and compare with the checksums recorded in it, returns how many failed
+pub fn verify_archive(backup_file: &str, options: &RestoreOptions) -> Result<usize, Box<dyn std::error::Error>> {
+    let path = Path::new(backup_file);
+    if !path.exists() {
+        return Err(format!("Backup file does not exist: {}", backup_file).into());
+    }
+    let Some(key) = archive_key(path, options)? else {
+        return Ok(0);
+    };
+    println!("{}", "\n---- Verify Backup ----".blue().bold());
+    println!("Reading {}", backup_file);
+
+    //one pass, the checksums are near the end and a compressed archive can only be read front to back
+    let mut archive = Archive::new(compression::open_decoder(path, key.as_ref())?);
+    let mut computed: HashMap<PathBuf, String> = HashMap::new();
+    let mut recorded = None;
+    let mut partial = Vec::new();
+    let mut read_error = None;
+    for entry in archive.entries()? {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                read_error = Some(e);
+                break;
+            }
+        };
+        let name = entry.path()?.to_path_buf();
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous if name == sums_entry() => {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                recorded = Some(parse_sums(&data));
+            }
+            EntryType::Regular | EntryType::Continuous if name == Path::new(utils::STATE_DIR).join(manifest::MANIFEST_FILE) => {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                partial = manifest::partial_files(&data);
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                let mut hasher = Sha256::new();
+                if let Err(e) = io::copy(&mut entry, &mut hasher) {
+                    read_error = Some(e);
+                    break;
+                }
+                computed.insert(name, format!("{:x}", hasher.finalize()));
+            }
+            //a further name of a file stored earlier, its content is that file's
+            EntryType::Link => {
+                if let Some(sum) = entry.link_name()?.and_then(|first| computed.get(first.as_ref()).cloned()) {
+                    computed.insert(name, sum);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let Some(recorded) = recorded else {
+        if let Some(e) = read_error {
+            return Err(format!("The archive could not be read to the end: {}", e).into());
+        }
+        return Err("This archive has no checksums to verify, it was made before backups recorded them or was cut short.".into());
+    };
+
+    let mut failed = 0;
+    for (name, expected) in &recorded {
+        match computed.get(name) {
+            Some(actual) if actual == expected => {}
+            Some(_) => {
+                println!("{}", format!("{}: FAILED, the content doesn't match its checksum", name.display()).red());
+                failed += 1;
+            }
+            None => {
+                println!("{}", format!("{}: FAILED, listed but not in the archive", name.display()).red());
+                failed += 1;
+            }
+        }
+    }
+    if let Some(e) = read_error {
+        println!("{}", format!("The archive could not be read to the end: {}", e).red());
+        failed += 1;
+    }
+
+    if failed == 0 {
+        println!("{}", format!("Files checked: {}, all match their checksums", recorded.len()).green());
+    }
+    manifest::warn_partial(&partial);
+    Ok(failed)
+}
+
+//the checksums recorded in an archive, for checking restored files against them
+pub fn recorded_sums(backup_file: &str, options: &RestoreOptions) -> Result<Option<Sums>, Box<dyn std::error::Error>> {
+    let path = Path::new(backup_file);
+    if !path.exists() {
+        return Err(format!("Backup file does not exist: {}", backup_file).into());
+    }
+    let Some(key) = archive_key(path, options)? else {
+        return Ok(None);
+    };
+    match restore::read_archived_entry(path, key.as_ref(), &sums_entry()) {
+        Ok(data) => {
+            manifest::warn_partial(&manifest::read_entry(path, key.as_ref()).map(|data| manifest::partial_files(&data)).unwrap_or_default());
+            Ok(Some(parse_sums(&data)))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err("This archive has no checksums to verify, it was made before backups recorded them or was cut short.".into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+//hash the restored files below the target, files that aren't there were left out of the restore and only counted
+pub fn verify_restored(sums: &[(PathBuf, String)], target: &Path) -> usize {
+    println!("{}", "\n---- Verify Restored Files ----".blue().bold());
+    let mut checked = 0;
+    let mut absent = 0;
+    let mut failed = 0;
+    for (name, expected) in sums {
+        let path = target.join(name);
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_file() => {}
+            Ok(_) => {
+                println!("{}", format!("{}: FAILED, not a regular file", path.display()).red());
+                failed += 1;
+                continue;
+            }
+            Err(_) => {
+                absent += 1;
+                continue;
+            }
+        }
+        checked += 1;
+        match sha256_file(&path) {
+            Ok(actual) if actual == *expected => {}
+            Ok(_) => {
+                println!("{}", format!("{}: FAILED, the content doesn't match its checksum", path.display()).red());
+                failed += 1;
+            }
+            Err(e) => {
+                println!("{}", format!("{}: FAILED, could not be read: {}", path.display(), e).red());
+                failed += 1;
+            }
+        }
+    }
+
+    println!("Restored files checked: {}", checked);
+    if absent > 0 {
+        println!("{}", format!("Files in the archive but not in the target: {}", absent).yellow());
+    }
+    if failed == 0 && checked > 0 {
+        println!("{}", "All restored files match their checksums".green());
+    }
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_lines_round_trip_and_escape_like_sha256sum() {
+        let sum = "a".repeat(64);
+        assert_eq!(sums_line(&sum, Path::new("home/dave/notes.txt")), format!("{}  home/dave/notes.txt\n", sum).into_bytes());
+        assert_eq!(sums_line(&sum, Path::new("odd\nname\\x")), format!("\\{}  odd\\nname\\\\x\n", sum).into_bytes());
+
+        let mut data = sums_line(&sum, Path::new("home/dave/notes.txt"));
+        data.extend(sums_line(&sum, Path::new("odd\nname\\x")));
+        data.extend(sums_line(&sum, Path::new(OsStr::from_bytes(b"caf\xe9"))));
+        data.extend(b"not a checksum line\n");
+        assert_eq!(
+            parse_sums(&data),
+            vec![
+                (PathBuf::from("home/dave/notes.txt"), sum.clone()),
+                (PathBuf::from("odd\nname\\x"), sum.clone()),
+                (PathBuf::from(OsStr::from_bytes(b"caf\xe9")), sum.clone()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,329 @@
+use age::secrecy::{ExposeSecret, SecretString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+//the built-in format for passphrase encryption, readable without age or gpg installed:
+//header | chunk 0 | chunk 1 | ... | last chunk
+//header: magic, version, chunk size, argon2id memory (KiB), iterations and lanes, salt, nonce prefix
+//every chunk is chunk size bytes of XChaCha20-Poly1305 ciphertext plus its tag, the last one is shorter,
+//possibly just a tag, so a stream cut at a chunk boundary is noticed
+const MAGIC: &[u8; 8] = b"LBTCRYPT";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+//the nonce is the prefix, a big endian chunk counter and a last chunk flag
+const NONCE_PREFIX_LEN: usize = 19;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + 12 + SALT_LEN + NONCE_PREFIX_LEN;
+
+pub const EXTENSION: &str = ".lbtc";
+
+//plaintext bytes per chunk, only this much of the archive is held in memory at a time
+const CHUNK_SIZE: u32 = 64 * 1024;
+
+//anything bigger in a header is a damaged file, not a real setting
+const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+
+//cost of turning the passphrase into a key, stored in the header so it can be raised later
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams { memory_kib: 64 * 1024, iterations: 3, lanes: 1 }
+    }
+}
+
+struct Header {
+    chunk_size: u32,
+    kdf: KdfParams,
+    salt: [u8; SALT_LEN],
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        for field in [self.chunk_size, self.kdf.memory_kib, self.kdf.iterations, self.kdf.lanes] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.nonce_prefix);
+        bytes
+    }
+
+    fn parse(bytes: &[u8; HEADER_LEN]) -> io::Result<Self> {
+        if &bytes[..MAGIC.len()] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an encrypted linux_backup archive"));
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported encryption format version {}", bytes[MAGIC.len()])));
+        }
+
+        let field = |i: usize| {
+            let start = MAGIC.len() + 1 + i * 4;
+            u32::from_le_bytes(bytes[start..start + 4].try_into().unwrap())
+        };
+        let header = Header {
+            chunk_size: field(0),
+            kdf: KdfParams { memory_kib: field(1), iterations: field(2), lanes: field(3) },
+            salt: bytes[HEADER_LEN - NONCE_PREFIX_LEN - SALT_LEN..HEADER_LEN - NONCE_PREFIX_LEN].try_into().unwrap(),
+            nonce_prefix: bytes[HEADER_LEN - NONCE_PREFIX_LEN..].try_into().unwrap(),
+        };
+
+        if header.chunk_size == 0 || header.chunk_size > MAX_CHUNK_SIZE || header.kdf.memory_kib > MAX_MEMORY_KIB {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "damaged encryption header"));
+        }
+        Ok(header)
+    }
+
+    fn cipher(&self, passphrase: &SecretString) -> io::Result<XChaCha20Poly1305> {
+        let params = Params::new(self.kdf.memory_kib, self.kdf.iterations, self.kdf.lanes, Some(32))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("damaged encryption header: {}", e)))?;
+
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.expose_secret().as_bytes(), &self.salt, &mut key)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+
+    fn nonce(&self, counter: u32, last: bool) -> XNonce {
+        let mut nonce = [0u8; 24];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..23].copy_from_slice(&counter.to_be_bytes());
+        nonce[23] = last as u8;
+        nonce.into()
+    }
+}
+
+//encrypts everything written to it, finish() must be called to write the last chunk
+pub struct CipherWriter<W: Write> {
+    inner: W,
+    header: Header,
+    //the header is authenticated with every chunk
+    aad: Vec<u8>,
+    cipher: XChaCha20Poly1305,
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> CipherWriter<W> {
+    pub fn new(inner: W, passphrase: &SecretString) -> io::Result<Self> {
+        CipherWriter::with_params(inner, passphrase, KdfParams::default(), CHUNK_SIZE)
+    }
+
+    pub fn with_params(mut inner: W, passphrase: &SecretString, kdf: KdfParams, chunk_size: u32) -> io::Result<Self> {
+        let mut header = Header { chunk_size, kdf, salt: [0; SALT_LEN], nonce_prefix: [0; NONCE_PREFIX_LEN] };
+        OsRng.fill_bytes(&mut header.salt);
+        OsRng.fill_bytes(&mut header.nonce_prefix);
+
+        let aad = header.to_bytes();
+        inner.write_all(&aad)?;
+        let cipher = header.cipher(passphrase)?;
+        Ok(CipherWriter { inner, header, aad, cipher, counter: 0, buffer: Vec::with_capacity(chunk_size as usize) })
+    }
+
+    fn write_chunk(&mut self, plain: &[u8], last: bool) -> io::Result<()> {
+        let nonce = self.header.nonce(self.counter, last);
+        let sealed = self.cipher
+            .encrypt(&nonce, Payload { msg: plain, aad: &self.aad })
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        self.inner.write_all(&sealed)?;
+
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| io::Error::other("archive too large for the encryption format"))?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let rest = std::mem::take(&mut self.buffer);
+        self.write_chunk(&rest, true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for CipherWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk_size = self.header.chunk_size as usize;
+        let taken = buf.len().min(chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..taken]);
+
+        //a full chunk is never the last one, the last one is written by finish()
+        if self.buffer.len() == chunk_size {
+            let full = std::mem::replace(&mut self.buffer, Vec::with_capacity(chunk_size));
+            self.write_chunk(&full, false)?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+//decrypts chunk by chunk, a wrong passphrase fails on the first chunk before any plaintext comes out
+pub struct CipherReader<R: Read> {
+    inner: R,
+    header: Header,
+    aad: Vec<u8>,
+    cipher: XChaCha20Poly1305,
+    counter: u32,
+    plain: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<R: Read> CipherReader<R> {
+    pub fn new(mut inner: R, passphrase: &SecretString) -> io::Result<Self> {
+        let mut bytes = [0u8; HEADER_LEN];
+        inner.read_exact(&mut bytes)?;
+        let header = Header::parse(&bytes)?;
+        let cipher = header.cipher(passphrase)?;
+
+        let mut reader = CipherReader { inner, header, aad: bytes.to_vec(), cipher, counter: 0, plain: Vec::new(), position: 0, done: false };
+        reader.next_chunk()?;
+        Ok(reader)
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let sealed_size = self.header.chunk_size as usize + TAG_LEN;
+        let mut sealed = vec![0u8; sealed_size];
+        let read = read_full(&mut self.inner, &mut sealed)?;
+        if read < TAG_LEN {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the encrypted archive is truncated"));
+        }
+
+        let last = read < sealed_size;
+        let nonce = self.header.nonce(self.counter, last);
+        self.plain = self.cipher
+            .decrypt(&nonce, Payload { msg: &sealed[..read], aad: &self.aad })
+            .map_err(|_| match self.counter {
+                0 => io::Error::new(io::ErrorKind::PermissionDenied, "wrong passphrase or key"),
+                chunk => io::Error::new(io::ErrorKind::InvalidData, format!("the encrypted archive is damaged at chunk {}", chunk)),
+            })?;
+        self.position = 0;
+        self.done = last;
+        self.counter = self.counter.wrapping_add(1);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for CipherReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+
+        let read = buf.len().min(self.plain.len() - self.position);
+        buf[..read].copy_from_slice(&self.plain[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+//fills the buffer unless the stream ends first, returns how much was read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+//the magic decides, like for age archives the name alone isn't enough
+pub fn is_encrypted(path: &Path) -> bool {
+    let mut magic = [0u8; MAGIC.len()];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| &magic == MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //cheap enough for debug builds, the format doesn't depend on the cost
+    const TEST_KDF: KdfParams = KdfParams { memory_kib: 64, iterations: 1, lanes: 1 };
+
+    fn passphrase(text: &str) -> SecretString {
+        SecretString::from(text.to_string())
+    }
+
+    fn seal(data: &[u8], chunk_size: u32) -> Vec<u8> {
+        let mut writer = CipherWriter::with_params(Vec::new(), &passphrase("correct horse"), TEST_KDF, chunk_size).unwrap();
+        //odd write sizes so writes straddle chunk boundaries
+        for piece in data.chunks(37) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn open(sealed: &[u8], text: &str) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        CipherReader::new(sealed, &passphrase(text))?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn streams_round_trip_across_chunk_boundaries() {
+        //tiny chunks stand in for the thousands of chunks of a multi-gigabyte archive
+        let chunk_size = 64;
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        for len in [0, 1, 63, 64, 65, 128, 4096, data.len()] {
+            let sealed = seal(&data[..len], chunk_size);
+            let chunks = len / chunk_size as usize + 1;
+            assert_eq!(sealed.len(), HEADER_LEN + len + chunks * TAG_LEN);
+            assert_eq!(open(&sealed, "correct horse").unwrap(), &data[..len]);
+        }
+
+        let sealed = seal(&data, CHUNK_SIZE);
+        assert_eq!(open(&sealed, "correct horse").unwrap(), data);
+    }
+
+    #[test]
+    fn wrong_passphrases_and_damage_are_caught() {
+        let data = vec![7u8; 1000];
+        let sealed = seal(&data, 64);
+
+        //the first chunk fails to authenticate before the reader hands out anything
+        let error = CipherReader::new(&sealed[..], &passphrase("battery staple")).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        let mut flipped = sealed.clone();
+        flipped[HEADER_LEN + 5 * (64 + TAG_LEN) + 3] ^= 1;
+        assert_eq!(open(&flipped, "correct horse").unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        //cut right after a full chunk, the missing last chunk is noticed
+        let truncated = &sealed[..HEADER_LEN + 4 * (64 + TAG_LEN)];
+        assert_eq!(open(truncated, "correct horse").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut tampered_header = sealed.clone();
+        tampered_header[HEADER_LEN - 1] ^= 1;
+        assert_eq!(open(&tampered_header, "correct horse").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let mut newer = sealed;
+        newer[MAGIC.len()] = 2;
+        assert_eq!(open(&newer, "correct horse").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
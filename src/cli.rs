@@ -1,53 +1,1216 @@
+use crate::backup::{self, ApplyRecommendation, BackupJob, BackupSettings, ExistingOutput, ProfileJob};
+use crate::casefold;
+use crate::catalog;
+use crate::changes;
+use crate::check;
+use crate::checksums;
+use crate::compression::{self, ArchiveCompression};
+use crate::config;
+use crate::conflicts;
+use crate::diff;
+use crate::encryption::{self, Encryption};
+use crate::entry_index;
+use crate::estimate;
+use crate::hangup;
+use crate::lock;
+use crate::paths;
+use crate::presets::{self, Preset};
+use crate::repack;
+use crate::restore::{restore_backup, show_archive_info, show_saved_disk_layout, DeletionPolicy, RestoreOptions};
+use crate::selector;
+use crate::selftest;
+use crate::services;
+use crate::sidecars;
+use crate::space;
+use crate::status;
+use crate::throttle::{self, Throttling};
+use crate::users;
+use crate::userns;
+use crate::utils;
+use crate::volumes;
 use clap::{Parser, Subcommand, ValueEnum};
+use colored::*;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(name = "linux_backup")]
 #[command(about = "A backup tool for Linux directories", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 pub struct Cli {
+    //without a subcommand the interactive menu is shown
     #[command(subcommand)]
-    pub command: Commands,
+    pub command: Option<Commands>,
+
+    ///run a backup profile from config.toml without any prompts
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
 }
 
 #[derive(Clone, ValueEnum, Debug)]
 pub enum BackupType {
-    //full backup (copies all files)
+    ///full backup (copies all files)
     Full,
-    //incremental backup (copies only files changed since last backup)
+    ///incremental backup (copies only files changed since last backup)
     Incremental,
-    //differential backup (copies files that have changed since original backup)
+    ///differential backup (copies files that have changed since original backup)
     Differential,
 }
 
+#[derive(Clone, ValueEnum, Debug)]
+pub enum CompressionFormat {
+    ///.tar.gz, readable everywhere
+    Gzip,
+    ///.tar.zst, faster and smaller
+    Zstd,
+    ///.tar.xz, smallest but slowest
+    Xz,
+    ///plain .tar, no compression at all
+    #[value(name = "none")]
+    Store,
+}
+
+#[derive(Clone, ValueEnum, Debug)]
+pub enum LowSpacePolicy {
+    ///close the archive and fail, it is kept as .partial
+    Abort,
+    ///leave out files larger than the space that is left and keep going
+    SkipLarger,
+    ///fill the destination regardless
+    Continue,
+}
+
+#[derive(Clone, ValueEnum, Debug)]
+pub enum ConflictPolicy {
+    ///replace existing files with the archived version
+    Overwrite,
+    ///keep existing files
+    Skip,
+    ///replace existing files only with newer archived ones
+    Newer,
+    ///decide per file, offered in the menu only
+    #[value(hide = true)]
+    Ask,
+}
+
+#[derive(Clone, ValueEnum, Debug)]
+pub enum CaseCollisionPolicy {
+    ///restore later names next to the first as "Name (case 2).txt"
+    Rename,
+    ///restore only the first of the names
+    Skip,
+    ///list the collisions and restore nothing
+    Abort,
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     //backup specific directories
+    #[command(about = "Back up the quick-pick directories")]
     Selective {
-        //output file name
-        #[arg(short, long, default_value = "backup.tar.gz")]
-        output: String,
+        ///directories to back up, repeatable or comma-separated (default: the quick-pick list)
+        #[arg(long, value_name = "PATH", value_delimiter = ',')]
+        dirs: Vec<PathBuf>,
+
+        ///output file name (default: backup.tar.gz, .tar.zst, .tar.xz or .tar)
+        #[arg(short, long)]
+        output: Option<String>,
 
-        //type of backup to perform
+        ///type of backup to perform
         #[arg(short, long, value_enum, default_value = "full")]
         backup_type: BackupType,
+
+        ///compression format of the archive
+        #[arg(long, value_enum, default_value = "gzip")]
+        compression: CompressionFormat,
+
+        ///compression level in the format's own range: gzip 1-9, zstd 1-22, xz 0-9 (default: 6, 3, 6)
+        ///
+        ///reproducible archives always use the default level
+        #[arg(long, value_name = "N", conflicts_with = "reproducible")]
+        compression_level: Option<i32>,
+
+        ///threads used for gzip compression (default: all available cores)
+        #[arg(long, value_name = "N")]
+        threads: Option<usize>,
+
+        ///produce byte-identical archives for identical content
+        #[arg(long)]
+        reproducible: bool,
+
+        ///replace an existing archive at the output path once the new one is complete
+        #[arg(long)]
+        overwrite: bool,
+
+        ///keep an existing archive at the output path as backup.tar.gz.1, with its checksums and index (earlier ones move on to .2 and so on)
+        #[arg(long, conflicts_with_all = ["overwrite", "volume_size"])]
+        rotate: bool,
+
+        ///don't write an archive when no files were backed up
+        #[arg(long)]
+        skip_empty: bool,
+
+        ///read the finished archive back from start to end, and fail when it doesn't read as a whole
+        #[arg(long)]
+        verify: bool,
+
+        ///write backup.tar.gz.index next to the archive, single entries are then read without decompressing everything before them (gzip and uncompressed archives)
+        #[arg(long, conflicts_with = "volume_size")]
+        index: bool,
+
+        ///skip files not modified within this duration (e.g. 90d, 12w, 1y)
+        #[arg(long, value_name = "DURATION")]
+        exclude_older_than: Option<String>,
+
+        ///what to do when the destination runs low on space during the run (low_water_mark in config.toml, default 5% of the filesystem up to 512M)
+        #[arg(long, value_enum, default_value = "abort")]
+        on_low_space: LowSpacePolicy,
+
+        ///skip files larger than this (e.g. 500M, 2G), VM images and ISOs without a list of globs
+        #[arg(long, value_name = "SIZE")]
+        max_file_size: Option<String>,
+
+        ///write the paths of the files skipped for their size to this file, one per line
+        #[arg(long, value_name = "FILE", requires = "max_file_size")]
+        list_skipped: Option<String>,
+
+        ///refuse to start when the archive would leave less than this free on the destination (e.g. 50G), and stop there
+        #[arg(long, value_name = "SIZE")]
+        min_free_after: Option<String>,
+
+        ///leave out a path, a glob like ~/projects/*/build, or every entry with a name like target or *.log, repeatable
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<PathBuf>,
+
+        ///leave out a bundle of patterns: media (videos, ISOs, FLAC), build (target/, node_modules/, *.o) or vm (disk images, docker layers), repeatable
+        #[arg(long, value_name = "PRESET", value_parser = presets::parse)]
+        preset: Vec<&'static Preset>,
+
+        ///decide what changed by content hash instead of timestamps
+        #[arg(long)]
+        hash_check: bool,
+
+        ///set damaged backup metadata aside and start a new backup chain
+        #[arg(long)]
+        reset_metadata: bool,
+
+        ///encrypt the archive with a passphrase, asked for with hidden input
+        #[arg(long)]
+        encrypt: bool,
+
+        ///encrypt the archive to this age public key instead, repeatable
+        #[arg(long, value_name = "AGE_KEY", conflicts_with = "encrypt")]
+        recipient: Vec<String>,
+
+        ///encrypt the finished archive with gpg to this key id, fingerprint or email, repeatable
+        #[arg(long, value_name = "KEYID", conflicts_with_all = ["encrypt", "recipient"])]
+        gpg_recipient: Vec<String>,
+
+        ///encrypt with a passphrase in linux_backup's own format (argon2id, XChaCha20-Poly1305), restores need neither age nor gpg
+        #[arg(long, conflicts_with_all = ["encrypt", "recipient", "gpg_recipient"])]
+        encrypt_builtin: bool,
+
+        ///store only or use the fastest level when most data is compressed already
+        #[arg(long)]
+        auto_compress: bool,
+
+        ///don't back up, explain which rule includes or excludes this path
+        #[arg(long, value_name = "PATH")]
+        why: Option<PathBuf>,
+        ///leave out extended attributes and file capabilities, saves a few syscalls per file on large trees
+        #[arg(long)]
+        no_xattrs: bool,
+
+        ///store POSIX ACLs set with setfacl
+        #[arg(long)]
+        acls: bool,
+
+        ///archive what symlinks point to instead of the links, like tar -h
+        #[arg(long)]
+        follow_symlinks: bool,
+
+        ///back up the contents of directories marked with a .nobackup file too
+        #[arg(long)]
+        ignore_nobackup: bool,
+
+        ///back up the contents of cache directories tagged with a CACHEDIR.TAG too
+        #[arg(long)]
+        include_caches: bool,
+
+        ///don't descend into filesystems mounted below the directories, like tar --one-file-system
+        #[arg(long)]
+        one_file_system: bool,
+
+        ///queue behind a running backup of the same chain for up to this long (e.g. 30m, 2h)
+        #[arg(long, value_name = "DURATION")]
+        wait_for_lock: Option<String>,
+
+        ///keep going in the background when the terminal closes, output goes to hangup.log in the state directory (already the default, accepted for scripts that pass it)
+        #[arg(long)]
+        survive_hangup: bool,
+
+        ///end the backup with the terminal, as SIGHUP normally does
+        #[arg(long, conflicts_with = "survive_hangup")]
+        no_survive_hangup: bool,
+
+        ///split a full backup into standalone volumes for discs: cd, dvd, dvd-dl, bd, bd-dl, bd-xl or a size like 4G
+        ///
+        ///each gets a .sha256 file, an index.json lists the files in each, the backup chain is left alone
+        #[arg(long, value_name = "SIZE", value_parser = volumes::parse_volume_size, conflicts_with_all = ["encrypt", "recipient", "gpg_recipient", "encrypt_builtin", "reset_metadata", "wait_for_lock", "min_free_after"])]
+        volume_size: Option<u64>,
     },
     //backup entire system (excluding system directories)
+    #[command(about = "Back up the whole system, excluding virtual and cache directories")]
     Full {
-        //output file name
-        #[arg(short, long, default_value = "system_backup.tar.gz")]
-        output: String,
+        ///output file name, or the output directory with --per-user
+        #[arg(short, long)]
+        output: Option<String>,
 
-        //type of backup to perform
+        ///type of backup to perform
         #[arg(short, long, value_enum, default_value = "full")]
         backup_type: BackupType,
+
+        ///compression format of the archive
+        #[arg(long, value_enum, default_value = "gzip")]
+        compression: CompressionFormat,
+
+        ///compression level in the format's own range: gzip 1-9, zstd 1-22, xz 0-9 (default: 6, 3, 6)
+        ///
+        ///reproducible archives always use the default level
+        #[arg(long, value_name = "N", conflicts_with = "reproducible")]
+        compression_level: Option<i32>,
+
+        ///threads used for gzip compression (default: all available cores)
+        #[arg(long, value_name = "N")]
+        threads: Option<usize>,
+
+        ///produce byte-identical archives for identical content
+        #[arg(long)]
+        reproducible: bool,
+
+        ///replace an existing archive at the output path once the new one is complete
+        #[arg(long)]
+        overwrite: bool,
+
+        ///keep an existing archive at the output path as system_backup.tar.gz.1, with its checksums and index (earlier ones move on to .2 and so on)
+        #[arg(long, conflicts_with = "overwrite")]
+        rotate: bool,
+
+        ///don't write an archive when no files were backed up
+        #[arg(long)]
+        skip_empty: bool,
+
+        ///read the finished archive back from start to end, and fail when it doesn't read as a whole
+        #[arg(long)]
+        verify: bool,
+
+        ///write backup.tar.gz.index next to the archive, single entries are then read without decompressing everything before them (gzip and uncompressed archives)
+        #[arg(long)]
+        index: bool,
+
+        ///skip files not modified within this duration (e.g. 90d, 12w, 1y)
+        #[arg(long, value_name = "DURATION")]
+        exclude_older_than: Option<String>,
+
+        ///what to do when the destination runs low on space during the run (low_water_mark in config.toml, default 5% of the filesystem up to 512M)
+        #[arg(long, value_enum, default_value = "abort")]
+        on_low_space: LowSpacePolicy,
+
+        ///skip files larger than this (e.g. 500M, 2G), VM images and ISOs without a list of globs
+        #[arg(long, value_name = "SIZE")]
+        max_file_size: Option<String>,
+
+        ///write the paths of the files skipped for their size to this file, one per line
+        #[arg(long, value_name = "FILE", requires = "max_file_size")]
+        list_skipped: Option<String>,
+
+        ///refuse to start when the archive would leave less than this free on the destination (e.g. 50G), and stop there
+        #[arg(long, value_name = "SIZE")]
+        min_free_after: Option<String>,
+
+        ///leave out a path, a glob like /srv/*/cache, or every entry with a name like target or *.log, repeatable
+        #[arg(long, value_name = "GLOB", conflicts_with = "per_user")]
+        exclude: Vec<PathBuf>,
+
+        ///leave out a bundle of patterns: media (videos, ISOs, FLAC), build (target/, node_modules/, *.o) or vm (disk images, docker layers), repeatable
+        #[arg(long, value_name = "PRESET", value_parser = presets::parse)]
+        preset: Vec<&'static Preset>,
+
+        ///decide what changed by content hash instead of timestamps
+        #[arg(long)]
+        hash_check: bool,
+
+        ///set damaged backup metadata aside and start a new backup chain
+        #[arg(long)]
+        reset_metadata: bool,
+
+        ///encrypt the archive with a passphrase, asked for with hidden input
+        #[arg(long)]
+        encrypt: bool,
+
+        ///encrypt the archive to this age public key instead, repeatable
+        #[arg(long, value_name = "AGE_KEY", conflicts_with = "encrypt")]
+        recipient: Vec<String>,
+
+        ///encrypt the finished archive with gpg to this key id, fingerprint or email, repeatable
+        #[arg(long, value_name = "KEYID", conflicts_with_all = ["encrypt", "recipient"])]
+        gpg_recipient: Vec<String>,
+
+        ///encrypt with a passphrase in linux_backup's own format (argon2id, XChaCha20-Poly1305), restores need neither age nor gpg
+        #[arg(long, conflicts_with_all = ["encrypt", "recipient", "gpg_recipient"])]
+        encrypt_builtin: bool,
+
+        ///store only or use the fastest level when most data is compressed already
+        #[arg(long)]
+        auto_compress: bool,
+
+        ///don't back up, explain which rule includes or excludes this path
+        #[arg(long, value_name = "PATH", conflicts_with = "per_user")]
+        why: Option<PathBuf>,
+
+        ///back up a system mounted here (e.g. /mnt/sysroot), stored as if it was mounted at /
+        #[arg(long, value_name = "DIR", conflicts_with = "per_user")]
+        root: Option<String>,
+
+        ///write one archive per user ({hostname}_{user}_{date}.tar.gz) with metadata in each home
+        #[arg(long)]
+        per_user: bool,
+
+        ///save this machine's partition tables, LUKS headers, RAID and LVM metadata for a bare-metal restore, root only
+        #[arg(long, conflicts_with_all = ["per_user", "root"])]
+        disk_layout: bool,
+        ///leave out extended attributes and file capabilities, saves a few syscalls per file on large trees
+        #[arg(long)]
+        no_xattrs: bool,
+
+        ///store POSIX ACLs set with setfacl
+        #[arg(long)]
+        acls: bool,
+
+        ///archive what symlinks point to instead of the links, like tar -h
+        #[arg(long)]
+        follow_symlinks: bool,
+
+        ///back up the contents of directories marked with a .nobackup file too
+        #[arg(long)]
+        ignore_nobackup: bool,
+
+        ///back up the contents of cache directories tagged with a CACHEDIR.TAG too
+        #[arg(long)]
+        include_caches: bool,
+
+        ///descend into filesystems mounted below the system directories, NFS shares and external disks included
+        #[arg(long)]
+        cross_filesystems: bool,
+
+        ///queue behind a running backup of the same chain for up to this long (e.g. 30m, 2h)
+        #[arg(long, value_name = "DURATION")]
+        wait_for_lock: Option<String>,
+
+        ///keep going in the background when the terminal closes, output goes to hangup.log in the state directory (already the default, accepted for scripts that pass it)
+        #[arg(long)]
+        survive_hangup: bool,
+
+        ///end the backup with the terminal, as SIGHUP normally does
+        #[arg(long, conflicts_with = "survive_hangup")]
+        no_survive_hangup: bool,
+    },
+    //estimate the compressed size of a backup without writing it
+    #[command(about = "Estimate how large a full backup would be, by compressing a sample")]
+    Estimate {
+        ///a profile from config.toml, estimated with its directories, exclusions and compression
+        #[arg(long, value_name = "NAME", conflicts_with = "dirs")]
+        profile: Option<String>,
+
+        ///directories to estimate, repeatable or comma-separated (default: the quick-pick list)
+        #[arg(long, value_name = "PATH", value_delimiter = ',')]
+        dirs: Vec<PathBuf>,
+
+        ///compression format to estimate for (default: the profile's, or gzip)
+        #[arg(long, value_enum)]
+        compression: Option<CompressionFormat>,
+
+        ///compression level to estimate for, in the format's own range
+        #[arg(long, value_name = "N")]
+        compression_level: Option<i32>,
+
+        ///leave out a path, a glob like ~/projects/*/build, or every entry with a name like target or *.log, repeatable
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<PathBuf>,
+
+        ///leave out a bundle of patterns: media, build or vm, repeatable
+        #[arg(long, value_name = "PRESET", value_parser = presets::parse)]
+        preset: Vec<&'static Preset>,
+
+        ///skip files not modified within this duration (e.g. 90d, 12w, 1y)
+        #[arg(long, value_name = "DURATION")]
+        exclude_older_than: Option<String>,
+
+        ///skip files larger than this (e.g. 500M, 2G)
+        #[arg(long, value_name = "SIZE")]
+        max_file_size: Option<String>,
+
+        ///count what symlinks point to instead of the links
+        #[arg(long)]
+        follow_symlinks: bool,
+
+        ///count the contents of directories marked with a .nobackup file too
+        #[arg(long)]
+        ignore_nobackup: bool,
+
+        ///count the contents of cache directories tagged with a CACHEDIR.TAG too
+        #[arg(long)]
+        include_caches: bool,
+
+        ///don't descend into filesystems mounted below the directories
+        #[arg(long)]
+        one_file_system: bool,
+
+        ///megabytes to actually compress, spread across file types
+        #[arg(long, value_name = "MB", default_value_t = 200)]
+        sample_size: u64,
+
+        ///print the estimate as JSON
+        #[arg(long)]
+        json: bool,
     },
     //restore from backup
+    #[command(about = "Restore a backup archive")]
     Restore {
-        //backup file to restore from
+        ///backup file to restore from
+        #[arg(short, long, required_unless_present = "apply_ownership")]
+        file: Option<String>,
+        
+        ///directory to restore to (default: the current directory, or the --root)
+        #[arg(short, long)]
+        target: Option<String>,
+
+        ///skip the check that the backup was made on a similar system
+        #[arg(long)]
+        ignore_origin: bool,
+
+        ///leave mode, owner and mtime of directories that already exist untouched
+        #[arg(long)]
+        no_touch_existing_dirs: bool,
+
+        ///chown entries to the ids their archived user and group names have on this system
+        #[arg(long)]
+        owner_by_name: bool,
+
+        ///with --owner-by-name, owner for entries whose names don't exist here (default: the archived ids)
+        #[arg(long, value_name = "USER", requires = "owner_by_name")]
+        fallback_owner: Option<String>,
+
+        ///restore into a system mounted here, owner names and the origin check use its files
+        #[arg(long, value_name = "DIR", conflicts_with = "target")]
+        root: Option<String>,
+
+        ///age identity file for archives encrypted to a public key (passphrases are asked for, gpg uses its keyring)
+        #[arg(long, value_name = "FILE")]
+        identity: Option<String>,
+
+        ///extract with the tar binary instead of the built-in extractor
+        #[arg(long)]
+        use_system_tar: bool,
+
+        ///what happens to archived files that already exist in the target
+        #[arg(long, value_enum, default_value = "overwrite")]
+        on_conflict: ConflictPolicy,
+
+        ///archived names that differ only in case, when the target ignores case (exFAT, NTFS, case-folding directories)
+        #[arg(long, value_enum, default_value = "rename")]
+        on_case_collision: CaseCollisionPolicy,
+
+        ///remove files an incremental or differential backup recorded as deleted, restore a chain in order with this
+        #[arg(long)]
+        apply_deletions: bool,
+
+        ///check the restored files against the checksums recorded in the archive afterwards
+        #[arg(long)]
+        verify: bool,
+
+        ///give entries their archived owner and exact mode (default when run as root), chown failures are only warned about
+        #[arg(long)]
+        preserve_owner: bool,
+
+        ///leave entries owned by the user running the restore, even as root
+        #[arg(long, conflicts_with = "preserve_owner")]
+        no_preserve_owner: bool,
+
+        ///read the archive at no more than this many bytes per second (e.g. 20M, 512K)
+        #[arg(long, value_name = "RATE", value_parser = throttle::parse_rate)]
+        limit_rate: Option<u64>,
+
+        ///run at idle I/O class and the lowest CPU priority, like nice -n 19 ionice -c 3
+        #[arg(long)]
+        background: bool,
+
+        ///sync restored files to disk every N files (default 1000 with --fsync-every-mb)
+        #[arg(long, value_name = "N")]
+        fsync_every: Option<u64>,
+
+        ///sync restored files to disk every M megabytes (default 256 with --fsync-every)
+        #[arg(long, value_name = "M")]
+        fsync_every_mb: Option<u64>,
+
+        ///restore nothing, write the saved disk layout to disk_layout/ in the target and print how to apply it by hand
+        #[arg(long)]
+        show_disk_layout: bool,
+
+        ///restore nothing, give the entries in the target the owners a restore in a user namespace recorded in .lb-ownership.json, as root
+        #[arg(long, conflicts_with_all = ["file", "root"])]
+        apply_ownership: bool,
+
+        ///write the matching entries into a new archive instead of the filesystem, nothing is extracted
+        #[arg(long, requires = "output", conflicts_with_all = ["target", "root", "use_system_tar", "show_disk_layout"])]
+        repack: bool,
+
+        ///with --repack, archived paths to take, may be repeated (e.g. 'home/dave/projects/clientX/**', default: everything)
+        #[arg(long, value_name = "PATTERN", requires = "repack")]
+        include: Vec<String>,
+
+        ///with --repack, the new archive, .zip or .tar, .tar.gz, .tar.zst, .tar.xz
+        #[arg(short, long, value_name = "FILE", requires = "repack")]
+        output: Option<String>,
+    },
+    //show what an archive records about where it was made
+    #[command(about = "Show the origin, compression, source filesystems and contents recorded in an archive")]
+    Info {
+        ///backup file to describe
         #[arg(short, long)]
         file: String,
-        
-        //directory to restore to
-        #[arg(short, long, default_value = ".")]
-        target: String,
+
+        ///age identity file for archives encrypted to a public key
+        #[arg(long, value_name = "FILE")]
+        identity: Option<String>,
+
+        ///list every archived file with its size, from the manifest
+        #[arg(long)]
+        files: bool,
+    },
+    //read an archive from start to end without extracting it, a cut or damaged one fails
+    #[command(about = "Check that an archive reads completely: every tar header, every entry's data and the compression trailer")]
+    Check {
+        ///backup file to read
+        file: String,
+
+        ///age identity file for archives encrypted to a public key
+        #[arg(long, value_name = "FILE")]
+        identity: Option<String>,
+    },
+    //what changed on disk since an archive was made
+    #[command(about = "List the files that are new, modified or deleted on disk since a backup was made")]
+    Diff {
+        ///backup file to compare with the filesystem
+        file: String,
+
+        ///only print the counts and sizes
+        #[arg(long)]
+        stat: bool,
+
+        ///age identity file for archives encrypted to a public key
+        #[arg(long, value_name = "FILE")]
+        identity: Option<String>,
+    },
+    //check an archive, or files restored from it, against the checksums recorded during the backup, or discs written with --volume-size
+    #[command(about = "Check a backup or restored files against the file checksums recorded in it, or volumes copied back off a disc")]
+    Verify {
+        ///backup file whose entries are hashed again and compared with its recorded checksums
+        #[arg(short, long, required_unless_present = "volume", conflicts_with = "volume")]
+        file: Option<String>,
+
+        ///check the files restored from the backup into this directory instead of the archive's own entries
+        #[arg(short, long, value_name = "DIR", requires = "file")]
+        target: Option<PathBuf>,
+
+        ///age identity file for archives encrypted to a public key
+        #[arg(long, value_name = "FILE", requires = "file")]
+        identity: Option<String>,
+
+        ///a volume, or a directory holding volumes, with their .sha256 files or the set's index.json next to them
+        #[arg(long, value_name = "PATH")]
+        volume: Option<PathBuf>,
+    },
+    //back up and restore a generated tree to check what works on this machine
+    #[command(about = "Back up, change, back up incrementally and restore a test tree, then report what survived")]
+    SelfTest {
+        ///run in a temporary directory below this one, to probe the filesystem it is on (default: the system temp directory)
+        #[arg(long, value_name = "DIR")]
+        dir: Option<String>,
     },
-} 
\ No newline at end of file
+    //how long ago each backup chain last completed, for a glance or a cron alert
+    #[command(about = "Show how long ago the last backups completed and whether the last run failed")]
+    Status {
+        ///exit with an error when a chain is older than status_stale_after, for cron or monitoring
+        #[arg(long)]
+        check: bool,
+    },
+    //a dry run against the last backup, what an incremental of a profile or some directories would take
+    #[command(about = "Show what changed since the last backup, grouped by directory, and which files were deleted")]
+    Changes {
+        ///a profile from config.toml
+        #[arg(long, value_name = "NAME", conflicts_with = "dirs")]
+        profile: Option<String>,
+        ///directories to look at instead, repeatable or comma-separated (default: the quick-pick list)
+        #[arg(long, value_name = "PATH", value_delimiter = ',')]
+        dirs: Vec<PathBuf>,
+    },
+    //every backup recorded in the catalog, per chain
+    #[command(about = "List the backups made so far, which full backup each incremental needs and which archives are gone")]
+    List,
+    //set up config.toml
+    #[command(about = "Write an example config.toml with a backup profile")]
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigAction {
+    //a commented example with every key and a profile to start from
+    #[command(about = "Write a commented example config.toml")]
+    Init {
+        ///replace an existing config.toml
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+impl From<CompressionFormat> for compression::CompressionFormat {
+    fn from(format: CompressionFormat) -> Self {
+        match format {
+            CompressionFormat::Gzip => compression::CompressionFormat::Gzip,
+            CompressionFormat::Zstd => compression::CompressionFormat::Zstd,
+            CompressionFormat::Xz => compression::CompressionFormat::Xz,
+            CompressionFormat::Store => compression::CompressionFormat::Store,
+        }
+    }
+}
+
+impl From<ConflictPolicy> for conflicts::ConflictPolicy {
+    fn from(policy: ConflictPolicy) -> Self {
+        match policy {
+            ConflictPolicy::Overwrite => conflicts::ConflictPolicy::Overwrite,
+            ConflictPolicy::Skip => conflicts::ConflictPolicy::Skip,
+            ConflictPolicy::Newer => conflicts::ConflictPolicy::Newer,
+            ConflictPolicy::Ask => conflicts::ConflictPolicy::Ask,
+        }
+    }
+}
+
+impl From<CaseCollisionPolicy> for casefold::CaseCollisionPolicy {
+    fn from(policy: CaseCollisionPolicy) -> Self {
+        match policy {
+            CaseCollisionPolicy::Rename => casefold::CaseCollisionPolicy::Rename,
+            CaseCollisionPolicy::Skip => casefold::CaseCollisionPolicy::Skip,
+            CaseCollisionPolicy::Abort => casefold::CaseCollisionPolicy::Abort,
+        }
+    }
+}
+
+impl From<BackupType> for utils::BackupType {
+    fn from(backup_type: BackupType) -> Self {
+        match backup_type {
+            BackupType::Full => utils::BackupType::Full,
+            BackupType::Incremental => utils::BackupType::Incremental,
+            BackupType::Differential => utils::BackupType::Differential,
+        }
+    }
+}
+
+impl From<LowSpacePolicy> for space::LowSpacePolicy {
+    fn from(policy: LowSpacePolicy) -> Self {
+        match policy {
+            LowSpacePolicy::Abort => space::LowSpacePolicy::Abort,
+            LowSpacePolicy::SkipLarger => space::LowSpacePolicy::SkipLarger,
+            LowSpacePolicy::Continue => space::LowSpacePolicy::Continue,
+        }
+    }
+}
+
+fn backup_settings(
+    backup_type: BackupType,
+    format: CompressionFormat,
+    threads: Option<usize>,
+    reproducible: bool,
+    exclude_older_than: Option<String>,
+    hash_check: bool,
+    encryption: Option<Encryption>
+) -> Result<BackupSettings, Box<dyn std::error::Error>> {
+    let max_age = match exclude_older_than {
+        Some(age) => Some((utils::parse_duration(&age)?, age)),
+        None => None,
+    };
+
+    Ok(BackupSettings {
+        backup_type: backup_type.into(),
+        compression: ArchiveCompression {
+            threads: threads.unwrap_or_else(compression::default_threads).max(1),
+            ..ArchiveCompression::new(format.into())
+        },
+        reproducible,
+        max_age,
+        max_size: None,
+        list_skipped: None,
+        presets: Vec::new(),
+        low_space: None,
+        hash_check,
+        encryption,
+        xattrs: true,
+        acls: false,
+        follow_symlinks: false,
+        nobackup_markers: true,
+        cache_dirs: true,
+        one_file_system: false,
+        wait_for_lock: None,
+        min_free_after: None,
+        portability: config::load_config()?.portability.unwrap_or_default(),
+        check_archive: false,
+        entry_index: false,
+        survive_hangup: true,
+    })
+}
+
+//what a command line run does with an archive already at the output path, None refuses to touch it
+fn existing_output(overwrite: bool, rotate: bool) -> Option<ExistingOutput> {
+    match (overwrite, rotate) {
+        (_, true) => Some(ExistingOutput::Rotate),
+        (true, false) => Some(ExistingOutput::Replace),
+        (false, false) => None,
+    }
+}
+
+//the passphrase is asked for here, before any scanning starts
+fn archive_encryption(encrypt: bool, builtin: bool, recipients: &[String], gpg_recipients: &[String]) -> Result<Option<Encryption>, Box<dyn std::error::Error>> {
+    if encrypt {
+        return Ok(Some(Encryption::Passphrase(encryption::prompt_passphrase(true)?)));
+    }
+    if builtin {
+        config::load_config()?.portability.unwrap_or_default().require_tar("--encrypt-builtin", "Use --encrypt to encrypt with a passphrase through age instead")?;
+        return Ok(Some(Encryption::Builtin(encryption::prompt_passphrase(true)?)));
+    }
+    if !gpg_recipients.is_empty() {
+        for recipient in gpg_recipients {
+            encryption::check_gpg_recipient(recipient)?;
+        }
+        return Ok(Some(Encryption::Gpg(gpg_recipients.to_vec())));
+    }
+    if recipients.is_empty() {
+        return Ok(None);
+    }
+
+    let recipients = recipients
+        .iter()
+        .map(|recipient| encryption::parse_recipient(recipient))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Some(Encryption::Recipients(recipients)))
+}
+
+fn reset_metadata_in(metadata_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(moved) = utils::reset_backup_metadata(metadata_dir)? {
+        println!("{}", format!("Backup metadata moved to {}, starting a new backup chain.", moved.display()).yellow());
+    }
+    Ok(())
+}
+
+//the quick-pick directories from config.toml, XDG user-dirs or the defaults
+fn quick_dirs(config: &config::Config) -> Vec<PathBuf> {
+    config::quick_dirs(config).into_iter().map(PathBuf::from).collect()
+}
+
+//directories named with --dirs must all exist, a typo shouldn't quietly shrink the backup
+//blanks around the commas are trimmed as bytes, the rest of a name is kept as given even when it isn't UTF-8
+fn named_sources(dirs: &[PathBuf]) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let dirs: Vec<PathBuf> = dirs
+        .iter()
+        .map(|dir| utils::absolute_path(Path::new(OsStr::from_bytes(dir.as_os_str().as_bytes().trim_ascii()))))
+        .collect();
+    backup::require_sources(dirs).map(|dirs| utils::normalize_sources(&dirs))
+}
+
+//--index is refused before the backup rather than warned about after it
+fn check_index_support(settings: &BackupSettings) -> Result<(), Box<dyn std::error::Error>> {
+    match entry_index::unsupported(settings.compression.format, settings.encryption.is_some()) {
+        Some(reason) if settings.entry_index => Err(format!("--index can't be used for this backup, {}", reason).into()),
+        _ => Ok(()),
+    }
+}
+
+//volume sets are always full backups, an existing set is only written over with --overwrite
+fn backup_to_volumes(sources: &[PathBuf], exclusions: &[PathBuf], settings: &BackupSettings, output: &Path, capacity: u64, overwrite: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if !matches!(settings.backup_type, utils::BackupType::Full) {
+        return Err("--volume-size writes full backups only, each volume has to restore on its own".into());
+    }
+    let output = utils::absolute_path(output);
+    sidecars::check_output_name(&output)?;
+    let existing = sidecars::set_conflicts(&output, settings.compression.format.extension());
+    if let (Some(existing), false) = (existing.first(), overwrite) {
+        return Err(format!("{} already exists, use --overwrite to replace the volume set", existing.display()).into());
+    }
+
+    if settings.survive_hangup {
+        hangup::survive();
+    }
+    let index = volumes::write_volumes(sources, exclusions, settings, &output, capacity)?;
+    if settings.check_archive {
+        for volume in &index.volumes {
+            check::check_written(&output.with_file_name(&volume.file), settings.encryption.as_ref())?;
+        }
+    }
+    //volumes of a longer earlier set would be verified and restored along with the new ones
+    for stale in existing {
+        let name = stale.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if sidecars::set_of(&name).is_some() && !index.volumes.iter().any(|volume| volume.file == name) {
+            fs::remove_file(&stale)?;
+            let _ = fs::remove_file(sidecars::checksum(&stale));
+            println!("Removed {}, a volume of the set it replaced", stale.display());
+        }
+    }
+    volumes::print_volume_summary(&index, output.parent().unwrap_or(Path::new("/")));
+    hangup::report_finished();
+    Ok(())
+}
+
+//run a subcommand from start to finish without asking anything
+pub fn run(command: Commands) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Commands::Selective { dirs, output, backup_type, compression, compression_level, threads, reproducible, overwrite, rotate, skip_empty, verify, index, exclude_older_than, on_low_space, max_file_size, list_skipped, min_free_after, exclude, preset, hash_check, reset_metadata, encrypt, recipient, gpg_recipient, encrypt_builtin, auto_compress, why, no_xattrs, acls, follow_symlinks, ignore_nobackup, include_caches, one_file_system, wait_for_lock, survive_hangup, no_survive_hangup, volume_size } => {
+            let encryption = archive_encryption(encrypt, encrypt_builtin, &recipient, &gpg_recipient)?;
+            let mut settings = backup_settings(backup_type, compression, threads, reproducible, exclude_older_than, hash_check, encryption)?;
+            settings.low_space = Some(on_low_space.into());
+            settings.xattrs = !no_xattrs;
+            settings.acls = acls;
+            settings.follow_symlinks = follow_symlinks;
+            settings.max_size = max_file_size.map(|size| utils::parse_size(&size).map(|bytes| (bytes, size))).transpose()?;
+            settings.list_skipped = list_skipped.map(|list| PathBuf::from(utils::expand_tilde(&list)));
+            settings.min_free_after = min_free_after.map(|size| utils::parse_size(&size).map(|bytes| (bytes, size))).transpose()?;
+            settings.nobackup_markers = !ignore_nobackup;
+            settings.cache_dirs = !include_caches;
+            settings.one_file_system = one_file_system;
+            settings.check_archive = verify;
+            settings.entry_index = index;
+            settings.survive_hangup = survive_hangup || !no_survive_hangup;
+            if let Some(level) = compression_level {
+                settings.compression = settings.compression.with_level(level)?;
+            }
+            let config = config::load_config()?;
+            settings.wait_for_lock = lock::configured_wait(wait_for_lock.as_deref(), &config)?;
+            if reset_metadata {
+                reset_metadata_in(&paths::state_dir())?;
+            }
+
+            let sources = if dirs.is_empty() {
+                //the quick-pick directories from config.toml, XDG user-dirs or the defaults
+                backup::require_sources(utils::normalize_sources(&quick_dirs(&config)))?
+            } else {
+                named_sources(&dirs)?
+            };
+
+            backup::announce_symlinked_sources(&sources);
+            let mut exclusions = presets::patterns(&preset);
+            exclusions.extend(utils::exclusion_patterns(&exclude));
+            settings.presets = preset;
+            if let Some(path) = why {
+                return backup::explain_selection(&sources, &exclusions, &settings, None, &path);
+            }
+
+            backup::warn_long_paths(&sources, config.max_path_length.unwrap_or(config::DEFAULT_MAX_PATH_LENGTH), settings.one_file_system);
+            if auto_compress {
+                backup::check_compressibility(&sources, &exclusions, &mut settings, ApplyRecommendation::Apply)?;
+            }
+
+            let output = output.unwrap_or_else(|| format!("backup{}", settings.archive_extension()));
+            if let Some(capacity) = volume_size {
+                return backup_to_volumes(&sources, &exclusions, &settings, Path::new(&output), capacity, overwrite);
+            }
+            check_index_support(&settings)?;
+            let (output_path, existing_output) = backup::resolve_output_path(&output, existing_output(overwrite, rotate))?;
+            backup::run_backup(BackupJob {
+                sources,
+                exclusions,
+                settings,
+                output_path,
+                existing_output,
+                interactive: false,
+                skip_empty,
+                profile: None,
+                skipped_sources: Vec::new(),
+                root: None,
+                services: Vec::new(),
+                disk_layout: false,
+            })
+            .map(|_| ())
+        }
+        Commands::Full { output, backup_type, compression, compression_level, threads, reproducible, overwrite, rotate, skip_empty, verify, index, exclude_older_than, on_low_space, max_file_size, list_skipped, min_free_after, exclude, preset, hash_check, reset_metadata, encrypt, recipient, gpg_recipient, encrypt_builtin, auto_compress, why, root, per_user, disk_layout, no_xattrs, acls, follow_symlinks, ignore_nobackup, include_caches, cross_filesystems, wait_for_lock, survive_hangup, no_survive_hangup } => {
+            let encryption = archive_encryption(encrypt, encrypt_builtin, &recipient, &gpg_recipient)?;
+            let mut settings = backup_settings(backup_type, compression, threads, reproducible, exclude_older_than, hash_check, encryption)?;
+            settings.low_space = Some(on_low_space.into());
+            settings.xattrs = !no_xattrs;
+            settings.acls = acls;
+            settings.follow_symlinks = follow_symlinks;
+            settings.max_size = max_file_size.map(|size| utils::parse_size(&size).map(|bytes| (bytes, size))).transpose()?;
+            settings.list_skipped = list_skipped.map(|list| PathBuf::from(utils::expand_tilde(&list)));
+            settings.min_free_after = min_free_after.map(|size| utils::parse_size(&size).map(|bytes| (bytes, size))).transpose()?;
+            settings.nobackup_markers = !ignore_nobackup;
+            settings.cache_dirs = !include_caches;
+            settings.one_file_system = !cross_filesystems;
+            settings.check_archive = verify;
+            settings.entry_index = index;
+            settings.survive_hangup = survive_hangup || !no_survive_hangup;
+            settings.presets = preset;
+            settings.wait_for_lock = lock::configured_wait(wait_for_lock.as_deref(), &config::load_config()?)?;
+            if let Some(level) = compression_level {
+                settings.compression = settings.compression.with_level(level)?;
+            }
+
+            let is_root = unsafe { libc::geteuid() == 0 };
+            if !is_root {
+                if disk_layout {
+                    return Err("--disk-layout needs root, partition tables and LUKS headers can't be read otherwise".into());
+                }
+                println!("{}", "Warning: Not running as root. Some system files may not be accessible.".yellow());
+            }
+
+            if per_user {
+                let output_dir = match output {
+                    Some(output) => PathBuf::from(utils::expand_tilde(&output)),
+                    None => std::env::current_dir()?,
+                };
+                let users = users::regular_users();
+                if reset_metadata {
+                    for user in &users {
+                        reset_metadata_in(&paths::user_state_dir(user))?;
+                    }
+                }
+                if auto_compress {
+                    let homes: Vec<PathBuf> = users.iter().map(|user| user.home.clone()).collect();
+                    backup::check_compressibility(&homes, &[], &mut settings, ApplyRecommendation::Apply)?;
+                }
+                return backup::run_per_user_backup(&users, &settings, &output_dir, false);
+            }
+
+            //system paths and exclusions are resolved below the alternate root
+            let root = match root {
+                Some(root) => {
+                    let root = utils::absolute_path(Path::new(&root));
+                    if !root.is_dir() {
+                        return Err(format!("{} is not a directory", root.display()).into());
+                    }
+                    Some(root)
+                }
+                None => None,
+            };
+            let rooted = |path: &Path| match &root {
+                Some(root) => utils::under_root(root, path),
+                None => path.to_path_buf(),
+            };
+
+            if reset_metadata {
+                match &root {
+                    Some(root) => reset_metadata_in(&paths::offline_state_dir(root))?,
+                    None => reset_metadata_in(&paths::state_dir())?,
+                }
+            }
+
+            let sources: Vec<PathBuf> = backup::system_sources(is_root).iter().map(|dir| rooted(dir)).collect();
+            let sources = backup::require_sources(sources)?;
+            let config = config::load_config()?;
+            backup::warn_long_paths(&sources, config.max_path_length.unwrap_or(config::DEFAULT_MAX_PATH_LENGTH), settings.one_file_system);
+            //name patterns match anywhere, paths given on the command line point into the alternate root too
+            let exclusions: Vec<PathBuf> = backup::SYSTEM_EXCLUSIONS
+                .iter()
+                .map(PathBuf::from)
+                .chain(presets::patterns(&settings.presets))
+                .chain(utils::exclusion_patterns(&exclude))
+                .map(|ex| if selector::is_name_pattern(&ex) { ex } else { rooted(&ex) })
+                .collect();
+            if let Some(path) = why {
+                return backup::explain_selection(&sources, &exclusions, &settings, root.as_deref(), &path);
+            }
+            if auto_compress {
+                backup::check_compressibility(&sources, &exclusions, &mut settings, ApplyRecommendation::Apply)?;
+            }
+
+            //services of an offline system aren't running, there is nothing to quiesce
+            let services = match &root {
+                Some(_) => Vec::new(),
+                None => services::plan(&sources, &config.services.clone().unwrap_or_default(), false)?,
+            };
+
+            let output = output.unwrap_or_else(|| format!("system_backup{}", settings.archive_extension()));
+            check_index_support(&settings)?;
+            let (output_path, existing_output) = backup::resolve_output_path(&output, existing_output(overwrite, rotate))?;
+            backup::run_backup(BackupJob {
+                sources,
+                exclusions,
+                settings,
+                output_path,
+                existing_output,
+                interactive: false,
+                skip_empty,
+                profile: None,
+                skipped_sources: Vec::new(),
+                root,
+                services,
+                disk_layout,
+            })
+            .map(|_| ())
+        }
+        Commands::Estimate { profile, dirs, compression, compression_level, exclude, preset, exclude_older_than, max_file_size, follow_symlinks, ignore_nobackup, include_caches, one_file_system, sample_size, json } => {
+            //the same selection a full backup of these directories would make, nothing archived
+            let config = config::load_config()?;
+            let mut job = match profile.as_deref() {
+                Some(name) => ProfileJob::resolve(Some(name), &config.profile(name)?, &config)?,
+                None => {
+                    let sources = if dirs.is_empty() {
+                        utils::normalize_sources(&quick_dirs(&config))
+                    } else {
+                        named_sources(&dirs)?
+                    };
+                    let dirs = sources.iter().map(|dir| dir.display().to_string()).collect();
+                    ProfileJob::resolve(None, &config::Profile { dirs, ..config::Profile::default() }, &config)?
+                }
+            };
+
+            let settings = &mut job.settings;
+            settings.backup_type = utils::BackupType::Full;
+            if let Some(format) = compression {
+                settings.compression = ArchiveCompression::new(format.into());
+            }
+            if let Some(level) = compression_level {
+                settings.compression = settings.compression.with_level(level)?;
+            }
+            if let Some(age) = exclude_older_than {
+                settings.max_age = Some((utils::parse_duration(&age)?, age));
+            }
+            if let Some(size) = max_file_size {
+                settings.max_size = Some((utils::parse_size(&size)?, size));
+            }
+            settings.follow_symlinks |= follow_symlinks;
+            settings.nobackup_markers &= !ignore_nobackup;
+            settings.cache_dirs &= !include_caches;
+            settings.one_file_system |= one_file_system;
+            job.exclusions.extend(presets::patterns(&preset));
+            job.exclusions.extend(utils::exclusion_patterns(&exclude));
+            job.settings.presets.extend(preset);
+
+            let current_time = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?.as_secs();
+            let selector = backup::file_selector(&job.exclusions, &job.settings, &utils::BackupMetadata::default(), current_time);
+            let estimate = estimate::estimate(&job.sources, selector, job.settings.compression, sample_size * 1024 * 1024)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&estimate)?);
+            } else {
+                estimate::print_estimate(&estimate);
+            }
+            Ok(())
+        }
+        Commands::Restore { file, target, ignore_origin, no_touch_existing_dirs, owner_by_name, fallback_owner, root, identity, use_system_tar, on_conflict, on_case_collision, apply_deletions, verify, preserve_owner, no_preserve_owner, limit_rate, background, fsync_every, fsync_every_mb, show_disk_layout, apply_ownership, repack, include, output } => {
+            let root = root.map(|root| utils::absolute_path(Path::new(&root)));
+            if let Some(root) = root.as_ref().filter(|root| !root.is_dir()) {
+                return Err(format!("{} is not a directory", root.display()).into());
+            }
+
+            let target = match &root {
+                Some(root) => root.display().to_string(),
+                None => target.unwrap_or_else(|| ".".to_string()),
+            };
+            if apply_ownership {
+                return match userns::apply_ownership(Path::new(&target))? {
+                    0 => Ok(()),
+                    failed => Err(format!("{} recorded owners could not be applied, they stay in {}", failed, userns::OWNERSHIP_FILE).into()),
+                };
+            }
+            let file = file.expect("clap requires --file without --apply-ownership");
+            //a command line run never stops to ask, even at a terminal, the menu is there for that
+            if matches!(on_conflict, ConflictPolicy::Ask) {
+                return Err("The ask conflict policy is only offered in the menu, use skip, overwrite or newer on the command line".into());
+            }
+            let options = RestoreOptions {
+                ignore_origin,
+                touch_existing_dirs: !no_touch_existing_dirs,
+                owner_by_name,
+                fallback_owner,
+                root,
+                identity: identity.map(|identity| PathBuf::from(utils::expand_tilde(&identity))),
+                system_tar: use_system_tar,
+                conflicts: on_conflict.into(),
+                case_collisions: on_case_collision.into(),
+                deletions: if apply_deletions { DeletionPolicy::Remove } else { DeletionPolicy::Keep },
+                verify,
+                preserve_owner: preserve_owner || (!no_preserve_owner && unsafe { libc::geteuid() == 0 }),
+                throttling: Throttling { limit_rate, background, sync: Throttling::sync_batch(fsync_every, fsync_every_mb) },
+            };
+            if show_disk_layout {
+                return show_saved_disk_layout(&file, Path::new(&target), &options);
+            }
+            if let (true, Some(output)) = (repack, output) {
+                return repack::repack_backup(&file, &output, &include, &options);
+            }
+            restore_backup(&file, &target, &options)
+        }
+        Commands::Info { file, identity, files } => show_archive_info(&file, &RestoreOptions {
+            identity: identity.map(|identity| PathBuf::from(utils::expand_tilde(&identity))),
+            ..RestoreOptions::default()
+        }, files),
+        Commands::Check { file, identity } => check::run_check(&file, &RestoreOptions {
+            identity: identity.map(|identity| PathBuf::from(utils::expand_tilde(&identity))),
+            ..RestoreOptions::default()
+        }),
+        Commands::Diff { file, stat, identity } => diff::show_diff(&file, &RestoreOptions {
+            identity: identity.map(|identity| PathBuf::from(utils::expand_tilde(&identity))),
+            ..RestoreOptions::default()
+        }, stat),
+        Commands::Verify { file, target, identity, volume } => {
+            let options = RestoreOptions {
+                identity: identity.map(|identity| PathBuf::from(utils::expand_tilde(&identity))),
+                ..RestoreOptions::default()
+            };
+            match (file, target, volume) {
+                (Some(file), Some(target), _) => match checksums::recorded_sums(&file, &options)? {
+                    Some(sums) => match checksums::verify_restored(&sums, &target) {
+                        0 => Ok(()),
+                        failed => Err(format!("{} restored files don't match the backup", failed).into()),
+                    },
+                    None => Ok(()),
+                },
+                (Some(file), None, _) => match checksums::verify_archive(&file, &options)? {
+                    0 => Ok(()),
+                    failed => Err(format!("{} files in the backup failed verification", failed).into()),
+                },
+                (None, _, Some(volume)) => match volumes::verify(&volume)? {
+                    0 => Ok(()),
+                    failed => Err(format!("{} volumes failed verification, burn them again from the originals", failed).into()),
+                },
+                (None, _, None) => unreachable!(),
+            }
+        }
+        Commands::SelfTest { dir } => {
+            let base = dir.map(|dir| PathBuf::from(utils::expand_tilde(&dir))).unwrap_or_else(std::env::temp_dir);
+            match selftest::run_self_test(&base)? {
+                0 => Ok(()),
+                failed => Err(format!("{} self-test checks failed", failed).into()),
+            }
+        }
+        Commands::Status { check } => match status::print_status(&config::load_config()?)? {
+            stale if check && stale > 0 => Err(format!("{} backup chains are stale", stale).into()),
+            _ => Ok(()),
+        },
+        Commands::Changes { profile, dirs } => {
+            let sources = if dirs.is_empty() {
+                utils::normalize_sources(&quick_dirs(&config::load_config()?))
+            } else {
+                named_sources(&dirs)?
+            };
+            changes::show_changes(&changes::resolve_job(profile.as_deref(), &sources)?)
+        }
+        Commands::List => catalog::show_catalogs(),
+        Commands::Config { action: ConfigAction::Init { force } } => {
+            let path = config::write_example(force)?;
+            println!("Wrote an example configuration to {}, edit the profile and run it with --profile nightly.", path.display());
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::compression::{ArchiveCompression, CompressionFormat};
+use crate::filter::FileFilter;
+use crate::selector::{EntryKind, FileSelector};
+use crate::utils;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+//formats that are compressed already, recompressing them only costs time
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp4", "mkv", "mov", "avi", "webm", "m4v",
+    "mp3", "flac", "ogg", "opus", "m4a", "aac",
+    "zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar",
+    "jar", "apk", "deb", "rpm", "docx", "xlsx", "pptx", "odt", "epub",
+];
+
+//only the start of a file is read to judge it
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+//files read per unknown extension, the verdict applies to all files with that extension
+const SAMPLES_PER_EXTENSION: usize = 4;
+
+//bits per byte above which data is treated as already compressed (8 is pure noise)
+const ENTROPY_THRESHOLD: f64 = 7.5;
+
+//below these shares of compressible bytes, store only or the fastest level is recommended
+const STORE_BELOW_PERCENT: f64 = 10.0;
+const FAST_BELOW_PERCENT: f64 = 50.0;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Compressibility {
+    pub files: u64,
+    pub bytes: u64,
+    //bytes in files that are not compressed already, by extension or by their entropy
+    pub compressible_bytes: u64,
+}
+
+impl Compressibility {
+    pub fn compressible_percent(&self) -> f64 {
+        if self.bytes == 0 {
+            return 100.0;
+        }
+        self.compressible_bytes as f64 * 100.0 / self.bytes as f64
+    }
+
+    //a cheaper setting when compression would barely shrink the data, None to keep the current one
+    pub fn recommendation(&self, current: ArchiveCompression) -> Option<ArchiveCompression> {
+        if current.format == CompressionFormat::Store || self.bytes == 0 {
+            return None;
+        }
+
+        let percent = self.compressible_percent();
+        if percent < STORE_BELOW_PERCENT {
+            return Some(ArchiveCompression { level: 0, format: CompressionFormat::Store, ..current });
+        }
+
+        let fastest = current.format.levels()[0].1;
+        if percent < FAST_BELOW_PERCENT && current.level > fastest {
+            return Some(ArchiveCompression { level: fastest, ..current });
+        }
+
+        None
+    }
+}
+
+//files of one extension and how much data they hold
+#[derive(Default)]
+struct ExtensionGroup {
+    files: Vec<PathBuf>,
+    bytes: u64,
+}
+
+//walk the sources and judge by extension, sampling a few files of every unknown extension
+pub fn analyze(sources: &[PathBuf], exclusions: &[PathBuf], one_file_system: bool) -> Compressibility {
+    let progress = ProgressBar::new_spinner();
+    progress.set_style(ProgressStyle::default_spinner()
+        .template("{spinner:.green} {msg}")
+        .unwrap());
+
+    let mut selector = FileSelector::new(exclusions, FileFilter::default()).one_file_system(one_file_system);
+    let mut groups: BTreeMap<String, ExtensionGroup> = BTreeMap::new();
+    let mut result = Compressibility::default();
+
+    for source in sources {
+        progress.set_message(format!("Checking how well {} compresses", source.display()));
+
+        for entry in selector.select(source) {
+            if entry.kind != EntryKind::File {
+                continue;
+            }
+
+            result.files += 1;
+            result.bytes += entry.size;
+
+            let extension = entry.path.extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            let group = groups.entry(extension).or_default();
+            group.bytes += entry.size;
+            group.files.push(entry.path);
+        }
+    }
+
+    for (extension, group) in &groups {
+        if !COMPRESSED_EXTENSIONS.contains(&extension.as_str()) && !looks_compressed(&group.files) {
+            result.compressible_bytes += group.bytes;
+        }
+    }
+
+    progress.finish_and_clear();
+    result
+}
+
+//the average entropy of a few files spread over the list, unreadable files are left out
+fn looks_compressed(files: &[PathBuf]) -> bool {
+    let step = (files.len() / SAMPLES_PER_EXTENSION).max(1);
+    let entropies: Vec<f64> = files
+        .iter()
+        .step_by(step)
+        .take(SAMPLES_PER_EXTENSION)
+        .filter_map(|path| sample_entropy(path).ok().flatten())
+        .collect();
+
+    !entropies.is_empty() && entropies.iter().sum::<f64>() / entropies.len() as f64 >= ENTROPY_THRESHOLD
+}
+
+//shannon entropy of the first SAMPLE_SIZE bytes in bits per byte, None for empty files
+fn sample_entropy(path: &Path) -> io::Result<Option<f64>> {
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE as usize);
+    File::open(path)?.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+    if sample.is_empty() {
+        return Ok(None);
+    }
+
+    let mut counts = [0u64; 256];
+    for &byte in &sample {
+        counts[byte as usize] += 1;
+    }
+
+    let total = sample.len() as f64;
+    let entropy = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum();
+
+    Ok(Some(entropy))
+}
+
+pub fn describe(compressibility: &Compressibility) -> String {
+    format!(
+        "{:.0}% of {} in {} files looks compressible",
+        compressibility.compressible_percent(),
+        utils::format_size(compressibility.bytes),
+        compressibility.files
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    //xorshift noise, stands in for data that is compressed already
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn media_heavy_sources_recommend_storing() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        //the extension decides for the photo, the content for the unlabelled blob
+        fs::write(root.join("photo.jpg"), vec![0u8; 400_000]).unwrap();
+        fs::write(root.join("blob.dat"), noise(500_000)).unwrap();
+        fs::write(root.join("notes.txt"), "a line of notes\n".repeat(2_000)).unwrap();
+
+        let sources = vec![root.to_path_buf()];
+        let result = analyze(&sources, &[], false);
+        assert_eq!(result, Compressibility { files: 3, bytes: 932_000, compressible_bytes: 32_000 });
+
+        let gzip = ArchiveCompression::new(CompressionFormat::Gzip);
+        let recommended = result.recommendation(gzip).unwrap();
+        assert_eq!(recommended.format, CompressionFormat::Store);
+
+        //a mixed tree only drops to the fastest level, mostly text keeps the chosen one
+        let mixed = Compressibility { files: 2, bytes: 100, compressible_bytes: 30 };
+        assert_eq!(mixed.recommendation(gzip), Some(ArchiveCompression { level: 1, ..gzip }));
+        let text = Compressibility { files: 2, bytes: 100, compressible_bytes: 90 };
+        assert_eq!(text.recommendation(gzip), None);
+    }
+}
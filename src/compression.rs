@@ -0,0 +1,305 @@
+use crate::encryption::{self, ArchiveKey};
+use crate::parallel_gzip::ParallelGzEncoder;
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: [u8; 6] = [0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+
+//a plain tar has no magic at the start, the ustar header field sits at offset 257
+const USTAR_MAGIC_OFFSET: usize = 257;
+const USTAR_MAGIC: &[u8; 5] = b"ustar";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompressionFormat {
+    Gzip,
+    Zstd,
+    Xz,
+    //a plain .tar, nothing is compressed
+    Store,
+}
+
+impl CompressionFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            CompressionFormat::Gzip => ".tar.gz",
+            CompressionFormat::Zstd => ".tar.zst",
+            CompressionFormat::Xz => ".tar.xz",
+            CompressionFormat::Store => ".tar",
+        }
+    }
+
+    //the choices offered by the level prompt as (label, level), the middle one is the default
+    pub fn levels(self) -> &'static [(&'static str, i32)] {
+        match self {
+            CompressionFormat::Gzip => &[("Fast (1)", 1), ("Default (6)", 6), ("Best (9)", 9)],
+            CompressionFormat::Zstd => &[("Fast (1)", 1), ("Default (3)", 3), ("Best (19)", 19)],
+            CompressionFormat::Xz => &[("Fast (1)", 1), ("Default (6)", 6), ("Best (9)", 9)],
+            CompressionFormat::Store => &[("None / store only", 0)],
+        }
+    }
+
+    pub fn default_level(self) -> i32 {
+        let levels = self.levels();
+        levels[levels.len() / 2].1
+    }
+
+    //every level the encoder accepts, the presets above are a few of these
+    pub fn level_range(self) -> RangeInclusive<i32> {
+        match self {
+            CompressionFormat::Gzip => 1..=9,
+            CompressionFormat::Zstd => 1..=22,
+            CompressionFormat::Xz => 0..=9,
+            CompressionFormat::Store => 0..=0,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CompressionFormat::Gzip => "gzip",
+            CompressionFormat::Zstd => "zstd",
+            CompressionFormat::Xz => "xz",
+            CompressionFormat::Store => "store only",
+        }
+    }
+
+    //guess the format from the file name, used when the file can't be read yet
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(CompressionFormat::Gzip)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(CompressionFormat::Zstd)
+        } else if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+            Some(CompressionFormat::Xz)
+        } else if name.ends_with(".tar") {
+            Some(CompressionFormat::Store)
+        } else {
+            None
+        }
+    }
+
+    //the magic bytes decide, whatever the file is called
+    pub fn detect(path: &Path) -> Option<Self> {
+        let mut magic = [0u8; USTAR_MAGIC_OFFSET + 5];
+        let mut file = File::open(path).ok()?;
+        let read = read_up_to(&mut file, &mut magic).ok()?;
+        Self::detect_bytes(&magic[..read])
+    }
+
+    //the same check on the start of a stream, such as a decrypted archive
+    fn detect_bytes(magic: &[u8]) -> Option<Self> {
+        let read = magic.len();
+        if read >= 2 && magic[..2] == GZIP_MAGIC {
+            Some(CompressionFormat::Gzip)
+        } else if read >= 4 && magic[..4] == ZSTD_MAGIC {
+            Some(CompressionFormat::Zstd)
+        } else if read >= 6 && magic[..6] == XZ_MAGIC {
+            Some(CompressionFormat::Xz)
+        } else if read >= USTAR_MAGIC_OFFSET + 5 && &magic[USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET + 5] == USTAR_MAGIC {
+            Some(CompressionFormat::Store)
+        } else {
+            None
+        }
+    }
+}
+
+//a single read may return less than asked for, keep going until the buffer is full or the file ends
+fn read_up_to(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+//format and level of a new archive, the level is in the format's own range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArchiveCompression {
+    pub format: CompressionFormat,
+    pub level: i32,
+    //gzip compresses this many blocks at once, the output doesn't depend on it
+    pub threads: usize,
+}
+
+impl ArchiveCompression {
+    pub fn new(format: CompressionFormat) -> Self {
+        ArchiveCompression { format, level: format.default_level(), threads: default_threads() }
+    }
+
+    //an explicit level, one outside the format's range is an error rather than clamped
+    pub fn with_level(mut self, level: i32) -> Result<Self, Box<dyn std::error::Error>> {
+        let range = self.format.level_range();
+        if !range.contains(&level) {
+            return Err(format!(
+                "Compression level {} is out of range for {}, valid levels are {} to {}",
+                level, self.format.name(), range.start(), range.end()
+            ).into());
+        }
+        self.level = level;
+        Ok(self)
+    }
+
+    //"zstd level 19", shown in summaries and the archive listing
+    pub fn label(self) -> String {
+        match self.format {
+            CompressionFormat::Store => "store only (.tar)".to_string(),
+            format => format!("{} level {}", format.name(), self.level),
+        }
+    }
+}
+
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+impl Default for ArchiveCompression {
+    fn default() -> Self {
+        ArchiveCompression::new(CompressionFormat::Gzip)
+    }
+}
+
+//the compressed stream a tar builder writes into
+pub enum ArchiveEncoder<W: Write = File> {
+    Gzip(ParallelGzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(XzEncoder<W>),
+    Store(W),
+}
+
+impl<W: Write> ArchiveEncoder<W> {
+    //no format stores a timestamp or file name, so reproducible archives stay reproducible
+    pub fn new(writer: W, compression: ArchiveCompression) -> io::Result<Self> {
+        Ok(match compression.format {
+            CompressionFormat::Gzip => {
+                let level = flate2::Compression::new(compression.level as u32);
+                ArchiveEncoder::Gzip(ParallelGzEncoder::new(writer, level, compression.threads))
+            }
+            CompressionFormat::Zstd => {
+                let mut encoder = zstd::Encoder::new(writer, compression.level)?;
+                encoder.include_checksum(true)?;
+                ArchiveEncoder::Zstd(encoder)
+            }
+            CompressionFormat::Xz => ArchiveEncoder::Xz(XzEncoder::new(writer, compression.level as u32)),
+            CompressionFormat::Store => ArchiveEncoder::Store(writer),
+        })
+    }
+
+    //write the trailer, zstd and xz streams are left truncated if this is skipped
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            ArchiveEncoder::Gzip(encoder) => encoder.finish(),
+            ArchiveEncoder::Zstd(encoder) => encoder.finish(),
+            ArchiveEncoder::Xz(encoder) => encoder.finish(),
+            ArchiveEncoder::Store(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for ArchiveEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ArchiveEncoder::Gzip(encoder) => encoder.write(buf),
+            ArchiveEncoder::Zstd(encoder) => encoder.write(buf),
+            ArchiveEncoder::Xz(encoder) => encoder.write(buf),
+            ArchiveEncoder::Store(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveEncoder::Gzip(encoder) => encoder.flush(),
+            ArchiveEncoder::Zstd(encoder) => encoder.flush(),
+            ArchiveEncoder::Xz(encoder) => encoder.flush(),
+            ArchiveEncoder::Store(writer) => writer.flush(),
+        }
+    }
+}
+
+//the decompressed tar stream of an archive in any supported format, encrypted ones need their key
+pub fn open_decoder(path: &Path, key: Option<&ArchiveKey>) -> io::Result<Box<dyn Read + Send>> {
+    open_detected(path, key).map(|(_, decoder)| decoder)
+}
+
+//the same, with the format found under the encryption
+pub fn open_detected(path: &Path, key: Option<&ArchiveKey>) -> io::Result<(CompressionFormat, Box<dyn Read + Send>)> {
+    if encryption::is_encrypted(path) {
+        let key = key.ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "the archive is encrypted"))?;
+        return decoder_for(BufReader::new(encryption::decrypt_file(path, key)?));
+    }
+    decoder_for(BufReader::new(File::open(path)?))
+}
+
+//the format is read off the first bytes of the stream itself
+fn decoder_for<R: BufRead + Send + 'static>(mut reader: R) -> io::Result<(CompressionFormat, Box<dyn Read + Send>)> {
+    let format = CompressionFormat::detect_bytes(reader.fill_buf()?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a tar, gzip, zstd or xz stream"))?;
+
+    let decoder: Box<dyn Read + Send> = match format {
+        CompressionFormat::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        CompressionFormat::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        CompressionFormat::Xz => Box::new(XzDecoder::new(reader)),
+        CompressionFormat::Store => Box::new(reader),
+    };
+    Ok((format, decoder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_formats_round_trip_and_are_detected() {
+        let temp = tempfile::tempdir().unwrap();
+
+        for format in [CompressionFormat::Gzip, CompressionFormat::Zstd, CompressionFormat::Xz] {
+            //the magic bytes win over a misleading name
+            let path = temp.path().join(format!("archive{:?}.bin", format));
+            let mut encoder = ArchiveEncoder::new(File::create(&path).unwrap(), ArchiveCompression::new(format)).unwrap();
+            encoder.write_all(b"hello archive").unwrap();
+            encoder.finish().unwrap();
+
+            assert_eq!(CompressionFormat::detect(&path), Some(format));
+            let mut contents = String::new();
+            open_decoder(&path, None).unwrap().read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "hello archive");
+        }
+
+        //a plain tar is only recognised by the ustar field of its first header
+        let tar_path = temp.path().join("archive.bin");
+        let mut archive = tar::Builder::new(ArchiveEncoder::new(File::create(&tar_path).unwrap(), ArchiveCompression::new(CompressionFormat::Store)).unwrap());
+        archive.append_data(&mut tar::Header::new_gnu(), "hello.txt", "hello".as_bytes()).unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+        assert_eq!(CompressionFormat::detect(&tar_path), Some(CompressionFormat::Store));
+        assert_eq!(ArchiveCompression::new(CompressionFormat::Store).level, 0);
+
+        let plain = temp.path().join("plain.tar.gz");
+        std::fs::write(&plain, "plain text").unwrap();
+        assert_eq!(CompressionFormat::detect(&plain), None);
+        assert_eq!(CompressionFormat::from_name("plain.tar.gz"), Some(CompressionFormat::Gzip));
+        assert!(open_decoder(&plain, None).is_err());
+    }
+
+    #[test]
+    fn levels_outside_the_format_range_are_rejected() {
+        let zstd = ArchiveCompression::new(CompressionFormat::Zstd);
+        assert_eq!(zstd.with_level(19).unwrap().level, 19);
+        assert_eq!(ArchiveCompression::new(CompressionFormat::Gzip).with_level(4).unwrap().label(), "gzip level 4");
+
+        let error = zstd.with_level(23).unwrap_err().to_string();
+        assert_eq!(error, "Compression level 23 is out of range for zstd, valid levels are 1 to 22");
+        assert!(ArchiveCompression::new(CompressionFormat::Gzip).with_level(0).is_err());
+        assert!(ArchiveCompression::new(CompressionFormat::Store).with_level(3).is_err());
+    }
+}
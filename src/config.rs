@@ -0,0 +1,416 @@
+use crate::paths;
+use crate::portability::Portability;
+use crate::services;
+use crate::utils;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::Once;
+
+pub const CONFIG_FILE: &str = "config.toml";
+
+//stored paths longer than this are reported before the backup starts
+pub const DEFAULT_MAX_PATH_LENGTH: usize = 3072;
+
+//how often each custom directory was used, kept in the state directory
+const CUSTOM_DIR_USAGE_FILE: &str = "custom_dir_usage.json";
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct Config {
+    //directories offered in the "Backup Selected Directories" list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quick_dirs: Option<Vec<String>>,
+
+    //warn about entries whose stored path is longer than this many bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_path_length: Option<usize>,
+
+    //restores with the ask conflict policy ask per file up to this many existing files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ask_conflict_limit: Option<usize>,
+
+    //how long a backup waits for a running backup of the same chain (e.g. 2h), unset fails right away
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait_for_lock: Option<String>,
+
+    //what a system backup does with a running database service (dump, stop or live), unlisted ones are asked about
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub services: Option<HashMap<String, services::Strategy>>,
+
+    //strict-tar refuses features plain tar can't extract, extended (the default) allows them and explains them in a README entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub portability: Option<Portability>,
+
+    //free space a running backup keeps on the destination before it stops (default 5% of the filesystem, at most 512M)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub low_water_mark: Option<String>,
+
+    //the status command and the main menu show a backup older than this in yellow (default 2d)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_warn_after: Option<String>,
+
+    //and older than this in red, status --check fails for it (default 7d)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_stale_after: Option<String>,
+
+    //values every profile starts from, [profile_defaults] in the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_defaults: Option<Profile>,
+
+    //named backups run with --profile NAME or Run Profile in the menu, [profiles.NAME] in the file
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+//a backup that runs without prompts, unset values come from [profile_defaults] and then the usual defaults
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dirs: Vec<String>,
+    //exclusion patterns like --exclude, added to the ones in [profile_defaults]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excludes: Vec<String>,
+    //exclusion presets like --preset, added the same way
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub presets: Vec<String>,
+    //full, incremental or differential (default full)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup_type: Option<String>,
+    //gzip, zstd, xz or none (default gzip)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression_level: Option<i32>,
+    //where the archives go (default the current directory)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_dir: Option<String>,
+    //free space the archive must leave on the destination, like 50G, checked before and during the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_free_after: Option<String>,
+    //drop the archive of a run that found nothing to back up (default true), the catalog still lists the run as empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip_empty: Option<bool>,
+}
+
+impl Profile {
+    //the profile's own values win, excludes and presets of both apply
+    pub fn merged(&self, defaults: &Profile) -> Profile {
+        let combined = |defaults: &[String], own: &[String]| {
+            let mut values = defaults.to_vec();
+            values.extend(own.iter().filter(|value| !defaults.contains(value)).cloned());
+            values
+        };
+        Profile {
+            dirs: if self.dirs.is_empty() { defaults.dirs.clone() } else { self.dirs.clone() },
+            excludes: combined(&defaults.excludes, &self.excludes),
+            presets: combined(&defaults.presets, &self.presets),
+            backup_type: self.backup_type.clone().or_else(|| defaults.backup_type.clone()),
+            compression: self.compression.clone().or_else(|| defaults.compression.clone()),
+            compression_level: self.compression_level.or(defaults.compression_level),
+            output_dir: self.output_dir.clone().or_else(|| defaults.output_dir.clone()),
+            min_free_after: self.min_free_after.clone().or_else(|| defaults.min_free_after.clone()),
+            skip_empty: self.skip_empty.or(defaults.skip_empty),
+        }
+    }
+}
+
+impl Config {
+    //a profile merged with [profile_defaults], ready to run
+    pub fn profile(&self, name: &str) -> Result<Profile, Box<dyn std::error::Error>> {
+        let profile = match self.profiles.get(name) {
+            Some(profile) => profile,
+            None if self.profiles.is_empty() => return Err(format!("There are no profiles in {}, config init writes an example", CONFIG_FILE).into()),
+            None => {
+                let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                return Err(format!("No profile named {} in {}, there are: {}", name, CONFIG_FILE, names.join(", ")).into());
+            }
+        };
+
+        let merged = profile.merged(self.profile_defaults.as_ref().unwrap_or(&Profile::default()));
+        if merged.dirs.is_empty() {
+            return Err(format!("Profile {} has no dirs to back up", name).into());
+        }
+        Ok(merged)
+    }
+
+    //exclusions picked during a backup, added to a profile or starting a new one for the directories they came from
+    pub fn save_exclusions(&mut self, name: &str, dirs: &[String], excludes: &[String]) {
+        let profile = self.profiles.entry(name.to_string()).or_insert_with(|| Profile { dirs: dirs.to_vec(), ..Profile::default() });
+        for exclude in excludes {
+            if !profile.excludes.contains(exclude) {
+                profile.excludes.push(exclude.clone());
+            }
+        }
+    }
+}
+
+//written by config init, every key explained and the ones without a profile left commented out
+pub const EXAMPLE_CONFIG: &str = r#"# linux_backup settings, every key is optional
+
+# Directories offered in "Backup Selected Directories" (default: the XDG user directories)
+# quick_dirs = ["~/Documents", "~/Projects", "/etc"]
+
+# Warn about entries whose stored path is longer than this many bytes
+# max_path_length = 3072
+
+# Restores from the menu that ask about conflicts decide file by file up to this many existing files
+# ask_conflict_limit = 10
+
+# How long a backup waits for a running backup of the same chain, unset fails right away
+# wait_for_lock = "2h"
+
+# strict-tar refuses features plain tar can't extract, extended allows them
+# portability = "extended"
+
+# Free space a running backup leaves on the destination before --on-low-space applies (default 5% of the filesystem, at most 512M)
+# low_water_mark = "1G"
+
+# The status command shows backups older than these in yellow and red
+# status_warn_after = "2d"
+# status_stale_after = "7d"
+
+# What a system backup does with a running database service: dump, stop or live
+# [services]
+# postgresql = "dump"
+
+# Values every profile starts from. A profile's own values win, excludes and presets are added up.
+[profile_defaults]
+compression = "zstd"
+output_dir = "~/Backups"
+excludes = ["*.tmp"]
+# Refuse to start a backup that would leave less than this free on the destination
+# min_free_after = "50G"
+# Keep the archive of a run that found no changed files instead of only listing it as empty
+# skip_empty = false
+
+# Run with linux_backup --profile nightly, or Run Profile in the menu. No questions are asked.
+[profiles.nightly]
+dirs = ["~/Documents", "~/Projects"]
+excludes = ["*.log"]
+# media, build and vm, see --preset
+presets = ["build"]
+# full, incremental or differential
+backup_type = "incremental"
+# gzip, zstd, xz or none, with an optional level in the format's range
+compression_level = 3
+"#;
+
+//unknown keys are warned about once per run, they are usually a typo
+static UNKNOWN_KEYS_WARNED: Once = Once::new();
+
+pub fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
+    let config_file = paths::config_dir().join(CONFIG_FILE);
+
+    if config_file.exists() {
+        let contents = fs::read_to_string(&config_file)?;
+        let (config, unknown) = parse_config(&contents)?;
+        UNKNOWN_KEYS_WARNED.call_once(|| {
+            for key in unknown {
+                println!("{}", format!("Warning: Unknown key {} in {}, it is ignored", key, config_file.display()).yellow());
+            }
+        });
+        Ok(config)
+    } else {
+        Ok(Config::default())
+    }
+}
+
+//the config and the dotted paths of the keys it doesn't know
+pub fn parse_config(contents: &str) -> Result<(Config, Vec<String>), Box<dyn std::error::Error>> {
+    let mut unknown = Vec::new();
+    let config = serde_ignored::deserialize(toml::Deserializer::new(contents), |path| unknown.push(path.to_string()))?;
+    Ok((config, unknown))
+}
+
+//config init, an existing file is only replaced when asked to
+pub fn write_example(force: bool) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    let dir = paths::config_dir();
+    let config_file = dir.join(CONFIG_FILE);
+    if config_file.exists() && !force {
+        return Err(format!("{} exists already, pass --force to replace it", config_file.display()).into());
+    }
+    fs::create_dir_all(&dir)?;
+    fs::write(&config_file, EXAMPLE_CONFIG)?;
+    Ok(config_file)
+}
+
+pub fn save_config(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = paths::config_dir();
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(CONFIG_FILE), toml::to_string_pretty(config)?)?;
+    Ok(())
+}
+
+//the quick-pick list: config.toml, then XDG user-dirs, then the built-in defaults
+pub fn configured_quick_dirs(config: &Config) -> Vec<String> {
+    let home = utils::home_dir();
+
+    if let Some(quick_dirs) = &config.quick_dirs {
+        return quick_dirs.clone();
+    }
+
+    let user_dirs = fs::read_to_string(home.join(".config/user-dirs.dirs"))
+        .map(|contents| parse_user_dirs(&contents, &home))
+        .unwrap_or_default();
+
+    if user_dirs.is_empty() {
+        return default_quick_dirs(&home);
+    }
+
+    let mut quick_dirs = user_dirs;
+    quick_dirs.extend([
+        home.join(".config").display().to_string(),
+        home.join(".local/share").display().to_string(),
+        "/etc".to_string(),
+    ]);
+    quick_dirs
+}
+
+//the quick-pick list with ~ expanded and directories that don't exist left out
+pub fn quick_dirs(config: &Config) -> Vec<String> {
+    configured_quick_dirs(config)
+        .iter()
+        .map(|dir| utils::expand_tilde(dir))
+        .filter(|dir| Path::new(dir).exists())
+        .collect()
+}
+
+fn default_quick_dirs(home: &Path) -> Vec<String> {
+    ["Documents", "Pictures", "Videos", "Music", "Downloads", ".config", ".local/share"]
+        .iter()
+        .map(|dir| home.join(dir).display().to_string())
+        .chain(["/etc".to_string()])
+        .collect()
+}
+
+//parse ~/.config/user-dirs.dirs, skipping the desktop and entries that point at $HOME itself
+fn parse_user_dirs(contents: &str, home: &Path) -> Vec<String> {
+    let home = home.display().to_string();
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .filter(|(key, _)| key.starts_with("XDG_") && key.ends_with("_DIR") && *key != "XDG_DESKTOP_DIR")
+        .map(|(_, value)| value.trim_matches('"').replace("$HOME", &home))
+        .filter(|dir| dir.trim_end_matches('/') != home)
+        .collect()
+}
+
+//count a use of a custom directory and return how often it has been used so far
+pub fn record_custom_dir(metadata_dir: &Path, dir: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    let usage_file = metadata_dir.join(CUSTOM_DIR_USAGE_FILE);
+
+    let mut usage: HashMap<String, u32> = match fs::read_to_string(&usage_file) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    };
+
+    let count = usage.entry(dir.to_string()).or_insert(0);
+    *count += 1;
+    let count = *count;
+
+    fs::create_dir_all(metadata_dir)?;
+    fs::write(usage_file, serde_json::to_string_pretty(&usage)?)?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_dirs_are_parsed_and_expanded() {
+        let contents = r#"
+# This file is written by xdg-user-dirs-update
+XDG_DESKTOP_DIR="$HOME/Desktop"
+XDG_DOCUMENTS_DIR="$HOME/Dokumente"
+XDG_PROJECTS_DIR="$HOME/Projects"
+XDG_TEMPLATES_DIR="$HOME/"
+XDG_MUSIC_DIR="/data/music"
+"#;
+
+        assert_eq!(
+            parse_user_dirs(contents, Path::new("/home/dave")),
+            vec!["/home/dave/Dokumente", "/home/dave/Projects", "/data/music"]
+        );
+    }
+
+    #[test]
+    fn quick_dirs_round_trip_through_toml() {
+        let config = Config {
+            quick_dirs: Some(vec!["~/Projects".to_string(), "/etc".to_string()]),
+            max_path_length: Some(2048),
+            ask_conflict_limit: None,
+            wait_for_lock: None,
+            services: Some(HashMap::from([("postgresql".to_string(), services::Strategy::Dump)])),
+            portability: Some(Portability::StrictTar),
+            low_water_mark: Some("1G".to_string()),
+            status_warn_after: None,
+            status_stale_after: Some("14d".to_string()),
+            profile_defaults: None,
+            profiles: BTreeMap::from([("nightly".to_string(), Profile { dirs: vec!["~/Documents".to_string()], ..Profile::default() })]),
+        };
+        let parsed: Config = toml::from_str(&toml::to_string_pretty(&config).unwrap()).unwrap();
+
+        assert_eq!(parsed, config);
+        assert_eq!(toml::from_str::<Config>("").unwrap(), Config::default());
+        assert_eq!(toml::from_str::<Config>("portability = \"strict-tar\"").unwrap().portability, Some(Portability::StrictTar));
+    }
+
+    #[test]
+    fn profiles_are_merged_with_their_defaults() {
+        let (config, unknown) = parse_config(EXAMPLE_CONFIG).unwrap();
+        assert!(unknown.is_empty(), "{:?}", unknown);
+
+        let nightly = config.profile("nightly").unwrap();
+        assert_eq!(nightly.dirs, ["~/Documents", "~/Projects"]);
+        assert_eq!(nightly.excludes, ["*.tmp", "*.log"]);
+        assert_eq!(nightly.presets, ["build"]);
+        assert_eq!(nightly.backup_type.as_deref(), Some("incremental"));
+        assert_eq!(nightly.compression.as_deref(), Some("zstd"));
+        assert_eq!(nightly.compression_level, Some(3));
+        assert_eq!(nightly.output_dir.as_deref(), Some("~/Backups"));
+
+        //a profile's own values win over the defaults, without defaults nothing is filled in
+        let own = Profile { compression: Some("xz".to_string()), excludes: vec!["*.tmp".to_string()], skip_empty: Some(false), ..nightly.clone() };
+        let merged = own.merged(config.profile_defaults.as_ref().unwrap());
+        assert_eq!(merged.compression.as_deref(), Some("xz"));
+        assert_eq!(merged.skip_empty, Some(false));
+        assert_eq!(merged.excludes, ["*.tmp"]);
+        assert_eq!(Profile::default().merged(&Profile::default()), Profile::default());
+
+        assert!(config.profile("weekly").unwrap_err().to_string().contains("there are: nightly"));
+        assert!(Config::default().profile("nightly").unwrap_err().to_string().contains("config init"));
+        let (empty, _) = parse_config("[profiles.empty]\nbackup_type = \"full\"\n").unwrap();
+        assert!(empty.profile("empty").unwrap_err().to_string().contains("no dirs"));
+    }
+
+    #[test]
+    fn chosen_exclusions_are_saved_into_profiles() {
+        let (mut config, _) = parse_config(EXAMPLE_CONFIG).unwrap();
+        config.save_exclusions("nightly", &["/elsewhere".to_string()], &["/home/dave/Projects/vm-images".to_string(), "*.log".to_string()]);
+        let nightly = &config.profiles["nightly"];
+        assert_eq!(nightly.dirs, ["~/Documents", "~/Projects"]);
+        assert_eq!(nightly.excludes.iter().filter(|exclude| *exclude == "*.log").count(), 1);
+        assert!(nightly.excludes.contains(&"/home/dave/Projects/vm-images".to_string()));
+
+        config.save_exclusions("media", &["/home/dave/Videos".to_string()], &["/home/dave/Videos/raw".to_string()]);
+        let (saved, unknown) = parse_config(&toml::to_string_pretty(&config).unwrap()).unwrap();
+        assert!(unknown.is_empty(), "{:?}", unknown);
+        let media = saved.profile("media").unwrap();
+        assert_eq!(media.dirs, ["/home/dave/Videos"]);
+        assert!(media.excludes.contains(&"/home/dave/Videos/raw".to_string()));
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_by_path() {
+        let (config, unknown) = parse_config("quick_dir = [\"~\"]\nmax_path_length = 100\n[profiles.home]\ndirs = [\"~\"]\nexclude = [\"*.log\"]\n").unwrap();
+        assert_eq!(config.max_path_length, Some(100));
+        assert_eq!(config.profiles["home"].dirs, ["~"]);
+        assert_eq!(unknown, ["quick_dir", "profiles.home.exclude"]);
+    }
+}
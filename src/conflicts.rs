@@ -0,0 +1,263 @@
+use crate::prompt::Select;
+use crate::utils;
+use colored::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+//up to this many conflicts are asked about one by one, more get a single bulk question
+pub const DEFAULT_ASK_LIMIT: usize = 10;
+
+//command the archived and local versions of a text file are compared with, "diff -u" when unset
+const DIFF_VAR: &str = "LINUX_BACKUP_DIFF";
+
+//bytes looked at to tell text from binary files
+const TEXT_SNIFF_BYTES: usize = 8192;
+
+//what happens to archived files that already exist in the target
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConflictPolicy {
+    //replace them with the archived version
+    #[default]
+    Overwrite,
+    //keep the local files
+    Skip,
+    //restore only when the archived file is newer
+    Newer,
+    //decide per file when there are few conflicts, once for all of them otherwise
+    Ask,
+}
+
+//what was decided for one existing file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    KeepLocal,
+    Restore,
+    RestoreAsCopy,
+}
+
+impl Decision {
+    fn describe(&self) -> &'static str {
+        match self {
+            Decision::KeepLocal => "kept the local file",
+            Decision::Restore => "restored over the local file",
+            Decision::RestoreAsCopy => "restored as a copy",
+        }
+    }
+}
+
+//an archived file whose path is taken in the target, found before anything is extracted
+pub struct Conflict {
+    pub name: PathBuf,
+    pub path: PathBuf,
+    pub archived_size: u64,
+    pub archived_mtime: u64,
+    //the archived content looked like text, so it can be diffed
+    pub archived_text: bool,
+}
+
+//the decisions for one restore, asked up front and looked up while extracting
+#[derive(Default)]
+pub struct ConflictPlan {
+    //applies to every existing file without a decision of its own, never Ask
+    policy: ConflictPolicy,
+    asked: HashMap<PathBuf, Decision>,
+    pub outcomes: Vec<(PathBuf, Decision, bool)>,
+}
+
+impl ConflictPlan {
+    pub fn new(policy: ConflictPolicy) -> Self {
+        ConflictPlan { policy, ..ConflictPlan::default() }
+    }
+
+    //what to do with an archived entry whose path exists in the target already
+    pub fn decide(&mut self, name: &Path, path: &Path, archived_mtime: u64) -> Decision {
+        let (decision, asked) = match self.asked.get(name) {
+            Some(decision) => (*decision, true),
+            None => {
+                let decision = match self.policy {
+                    ConflictPolicy::Overwrite | ConflictPolicy::Ask => Decision::Restore,
+                    ConflictPolicy::Skip => Decision::KeepLocal,
+                    ConflictPolicy::Newer => match fs::symlink_metadata(path) {
+                        Ok(local) if local.mtime() >= archived_mtime as i64 => Decision::KeepLocal,
+                        _ => Decision::Restore,
+                    },
+                };
+                (decision, false)
+            }
+        };
+        self.outcomes.push((name.to_path_buf(), decision, asked));
+        decision
+    }
+
+    //ask about each conflict, or about all of them at once past the limit
+    pub fn ask(&mut self, conflicts: &[Conflict], limit: usize, read_archived: &dyn Fn(&Path) -> io::Result<Vec<u8>>) -> Result<(), Box<dyn std::error::Error>> {
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+
+        if conflicts.len() > limit {
+            self.policy = ask_bulk_policy(conflicts.len())?;
+            return Ok(());
+        }
+
+        println!("{}", format!("{} files in the archive exist in the target already.", conflicts.len()).yellow());
+        for conflict in conflicts {
+            let decision = ask_conflict(conflict, read_archived)?;
+            self.asked.insert(conflict.name.clone(), decision);
+        }
+        Ok(())
+    }
+
+    pub fn report(&self) {
+        if self.outcomes.is_empty() {
+            return;
+        }
+
+        let count = |decision: Decision| self.outcomes.iter().filter(|(_, d, _)| *d == decision).count();
+        println!(
+            "Existing files: {} restored over, {} kept, {} restored as copies",
+            count(Decision::Restore),
+            count(Decision::KeepLocal),
+            count(Decision::RestoreAsCopy)
+        );
+        for (name, decision, _) in self.outcomes.iter().filter(|(_, _, asked)| *asked) {
+            println!("  {}: {}", name.display(), decision.describe());
+        }
+    }
+}
+
+fn ask_bulk_policy(conflicts: usize) -> Result<ConflictPolicy, Box<dyn std::error::Error>> {
+    let options = vec!["Restore the archived versions", "Keep the local files", "Keep whichever is newer"];
+    let selection = Select::new()
+        .with_prompt(format!("{} files in the archive exist in the target already, what should happen to them?", conflicts))
+        .default(0)
+        .items(&options)
+        .interact()?;
+
+    Ok(match selection {
+        0 => ConflictPolicy::Overwrite,
+        1 => ConflictPolicy::Skip,
+        _ => ConflictPolicy::Newer,
+    })
+}
+
+fn ask_conflict(conflict: &Conflict, read_archived: &dyn Fn(&Path) -> io::Result<Vec<u8>>) -> Result<Decision, Box<dyn std::error::Error>> {
+    let local = fs::symlink_metadata(&conflict.path)?;
+    let copy = copy_path(&conflict.path);
+    let can_diff = conflict.archived_text && local.is_file() && is_text(&conflict.path);
+
+    println!("\n{}", conflict.name.display().to_string().bold());
+    println!("  on disk:  {:>10}  modified {}", utils::format_size(local.size()), format_mtime(local.mtime()));
+    println!("  archived: {:>10}  modified {}", utils::format_size(conflict.archived_size), format_mtime(conflict.archived_mtime as i64));
+
+    let mut options = vec![
+        "Keep the local file".to_string(),
+        "Restore the archived file".to_string(),
+        format!("Restore it as {}", copy.file_name().unwrap_or_default().to_string_lossy()),
+    ];
+    if can_diff {
+        options.push("Show the differences".to_string());
+    }
+
+    loop {
+        let selection = Select::new()
+            .with_prompt("What should happen to it?")
+            .default(0)
+            .items(&options)
+            .interact()?;
+
+        match selection {
+            0 => return Ok(Decision::KeepLocal),
+            1 => return Ok(Decision::Restore),
+            2 => return Ok(Decision::RestoreAsCopy),
+            _ => {
+                if let Err(e) = show_diff(&conflict.path, &read_archived(&conflict.name)?) {
+                    println!("{}", format!("Could not compare the files: {}", e).red());
+                }
+            }
+        }
+    }
+}
+
+//run the diff command on the local file and a temporary copy of the archived one
+fn show_diff(local: &Path, archived: &[u8]) -> io::Result<()> {
+    let command = std::env::var(DIFF_VAR).unwrap_or_else(|_| "diff -u".to_string());
+    let mut words = command.split_whitespace();
+    let program = words.next().unwrap_or("diff");
+
+    let temp = std::env::temp_dir().join(format!(
+        "linux_backup-{}-{}.archived",
+        std::process::id(),
+        local.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    fs::write(&temp, archived)?;
+    let status = Command::new(program).args(words).arg(local).arg(&temp).status();
+    let _ = fs::remove_file(&temp);
+    status.map(|_| ())
+}
+
+//where "restore as copy" puts the archived file: next to the local one, never over another file
+pub fn copy_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let mut copy = path.with_file_name(format!("{}.restored", name));
+    let mut n = 1;
+    while fs::symlink_metadata(&copy).is_ok() {
+        copy = path.with_file_name(format!("{}.restored.{}", name, n));
+        n += 1;
+    }
+    copy
+}
+
+//no NUL bytes at the start, the same guess diff and grep make
+pub fn looks_like_text(bytes: &[u8]) -> bool {
+    !bytes[..bytes.len().min(TEXT_SNIFF_BYTES)].contains(&0)
+}
+
+fn is_text(path: &Path) -> bool {
+    use std::io::Read;
+    let mut start = Vec::with_capacity(TEXT_SNIFF_BYTES);
+    match fs::File::open(path) {
+        Ok(file) => file.take(TEXT_SNIFF_BYTES as u64).read_to_end(&mut start).is_ok() && looks_like_text(&start),
+        Err(_) => false,
+    }
+}
+
+fn format_mtime(mtime: i64) -> String {
+    chrono::DateTime::from_timestamp(mtime, 0)
+        .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn policies_and_answers_decide_per_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let local = temp.path().join("notes.txt");
+        fs::write(&local, "local").unwrap();
+        fs::File::open(&local).unwrap().set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(2_000)).unwrap();
+
+        let mut newer = ConflictPlan::new(ConflictPolicy::Newer);
+        assert_eq!(newer.decide(Path::new("notes.txt"), &local, 1_000), Decision::KeepLocal);
+        assert_eq!(newer.decide(Path::new("notes.txt"), &local, 3_000), Decision::Restore);
+
+        //an answer wins over the policy and is reported as asked
+        let mut asked = ConflictPlan::new(ConflictPolicy::Skip);
+        asked.asked.insert(PathBuf::from("notes.txt"), Decision::RestoreAsCopy);
+        assert_eq!(asked.decide(Path::new("notes.txt"), &local, 1_000), Decision::RestoreAsCopy);
+        assert_eq!(asked.decide(Path::new("other.txt"), &local, 1_000), Decision::KeepLocal);
+        assert_eq!(asked.outcomes[0], (PathBuf::from("notes.txt"), Decision::RestoreAsCopy, true));
+
+        assert_eq!(copy_path(&local), temp.path().join("notes.txt.restored"));
+        fs::write(temp.path().join("notes.txt.restored"), "").unwrap();
+        assert_eq!(copy_path(&local), temp.path().join("notes.txt.restored.1"));
+        assert!(looks_like_text(b"plain\n") && !looks_like_text(b"\x7fELF\0\0"));
+    }
+}
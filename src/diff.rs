@@ -0,0 +1,290 @@
+use crate::backup;
+use crate::checksums::{self, SUMS_FILE};
+use crate::compression;
+use crate::config::{self, Profile};
+use crate::encryption::{self, ArchiveKey};
+use crate::entry_index;
+use crate::manifest::{self, Manifest, MANIFEST_FILE};
+use crate::restore::{self, RestoreOptions};
+use crate::restore_script::sha256_file;
+use crate::selector::EntryKind;
+use crate::sysinfo;
+use crate::utils::{self, BackupMetadata, BackupType};
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tar::{Archive, EntryType};
+
+//an entry as the archive holds it, the data stays in the archive
+struct Archived {
+    kind: EntryKind,
+    //further names of a hard-linked file carry no size of their own
+    size: Option<u64>,
+    mtime: u64,
+    //symlink targets are only known when the archive is read, not from an entry index
+    link: Option<PathBuf>,
+}
+
+//what the archive says about itself and its entries
+struct ArchiveListing {
+    entries: HashMap<PathBuf, Archived>,
+    manifest: Option<Vec<u8>>,
+    sums: Vec<u8>,
+}
+
+//files that differ between an archive and the filesystem, each with its size on disk or in the archive
+#[derive(Default)]
+pub struct Diff {
+    pub new: Vec<(PathBuf, u64)>,
+    pub modified: Vec<(PathBuf, u64)>,
+    pub deleted: Vec<(PathBuf, u64)>,
+}
+
+fn kind_of(entry_type: EntryType) -> Option<EntryKind> {
+    match entry_type {
+        EntryType::Regular | EntryType::Continuous | EntryType::Link => Some(EntryKind::File),
+        EntryType::Directory => Some(EntryKind::Directory),
+        EntryType::Symlink => Some(EntryKind::Symlink),
+        EntryType::Char | EntryType::Block | EntryType::Fifo => Some(EntryKind::Special),
+        _ => None,
+    }
+}
+
+//the names and headers of the archive, from its entry index when it has one, the checksums and the manifest along with them
+fn read_archive(path: &Path, key: Option<&ArchiveKey>, progress: &ProgressBar) -> Result<ArchiveListing, Box<dyn std::error::Error>> {
+    let mut archived = HashMap::new();
+    let sums_name = Path::new(utils::STATE_DIR).join(SUMS_FILE);
+    let manifest_name = Path::new(utils::STATE_DIR).join(MANIFEST_FILE);
+
+    if let Some(entries) = key.is_none().then(|| entry_index::entries(path)).flatten() {
+        for entry in entries {
+            let name = PathBuf::from(&entry.path);
+            let kind = match entry.kind.as_str() {
+                "file" | "hardlink" => EntryKind::File,
+                "dir" => EntryKind::Directory,
+                "symlink" => EntryKind::Symlink,
+                _ => EntryKind::Special,
+            };
+            if !restore::is_bookkeeping(&name) {
+                let size = (entry.kind != "hardlink").then_some(entry.size);
+                archived.insert(name, Archived { kind, size, mtime: entry.mtime, link: None });
+            }
+        }
+        let sums = entry_index::read_entry(path, &sums_name).and_then(Result::ok).unwrap_or_default();
+        return Ok(ArchiveListing { entries: archived, manifest: manifest::read_entry(path, None), sums });
+    }
+
+    let mut manifest_data = None;
+    let mut sums = Vec::new();
+    let mut archive = Archive::new(compression::open_decoder(path, key)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_path_buf();
+        if name == sums_name {
+            entry.read_to_end(&mut sums)?;
+        } else if name == manifest_name {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            manifest_data = Some(data);
+        } else if let (false, Some(kind)) = (restore::is_bookkeeping(&name), kind_of(entry.header().entry_type())) {
+            let size = (entry.header().entry_type() != EntryType::Link).then(|| entry.header().size()).transpose()?;
+            let link = match kind {
+                EntryKind::Symlink => entry.link_name()?.map(|target| target.to_path_buf()),
+                _ => None,
+            };
+            archived.insert(name, Archived { kind, size, mtime: entry.header().mtime().unwrap_or(0), link });
+        }
+        if archived.len().is_multiple_of(1000) {
+            progress.set_message(format!("Reading the archive, {} entries...", archived.len()));
+        }
+    }
+    Ok(ArchiveListing { entries: archived, manifest: manifest_data, sums })
+}
+
+//unchanged timestamps and sizes are taken on trust, a differing timestamp is settled by the recorded checksum when there is one
+fn differs(archived: &Archived, path: &Path, kind: &EntryKind, size: u64, sum: Option<&String>) -> bool {
+    if archived.kind != *kind {
+        return true;
+    }
+    match kind {
+        EntryKind::File => {
+            if archived.size.is_some_and(|archived_size| archived_size != size) {
+                return true;
+            }
+            let mtime = fs::symlink_metadata(path).map_or(0, |metadata| metadata.mtime().max(0) as u64);
+            if mtime == archived.mtime && archived.size.is_some() {
+                return false;
+            }
+            match sum {
+                Some(sum) => sha256_file(path).map_or(true, |actual| actual != *sum),
+                None => true,
+            }
+        }
+        EntryKind::Symlink => archived.link.as_ref().is_some_and(|target| fs::read_link(path).ok().as_ref() != Some(target)),
+        _ => false,
+    }
+}
+
+//walk the sources the way the backup did and compare what it finds with the archive
+fn compare(sources: &[PathBuf], exclusions: &[PathBuf], mut archived: HashMap<PathBuf, Archived>, sums: &HashMap<PathBuf, String>, full: bool, progress: &ProgressBar) -> Result<Diff, Box<dyn std::error::Error>> {
+    let config = config::load_config()?;
+    let settings = backup::profile_settings(&Profile { backup_type: Some("full".to_string()), ..Profile::default() }, &config)?;
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let mut selector = backup::file_selector(exclusions, &settings, &BackupMetadata::default(), current_time);
+
+    let mut diff = Diff::default();
+    let mut compared = 0u64;
+    for source in sources {
+        for entry in selector.select(source) {
+            compared += 1;
+            if compared.is_multiple_of(1000) {
+                progress.set_message(format!("Compared {} entries...", compared));
+            }
+            let name = entry.path.strip_prefix("/").unwrap_or(&entry.path).to_path_buf();
+            match archived.remove(&name) {
+                Some(archived) if differs(&archived, &entry.path, &entry.kind, entry.size, sums.get(&name)) => diff.modified.push((entry.path, entry.size)),
+                //an incremental archive leaves out everything that didn't change, so nothing is new to it
+                None if full && entry.kind != EntryKind::Directory => diff.new.push((entry.path, entry.size)),
+                _ => {}
+            }
+        }
+    }
+
+    //what is left wasn't found by the walk, it is gone or now excluded
+    for (name, archived) in archived {
+        let path = Path::new("/").join(&name);
+        if archived.kind != EntryKind::Directory && fs::symlink_metadata(&path).is_err() {
+            diff.deleted.push((path, archived.size.unwrap_or(0)));
+        }
+    }
+    for list in [&mut diff.new, &mut diff.modified, &mut diff.deleted] {
+        list.sort();
+    }
+    Ok(diff)
+}
+
+fn total(files: &[(PathBuf, u64)]) -> String {
+    let noun = if files.len() == 1 { "file" } else { "files" };
+    format!("{} {}, {}", files.len(), noun, utils::format_size(files.iter().map(|(_, size)| size).sum()))
+}
+
+pub fn print_diff(diff: &Diff, stat: bool) {
+    if !stat {
+        for (label, files, mark) in [("New", &diff.new, "+"), ("Modified", &diff.modified, "~"), ("Deleted", &diff.deleted, "-")] {
+            if files.is_empty() {
+                continue;
+            }
+            println!("\n{}", format!("{}:", label).bold());
+            for (path, size) in files {
+                let line = format!("  {} {} ({})", mark, path.display(), utils::format_size(*size));
+                match mark {
+                    "+" => println!("{}", line.green()),
+                    "~" => println!("{}", line.yellow()),
+                    _ => println!("{}", line.red()),
+                }
+            }
+        }
+        println!();
+    }
+    println!("New: {}", total(&diff.new));
+    println!("Modified: {}", total(&diff.modified));
+    println!("Deleted: {}", total(&diff.deleted));
+    if diff.new.is_empty() && diff.modified.is_empty() && diff.deleted.is_empty() {
+        println!("{}", "Nothing changed since the backup.".green());
+    }
+}
+
+//the diff subcommand: what changed on disk since the archive was made
+pub fn show_diff(backup_file: &str, options: &RestoreOptions, stat: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(backup_file);
+    if !path.exists() {
+        return Err(format!("Backup file does not exist: {}", backup_file).into());
+    }
+    let key = if encryption::is_encrypted(path) {
+        match restore::unlock_archive(path, options)? {
+            Some(key) => Some(key),
+            None => return Ok(()),
+        }
+    } else {
+        None
+    };
+
+    let info = sysinfo::read_from_archive(path, key.as_ref()).ok_or("This archive was not made by linux_backup, it doesn't say which directories it holds")?;
+    if let Some(root) = &info.offline_root {
+        return Err(format!("This archive was made from a system mounted at {}, it can't be compared with the running one", root).into());
+    }
+
+    println!("{}", "\n---- Diff Against the Filesystem ----".blue().bold());
+    println!("Archive: {}", backup_file);
+    let progress = ProgressBar::new_spinner();
+    progress.set_style(ProgressStyle::default_spinner()
+        .template("{spinner:.green} {msg}")
+        .unwrap());
+    progress.set_message("Reading the archive...");
+
+    let listing = read_archive(path, key.as_ref(), &progress)?;
+    let manifest: Option<Manifest> = listing.manifest.as_deref().and_then(manifest::parse);
+    let sums: HashMap<PathBuf, String> = checksums::parse_sums(&listing.sums).into_iter().collect();
+    let sources: Vec<PathBuf> = info.sources.iter().map(PathBuf::from).collect();
+    let exclusions: Vec<PathBuf> = manifest.iter().flat_map(|manifest| manifest.exclusions.iter().map(PathBuf::from)).collect();
+    let full = manifest.as_ref().is_none_or(|manifest| matches!(manifest.backup_type, BackupType::Full));
+
+    progress.set_message("Comparing with the filesystem...");
+    let diff = compare(&sources, &exclusions, listing.entries, &sums, full, &progress);
+    progress.finish_and_clear();
+    let diff = diff?;
+
+    match &manifest {
+        Some(manifest) => println!("Backup: {}", manifest.describe()),
+        None => println!("{}", "The archive has no manifest, it was compared without the exclusions it was made with.".yellow()),
+    }
+    if !full {
+        println!("{}", "This backup only holds what changed since an earlier one, files it doesn't hold aren't listed as new.".yellow());
+    }
+    print_diff(&diff, stat);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn files_are_sorted_into_new_modified_and_deleted() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().join("src");
+        fs::create_dir_all(&root).unwrap();
+        for name in ["same.txt", "touched.txt", "edited.txt", "grown.txt", "new.txt"] {
+            fs::write(root.join(name), "content").unwrap();
+        }
+        symlink("same.txt", root.join("link")).unwrap();
+        let mtime = |name: &str| fs::metadata(root.join(name)).unwrap().mtime() as u64;
+        let name = |file: &str| root.join(file).strip_prefix("/").unwrap().to_path_buf();
+        let file = |file: &str, size: u64, mtime: u64| (name(file), Archived { kind: EntryKind::File, size: Some(size), mtime, link: None });
+
+        let archived: HashMap<PathBuf, Archived> = [
+            file("same.txt", 7, mtime("same.txt")),
+            //a new timestamp, but the content still matches its checksum
+            file("touched.txt", 7, 1),
+            file("edited.txt", 7, 1),
+            file("grown.txt", 3, mtime("grown.txt")),
+            file("gone.txt", 4, 1),
+            (name("link"), Archived { kind: EntryKind::Symlink, size: Some(0), mtime: 0, link: Some(PathBuf::from("edited.txt")) }),
+        ]
+        .into_iter()
+        .collect();
+        let content = sha256_file(&root.join("same.txt")).unwrap();
+        let sums: HashMap<PathBuf, String> = [(name("touched.txt"), content), (name("edited.txt"), "0".repeat(64))].into_iter().collect();
+
+        let diff = compare(std::slice::from_ref(&root), &[], archived, &sums, true, &ProgressBar::hidden()).unwrap();
+        assert_eq!(diff.new, vec![(root.join("new.txt"), 7)]);
+        assert_eq!(diff.modified.iter().map(|(path, _)| path.clone()).collect::<Vec<_>>(), vec![root.join("edited.txt"), root.join("grown.txt"), root.join("link")]);
+        assert_eq!(diff.deleted, vec![(root.join("gone.txt"), 4)]);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::services;
+use crate::sysinfo::SYSINFO_DIR;
+use colored::*;
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use tar::{Archive, Builder, Header};
+
+//partition tables, LUKS headers, RAID and LVM metadata, next to the descriptor
+pub const DISKS_DIR: &str = ".linux_backup_sysinfo/disks";
+
+//reading a partition table or a header takes a moment, a hanging disk shouldn't hold up the backup
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+//the layout of this machine's disks, saved to a private temporary directory that is removed when dropped
+pub struct DiskLayout {
+    dir: PathBuf,
+    //file names below DISKS_DIR
+    pub files: Vec<String>,
+}
+
+impl DiskLayout {
+    //root only, every tool is optional and a failing one is warned about and left out
+    pub fn capture() -> io::Result<Self> {
+        let dir = std::env::temp_dir().join(format!("linux_backup-{}-disks", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        //LUKS headers together with a passphrase open the disk, nobody else gets to read them on the way
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        println!("{}", "Saving the disk layout...".bold());
+
+        for (device, kind, fstype) in block_devices() {
+            let name = device.rsplit('/').next().unwrap_or(&device).to_string();
+            //zram devices are compressed swap in memory, there is no partition table to keep
+            if kind == "disk" && !name.starts_with("zram") {
+                save_output(&dir, &format!("sfdisk-{}.txt", name), Command::new("sfdisk").args(["--dump", &device]));
+            }
+            if fstype == "crypto_LUKS" {
+                let backup = dir.join(format!("luks-{}.img", name));
+                let result = services::run_with_timeout(Command::new("cryptsetup").args(["luksHeaderBackup", &device, "--header-backup-file"]).arg(&backup), COMMAND_TIMEOUT);
+                warn_on_failure(&format!("the LUKS header of {}", device), result);
+            }
+        }
+
+        //both print nothing and succeed when there are no arrays or volume groups
+        if Path::new("/proc/mdstat").exists() {
+            save_output(&dir, "mdadm.conf", Command::new("mdadm").args(["--detail", "--scan"]));
+        }
+        if !services::output_with_timeout(Command::new("vgs").args(["--noheadings", "-o", "vg_name"]), COMMAND_TIMEOUT).unwrap_or_default().is_empty() {
+            let result = services::run_with_timeout(Command::new("vgcfgbackup").arg("-f").arg(dir.join("lvm-%s.vg")).stdout(std::process::Stdio::null()), COMMAND_TIMEOUT);
+            warn_on_failure("the LVM metadata", result);
+        }
+
+        let mut files: Vec<String> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.metadata().is_ok_and(|metadata| metadata.len() > 0))
+            .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+            .collect();
+        files.sort();
+
+        if files.is_empty() {
+            println!("{}", "Warning: No disk layout could be saved, the archive can't be used to partition a new disk.".yellow());
+        } else {
+            println!("{}", format!("Saved the disk layout: {}", files.join(", ")).green());
+        }
+        Ok(DiskLayout { dir, files })
+    }
+
+    //stored readable by root only, like the service dumps
+    pub fn append_to_archive<W: Write>(&self, archive: &mut Builder<W>, mtime: u64) -> io::Result<()> {
+        for name in &self.files {
+            let mut file = File::open(self.dir.join(name))?;
+            let mut header = Header::new_gnu();
+            header.set_size(file.metadata()?.len());
+            header.set_mode(0o600);
+            header.set_mtime(mtime);
+            header.set_cksum();
+            archive.append_data(&mut header, format!("{}/{}", DISKS_DIR, name), &mut file as &mut dyn Read)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskLayout {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+//(device, type, filesystem type) of every block device, partitions and mapped devices included
+fn block_devices() -> Vec<(String, String, String)> {
+    let output = match services::output_with_timeout(Command::new("lsblk").args(["-pnlo", "NAME,TYPE,FSTYPE"]), COMMAND_TIMEOUT) {
+        Ok(output) => output,
+        Err(e) => {
+            println!("{}", format!("Warning: Could not list the block devices: {}", e).yellow());
+            return Vec::new();
+        }
+    };
+
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?.to_string(), fields.next()?.to_string(), fields.next().unwrap_or_default().to_string()))
+        })
+        .collect()
+}
+
+fn save_output(dir: &Path, name: &str, command: &mut Command) {
+    let result = File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(dir.join(name))
+        .map_err(|e| e.to_string())
+        .and_then(|file| services::run_with_timeout(command.stdout(file), COMMAND_TIMEOUT));
+    warn_on_failure(name, result);
+}
+
+fn warn_on_failure(what: &str, result: Result<(), String>) {
+    if let Err(e) = result {
+        println!("{}", format!("Warning: Could not save {}: {}", what, e).yellow());
+    }
+}
+
+//write the saved layout into `dir` without applying any of it, returns the files written
+pub fn extract<R: Read>(archive: &mut Archive<R>, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        if !path.starts_with(SYSINFO_DIR) {
+            //the descriptor and the layout come first, the rest of the archive doesn't need reading
+            break;
+        }
+        let Some(name) = path.strip_prefix(DISKS_DIR).ok().and_then(|name| name.file_name()) else {
+            continue;
+        };
+
+        if files.is_empty() {
+            DirBuilder::new().mode(0o700).recursive(true).create(dir)?;
+        }
+        let target = dir.join(name);
+        let mut file = File::options().write(true).create(true).truncate(true).mode(0o600).open(&target)?;
+        io::copy(&mut entry, &mut file)?;
+        files.push(target);
+    }
+    Ok(files)
+}
+
+//how a saved file is put back, by hand and after checking the device names on the new machine
+pub fn instructions(file: &Path) -> String {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    let path = file.display();
+    if let Some(disk) = name.strip_prefix("sfdisk-").and_then(|name| name.strip_suffix(".txt")) {
+        format!("Partition table of /dev/{}, recreate it with: sfdisk /dev/{} < {}", disk, disk, path)
+    } else if let Some(device) = name.strip_prefix("luks-").and_then(|name| name.strip_suffix(".img")) {
+        format!("LUKS header of /dev/{}, put it back with: cryptsetup luksHeaderRestore /dev/{} --header-backup-file {}", device, device, path)
+    } else if name == "mdadm.conf" {
+        format!("RAID arrays, copy it to /etc/mdadm/mdadm.conf and run: mdadm --assemble --scan ({})", path)
+    } else if let Some(group) = name.strip_prefix("lvm-").and_then(|name| name.strip_suffix(".vg")) {
+        format!("LVM volume group {}, restore its metadata with: vgcfgrestore -f {} {}", group, path, group)
+    } else {
+        path.to_string()
+    }
+}
+
+//print what was extracted, the text files in full, nothing is ever applied
+pub fn describe(files: &[PathBuf]) {
+    for file in files {
+        println!("\n{}", instructions(file).bold());
+        let is_text = file.extension().is_none_or(|extension| extension != "img");
+        if let Some(contents) = fs::read_to_string(file).ok().filter(|_| is_text) {
+            println!("{}", contents.trim_end());
+        }
+    }
+    println!(
+        "\n{}",
+        "Nothing was applied. Check the device names on this machine with lsblk first: these commands overwrite partition tables and headers, and a wrong device loses its data.".yellow().bold()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn saved_layout_is_extracted_privately_with_instructions() {
+        let temp = tempfile::tempdir().unwrap();
+        let saved = temp.path().join("saved");
+        fs::create_dir(&saved).unwrap();
+        fs::write(saved.join("sfdisk-sda.txt"), "label: gpt\n/dev/sda1 : start=2048, size=1048576\n").unwrap();
+        fs::write(saved.join("luks-sda2.img"), [0u8; 64]).unwrap();
+        let layout = DiskLayout { dir: saved.clone(), files: vec!["luks-sda2.img".to_string(), "sfdisk-sda.txt".to_string()] };
+
+        let mut archive = Builder::new(Vec::new());
+        crate::utils::append_json_entry(&mut archive, crate::sysinfo::SYSINFO_ENTRY, &"{}", 0).unwrap();
+        layout.append_to_archive(&mut archive, 0).unwrap();
+        let mut header = Header::new_gnu();
+        header.set_size(0);
+        archive.append_data(&mut header, "etc/hostname", io::empty()).unwrap();
+        let data = archive.into_inner().unwrap();
+
+        //dropping the layout removes its temporary directory
+        drop(layout);
+        assert!(!saved.exists());
+
+        let extracted = temp.path().join("disk_layout");
+        let files = extract(&mut Archive::new(data.as_slice()), &extracted).unwrap();
+        assert_eq!(files, vec![extracted.join("luks-sda2.img"), extracted.join("sfdisk-sda.txt")]);
+        assert_eq!(fs::metadata(&files[0]).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(&extracted).unwrap().permissions().mode() & 0o777, 0o700);
+
+        assert!(instructions(&files[0]).contains("cryptsetup luksHeaderRestore /dev/sda2"));
+        assert!(instructions(&files[1]).contains("sfdisk /dev/sda <"));
+        assert!(instructions(Path::new("lvm-vg0.vg")).ends_with("vgcfgrestore -f lvm-vg0.vg vg0"));
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+//names kept in memory before they are moved to a sorted index on disk
+const MEMORY_ENTRIES: usize = 250_000;
+
+//key, dev, ino, size, mtime, origin offset and origin length
+const RECORD_SIZE: usize = 64;
+
+//set to print every skipped duplicate
+const DEBUG_VAR: &str = "LINUX_BACKUP_DEBUG";
+
+//keeps spill directories of several archives in one process apart
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+//what identifies the content written for a name, the same file unchanged gives the same fingerprint
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Fingerprint {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+}
+
+impl Fingerprint {
+    fn of(metadata: &fs::Metadata) -> Self {
+        Fingerprint { dev: metadata.dev(), ino: metadata.ino(), size: metadata.size(), mtime: metadata.mtime() }
+    }
+}
+
+struct Emitted {
+    fingerprint: Fingerprint,
+    origin: PathBuf,
+}
+
+//what became of an entry offered to the archive
+#[derive(Debug, PartialEq)]
+pub enum Emission {
+    New,
+    //the same file was written under this name already
+    Duplicate,
+    //another file was written under this name, the first one is kept
+    Collision { first: PathBuf },
+}
+
+//a name two different files were archived under
+pub struct Collision {
+    pub name: PathBuf,
+    pub first: PathBuf,
+    pub second: PathBuf,
+}
+
+//the sorted index and the origins it points into, removed when the archive is done
+struct Spill {
+    dir: PathBuf,
+    index: File,
+    origins: File,
+    origins_len: u64,
+}
+
+//the entry names written to one archive, overlapping sources would otherwise store paths twice
+pub struct EmittedPaths {
+    memory: HashMap<[u8; 16], Emitted>,
+    memory_limit: usize,
+    spill: Option<Spill>,
+    pub duplicates: u64,
+    pub collisions: Vec<Collision>,
+}
+
+impl Default for EmittedPaths {
+    fn default() -> Self {
+        EmittedPaths::with_memory_limit(MEMORY_ENTRIES)
+    }
+}
+
+impl EmittedPaths {
+    pub fn with_memory_limit(memory_limit: usize) -> Self {
+        EmittedPaths {
+            memory: HashMap::new(),
+            memory_limit: memory_limit.max(1),
+            spill: None,
+            duplicates: 0,
+            collisions: Vec::new(),
+        }
+    }
+
+    //remember the name unless it was written already, only New entries go into the archive
+    pub fn record(&mut self, name: &Path, origin: &Path, metadata: &fs::Metadata) -> io::Result<Emission> {
+        //spilled before the lookup rather than after the insert, so the name recorded last is always in memory and can be forgotten
+        if self.memory.len() >= self.memory_limit {
+            self.spill_memory()?;
+        }
+        let key = key_of(name);
+        let fingerprint = Fingerprint::of(metadata);
+
+        let earlier = match self.memory.get(&key) {
+            Some(emitted) => Some((emitted.fingerprint, emitted.origin.clone())),
+            None => self.find_spilled(&key)?,
+        };
+
+        match earlier {
+            None => {
+                self.memory.insert(key, Emitted { fingerprint, origin: origin.to_path_buf() });
+                Ok(Emission::New)
+            }
+            Some((earlier, _)) if earlier == fingerprint => {
+                self.duplicates += 1;
+                if std::env::var_os(DEBUG_VAR).is_some() {
+                    eprintln!("debug: {} is in the archive already, skipped", name.display());
+                }
+                Ok(Emission::Duplicate)
+            }
+            Some((_, first)) => {
+                self.collisions.push(Collision { name: name.to_path_buf(), first: first.clone(), second: origin.to_path_buf() });
+                Ok(Emission::Collision { first })
+            }
+        }
+    }
+
+    //takes back the name recorded last, its entry never made it into the archive
+    pub fn forget(&mut self, name: &Path) {
+        self.memory.remove(&key_of(name));
+    }
+
+    fn find_spilled(&self, key: &[u8; 16]) -> io::Result<Option<(Fingerprint, PathBuf)>> {
+        let spill = match &self.spill {
+            Some(spill) => spill,
+            None => return Ok(None),
+        };
+
+        //binary search over the fixed size records
+        let mut record = [0u8; RECORD_SIZE];
+        let (mut low, mut high) = (0, spill.index.metadata()?.len() / RECORD_SIZE as u64);
+        while low < high {
+            let middle = (low + high) / 2;
+            spill.index.read_exact_at(&mut record, middle * RECORD_SIZE as u64)?;
+            match record[..16].cmp(&key[..]) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => {
+                    let (fingerprint, offset, len) = decode(&record);
+                    let mut origin = vec![0u8; len as usize];
+                    spill.origins.read_exact_at(&mut origin, offset)?;
+                    return Ok(Some((fingerprint, PathBuf::from(std::ffi::OsStr::from_bytes(&origin)))));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    //merge the names in memory into the sorted index on disk
+    fn spill_memory(&mut self) -> io::Result<()> {
+        if self.spill.is_none() {
+            let dir = std::env::temp_dir().join(format!(
+                "linux_backup-{}-emitted-{}",
+                std::process::id(),
+                SPILLS.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&dir)?;
+            let index = File::create(dir.join("index"))?;
+            let origins = OpenOptions::new().read(true).append(true).create(true).open(dir.join("origins"))?;
+            self.spill = Some(Spill { dir, index, origins, origins_len: 0 });
+        }
+        let spill = self.spill.as_mut().unwrap();
+
+        let mut fresh: Vec<([u8; 16], Emitted)> = self.memory.drain().collect();
+        fresh.sort_unstable_by_key(|a| a.0);
+
+        let mut origins = BufWriter::new(&spill.origins);
+        let mut records = Vec::with_capacity(fresh.len());
+        for (key, emitted) in fresh {
+            let origin = emitted.origin.as_os_str().as_bytes();
+            origins.write_all(origin)?;
+            records.push(encode(&key, &emitted.fingerprint, spill.origins_len, origin.len() as u64));
+            spill.origins_len += origin.len() as u64;
+        }
+        origins.flush()?;
+        drop(origins);
+
+        //both sides are sorted, one pass writes the merged index
+        let merged_path = spill.dir.join("index.merged");
+        let mut merged = BufWriter::new(File::create(&merged_path)?);
+        let mut existing = BufReader::new(File::open(spill.dir.join("index"))?);
+        let mut next_existing = read_record(&mut existing)?;
+        let mut fresh = records.into_iter().peekable();
+        loop {
+            let take_existing = match (&next_existing, fresh.peek()) {
+                (Some(old), Some(new)) => old[..16] <= new[..16],
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if take_existing {
+                merged.write_all(&next_existing.take().unwrap())?;
+                next_existing = read_record(&mut existing)?;
+            } else {
+                merged.write_all(&fresh.next().unwrap())?;
+            }
+        }
+        merged.flush()?;
+        drop(merged);
+
+        fs::rename(&merged_path, spill.dir.join("index"))?;
+        spill.index = File::open(spill.dir.join("index"))?;
+        Ok(())
+    }
+}
+
+impl Drop for EmittedPaths {
+    fn drop(&mut self) {
+        if let Some(spill) = &self.spill {
+            let _ = fs::remove_dir_all(&spill.dir);
+        }
+    }
+}
+
+//128 bits of blake3 keep the names short while collisions stay out of reach
+fn key_of(name: &Path) -> [u8; 16] {
+    let hash = blake3::hash(name.as_os_str().as_bytes());
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&hash.as_bytes()[..16]);
+    key
+}
+
+fn encode(key: &[u8; 16], fingerprint: &Fingerprint, offset: u64, len: u64) -> [u8; RECORD_SIZE] {
+    let mut record = [0u8; RECORD_SIZE];
+    record[..16].copy_from_slice(key);
+    let fields = [fingerprint.dev, fingerprint.ino, fingerprint.size, fingerprint.mtime as u64, offset, len];
+    for (i, field) in fields.iter().enumerate() {
+        record[16 + i * 8..24 + i * 8].copy_from_slice(&field.to_le_bytes());
+    }
+    record
+}
+
+fn decode(record: &[u8; RECORD_SIZE]) -> (Fingerprint, u64, u64) {
+    let field = |i: usize| u64::from_le_bytes(record[16 + i * 8..24 + i * 8].try_into().unwrap());
+    let fingerprint = Fingerprint { dev: field(0), ino: field(1), size: field(2), mtime: field(3) as i64 };
+    (fingerprint, field(4), field(5))
+}
+
+//None at the end of the index
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<[u8; RECORD_SIZE]>> {
+    let mut record = [0u8; RECORD_SIZE];
+    match reader.read_exact(&mut record) {
+        Ok(()) => Ok(Some(record)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_found_after_spilling_to_disk() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut files = Vec::new();
+        for i in 0..10 {
+            let path = temp.path().join(format!("file{}", i));
+            fs::write(&path, format!("content {}", i)).unwrap();
+            files.push(path);
+        }
+
+        let mut emitted = EmittedPaths::with_memory_limit(3);
+        for path in &files {
+            assert_eq!(emitted.record(path, path, &fs::metadata(path).unwrap()).unwrap(), Emission::New);
+        }
+        let spill_dir = emitted.spill.as_ref().unwrap().dir.clone();
+        assert!(spill_dir.exists());
+
+        //a name spilled in the first round and one still in memory
+        for path in [&files[0], &files[9]] {
+            assert_eq!(emitted.record(path, path, &fs::metadata(path).unwrap()).unwrap(), Emission::Duplicate);
+        }
+
+        //another file under an archived name is kept out and reported with both origins
+        let other = &files[5];
+        let emission = emitted.record(&files[1], other, &fs::metadata(other).unwrap()).unwrap();
+        assert_eq!(emission, Emission::Collision { first: files[1].clone() });
+        assert_eq!(emitted.duplicates, 2);
+        assert_eq!(emitted.collisions[0].second, *other);
+
+        drop(emitted);
+        assert!(!spill_dir.exists());
+    }
+
+    #[test]
+    fn the_name_recorded_last_can_be_forgotten() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut emitted = EmittedPaths::with_memory_limit(1);
+        let first = temp.path().join("first");
+        let second = temp.path().join("second");
+        fs::write(&first, "first").unwrap();
+        fs::write(&second, "second").unwrap();
+
+        emitted.record(&first, &first, &fs::metadata(&first).unwrap()).unwrap();
+        assert_eq!(emitted.record(&second, &second, &fs::metadata(&second).unwrap()).unwrap(), Emission::New);
+        emitted.forget(&second);
+        assert_eq!(emitted.record(&second, &second, &fs::metadata(&second).unwrap()).unwrap(), Emission::New);
+        assert_eq!(emitted.duplicates, 0);
+    }
+}
@@ -0,0 +1,453 @@
+use crate::cipher::{self, CipherReader, CipherWriter};
+use crate::prompt::Password;
+use age::secrecy::SecretString;
+use age::stream::{StreamReader, StreamWriter};
+use age::{x25519, DecryptError, Decryptor, Encryptor};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::iter;
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+//every binary age file starts with this line
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1\n";
+
+//added after the archive extension, backup.tar.gz -> backup.tar.gz.age or backup.tar.gz.gpg
+const AGE_EXTENSION: &str = ".age";
+const GPG_EXTENSION: &str = ".gpg";
+
+//gpg output has no magic worth relying on, these names mark it
+const GPG_EXTENSIONS: &[&str] = &["gpg", "pgp"];
+
+//how a new archive is encrypted
+pub enum Encryption {
+    Passphrase(SecretString),
+    //age public keys (age1...), the matching identity files decrypt it
+    Recipients(Vec<x25519::Recipient>),
+    //gpg key ids, fingerprints or emails, anyone holding one of the secret keys can restore
+    Gpg(Vec<String>),
+    //argon2id and XChaCha20-Poly1305 in linux_backup's own format, needs neither age nor gpg
+    Builtin(SecretString),
+}
+
+impl Encryption {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encryption::Gpg(_) => GPG_EXTENSION,
+            Encryption::Builtin(_) => cipher::EXTENSION,
+            _ => AGE_EXTENSION,
+        }
+    }
+}
+
+//what unlocks an encrypted archive
+pub enum ArchiveKey {
+    Passphrase(SecretString),
+    Identities(Vec<x25519::Identity>),
+    //gpg finds the secret key in its keyring and asks for its passphrase itself
+    Gpg,
+}
+
+//the file a tar stream is written to, encrypted on the way when asked to
+pub enum ArchiveOutput<W: Write = File> {
+    Plain(W),
+    Encrypted(StreamWriter<W>),
+    //gpg writes the file, the stream is fed to it
+    Gpg(GpgWriter),
+    Builtin(CipherWriter<W>),
+}
+
+//a running gpg --encrypt, its output goes straight to the archive file
+pub struct GpgWriter {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+impl<W: Write + Into<Stdio>> ArchiveOutput<W> {
+    pub fn new(writer: W, encryption: Option<&Encryption>) -> io::Result<Self> {
+        let encryptor = match encryption {
+            None => return Ok(ArchiveOutput::Plain(writer)),
+            Some(Encryption::Gpg(recipients)) => return Ok(ArchiveOutput::Gpg(gpg_encrypt(writer.into(), recipients)?)),
+            Some(Encryption::Builtin(passphrase)) => return Ok(ArchiveOutput::Builtin(CipherWriter::new(writer, passphrase)?)),
+            Some(Encryption::Passphrase(passphrase)) => Encryptor::with_user_passphrase(passphrase.clone()),
+            Some(Encryption::Recipients(recipients)) => {
+                Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+                    .map_err(|e| io::Error::other(e.to_string()))?
+            }
+        };
+        Ok(ArchiveOutput::Encrypted(encryptor.wrap_output(writer)?))
+    }
+}
+
+impl<W: Write> ArchiveOutput<W> {
+    //the last encrypted chunk is only written here, without it the archive can't be decrypted
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            ArchiveOutput::Plain(mut writer) => writer.flush(),
+            ArchiveOutput::Encrypted(writer) => writer.finish().map(|_| ()),
+            ArchiveOutput::Gpg(writer) => writer.finish(),
+            ArchiveOutput::Builtin(writer) => writer.finish().map(|_| ()),
+        }
+    }
+}
+
+impl<W: Write> Write for ArchiveOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ArchiveOutput::Plain(writer) => writer.write(buf),
+            ArchiveOutput::Encrypted(writer) => writer.write(buf),
+            ArchiveOutput::Gpg(writer) => writer.stdin.write(buf),
+            ArchiveOutput::Builtin(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveOutput::Plain(writer) => writer.flush(),
+            ArchiveOutput::Encrypted(writer) => writer.flush(),
+            ArchiveOutput::Gpg(writer) => writer.stdin.flush(),
+            ArchiveOutput::Builtin(writer) => writer.flush(),
+        }
+    }
+}
+
+impl GpgWriter {
+    //closing its input lets gpg write the last packet, a failure there is gpg's own message
+    fn finish(self) -> io::Result<()> {
+        drop(self.stdin);
+        let output = self.child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("gpg could not encrypt the archive: {}", String::from_utf8_lossy(&output.stderr).trim())));
+        }
+        Ok(())
+    }
+}
+
+//--trust-model always: the recipients were named explicitly, unsigned team keys are fine
+fn gpg_encrypt(output: Stdio, recipients: &[String]) -> io::Result<GpgWriter> {
+    let mut command = Command::new("gpg");
+    command.args(["--batch", "--yes", "--quiet", "--trust-model", "always", "--encrypt"]);
+    for recipient in recipients {
+        command.arg("--recipient").arg(recipient);
+    }
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(output)
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(gpg_spawn_error)?;
+    let stdin = child.stdin.take().unwrap();
+    Ok(GpgWriter { child, stdin })
+}
+
+fn gpg_spawn_error(error: io::Error) -> io::Error {
+    match error.kind() {
+        io::ErrorKind::NotFound => io::Error::new(io::ErrorKind::NotFound, "gpg is not installed"),
+        _ => error,
+    }
+}
+
+//the header decides for age, a .age name alone isn't enough, gpg archives go by their name
+pub fn is_encrypted(path: &Path) -> bool {
+    let mut magic = [0u8; AGE_MAGIC.len()];
+    let age = File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| magic == AGE_MAGIC);
+    age || is_gpg(path) || cipher::is_encrypted(path)
+}
+
+//backup.tar.gz.gpg or .pgp
+pub fn is_gpg(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| GPG_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+//true for archives encrypted with a passphrase, false for ones encrypted to recipients
+pub fn uses_passphrase(path: &Path) -> io::Result<bool> {
+    if cipher::is_encrypted(path) {
+        return Ok(true);
+    }
+    let decryptor = Decryptor::new(File::open(path)?).map_err(decrypt_error)?;
+    Ok(decryptor.is_scrypt())
+}
+
+//the plaintext of an encrypted archive file
+pub fn decrypt_file(path: &Path, key: &ArchiveKey) -> io::Result<Box<dyn Read + Send>> {
+    match key {
+        ArchiveKey::Gpg => Ok(Box::new(gpg_decrypt(path)?)),
+        ArchiveKey::Passphrase(passphrase) if cipher::is_encrypted(path) => Ok(Box::new(CipherReader::new(File::open(path)?, passphrase)?)),
+        _ => Ok(Box::new(decrypt(File::open(path)?, key)?)),
+    }
+}
+
+//the plaintext of an age stream, a wrong key fails here before anything is read
+fn decrypt<R: Read>(reader: R, key: &ArchiveKey) -> io::Result<StreamReader<R>> {
+    let decryptor = Decryptor::new(reader).map_err(decrypt_error)?;
+
+    let result = match key {
+        ArchiveKey::Passphrase(passphrase) => {
+            let identity = age::scrypt::Identity::new(passphrase.clone());
+            decryptor.decrypt(iter::once(&identity as &dyn age::Identity))
+        }
+        ArchiveKey::Identities(identities) => decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity)),
+        ArchiveKey::Gpg => return Err(io::Error::new(io::ErrorKind::InvalidInput, "gpg archives are decrypted by gpg")),
+    };
+
+    result.map_err(decrypt_error)
+}
+
+//gpg's output, a failure shows up at the end of the stream with gpg's error output as the message
+pub struct GpgReader {
+    child: Child,
+    stdout: ChildStdout,
+    finished: bool,
+}
+
+impl Read for GpgReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() && !self.finished {
+            self.finished = true;
+            let mut errors = String::new();
+            if let Some(mut stderr) = self.child.stderr.take() {
+                stderr.read_to_string(&mut errors)?;
+            }
+            if !self.child.wait()?.success() {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, errors.trim().to_string()));
+            }
+        }
+        Ok(read)
+    }
+}
+
+//a reader dropped halfway must not leave gpg running
+impl Drop for GpgReader {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+//not --batch, gpg may need to ask for the secret key's passphrase
+fn gpg_decrypt(path: &Path) -> io::Result<GpgReader> {
+    let mut child = Command::new("gpg")
+        .args(["--quiet", "--decrypt"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(gpg_spawn_error)?;
+    let stdout = child.stdout.take().unwrap();
+    Ok(GpgReader { child, stdout, finished: false })
+}
+
+//public keys in the keyring as (fingerprint, user id), revoked and expired ones left out
+pub fn gpg_public_keys() -> io::Result<Vec<(String, String)>> {
+    let output = Command::new("gpg")
+        .args(["--batch", "--list-keys", "--with-colons"])
+        .stderr(Stdio::null())
+        .output()
+        .map_err(gpg_spawn_error)?;
+    Ok(parse_gpg_keys(&String::from_utf8_lossy(&output.stdout)))
+}
+
+//the fpr and uid lines following each pub line of --with-colons output
+fn parse_gpg_keys(listing: &str) -> Vec<(String, String)> {
+    let mut keys: Vec<(String, String)> = Vec::new();
+    let mut usable = false;
+
+    for line in listing.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.first() {
+            Some(&"pub") => {
+                usable = !matches!(fields.get(1), Some(&"r") | Some(&"e") | Some(&"d"));
+                if usable {
+                    keys.push((String::new(), String::new()));
+                }
+            }
+            Some(&"fpr") if usable => {
+                if let Some(key) = keys.last_mut().filter(|key| key.0.is_empty()) {
+                    key.0 = fields.get(9).unwrap_or(&"").to_string();
+                }
+            }
+            Some(&"uid") if usable && !matches!(fields.get(1), Some(&"r") | Some(&"e")) => {
+                if let Some(key) = keys.last_mut().filter(|key| key.1.is_empty()) {
+                    key.1 = fields.get(9).unwrap_or(&"").to_string();
+                }
+            }
+            Some(&"sub") => usable = false,
+            _ => {}
+        }
+    }
+
+    keys.retain(|key| !key.0.is_empty());
+    keys
+}
+
+//a typo in a key id should stop the backup before anything is scanned
+pub fn check_gpg_recipient(recipient: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let status = Command::new("gpg")
+        .args(["--batch", "--list-keys", "--"])
+        .arg(recipient)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(gpg_spawn_error)?;
+
+    if !status.success() {
+        return Err(format!("No GPG public key found for {}", recipient).into());
+    }
+    Ok(())
+}
+
+fn decrypt_error(error: DecryptError) -> io::Error {
+    match error {
+        DecryptError::DecryptionFailed | DecryptError::NoMatchingKeys | DecryptError::KeyDecryptionFailed => {
+            io::Error::new(io::ErrorKind::PermissionDenied, "wrong passphrase or key")
+        }
+        DecryptError::Io(e) => e,
+        other => io::Error::new(io::ErrorKind::InvalidData, other.to_string()),
+    }
+}
+
+//hidden input, asked twice when encrypting so a typo doesn't lock the backup away
+pub fn prompt_passphrase(confirm: bool) -> Result<SecretString, Box<dyn std::error::Error>> {
+    let prompt = Password::new().with_prompt("Passphrase");
+    let passphrase = if confirm {
+        prompt.with_confirmation("Repeat passphrase", "The passphrases don't match").interact()?
+    } else {
+        prompt.interact()?
+    };
+
+    if passphrase.is_empty() {
+        return Err("The passphrase must not be empty".into());
+    }
+    Ok(SecretString::from(passphrase))
+}
+
+pub fn parse_recipient(recipient: &str) -> Result<x25519::Recipient, Box<dyn std::error::Error>> {
+    recipient.trim().parse().map_err(|e| format!("Not an age public key ({}): {}", e, recipient).into())
+}
+
+//the AGE-SECRET-KEY-... lines of an identity file as written by age-keygen
+pub fn read_identities(path: &Path) -> Result<Vec<x25519::Identity>, Box<dyn std::error::Error>> {
+    let identities: Vec<x25519::Identity> = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("AGE-SECRET-KEY-"))
+        .filter_map(|line| line.parse().ok())
+        .collect();
+
+    if identities.is_empty() {
+        return Err(format!("No age identities found in {}", path.display()).into());
+    }
+    Ok(identities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn encrypt(path: &Path, encryption: &Encryption) {
+        let mut output = ArchiveOutput::new(File::create(path).unwrap(), Some(encryption)).unwrap();
+        output.write_all(b"secret archive").unwrap();
+        output.finish().unwrap();
+    }
+
+    #[test]
+    fn archives_decrypt_only_with_the_right_key() {
+        let temp = tempfile::tempdir().unwrap();
+
+        let identity = x25519::Identity::generate();
+        let keyed = temp.path().join("keyed.tar.gz.age");
+        encrypt(&keyed, &Encryption::Recipients(vec![identity.to_public()]));
+        assert!(is_encrypted(&keyed));
+        assert!(!uses_passphrase(&keyed).unwrap());
+
+        let identity_file = temp.path().join("key.txt");
+        fs::write(&identity_file, format!("# created: today\n{}\n", identity.to_string().expose_secret())).unwrap();
+        let key = ArchiveKey::Identities(read_identities(&identity_file).unwrap());
+        let mut contents = String::new();
+        decrypt(File::open(&keyed).unwrap(), &key).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "secret archive");
+
+        let locked = temp.path().join("locked.tar.gz.age");
+        encrypt(&locked, &Encryption::Passphrase(SecretString::from("correct horse".to_string())));
+        assert!(uses_passphrase(&locked).unwrap());
+
+        let wrong = ArchiveKey::Passphrase(SecretString::from("battery staple".to_string()));
+        let error = decrypt(File::open(&locked).unwrap(), &wrong).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(!is_encrypted(&identity_file));
+    }
+
+    #[test]
+    fn gpg_key_listings_leave_out_revoked_keys() {
+        let listing = "\
+tru::1:1700000000:0:3:1:5
+pub:u:255:22:AAAA1111BBBB2222:1700000000:::u:::scESC:::::ed25519:::0:
+fpr:::::::::1111AAAA2222BBBB3333CCCC4444DDDD5555EEEE:
+uid:u::::1700000000::0A1B::Alice Admin <alice@example.com>::::::::::0:
+sub:u:255:18:CCCC3333DDDD4444:1700000000::::::e:::::cv25519::
+fpr:::::::::0000SUBKEY0000:
+pub:r:255:22:EEEE5555FFFF6666:1600000000:::u:::sc:::::ed25519:::0:
+fpr:::::::::REVOKED0000:
+uid:r::::1600000000::2C3D::Old Key <old@example.com>::::::::::0:
+";
+        assert_eq!(
+            parse_gpg_keys(listing),
+            vec![("1111AAAA2222BBBB3333CCCC4444DDDD5555EEEE".to_string(), "Alice Admin <alice@example.com>".to_string())]
+        );
+    }
+
+    #[test]
+    fn gpg_archives_decrypt_only_with_the_secret_key() {
+        if Command::new("gpg").arg("--version").output().is_err() {
+            return;
+        }
+
+        let temp = tempfile::tempdir().unwrap();
+        let keyring = temp.path().join("keyring");
+        let empty = temp.path().join("empty");
+        for home in [&keyring, &empty] {
+            fs::create_dir(home).unwrap();
+            fs::set_permissions(home, fs::Permissions::from_mode(0o700)).unwrap();
+        }
+
+        std::env::set_var("GNUPGHOME", &keyring);
+        let generated = Command::new("gpg")
+            .args(["--batch", "--passphrase", "", "--quick-gen-key", "admin@example.com", "default", "default", "never"])
+            .output()
+            .unwrap();
+        assert!(generated.status.success());
+        check_gpg_recipient("admin@example.com").unwrap();
+        assert!(check_gpg_recipient("nobody@example.com").is_err());
+        assert_eq!(gpg_public_keys().unwrap()[0].1, "admin@example.com");
+
+        let archive = temp.path().join("backup.tar.gz.gpg");
+        let mut output = ArchiveOutput::new(File::create(&archive).unwrap(), Some(&Encryption::Gpg(vec!["admin@example.com".to_string()]))).unwrap();
+        output.write_all(b"secret archive").unwrap();
+        output.finish().unwrap();
+        assert!(is_encrypted(&archive));
+
+        let mut contents = String::new();
+        decrypt_file(&archive, &ArchiveKey::Gpg).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "secret archive");
+
+        //without the secret key gpg's own explanation comes through
+        std::env::set_var("GNUPGHOME", &empty);
+        let error = decrypt_file(&archive, &ArchiveKey::Gpg).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("secret key"), "{}", error);
+
+        for home in [&keyring, &empty] {
+            let _ = Command::new("gpgconf").args(["--kill", "gpg-agent"]).env("GNUPGHOME", home).status();
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use crate::compression::CompressionFormat;
+use crate::encryption;
+use crate::sidecars;
+use flate2::bufread::{GzDecoder, MultiGzDecoder};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+use tar::{Archive, EntryType};
+
+//bumped when a field changes meaning, readers ignore indexes of a version they don't know
+const VERSION: u32 = 1;
+
+//the first line of backup.tar.gz.index, one IndexEntry per line follows
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct IndexHeader {
+    pub version: u32,
+    pub format: String,
+    //an index whose archive was replaced or cut is ignored
+    pub archive_size: u64,
+}
+
+//one archive entry, `offset` is where decompression can start in the archive file: the gzip member holding the
+//entry's first header, every gzip member of an archive stands alone. `skip` is how far into that member's output the header is
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct IndexEntry {
+    pub path: String,
+    pub size: u64,
+    pub mtime: u64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub offset: u64,
+    pub skip: u64,
+}
+
+//zstd and xz streams and encrypted archives have no points to start decompressing from
+pub fn unsupported(format: CompressionFormat, encrypted: bool) -> Option<String> {
+    if encrypted {
+        return Some("encrypted archives can't be read from the middle".to_string());
+    }
+    match format {
+        CompressionFormat::Gzip | CompressionFormat::Store => None,
+        other => Some(format!("{} archives can't be read from the middle, only gzip and uncompressed ones", other.name())),
+    }
+}
+
+//"gzip" or "tar", the only formats indexes are written for
+fn format_name(format: CompressionFormat) -> &'static str {
+    match format {
+        CompressionFormat::Gzip => "gzip",
+        _ => "tar",
+    }
+}
+
+fn kind_name(kind: EntryType) -> &'static str {
+    match kind {
+        EntryType::Regular | EntryType::Continuous => "file",
+        EntryType::Directory => "dir",
+        EntryType::Symlink => "symlink",
+        EntryType::Link => "hardlink",
+        EntryType::Char => "char",
+        EntryType::Block => "block",
+        EntryType::Fifo => "fifo",
+        _ => "other",
+    }
+}
+
+//counts what the decoder took from the file, the gzip member that ends leaves the next one's bytes in the buffer
+struct Consumed<R> {
+    inner: R,
+    consumed: u64,
+}
+
+impl<R: BufRead> Read for Consumed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.consumed += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Consumed<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.consumed += amount as u64;
+        self.inner.consume(amount);
+    }
+}
+
+//a gzip stream read one member at a time, noting where each starts as (file offset, tar stream offset)
+struct Members<R> {
+    source: Option<Consumed<R>>,
+    decoder: Option<GzDecoder<Consumed<R>>>,
+    position: u64,
+    starts: Rc<RefCell<Vec<(u64, u64)>>>,
+}
+
+impl<R: BufRead> Read for Members<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(decoder) = &mut self.decoder {
+                let n = decoder.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    self.position += n as u64;
+                    return Ok(n);
+                }
+                self.source = self.decoder.take().map(GzDecoder::into_inner);
+            }
+            let Some(mut source) = self.source.take() else {
+                return Ok(0);
+            };
+            if source.fill_buf()?.is_empty() {
+                return Ok(0);
+            }
+            self.starts.borrow_mut().push((source.consumed, self.position));
+            self.decoder = Some(GzDecoder::new(source));
+        }
+    }
+}
+
+//read the finished archive once and write backup.tar.gz.index next to it, returns how many entries it lists
+pub fn write_index(archive: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+    let format = CompressionFormat::detect(archive).ok_or("not a tar, gzip, zstd or xz archive")?;
+    if let Some(reason) = unsupported(format, encryption::is_encrypted(archive)) {
+        return Err(reason.into());
+    }
+
+    let starts = Rc::new(RefCell::new(Vec::new()));
+    let file = BufReader::new(File::open(archive)?);
+    let reader: Box<dyn Read> = match format {
+        CompressionFormat::Gzip => Box::new(Members { source: Some(Consumed { inner: file, consumed: 0 }), decoder: None, position: 0, starts: starts.clone() }),
+        _ => {
+            starts.borrow_mut().push((0, 0));
+            Box::new(file)
+        }
+    };
+
+    let path = sidecars::entry_index(archive);
+    let temp = sidecars::partial(&path);
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    let header = IndexHeader { version: VERSION, format: format_name(format).to_string(), archive_size: fs::metadata(archive)?.len() };
+    serde_json::to_writer(&mut writer, &header)?;
+    writer.write_all(b"\n")?;
+
+    let mut tar = Archive::new(reader);
+    let mut count = 0;
+    //the first header of an entry, long names and PAX records come before the header the entry is read from
+    let mut next = 0;
+    let result: Result<(), Box<dyn std::error::Error>> = (|| {
+        for entry in tar.entries()? {
+            let entry = entry?;
+            let start = next;
+            let stored = entry.header().entry_size()?;
+            next = entry.raw_file_position() + stored.div_ceil(512) * 512;
+
+            let starts = starts.borrow();
+            let (offset, member) = starts[starts.partition_point(|&(_, member)| member <= start) - 1];
+            let indexed = IndexEntry {
+                path: entry.path()?.to_string_lossy().to_string(),
+                size: entry.header().size()?,
+                mtime: entry.header().mtime().unwrap_or(0),
+                kind: kind_name(entry.header().entry_type()).to_string(),
+                offset,
+                skip: start - member,
+            };
+            serde_json::to_writer(&mut writer, &indexed)?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    fs::rename(&temp, &path)?;
+    Ok(count)
+}
+
+//the index next to an archive when there is one and it was written for this very file
+fn open_index(archive: &Path) -> Option<(IndexHeader, io::Lines<BufReader<File>>)> {
+    let mut lines = BufReader::new(File::open(sidecars::entry_index(archive)).ok()?).lines();
+    let header: IndexHeader = serde_json::from_str(&lines.next()?.ok()?).ok()?;
+    let usable = header.version == VERSION
+        && fs::metadata(archive).ok()?.len() == header.archive_size
+        && CompressionFormat::detect(archive).map(format_name) == Some(header.format.as_str());
+    usable.then_some((header, lines))
+}
+
+//every entry the index lists, None without a usable index
+pub fn entries(archive: &Path) -> Option<impl Iterator<Item = IndexEntry>> {
+    let (_, lines) = open_index(archive)?;
+    Some(lines.map_while(|line| serde_json::from_str(&line.ok()?).ok()))
+}
+
+//the content of one entry, read from where the index says it starts
+//None when there is no usable index or the entry isn't where it says, the caller reads the whole archive then
+pub fn read_entry(archive: &Path, name: &Path) -> Option<io::Result<Vec<u8>>> {
+    let (header, lines) = open_index(archive)?;
+    let wanted = name.to_string_lossy();
+    let mut found = None;
+    for line in lines {
+        let entry: IndexEntry = serde_json::from_str(&line.ok()?).ok()?;
+        if entry.path == wanted {
+            found = Some(entry);
+            break;
+        }
+    }
+    let Some(entry) = found else {
+        return Some(Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the archive", name.display()))));
+    };
+
+    let mut file = File::open(archive).ok()?;
+    file.seek(SeekFrom::Start(entry.offset)).ok()?;
+    let mut reader: Box<dyn Read> = match header.format.as_str() {
+        "gzip" => Box::new(MultiGzDecoder::new(BufReader::new(file))),
+        _ => Box::new(BufReader::new(file)),
+    };
+    io::copy(&mut (&mut reader).take(entry.skip), &mut io::sink()).ok()?;
+
+    let mut tar = Archive::new(reader);
+    let mut first = tar.entries().ok()?.next()?.ok()?;
+    if first.path().ok()?.as_ref() != name {
+        return None;
+    }
+    let mut content = Vec::new();
+    Some(first.read_to_end(&mut content).map(|_| content))
+}
+
+//a new backup at the same path without an index mustn't leave the last one's behind
+pub fn remove_stale(archive: &Path) {
+    let _ = fs::remove_file(sidecars::entry_index(archive));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{ArchiveCompression, ArchiveEncoder};
+    use tar::{Builder, Header};
+
+    fn write_archive(path: &Path, format: CompressionFormat) {
+        let mut builder = Builder::new(ArchiveEncoder::new(File::create(path).unwrap(), ArchiveCompression::new(format)).unwrap());
+        //over three gzip members of data that doesn't compress, then a long name that needs a header of its own
+        let mut state = 0x2545f4914f6cdd1du64;
+        let noise: Vec<u8> = (0..3_300_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        for (name, data) in [("a/noise.bin", noise.as_slice()), (&format!("a/{}/deep.txt", "long".repeat(40)) as &str, b"deep".as_slice()), ("a/last.txt", b"last".as_slice())] {
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(1_700_000_000);
+            builder.append_data(&mut header, name, data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn entries_are_read_from_their_gzip_member() {
+        let temp = tempfile::tempdir().unwrap();
+        for format in [CompressionFormat::Gzip, CompressionFormat::Store] {
+            let archive = temp.path().join(format!("backup{}", format.extension()));
+            write_archive(&archive, format);
+            assert_eq!(write_index(&archive).unwrap(), 3);
+
+            let index = fs::read_to_string(sidecars::entry_index(&archive)).unwrap();
+            let entries: Vec<IndexEntry> = index.lines().skip(1).map(|line| serde_json::from_str(line).unwrap()).collect();
+            assert_eq!(entries[0].kind, "file");
+            assert_eq!(entries[0].mtime, 1_700_000_000);
+            if format == CompressionFormat::Gzip {
+                //the entries after the noise start in a later member, not at the beginning of the file
+                assert!(entries[1].offset > 0 && entries[1].skip < 1024 * 1024);
+            }
+
+            let long = format!("a/{}/deep.txt", "long".repeat(40));
+            assert_eq!(read_entry(&archive, Path::new(&long)).unwrap().unwrap(), b"deep");
+            assert_eq!(read_entry(&archive, Path::new("a/last.txt")).unwrap().unwrap(), b"last");
+            assert_eq!(read_entry(&archive, Path::new("a/gone.txt")).unwrap().unwrap_err().kind(), io::ErrorKind::NotFound);
+
+            //an archive written over since is read in full again
+            fs::write(&archive, b"replaced").unwrap();
+            assert!(read_entry(&archive, Path::new("a/last.txt")).is_none());
+        }
+    }
+}
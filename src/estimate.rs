@@ -0,0 +1,258 @@
+use crate::compression::{ArchiveCompression, ArchiveEncoder};
+use crate::selector::{EntryKind, FileSelector};
+use crate::utils;
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+//at most this much is read from any one sampled file, so the sample spreads over many files
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+//a tar header block plus padding per file, after compression (headers are mostly zeros)
+const COMPRESSED_ENTRY_OVERHEAD: u64 = 128;
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct SourceEstimate {
+    pub source: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Estimate {
+    pub sources: Vec<SourceEstimate>,
+    pub files: u64,
+    pub bytes: u64,
+    pub format: String,
+    pub level: i32,
+    //bytes actually compressed to work out the ratios, and from how many files
+    pub sample_bytes: u64,
+    pub sample_files: u64,
+    pub estimated_compressed_bytes: u64,
+}
+
+//files of one extension, kept so a spread-out sample can be taken from them later
+#[derive(Default)]
+struct ExtensionGroup {
+    files: Vec<PathBuf>,
+    bytes: u64,
+}
+
+//counts what passes through without keeping any of it
+#[derive(Default)]
+struct CountingWriter {
+    written: u64,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+//scan the sources through the backup's own selector, compress a sample of about sample_budget bytes and extrapolate per extension
+pub fn estimate(sources: &[PathBuf], mut selector: FileSelector, compression: ArchiveCompression, sample_budget: u64) -> Result<Estimate, Box<dyn std::error::Error>> {
+    let progress = ProgressBar::new_spinner();
+    progress.set_style(ProgressStyle::default_spinner()
+        .template("{spinner:.green} {msg}")
+        .unwrap());
+
+    let mut groups: BTreeMap<String, ExtensionGroup> = BTreeMap::new();
+    let mut per_source = Vec::new();
+
+    for source in sources {
+        progress.set_message(format!("Scanning {}", source.display()));
+        let mut source_estimate = SourceEstimate { source: source.display().to_string(), ..SourceEstimate::default() };
+
+        for entry in selector.select(source) {
+            if entry.kind != EntryKind::File {
+                continue;
+            }
+
+            source_estimate.files += 1;
+            source_estimate.bytes += entry.size;
+
+            let group = groups.entry(extension_of(&entry.path)).or_default();
+            group.bytes += entry.size;
+            group.files.push(entry.path);
+        }
+
+        per_source.push(source_estimate);
+    }
+
+    let files: u64 = per_source.iter().map(|s| s.files).sum();
+    let bytes: u64 = per_source.iter().map(|s| s.bytes).sum();
+
+    //each extension gets a share of the sample proportional to its share of the data
+    let mut sample_bytes = 0;
+    let mut sample_files = 0;
+    let mut estimated = 0;
+    let mut unsampled_bytes = 0;
+
+    for (extension, group) in &groups {
+        let share = if bytes == 0 { 0 } else { (sample_budget as u128 * group.bytes as u128 / bytes as u128) as u64 };
+        if share == 0 || group.bytes == 0 {
+            unsampled_bytes += group.bytes;
+            continue;
+        }
+
+        progress.set_message(format!("Sampling .{} files", extension));
+        let (read, compressed, sampled) = compress_sample(group, share, compression)?;
+        if read == 0 {
+            unsampled_bytes += group.bytes;
+            continue;
+        }
+
+        sample_bytes += read;
+        sample_files += sampled;
+        estimated += (group.bytes as u128 * compressed as u128 / read as u128) as u64;
+    }
+
+    progress.finish_and_clear();
+
+    //extensions too small to get a share of the sample use the overall ratio
+    if sample_bytes > 0 {
+        estimated += (unsampled_bytes as u128 * estimated as u128 / (bytes - unsampled_bytes).max(1) as u128) as u64;
+    } else {
+        estimated += unsampled_bytes;
+    }
+    estimated += files * COMPRESSED_ENTRY_OVERHEAD;
+
+    Ok(Estimate {
+        sources: per_source,
+        files,
+        bytes,
+        format: format!("{:?}", compression.format).to_lowercase(),
+        level: compression.level,
+        sample_bytes,
+        sample_files,
+        estimated_compressed_bytes: estimated,
+    })
+}
+
+//compress up to `budget` bytes taken from files spread evenly over the list, returns (read, compressed, files)
+fn compress_sample(group: &ExtensionGroup, budget: u64, compression: ArchiveCompression) -> io::Result<(u64, u64, u64)> {
+    let files = &group.files;
+    let per_file = (group.bytes / files.len() as u64).clamp(1, CHUNK_SIZE);
+    let wanted = budget.div_ceil(per_file).max(1) as usize;
+    let step = (files.len() / wanted).max(1);
+
+    let mut encoder = ArchiveEncoder::new(CountingWriter::default(), compression)?;
+    let mut read = 0;
+    let mut sampled = 0;
+
+    for path in files.iter().step_by(step) {
+        if read >= budget {
+            break;
+        }
+
+        //unreadable files are simply left out of the sample
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let copied = match io::copy(&mut file.take(CHUNK_SIZE.min(budget - read)), &mut encoder) {
+            Ok(copied) => copied,
+            Err(_) => continue,
+        };
+
+        read += copied;
+        sampled += 1;
+    }
+
+    let compressed = encoder.finish()?.written;
+    Ok((read, compressed, sampled))
+}
+
+fn extension_of(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+pub fn print_estimate(estimate: &Estimate) {
+    println!("{}", "\n---- Backup Size Estimate ----".blue().bold());
+
+    for source in &estimate.sources {
+        println!("  {:<40} {:>10} files  {:>10}", source.source, source.files, utils::format_size(source.bytes));
+    }
+    println!("  {:<40} {:>10} files  {:>10}", "Total", estimate.files, utils::format_size(estimate.bytes));
+
+    println!(
+        "\nEstimated archive size ({} level {}): {}",
+        estimate.format,
+        estimate.level,
+        format!("~{}", utils::format_size(estimate.estimated_compressed_bytes)).green().bold()
+    );
+    println!(
+        "{}",
+        format!(
+            "This is an estimate, extrapolated from compressing {} sampled from {} files.",
+            utils::format_size(estimate.sample_bytes),
+            estimate.sample_files
+        ).yellow()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::CompressionFormat;
+    use crate::filter::FileFilter;
+    use std::fs;
+
+    #[test]
+    fn estimate_counts_sources_and_compresses_a_sample() {
+        let temp = tempfile::tempdir().unwrap();
+        let text = temp.path().join("text");
+        let data = temp.path().join("data");
+        fs::create_dir_all(&text).unwrap();
+        fs::create_dir_all(&data).unwrap();
+        for i in 0..4 {
+            fs::write(text.join(format!("{}.txt", i)), "the same line again\n".repeat(5000)).unwrap();
+        }
+        fs::write(data.join("a.bin"), vec![0u8; 50_000]).unwrap();
+
+        let sources = vec![text.clone(), data.clone()];
+        let estimate = estimate(&sources, FileSelector::new(&[], FileFilter::default()), ArchiveCompression::new(CompressionFormat::Gzip), 1024 * 1024).unwrap();
+
+        assert_eq!(estimate.sources[0], SourceEstimate { source: text.display().to_string(), files: 4, bytes: 400_000 });
+        assert_eq!(estimate.sources[1].files, 1);
+        assert_eq!(estimate.bytes, 450_000);
+        assert_eq!(estimate.sample_bytes, 450_000);
+        assert_eq!(estimate.sample_files, 5);
+        //repetitive data compresses to a small fraction of its size
+        assert!(estimate.estimated_compressed_bytes > 0);
+        assert!(estimate.estimated_compressed_bytes < 25_000);
+    }
+
+    #[test]
+    fn estimate_leaves_out_what_the_backup_would() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("src");
+        fs::create_dir_all(source.join("target")).unwrap();
+        fs::create_dir_all(source.join("private")).unwrap();
+        fs::write(source.join("kept.txt"), vec![b'a'; 1000]).unwrap();
+        fs::write(source.join("target/build.o"), vec![b'b'; 5000]).unwrap();
+        fs::write(source.join("private/.nobackup"), "").unwrap();
+        fs::write(source.join("private/secret.txt"), vec![b'c'; 7000]).unwrap();
+        fs::write(source.join("huge.iso"), vec![0u8; 20_000]).unwrap();
+
+        let filter = FileFilter::default().with_max_size(Some((10_000, "10K".to_string())));
+        let selector = FileSelector::new(&[PathBuf::from("target")], filter);
+        let estimate = estimate(&[source], selector, ArchiveCompression::new(CompressionFormat::Gzip), 1024 * 1024).unwrap();
+
+        //the empty marker is kept, like in the archive
+        assert_eq!(estimate.files, 2);
+        assert_eq!(estimate.bytes, 1000);
+    }
+}
@@ -0,0 +1,48 @@
+use crate::utils;
+use std::fs;
+
+//per-run filters applied to every file before it is archived
+#[derive(Default)]
+pub struct FileFilter {
+    //files last modified at or before this unix time are skipped
+    pub modified_after: Option<u64>,
+    //the user-facing form of the age limit, e.g. "90d"
+    pub max_age_label: Option<String>,
+    //files larger than this many bytes are skipped
+    pub max_size: Option<u64>,
+    //the user-facing form of the size limit, e.g. "2G"
+    pub max_size_label: Option<String>,
+}
+
+impl FileFilter {
+    pub fn new(max_age: Option<(u64, String)>, now: u64) -> Self {
+        match max_age {
+            Some((seconds, label)) => FileFilter {
+                modified_after: Some(now.saturating_sub(seconds)),
+                max_age_label: Some(label),
+                ..FileFilter::default()
+            },
+            None => FileFilter::default(),
+        }
+    }
+
+    pub fn with_max_size(mut self, max_size: Option<(u64, String)>) -> Self {
+        if let Some((bytes, label)) = max_size {
+            self.max_size = Some(bytes);
+            self.max_size_label = Some(label);
+        }
+        self
+    }
+
+    //regular files only, a directory's or a symlink's own size says nothing about what it holds
+    pub fn too_large(&self, metadata: &fs::Metadata) -> bool {
+        self.max_size.is_some_and(|max| metadata.len() > max)
+    }
+
+    pub fn allows(&self, metadata: &fs::Metadata) -> bool {
+        match self.modified_after {
+            Some(cutoff) => utils::modified_after(metadata, cutoff),
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::paths;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+use std::thread;
+
+//set from the signal thread and read by every prompt
+static HUNG_UP: AtomicBool = AtomicBool::new(false);
+static REGISTER: Once = Once::new();
+
+//in the state directory, appended to by every run that lost its terminal
+pub const LOG_FILE: &str = "hangup.log";
+
+pub fn log_path() -> PathBuf {
+    paths::state_dir().join(LOG_FILE)
+}
+
+//keeps a backup going when the terminal it was started from closes, SIGHUP would end it halfway through otherwise
+//a closed terminal fails every write, so from then on stdout and stderr go to the log
+pub fn survive() {
+    REGISTER.call_once(|| match Signals::new([SIGHUP]) {
+        //a thread of its own rather than the handler, which may only make system calls and couldn't tell the time
+        //it keeps the signals for the rest of the process, a later SIGHUP would end the run otherwise
+        Ok(mut signals) => {
+            thread::spawn(move || {
+                for _ in signals.forever() {
+                    hang_up();
+                }
+            });
+        }
+        Err(e) => println!("Warning: The backup can't outlive its terminal, SIGHUP can't be caught: {}", e),
+    });
+}
+
+//the first hangup moves the output to the log, behind a notice saying when it happened
+fn hang_up() {
+    if HUNG_UP.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let log = log_path();
+    if let Some(dir) = log.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let Ok(mut file) = OpenOptions::new().create(true).append(true).mode(0o600).open(&log) else {
+        return;
+    };
+    let notice = format!(
+        "\n---- {} linux_backup (process {}) ----\nThe terminal hung up, the run goes on in the background and writes its output here.\nA question that needs an answer from now on ends the run.\n",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        std::process::id()
+    );
+    let _ = file.write_all(notice.as_bytes());
+    unsafe {
+        libc::dup2(file.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO);
+    }
+}
+
+pub fn hung_up() -> bool {
+    HUNG_UP.load(Ordering::SeqCst)
+}
+
+//the log says how a run that lost its terminal ended, the error of one that failed is written there by main
+pub fn report_finished() {
+    if hung_up() {
+        println!("Finished in the background, the terminal had hung up.");
+    }
+}
+
+//prompts ask this first, the terminal that would answer them is gone
+//the run stops with the error and leaves what it finished where it is, unfinished archives stay .partial and the metadata untouched
+pub fn answerable() -> io::Result<()> {
+    if hung_up() {
+        return Err(io::Error::other("the terminal hung up and nobody is left to answer, stopping the run here"));
+    }
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use colored::*;
+use glob::{MatchOptions, Pattern};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//a file listing what to leave out below the directory it is in, in a subset of the .gitignore syntax
+pub const IGNORE_FILE: &str = ".backupignore";
+
+//a * doesn't cross a /, ** does, and dot files match like any other name
+const OPTIONS: MatchOptions = MatchOptions { case_sensitive: true, require_literal_separator: true, require_literal_leading_dot: false };
+
+struct Rule {
+    pattern: Pattern,
+    //a leading ! takes an entry back in that an earlier rule left out
+    negated: bool,
+    //a trailing / only matches directories
+    dir_only: bool,
+    //a / at the start or in the middle ties the pattern to the ignore file's directory
+    //without one it matches names at any depth below it
+    anchored: bool,
+}
+
+pub struct IgnoreFile {
+    pub path: PathBuf,
+    dir: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl IgnoreFile {
+    //blank lines and # comments are skipped, patterns that don't compile are warned about and skipped
+    pub fn parse(dir: &Path, contents: &str) -> Self {
+        let path = dir.join(IGNORE_FILE);
+        let mut rules = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let anchored = line.contains('/');
+            let line = line.strip_prefix('/').unwrap_or(line);
+
+            match Pattern::new(line) {
+                Ok(pattern) => rules.push(Rule { pattern, negated, dir_only, anchored }),
+                Err(e) => println!("{}", format!("Warning: {} line {}: {}, the line is ignored", path.display(), number + 1, e.msg).yellow()),
+            }
+        }
+
+        IgnoreFile { path, dir: dir.to_path_buf(), rules }
+    }
+
+    //the directory's ignore file, if it has a readable one
+    pub fn load(dir: &Path) -> Option<Self> {
+        let path = dir.join(IGNORE_FILE);
+        if !path.symlink_metadata().is_ok_and(|metadata| metadata.is_file()) {
+            return None;
+        }
+        match fs::read_to_string(&path) {
+            Ok(contents) => Some(IgnoreFile::parse(dir, &contents)),
+            Err(e) => {
+                println!("{}", format!("Warning: Could not read {}: {}, nothing is left out for it", path.display(), e).yellow());
+                None
+            }
+        }
+    }
+
+    //the last matching rule decides, Some(true) leaves the entry out and Some(false) takes it back
+    fn decide(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.dir).ok()?;
+        //names that aren't valid UTF-8 only ever meet a wildcard, the literal parts of a pattern are UTF-8
+        let relative = relative.to_string_lossy();
+        let name = path.file_name()?.to_string_lossy();
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                (is_dir || !rule.dir_only) && if rule.anchored { rule.pattern.matches_with(&relative, OPTIONS) } else { rule.pattern.matches_with(&name, OPTIONS) }
+            })
+            .map(|rule| !rule.negated)
+    }
+}
+
+//the ignore files of the directories the walk is in, outermost first
+#[derive(Default)]
+pub struct IgnoreStack {
+    files: Vec<IgnoreFile>,
+}
+
+impl IgnoreStack {
+    //forget the ignore files of directories the walk has left
+    pub fn leave(&mut self, path: &Path) {
+        self.files.retain(|file| path.starts_with(&file.dir));
+    }
+
+    pub fn enter(&mut self, dir: &Path) {
+        self.files.extend(IgnoreFile::load(dir));
+    }
+
+    //the ignore file that leaves `path` out, a nested file overrides the ones above it like in git
+    pub fn ignoring(&self, path: &Path, is_dir: bool) -> Option<&Path> {
+        self.files
+            .iter()
+            .rev()
+            .find_map(|file| file.decide(path, is_dir).map(|ignored| (file, ignored)))
+            .filter(|(_, ignored)| *ignored)
+            .map(|(file, _)| file.path.as_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_follow_the_gitignore_rules() {
+        let file = IgnoreFile::parse(
+            Path::new("/home/dave/projects"),
+            "# build output\n\ntarget/\n*.iso   \nnode_modules/\n/notes.txt\ndocs/*.pdf\n!keep.iso\nlogs/***\n",
+        );
+        assert_eq!(file.rules.len(), 6);
+
+        let cases = [
+            //(path, is_dir, decision)
+            ("/home/dave/projects/app/target", true, Some(true)),
+            ("/home/dave/projects/app/target", false, None),
+            ("/home/dave/projects/disk.iso", false, Some(true)),
+            ("/home/dave/projects/a/b/c/disk.iso", false, Some(true)),
+            ("/home/dave/projects/keep.iso", false, Some(false)),
+            ("/home/dave/projects/web/node_modules", true, Some(true)),
+            ("/home/dave/projects/notes.txt", false, Some(true)),
+            ("/home/dave/projects/app/notes.txt", false, None),
+            ("/home/dave/projects/docs/manual.pdf", false, Some(true)),
+            ("/home/dave/projects/docs/old/manual.pdf", false, None),
+            ("/home/dave/projects/app/main.rs", false, None),
+            ("/home/dave/other/disk.iso", false, None),
+        ];
+        for (path, is_dir, decision) in cases {
+            assert_eq!(file.decide(Path::new(path), is_dir), decision, "{}", path);
+        }
+    }
+
+    #[test]
+    fn nested_files_override_outer_ones_and_are_forgotten_outside_their_directory() {
+        let mut stack = IgnoreStack::default();
+        stack.files.push(IgnoreFile::parse(Path::new("/p"), "*.log\n"));
+        stack.files.push(IgnoreFile::parse(Path::new("/p/app"), "!debug.log\nbuild\n"));
+
+        assert_eq!(stack.ignoring(Path::new("/p/app/debug.log"), false), None);
+        assert_eq!(stack.ignoring(Path::new("/p/app/error.log"), false), Some(Path::new("/p/.backupignore")));
+        assert_eq!(stack.ignoring(Path::new("/p/app/build"), true), Some(Path::new("/p/app/.backupignore")));
+
+        stack.leave(Path::new("/p/web"));
+        assert_eq!(stack.files.len(), 1);
+        assert_eq!(stack.ignoring(Path::new("/p/web/debug.log"), false), Some(Path::new("/p/.backupignore")));
+        assert_eq!(stack.ignoring(Path::new("/p/web/build"), true), None);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::config::Config;
+use crate::utils::{self, BackupType};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+//held next to the chain's metadata while a backup of that chain runs
+pub const LOCK_FILE: &str = "backup.lock";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+//how often a queued run says it is still waiting
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(60);
+
+//who holds the lock, written into the lock file so a queued run can say what it waits for
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LockInfo {
+    //the state directory of the chain, every chain has its own
+    pub chain: String,
+    pub operation: String,
+    pub pid: u32,
+    pub started: u64,
+}
+
+impl LockInfo {
+    //"pid 1234, full backup, started 02:00"
+    fn describe(&self) -> String {
+        let started = chrono::DateTime::from_timestamp(self.started as i64, 0)
+            .map(|time| time.with_timezone(&chrono::Local).format("%H:%M").to_string())
+            .unwrap_or_else(|| "at an unknown time".to_string());
+        format!("pid {}, {}, started {}", self.pid, self.operation, started)
+    }
+}
+
+//an flock, so the kernel releases it when a run dies without cleaning up
+pub struct BackupLock {
+    file: File,
+    //the run this one queued behind, None if the lock was free
+    pub waited_for: Option<LockInfo>,
+}
+
+impl BackupLock {
+    //the run waited for completed and recorded itself in the chain's metadata, so it covers what this
+    //incremental or differential would have taken, anything changed since goes into the next run
+    pub fn superseded(&self, backup_type: &BackupType, last_backup_time: Option<u64>) -> bool {
+        match (&self.waited_for, last_backup_time) {
+            (Some(holder), Some(last)) => !matches!(backup_type, BackupType::Full) && last >= holder.started,
+            _ => false,
+        }
+    }
+}
+
+impl Drop for BackupLock {
+    fn drop(&mut self) {
+        //the next holder rewrites it, an empty file just means nobody is running
+        let _ = self.file.set_len(0);
+    }
+}
+
+pub fn operation(backup_type: &BackupType) -> &'static str {
+    match backup_type {
+        BackupType::Full => "full backup",
+        BackupType::Incremental => "incremental backup",
+        BackupType::Differential => "differential backup",
+    }
+}
+
+//--wait-for-lock wins over wait_for_lock in the config, neither means fail right away
+pub fn configured_wait(flag: Option<&str>, config: &Config) -> Result<Option<Duration>, String> {
+    flag.or(config.wait_for_lock.as_deref())
+        .map(|text| utils::parse_duration(text).map(Duration::from_secs))
+        .transpose()
+}
+
+//take the chain's lock, queueing behind a running backup for up to `wait`
+pub fn acquire(state_dir: &Path, operation: &str, wait: Option<Duration>) -> Result<BackupLock, Box<dyn std::error::Error>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(state_dir.join(LOCK_FILE))?;
+
+    let mut waited_for = None;
+    if !try_lock(&file)? {
+        waited_for = read_info(&mut file);
+        let holder = waited_for.as_ref().map(LockInfo::describe).unwrap_or_else(|| "holder unknown".to_string());
+        let timeout = match wait {
+            Some(timeout) => timeout,
+            None => return Err(format!("Another backup of this chain is running ({}), use --wait-for-lock to queue behind it", holder).into()),
+        };
+
+        println!("{}", format!("Waiting for running backup ({})", holder).yellow());
+        let start = Instant::now();
+        let mut last_progress = start;
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            if try_lock(&file)? {
+                break;
+            }
+            if start.elapsed() >= timeout {
+                return Err(format!("Gave up after waiting {} minutes for the running backup ({})", start.elapsed().as_secs() / 60, holder).into());
+            }
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                println!("Still waiting for the running backup, {} minutes so far", start.elapsed().as_secs() / 60);
+                last_progress = Instant::now();
+            }
+        }
+        println!("The running backup finished, continuing.");
+    }
+
+    let info = LockInfo {
+        chain: state_dir.display().to_string(),
+        operation: operation.to_string(),
+        pid: std::process::id(),
+        started: now(),
+    };
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(serde_json::to_string(&info)?.as_bytes())?;
+    Ok(BackupLock { file, waited_for })
+}
+
+fn try_lock(file: &File) -> io::Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+fn read_info(file: &mut File) -> Option<LockInfo> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_second_run_fails_or_times_out_while_the_lock_is_held() {
+        let temp = tempfile::tempdir().unwrap();
+        let held = acquire(temp.path(), "full backup", None).unwrap();
+        assert!(held.waited_for.is_none());
+
+        let info: LockInfo = serde_json::from_str(&std::fs::read_to_string(temp.path().join(LOCK_FILE)).unwrap()).unwrap();
+        assert_eq!((info.operation.as_str(), info.pid), ("full backup", std::process::id()));
+
+        let error = acquire(temp.path(), "incremental backup", None).err().unwrap().to_string();
+        assert!(error.contains("full backup") && error.contains(&format!("pid {}", std::process::id())));
+        assert!(acquire(temp.path(), "incremental backup", Some(Duration::ZERO)).is_err());
+
+        drop(held);
+        let lock = acquire(temp.path(), "incremental backup", None).unwrap();
+
+        //queued behind a full backup that went on to record itself
+        let started = info.started;
+        let queued = BackupLock { file: lock.file.try_clone().unwrap(), waited_for: Some(info) };
+        assert!(queued.superseded(&BackupType::Incremental, Some(started + 1)));
+        assert!(!queued.superseded(&BackupType::Full, Some(u64::MAX)));
+        assert!(!queued.superseded(&BackupType::Incremental, Some(started - 1)));
+    }
+}
@@ -1,47 +1,189 @@
+mod acl;
+mod appender;
 mod backup;
+mod casefold;
+mod catalog;
+mod changes;
+mod check;
+mod checksums;
+mod cipher;
+mod cli;
+mod compressibility;
+mod compression;
+mod config;
+mod conflicts;
+mod diff;
+mod disks;
+mod emitted;
+mod encryption;
+mod entry_index;
+mod estimate;
+mod filter;
+mod hangup;
+mod ignore;
+mod lock;
+mod manifest;
+mod mounts;
+mod parallel_gzip;
+mod paths;
+mod pause;
+mod portability;
+mod presets;
+mod prompt;
+mod repack;
 mod restore;
+mod restore_script;
+mod selector;
+mod selftest;
+mod services;
+mod settings;
+mod sidecars;
+mod sqlite;
+mod space;
+mod status;
+mod sysinfo;
+mod throttle;
+mod users;
+mod userns;
 mod utils;
+mod volumes;
+mod xattrs;
 
-use backup::{backup_selected_directories, backup_system};
+use backup::{backup_selected_directories, backup_system, run_profile};
+use clap::Parser;
+use cli::Cli;
 use colored::*;
-use dialoguer::{theme::ColorfulTheme, Input, Select};
-use restore::restore_backup;
+use conflicts::ConflictPolicy;
+use prompt::{Confirm, Input, Select};
+use restore::{choose_archive, restore_backup, show_archive_info, DeletionPolicy, RestoreOptions};
+use settings::settings_menu;
+use std::path::{Path, PathBuf};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    prompt::init();
+
+    //a subcommand or a profile runs without prompts, otherwise fall back to the menu
+    let cli = Cli::parse();
+    if let Some(command) = cli.command {
+        return cli::run(command);
+    }
+    if let Some(profile) = cli.profile {
+        return run_profile(&profile).map(|_| ());
+    }
+
     println!("{}", "\n===== LINUX BACKUP TOOL =====\n".green().bold());
     println!("A utility for backing up your Linux system");
     println!("{}", "--------------------------------\n".green());
 
+    //what earlier actions in this session left behind, offered as defaults by the later ones
+    let mut session = Session::default();
+
     //main menu loop
     loop {
-        let options = vec!["Backup Selected Directories", "Backup System", "Restore Backup", "Exit"];
+        if let Some(line) = config::load_config().ok().and_then(|config| status::menu_line(&config)) {
+            println!("{}\n", line);
+        }
+        //Run Profile only shows up once config.toml has profiles, What Changed once there is a backup to compare with
+        let profiles: Vec<String> = config::load_config().map(|config| config.profiles.into_keys().collect()).unwrap_or_default();
+        let mut options = vec!["Backup Selected Directories", "Backup System"];
+        if !profiles.is_empty() {
+            options.push("Run Profile");
+        }
+        if utils::load_metadata_store(&paths::state_dir()).is_ok_and(|store| store.last_backup_time().is_some()) {
+            options.push("What Changed");
+        }
+        if catalog::load_catalog(&paths::state_dir()).is_ok_and(|entries| !entries.is_empty()) {
+            options.push("View Backup History");
+        }
+        options.extend(["Restore Backup", "Show Archive Info", "Verify Backup", "Settings", "Exit"]);
         
-        let selection = Select::with_theme(&ColorfulTheme::default())
+        let selection = Select::new()
             .with_prompt("What would you like to do?")
             .default(0)
             .items(&options)
             .interact()?;
             
-        match selection {
-            0 => backup_selected_directories()?,
-            1 => backup_system()?,
-            2 => {
-                //get backup file path
-                let default_path = std::env::current_dir()?;
-                let backup_file: String = Input::with_theme(&ColorfulTheme::default())
-                    .with_prompt("Enter path to backup file")
-                    .default(default_path.to_string_lossy().to_string())
-                    .interact_text()?;
-                
-                //get restore destination
-                let restore_path: String = Input::with_theme(&ColorfulTheme::default())
-                    .with_prompt("Enter restore destination")
-                    .default(".".to_string())
-                    .interact_text()?;
-                    
-                restore_backup(&backup_file, &restore_path)?;
+        match options[selection] {
+            "Backup Selected Directories" => session.backed_up(backup_selected_directories()?),
+            "Backup System" => session.backed_up(backup_system()?),
+            "Run Profile" => {
+                //the profile run last in this session is preselected
+                let last = session.last_profile.as_ref().and_then(|last| profiles.iter().position(|profile| profile == last));
+                let profile = Select::new()
+                    .with_prompt("Which profile?")
+                    .default(last.unwrap_or(0))
+                    .items(&profiles)
+                    .interact()?;
+                session.last_profile = Some(profiles[profile].clone());
+                session.backed_up(run_profile(&profiles[profile])?);
+            },
+            "What Changed" => session.backed_up(changes::changes_menu()?),
+            "View Backup History" => match &session.last_profile {
+                Some(profile) if Confirm::new()
+                    .with_prompt(format!("Show history for profile '{}'?", profile))
+                    .default(true)
+                    .interact()? => catalog::show_profile_catalog(profile)?,
+                _ => catalog::show_catalogs()?,
+            },
+            "Restore Backup" => {
+                if let Some(backup_file) = session.prompt_archive("restore")? {
+                    //get restore destination, the last one of this session first
+                    let restore_path: String = Input::new()
+                        .with_prompt("Enter restore destination")
+                        .default(session.last_target.clone().unwrap_or_else(|| ".".to_string()))
+                        .interact_text()?;
+
+                    //without root the archived owners can't be set, but the exact modes still can
+                    let preserve_owner = Confirm::new()
+                        .with_prompt("Preserve ownership and permissions from the archive?")
+                        .default(unsafe { libc::geteuid() == 0 })
+                        .interact()?;
+                    let throttling = throttle::prompt_throttling()?;
+
+                    //only asks when files in the archive exist at the destination already
+                    let result = restore_backup(&backup_file, &restore_path, &RestoreOptions {
+                        conflicts: ConflictPolicy::Ask,
+                        deletions: DeletionPolicy::Ask,
+                        preserve_owner,
+                        throttling,
+                        ..RestoreOptions::default()
+                    });
+                    //a failed restore is reported and the session goes on, what was restored can still be verified
+                    if let Err(e) = result {
+                        println!("{}", e.to_string().red());
+                    }
+                    session.last_target = Some(restore_path);
+                }
+            },
+            "Show Archive Info" => {
+                if let Some(backup_file) = session.prompt_archive("inspect")? {
+                    show_archive_info(&backup_file, &RestoreOptions::default(), false)?;
+                }
             },
-            3 => {
+            "Verify Backup" => {
+                if let Some(backup_file) = session.prompt_archive("verify")? {
+                    //the restore of this session is offered, an empty answer checks the archive itself
+                    let target: String = Input::new()
+                        .with_prompt("Directory the backup was restored to (leave empty to check the archive itself)")
+                        .default(session.last_target.clone().unwrap_or_default())
+                        .allow_empty(true)
+                        .interact_text()?;
+                    let result = if target.trim().is_empty() {
+                        checksums::verify_archive(&backup_file, &RestoreOptions::default())
+                    } else {
+                        checksums::recorded_sums(&backup_file, &RestoreOptions::default())
+                            .map(|sums| sums.map_or(0, |sums| checksums::verify_restored(&sums, Path::new(target.trim()))))
+                    };
+                    //an archive without checksums is a message here, not the end of the session
+                    match result {
+                        Ok(0) => {}
+                        Ok(failed) => println!("{}", format!("Files that failed verification: {}", failed).red().bold()),
+                        Err(e) => println!("{}", e.to_string().red()),
+                    }
+                }
+            },
+            "Settings" => settings_menu()?,
+            "Exit" => {
                 println!("Exiting...");
                 break;
             },
@@ -49,14 +191,59 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         
         println!("\nPress Enter to continue...");
-        let _: String = Input::with_theme(&ColorfulTheme::default())
+        let _: String = Input::new()
             .with_prompt(" ")
             .allow_empty(true)
             .interact_text()?;
             
-        //clear the screen
-        print!("\x1B[2J\x1B[1;1H");
+        prompt::clear_screen();
     }
 
     Ok(())
 }
+
+//state carried from one menu action to the next, forgotten on exit
+#[derive(Default)]
+struct Session {
+    //the archive the last backup of this session wrote
+    last_backup: Option<PathBuf>,
+    //where the last restore of this session went
+    last_target: Option<String>,
+    //the profile run last in this session
+    last_profile: Option<String>,
+}
+
+impl Session {
+    fn backed_up(&mut self, archive: Option<PathBuf>) {
+        if archive.is_some() {
+            self.last_backup = archive;
+        }
+    }
+
+    //the archive to work on, the one just created is offered first
+    fn prompt_archive(&self, action: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if let Some(last) = self.last_backup.as_ref().filter(|last| last.exists()) {
+            let name = last.file_name().unwrap_or(last.as_os_str()).to_string_lossy();
+            if Confirm::new()
+                .with_prompt(format!("Use the last backup ({})?", name))
+                .default(true)
+                .interact()?
+            {
+                return Ok(Some(last.display().to_string()));
+            }
+        }
+
+        //get backup file path, a directory means picking one of the archives inside it
+        let default_path = std::env::current_dir()?;
+        let backup_file: String = Input::new()
+            .with_prompt(format!("Enter path to backup file to {}", action))
+            .default(default_path.to_string_lossy().to_string())
+            .interact_text()?;
+
+        if Path::new(&backup_file).is_dir() {
+            choose_archive(Path::new(&backup_file))
+        } else {
+            Ok(Some(backup_file))
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use crate::checksums::{self, SUMS_FILE};
+use crate::compression;
+use crate::encryption::ArchiveKey;
+use crate::entry_index;
+use crate::status;
+use crate::utils::{self, BackupType};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tar::{Archive, Builder, Header};
+
+//the last entry of every backup, an archive without it was not finished
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+//keeps the listings of several archives in one process apart
+static LISTINGS: AtomicUsize = AtomicUsize::new(0);
+
+//what a backup holds and how it was made, readable without extracting anything
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub tool_version: String,
+    pub backup_type: BackupType,
+    //when the backup started, unset in reproducible archives
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    //the backup an incremental or differential one took the changes since
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changes_since: Option<u64>,
+    pub sources: Vec<String>,
+    pub exclusions: Vec<String>,
+    pub file_count: u64,
+    pub total_size: u64,
+}
+
+//one archived file, symlink or special file, names that aren't valid UTF-8 show with replacement characters
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestFile {
+    pub path: String,
+    pub size: u64,
+    //set when reading the file failed partway, the rest of its entry is zeros
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partial: Option<PartialFile>,
+}
+
+//how far a partially read file got
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PartialFile {
+    pub read: u64,
+    pub error_offset: u64,
+}
+
+//the files part of the manifest, read on its own since it can be long
+#[derive(Deserialize)]
+struct ManifestFiles {
+    files: Vec<ManifestFile>,
+}
+
+//archived names and sizes, and the checksums of the files, written to temporary files as the walk goes so a system backup doesn't hold them in memory
+pub struct FileListing {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    sums_path: PathBuf,
+    sums: BufWriter<File>,
+    files: u64,
+    bytes: u64,
+}
+
+impl FileListing {
+    pub fn new() -> io::Result<Self> {
+        let base = std::env::temp_dir().join(format!("linux_backup-{}-{}", std::process::id(), LISTINGS.fetch_add(1, Ordering::Relaxed)));
+        let path = PathBuf::from(format!("{}-listing.json", base.display()));
+        let writer = BufWriter::new(File::options().read(true).write(true).create(true).truncate(true).open(&path)?);
+        let sums_path = PathBuf::from(format!("{}-sums.txt", base.display()));
+        let sums = BufWriter::new(File::options().read(true).write(true).create(true).truncate(true).open(&sums_path)?);
+        Ok(FileListing { path, writer, sums_path, sums, files: 0, bytes: 0 })
+    }
+
+    //symlinks, special files and files that were never read have no checksum
+    //neither do partially read ones, the hash of their zero-padded entry would only ever match itself
+    pub fn push(&mut self, name: &Path, size: u64, sha256: Option<&str>, partial: Option<PartialFile>) -> io::Result<()> {
+        if self.files > 0 {
+            self.writer.write_all(b",")?;
+        }
+        let complete = partial.is_none();
+        serde_json::to_writer(&mut self.writer, &ManifestFile { path: name.to_string_lossy().to_string(), size, partial })?;
+        if let Some(sha256) = sha256.filter(|_| complete) {
+            self.sums.write_all(&checksums::sums_line(sha256, name))?;
+        }
+        self.files += 1;
+        self.bytes += size;
+        Ok(())
+    }
+
+    //the checksums, then the manifest's fields with the listing streamed in as its files
+    pub fn append_to_archive<W: Write>(&mut self, archive: &mut Builder<W>, manifest: Manifest, mtime: u64) -> io::Result<()> {
+        self.sums.flush()?;
+        let sums = self.sums.get_mut();
+        let length = sums.stream_position()?;
+        sums.rewind()?;
+        let mut header = Header::new_gnu();
+        header.set_size(length);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        archive.append_data(&mut header, Path::new(utils::STATE_DIR).join(SUMS_FILE), BufReader::new(sums.take(length)))?;
+
+        let manifest = Manifest { file_count: self.files, total_size: self.bytes, ..manifest };
+        let fields = serde_json::to_string(&manifest)?;
+        let head = format!("{},\"files\":[", &fields[..fields.len() - 1]);
+        let tail = "]}";
+
+        self.writer.flush()?;
+        let listing = self.writer.get_mut();
+        let length = listing.stream_position()?;
+        listing.rewind()?;
+
+        let mut header = Header::new_gnu();
+        header.set_size(head.len() as u64 + length + tail.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_cksum();
+
+        let data = head.as_bytes().chain(BufReader::new(listing.take(length))).chain(tail.as_bytes());
+        archive.append_data(&mut header, Path::new(utils::STATE_DIR).join(MANIFEST_FILE), data)
+    }
+
+    //the same checksums next to the finished archive, for sha256sum -c in a restore target
+    pub fn save_sums(&mut self, path: &Path) -> io::Result<()> {
+        self.sums.flush()?;
+        fs::copy(&self.sums_path, path).map(|_| ())
+    }
+}
+
+impl Drop for FileListing {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(&self.sums_path);
+    }
+}
+
+//every tar header is read on the way, the manifest is the last entry, unless the archive has an entry index
+//None for archives without one, older ones or ones that weren't finished
+pub fn read_entry(archive_path: &Path, key: Option<&ArchiveKey>) -> Option<Vec<u8>> {
+    if key.is_none() {
+        if let Some(content) = entry_index::read_entry(archive_path, &Path::new(utils::STATE_DIR).join(MANIFEST_FILE)) {
+            return content.ok();
+        }
+    }
+    let mut archive = Archive::new(compression::open_decoder(archive_path, key).ok()?);
+    for entry in archive.entries().ok()? {
+        let mut entry = entry.ok()?;
+        if entry.path().ok()?.as_ref() == Path::new(utils::STATE_DIR).join(MANIFEST_FILE) {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).ok()?;
+            return Some(data);
+        }
+    }
+    None
+}
+
+//the files are skipped over, a summary doesn't need them
+pub fn parse(data: &[u8]) -> Option<Manifest> {
+    serde_json::from_slice(data).ok()
+}
+
+pub fn parse_files(data: &[u8]) -> Option<Vec<ManifestFile>> {
+    serde_json::from_slice::<ManifestFiles>(data).ok().map(|listing| listing.files)
+}
+
+//the files the backup could only read partway, empty for older manifests
+pub fn partial_files(data: &[u8]) -> Vec<ManifestFile> {
+    parse_files(data).unwrap_or_default().into_iter().filter(|file| file.partial.is_some()).collect()
+}
+
+//info, verify and restore all point these out, their content past the error isn't the original
+pub fn warn_partial(files: &[ManifestFile]) {
+    if files.is_empty() {
+        return;
+    }
+    println!("{}", format!("Warning: {} files were only partially read when the backup was made, their missing bytes are zeros and they have no checksum:", files.len()).yellow());
+    for file in files {
+        if let Some(partial) = &file.partial {
+            println!("  {}: {} of {} bytes, I/O error at offset {}", file.path, partial.read, file.size, partial.error_offset);
+        }
+    }
+}
+
+impl Manifest {
+    //"incremental backup made 2026-03-01 02:00, 120 files (4.2 MB)"
+    pub fn describe(&self) -> String {
+        let kind = match self.backup_type {
+            BackupType::Full => "full",
+            BackupType::Incremental => "incremental",
+            BackupType::Differential => "differential",
+        };
+        let made = self.created.map(|time| format!(" made {}", status::local_time(time))).unwrap_or_default();
+        format!("{} backup{}, {} files ({})", kind, made, self.file_count, utils::format_size(self.total_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_listing_is_streamed_into_the_manifest() {
+        let mut listing = FileListing::new().unwrap();
+        listing.push(Path::new("home/dave/notes.txt"), 120, Some(&"b".repeat(64)), None).unwrap();
+        listing.push(Path::new("home/dave/link"), 0, None, None).unwrap();
+        let partial = PartialFile { read: 4096, error_offset: 4096 };
+        listing.push(Path::new("home/dave/disk.img"), 8192, Some(&"c".repeat(64)), Some(partial.clone())).unwrap();
+        let (path, sums_path) = (listing.path.clone(), listing.sums_path.clone());
+
+        let manifest = Manifest {
+            tool_version: "1.0".to_string(),
+            backup_type: BackupType::Incremental,
+            created: Some(200),
+            changes_since: Some(100),
+            sources: vec!["/home/dave".to_string()],
+            exclusions: vec!["*.tmp".to_string()],
+            file_count: 0,
+            total_size: 0,
+        };
+        let mut archive = Builder::new(Vec::new());
+        listing.append_to_archive(&mut archive, manifest.clone(), 0).unwrap();
+        drop(listing);
+        assert!(!path.exists() && !sums_path.exists());
+
+        let data = archive.into_inner().unwrap();
+        let mut reader = Archive::new(data.as_slice());
+        let mut entries = reader.entries().unwrap();
+        let mut sums = Vec::new();
+        entries.next().unwrap().unwrap().read_to_end(&mut sums).unwrap();
+        assert_eq!(checksums::parse_sums(&sums), vec![(PathBuf::from("home/dave/notes.txt"), "b".repeat(64))]);
+        let mut json = Vec::new();
+        entries.next().unwrap().unwrap().read_to_end(&mut json).unwrap();
+
+        assert_eq!(parse(&json), Some(Manifest { file_count: 3, total_size: 8312, ..manifest }));
+        assert_eq!(
+            parse_files(&json).unwrap(),
+            vec![
+                ManifestFile { path: "home/dave/notes.txt".to_string(), size: 120, partial: None },
+                ManifestFile { path: "home/dave/link".to_string(), size: 0, partial: None },
+                ManifestFile { path: "home/dave/disk.img".to_string(), size: 8192, partial: Some(partial.clone()) },
+            ]
+        );
+        assert_eq!(partial_files(&json).len(), 1);
+    }
+}
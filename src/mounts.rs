@@ -0,0 +1,161 @@
+use crate::space;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//mount options that change what a restore can reproduce, the rest are left out of the descriptor
+const RELEVANT_OPTIONS: &[&str] = &["ro", "noatime", "relatime", "acl", "noacl", "user_xattr", "nouser_xattr"];
+
+//filesystems without extended attributes or ACLs at all
+const NO_XATTR_FILESYSTEMS: &[&str] = &["vfat", "msdos", "exfat", "fuseblk", "iso9660", "udf"];
+
+//filesystems where names differing only in case are the same file
+const CASE_INSENSITIVE_FILESYSTEMS: &[&str] = &["vfat", "msdos", "exfat"];
+
+//the filesystem a source directory lived on, restores explain lost ACLs or case collisions with it
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Filesystem {
+    pub path: String,
+    pub fs_type: String,
+    pub mount_point: String,
+    //block device or remote share, with the kernel's major:minor
+    pub device: String,
+    pub options: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub free_bytes: Option<u64>,
+}
+
+impl Filesystem {
+    pub fn supports_xattrs(&self) -> bool {
+        !NO_XATTR_FILESYSTEMS.contains(&self.fs_type.as_str()) && !self.options.iter().any(|o| o == "nouser_xattr")
+    }
+
+    pub fn supports_acls(&self) -> bool {
+        !NO_XATTR_FILESYSTEMS.contains(&self.fs_type.as_str()) && !self.options.iter().any(|o| o == "noacl")
+    }
+
+    pub fn case_insensitive(&self) -> bool {
+        CASE_INSENSITIVE_FILESYSTEMS.contains(&self.fs_type.as_str())
+    }
+
+    //"ext4 on /dev/sda2 (8:2) at /home, noatime, 120.5 GB free"
+    pub fn describe(&self) -> String {
+        let mut description = format!("{} on {} at {}", self.fs_type, self.device, self.mount_point);
+        if !self.options.is_empty() {
+            description.push_str(&format!(", {}", self.options.join(",")));
+        }
+        if let Some(free) = self.free_bytes {
+            description.push_str(&format!(", {} free", utils::format_size(free)));
+        }
+        description
+    }
+}
+
+//one line of /proc/self/mountinfo
+struct Mount {
+    device_number: String,
+    mount_point: PathBuf,
+    fs_type: String,
+    source: String,
+    options: Vec<String>,
+}
+
+//the mount a path is on, a target that doesn't exist yet is judged by its closest existing parent
+pub fn filesystem_of(path: &Path) -> Option<Filesystem> {
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let resolved = fs::canonicalize(existing).ok()?;
+    let mounts = parse_mountinfo(&fs::read_to_string("/proc/self/mountinfo").ok()?);
+
+    //the longest matching mount point wins, later lines for the same point are mounted on top
+    let mount = mounts
+        .iter()
+        .filter(|mount| resolved.starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.as_os_str().len())?;
+
+    Some(Filesystem {
+        path: path.display().to_string(),
+        fs_type: mount.fs_type.clone(),
+        mount_point: mount.mount_point.display().to_string(),
+        device: format!("{} ({})", mount.source, mount.device_number),
+        options: mount.options.clone(),
+        free_bytes: space::free_space(&resolved),
+    })
+}
+
+//id parent major:minor root mount-point options [optional fields] - type source super-options
+fn parse_mountinfo(mountinfo: &str) -> Vec<Mount> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (mount, filesystem) = line.split_once(" - ")?;
+            let mount: Vec<&str> = mount.split(' ').collect();
+            let filesystem: Vec<&str> = filesystem.split(' ').collect();
+            if mount.len() < 6 || filesystem.len() < 3 {
+                return None;
+            }
+
+            let mut options: Vec<String> = mount[5]
+                .split(',')
+                .chain(filesystem[2].split(','))
+                .filter(|option| RELEVANT_OPTIONS.contains(option))
+                .map(str::to_string)
+                .collect();
+            options.dedup();
+
+            Some(Mount {
+                device_number: mount[2].to_string(),
+                mount_point: PathBuf::from(unescape(mount[4])),
+                fs_type: filesystem[0].to_string(),
+                source: unescape(filesystem[1]),
+                options,
+            })
+        })
+        .collect()
+}
+
+//spaces, tabs, newlines and backslashes are written as octal escapes like \040
+fn unescape(field: &str) -> String {
+    let mut bytes = Vec::with_capacity(field.len());
+    let raw = field.as_bytes();
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] == b'\\' && i + 4 <= raw.len() {
+            if let Some(value) = std::str::from_utf8(&raw[i + 1..i + 4]).ok().and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+                bytes.push(value);
+                i += 4;
+                continue;
+            }
+        }
+        bytes.push(raw[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mountinfo_lines_give_type_device_and_relevant_options() {
+        let mountinfo = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw,errors=remount-ro
+40 22 259:3 / /home rw,noatime shared:30 - ext4 /dev/nvme0n1p3 rw,nouser_xattr
+61 40 8:17 / /home/dave/USB\\040stick rw,nosuid,nodev,relatime - vfat /dev/sdb1 rw,fmask=0022,codepage=437
+";
+        let mounts = parse_mountinfo(mountinfo);
+        assert_eq!(mounts.len(), 3);
+        assert_eq!(mounts[1].options, vec!["noatime", "nouser_xattr"]);
+        assert_eq!(mounts[2].mount_point, PathBuf::from("/home/dave/USB stick"));
+        assert_eq!(mounts[2].device_number, "8:17");
+
+        let usb = Filesystem { fs_type: mounts[2].fs_type.clone(), ..Filesystem::default() };
+        assert!(usb.case_insensitive() && !usb.supports_acls());
+        let home = Filesystem { fs_type: "ext4".to_string(), options: mounts[1].options.clone(), ..Filesystem::default() };
+        assert!(!home.supports_xattrs() && home.supports_acls());
+
+        let root = filesystem_of(Path::new("/")).unwrap();
+        assert_eq!(root.mount_point, "/");
+    }
+}
@@ -0,0 +1,156 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::thread::{self, JoinHandle};
+
+//input is cut into blocks of this size, each one becomes its own gzip member
+//the cut points only depend on the data, so the output is the same for any thread count
+pub const BLOCK_SIZE: usize = 1024 * 1024;
+
+//writes a multi-member gzip stream, compressing up to `threads` blocks at the same time
+//gzip -d, tar -xzf and flate2's MultiGzDecoder read it like any other gzip file
+pub struct ParallelGzEncoder<W: Write> {
+    writer: Option<W>,
+    level: Compression,
+    threads: usize,
+    buffer: Vec<u8>,
+    pending: VecDeque<JoinHandle<io::Result<Vec<u8>>>>,
+    //an empty input still has to produce one valid member
+    wrote_member: bool,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    pub fn new(writer: W, level: Compression, threads: usize) -> Self {
+        ParallelGzEncoder {
+            writer: Some(writer),
+            level,
+            threads: threads.max(1),
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+            pending: VecDeque::new(),
+            wrote_member: false,
+        }
+    }
+
+    //hand the buffered block to a worker, or compress it right here with a single thread
+    fn submit_block(&mut self) -> io::Result<()> {
+        let block = std::mem::replace(&mut self.buffer, Vec::with_capacity(BLOCK_SIZE));
+        let level = self.level;
+        self.wrote_member = true;
+
+        if self.threads == 1 {
+            let member = compress_block(&block, level)?;
+            return self.write_member(&member);
+        }
+
+        while self.pending.len() >= self.threads {
+            self.write_oldest()?;
+        }
+        self.pending.push_back(thread::spawn(move || compress_block(&block, level)));
+        Ok(())
+    }
+
+    //members are written in submission order, whichever worker finishes first
+    fn write_oldest(&mut self) -> io::Result<()> {
+        if let Some(handle) = self.pending.pop_front() {
+            let member = handle
+                .join()
+                .map_err(|_| io::Error::other("gzip worker thread panicked"))??;
+            self.write_member(&member)?;
+        }
+        Ok(())
+    }
+
+    fn write_member(&mut self, member: &[u8]) -> io::Result<()> {
+        self.writer.as_mut().expect("encoder used after finish").write_all(member)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buffer.is_empty() || !self.wrote_member {
+            self.submit_block()?;
+        }
+        while !self.pending.is_empty() {
+            self.write_oldest()?;
+        }
+
+        let mut writer = self.writer.take().expect("encoder used after finish");
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+fn compress_block(block: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(block.len() / 2), level);
+    encoder.write_all(block)?;
+    encoder.finish()
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let take = buf.len().min(BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+
+        if self.buffer.len() == BLOCK_SIZE {
+            self.submit_block()?;
+        }
+        Ok(take)
+    }
+
+    //ends the current member early, so everything written so far reaches the writer
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.submit_block()?;
+        }
+        while !self.pending.is_empty() {
+            self.write_oldest()?;
+        }
+        self.writer.as_mut().expect("encoder used after finish").flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    fn sample_data() -> Vec<u8> {
+        //a bit over three blocks of text that compresses, with some variation
+        (0..BLOCK_SIZE as u64 * 3 / 40 + 123)
+            .flat_map(|i| format!("line {:>10} {}\n", i, i % 7).into_bytes())
+            .collect()
+    }
+
+    fn compress(data: &[u8], threads: usize) -> Vec<u8> {
+        let mut encoder = ParallelGzEncoder::new(Vec::new(), Compression::default(), threads);
+        //odd write sizes so blocks are filled across several writes
+        for chunk in data.chunks(100_003) {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn parallel_output_matches_serial_content_for_any_thread_count() {
+        let data = sample_data();
+
+        let mut serial = GzEncoder::new(Vec::new(), Compression::default());
+        serial.write_all(&data).unwrap();
+        let serial = serial.finish().unwrap();
+
+        let single = compress(&data, 1);
+        let parallel = compress(&data, 4);
+        assert_eq!(single, parallel);
+
+        for compressed in [serial, parallel] {
+            let mut decompressed = Vec::new();
+            MultiGzDecoder::new(compressed.as_slice()).read_to_end(&mut decompressed).unwrap();
+            assert_eq!(decompressed, data);
+        }
+
+        //an empty stream is still a valid gzip file
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(compress(&[], 4).as_slice()).read_to_end(&mut decompressed).unwrap();
+        assert!(decompressed.is_empty());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::users::{self, UserAccount};
+use crate::utils;
+use colored::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const APP_DIR: &str = "linux_backup";
+
+//left in the old metadata directory once its contents have moved
+pub const POINTER_FILE: &str = "MOVED.txt";
+
+//resolve an XDG base directory: explicit override, then the XDG variable, then the spec default under home
+fn base_dir(override_var: &str, xdg_var: &str, home: &Path, default: &str) -> PathBuf {
+    if let Some(dir) = std::env::var_os(override_var).filter(|v| !v.is_empty()) {
+        return PathBuf::from(dir);
+    }
+
+    //the spec says relative values are invalid and must be ignored
+    std::env::var_os(xdg_var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .unwrap_or_else(|| home.join(default))
+        .join(APP_DIR)
+}
+
+//config.toml lives in $XDG_CONFIG_HOME/linux_backup
+pub fn config_dir() -> PathBuf {
+    base_dir("LINUX_BACKUP_CONFIG_DIR", "XDG_CONFIG_HOME", &utils::home_dir(), ".config")
+}
+
+//backup metadata and usage counters live in $XDG_STATE_HOME/linux_backup
+//older versions used ~/.linux_backup_metadata, which is migrated on first use
+pub fn state_dir() -> PathBuf {
+    let home = utils::home_dir();
+    let state = base_dir("LINUX_BACKUP_STATE_DIR", "XDG_STATE_HOME", &home, ".local/state");
+    resolve_state_dir(&home.join(utils::METADATA_DIR), state, None)
+}
+
+//the state directory of another user, used by root for per-user backups
+//the environment describes the invoking user, so only the spec default applies here
+pub fn user_state_dir(user: &UserAccount) -> PathBuf {
+    resolve_state_dir(&user.home.join(utils::METADATA_DIR), user.home.join(".local/state").join(APP_DIR), Some(user))
+}
+
+//the same directory for status and history, which only read it: nothing is moved or created
+//a legacy directory that hasn't been migrated yet is where the user's metadata still is
+pub fn user_state_dir_lookup(user: &UserAccount) -> PathBuf {
+    let legacy = user.home.join(utils::METADATA_DIR);
+    if legacy.is_dir() && !legacy.join(POINTER_FILE).exists() {
+        return legacy;
+    }
+    user.home.join(".local/state").join(APP_DIR)
+}
+
+//root's state directory in a system mounted at root, where offline backups keep their chain
+pub fn offline_state_dir(root: &Path) -> PathBuf {
+    let home = root.join("root");
+    resolve_state_dir(&home.join(utils::METADATA_DIR), home.join(".local/state").join(APP_DIR), None)
+}
+
+//migrate if needed, and keep using the legacy directory if that fails
+fn resolve_state_dir(legacy: &Path, state: PathBuf, owner: Option<&UserAccount>) -> PathBuf {
+    match migrate_legacy_dir(legacy, &state, owner) {
+        Ok(()) => state,
+        Err(e) => {
+            println!("{}", format!("Warning: Could not move {} to {}: {}", legacy.display(), state.display(), e).yellow());
+            println!("{}", format!("Continuing with {}", legacy.display()).yellow());
+            legacy.to_path_buf()
+        }
+    }
+}
+
+//move the legacy metadata directory to its new place and leave a pointer behind
+//root migrating another user's directory hands everything it creates to that user
+pub fn migrate_legacy_dir(legacy: &Path, state: &Path, owner: Option<&UserAccount>) -> io::Result<()> {
+    if !legacy.is_dir() || legacy.join(POINTER_FILE).exists() {
+        return Ok(());
+    }
+
+    let mut created: Vec<PathBuf> = Vec::new();
+    if let Some(parent) = state.parent() {
+        created.extend(parent.ancestors().take_while(|dir| !dir.exists()).map(Path::to_path_buf));
+        fs::create_dir_all(parent)?;
+    }
+
+    if state.exists() {
+        //partially migrated, or the state dir was created by something else first
+        //move what is missing and keep whatever the new location already has
+        for entry in fs::read_dir(legacy)? {
+            let entry = entry?;
+            let target = state.join(entry.file_name());
+            if !target.exists() {
+                move_entry(&entry.path(), &target)?;
+            }
+        }
+    } else {
+        move_entry(legacy, state)?;
+        fs::create_dir_all(legacy)?;
+    }
+
+    //a move across filesystems copies, so the moved files are handed over as well
+    if let Some(owner) = owner {
+        created.push(legacy.to_path_buf());
+        for entry in walkdir::WalkDir::new(state) {
+            created.push(entry.map_err(io::Error::other)?.into_path());
+        }
+        for path in &created {
+            users::chown(path, owner)?;
+        }
+    }
+
+    //written last, its presence marks the migration as complete
+    let pointer = legacy.join(POINTER_FILE);
+    fs::write(&pointer, format!("linux_backup now keeps its backup metadata in {}\n", state.display()))?;
+    match owner {
+        Some(owner) => users::chown(&pointer, owner),
+        None => Ok(()),
+    }
+}
+
+//rename is atomic; across filesystems copy to a staging name first and rename that into place
+fn move_entry(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.raw_os_error() != Some(libc::EXDEV) => return Err(e),
+        Err(_) => {}
+    }
+
+    let staging = to.with_extension("migrating");
+    if staging.exists() {
+        remove_entry(&staging)?;
+    }
+    copy_entry(from, &staging)?;
+    fs::rename(&staging, to)?;
+    remove_entry(from)
+}
+
+fn copy_entry(from: &Path, to: &Path) -> io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_entry(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        fs::copy(from, to).map(|_| ())
+    }
+}
+
+fn remove_entry(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_install_creates_nothing_in_home() {
+        let temp = tempfile::tempdir().unwrap();
+        let legacy = temp.path().join(utils::METADATA_DIR);
+        let state = temp.path().join(".local/state/linux_backup");
+
+        migrate_legacy_dir(&legacy, &state, None).unwrap();
+
+        assert!(!legacy.exists());
+        assert!(!state.exists());
+    }
+
+    #[test]
+    fn legacy_install_is_moved_and_leaves_a_pointer() {
+        let temp = tempfile::tempdir().unwrap();
+        let legacy = temp.path().join(utils::METADATA_DIR);
+        let state = temp.path().join(".local/state/linux_backup");
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join("backup_metadata.json"), "{}").unwrap();
+
+        migrate_legacy_dir(&legacy, &state, None).unwrap();
+
+        assert_eq!(fs::read_to_string(state.join("backup_metadata.json")).unwrap(), "{}");
+        assert!(!legacy.join("backup_metadata.json").exists());
+        assert!(fs::read_to_string(legacy.join(POINTER_FILE)).unwrap().contains(&state.display().to_string()));
+
+        //a second run is a no-op
+        migrate_legacy_dir(&legacy, &state, None).unwrap();
+        assert!(state.join("backup_metadata.json").exists());
+    }
+
+    #[test]
+    fn partial_migration_keeps_newer_state() {
+        let temp = tempfile::tempdir().unwrap();
+        let legacy = temp.path().join(utils::METADATA_DIR);
+        let state = temp.path().join(".local/state/linux_backup");
+        fs::create_dir_all(&legacy).unwrap();
+        fs::create_dir_all(&state).unwrap();
+        fs::write(legacy.join("backup_metadata.json"), "old").unwrap();
+        fs::write(legacy.join("custom_dir_usage.json"), "usage").unwrap();
+        fs::write(state.join("backup_metadata.json"), "new").unwrap();
+
+        migrate_legacy_dir(&legacy, &state, None).unwrap();
+
+        assert_eq!(fs::read_to_string(state.join("backup_metadata.json")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(state.join("custom_dir_usage.json")).unwrap(), "usage");
+        assert!(legacy.join(POINTER_FILE).exists());
+    }
+
+    #[test]
+    fn another_users_migration_is_handed_to_them() {
+        use std::os::unix::fs::MetadataExt;
+
+        //changing owners needs root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let temp = tempfile::tempdir().unwrap();
+        let user = UserAccount { name: "dave".to_string(), uid: 4242, gid: 4343, home: temp.path().to_path_buf() };
+        let legacy = temp.path().join(utils::METADATA_DIR);
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join("backup_metadata.json"), "{}").unwrap();
+        users::chown(&legacy, &user).unwrap();
+        users::chown(&legacy.join("backup_metadata.json"), &user).unwrap();
+
+        //looking the directory up leaves the legacy one where it is
+        assert_eq!(user_state_dir_lookup(&user), legacy);
+        assert!(!temp.path().join(".local").exists());
+
+        let state = user_state_dir(&user);
+        assert_eq!(state, temp.path().join(".local/state/linux_backup"));
+        assert_eq!(user_state_dir_lookup(&user), state);
+        for path in [temp.path().join(".local"), temp.path().join(".local/state"), state.clone(), state.join("backup_metadata.json"), legacy.clone(), legacy.join(POINTER_FILE)] {
+            let metadata = fs::metadata(&path).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (4242, 4343), "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use indicatif::ProgressBar;
+use signal_hook::consts::{SIGUSR1, SIGUSR2};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+//set from the signal handlers, so it has to be a plain static
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static REGISTER: Once = Once::new();
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+//lets a running backup be paused with SIGUSR1 and resumed with SIGUSR2
+#[derive(Default)]
+pub struct PauseControl {
+    listening: bool,
+    pub paused_for: Duration,
+}
+
+impl PauseControl {
+    //the handlers are installed once per process, every run starts unpaused
+    pub fn listen() -> Self {
+        REGISTER.call_once(|| {
+            let registered = unsafe {
+                signal_hook::low_level::register(SIGUSR1, || PAUSED.store(true, Ordering::SeqCst))
+                    .and_then(|_| signal_hook::low_level::register(SIGUSR2, || PAUSED.store(false, Ordering::SeqCst)))
+            };
+            if let Err(e) = registered {
+                println!("Warning: Pausing with SIGUSR1/SIGUSR2 is unavailable: {}", e);
+            }
+        });
+        PAUSED.store(false, Ordering::SeqCst);
+
+        PauseControl { listening: true, paused_for: Duration::ZERO }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.listening && PAUSED.load(Ordering::SeqCst)
+    }
+
+    //block until resumed, the progress bar shows why nothing is happening
+    pub fn wait_while_paused(&mut self, progress: &ProgressBar) {
+        if !self.is_paused() {
+            return;
+        }
+
+        let previous = progress.message();
+        progress.set_message(format!("Paused, send SIGUSR2 to process {} to resume", std::process::id()));
+
+        let started = Instant::now();
+        while self.is_paused() {
+            progress.tick();
+            thread::sleep(POLL_INTERVAL);
+        }
+        self.paused_for += started.elapsed();
+
+        progress.set_message(previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_pause_and_resume_a_run() {
+        let mut pause = PauseControl::listen();
+        assert!(!pause.is_paused());
+
+        unsafe { libc::raise(SIGUSR1) };
+        assert!(pause.is_paused());
+
+        let resumer = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(300));
+            unsafe { libc::kill(libc::getpid(), SIGUSR2) };
+        });
+        pause.wait_while_paused(&ProgressBar::hidden());
+        resumer.join().unwrap();
+
+        assert!(!pause.is_paused());
+        assert!(pause.paused_for >= Duration::from_millis(250));
+        assert!(!PauseControl::default().is_paused());
+    }
+}
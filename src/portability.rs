@@ -0,0 +1,94 @@
+use crate::backup::BackupSettings;
+use crate::utils::BackupType;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Write};
+use tar::{Builder, Header};
+
+//a top-level note for whoever opens the archive with plain tar, restores skip it like the descriptor
+pub const README_ENTRY: &str = "README.linux_backup.txt";
+
+//how far an archive may stray from what plain GNU tar can extract, set with portability in config.toml
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Portability {
+    //tar -xf recovers everything, features that need linux_backup to read the archive are refused
+    StrictTar,
+    //every feature is allowed, a README entry says what plain tar does and doesn't recover
+    #[default]
+    Extended,
+}
+
+impl fmt::Display for Portability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Portability::StrictTar => write!(f, "strict-tar"),
+            Portability::Extended => write!(f, "extended"),
+        }
+    }
+}
+
+impl Portability {
+    //refuse a feature plain tar can't get past, with what to use instead
+    pub fn require_tar(self, feature: &str, instead: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self == Portability::StrictTar {
+            return Err(format!(
+                "{} makes an archive that plain tar can't extract, and portability is set to strict-tar in config.toml. {}, or set portability = \"extended\"",
+                feature, instead
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    //what the archive's descriptor and info show
+    pub fn describe(self) -> &'static str {
+        match self {
+            Portability::StrictTar => "strict-tar, plain tar -xf recovers everything",
+            Portability::Extended => "extended, see README.linux_backup.txt in the archive for what plain tar recovers",
+        }
+    }
+}
+
+//the README text for an archive written with these settings
+pub fn readme(settings: &BackupSettings) -> String {
+    let mut text = String::from(
+        "This archive was written by linux_backup. It is a regular tar archive, GNU tar extracts it\n\
+         without linux_backup installed:\n\n    tar -xf <archive> -C <target directory>\n\n\
+         A plain tar extraction recovers every file, directory, symlink and hard link with its\n\
+         permissions and times. It won't:\n\n",
+    );
+
+    let mut caveats = vec![
+        "- Skip the .linux_backup_sysinfo and .linux_backup_state directories and this file, they are\n  linux_backup's own records and can be deleted after extracting.".to_string(),
+        "- Warn about files that could only be read partly during the backup. They are stored padded\n  with zeros to their recorded size, linux_backup restore lists them.".to_string(),
+    ];
+    if settings.xattrs || settings.acls {
+        caveats.push("- Restore extended attributes, file capabilities and ACLs unless asked to, add\n  --xattrs --xattrs-include='*' --acls (as root for anything outside user.*).".to_string());
+    }
+    if !matches!(settings.backup_type, BackupType::Full) {
+        caveats.push("- Make this backup complete on its own. It only holds what changed, extract the full backup\n  first and every later one in order. Files deleted in between are not removed by tar.".to_string());
+    }
+    if settings.encryption.is_some() {
+        caveats.push("- Decrypt the archive. Every copy has to be decrypted with the same key before tar can read it.".to_string());
+    }
+
+    text.push_str(&caveats.join("\n"));
+    text.push_str("\n\nlinux_backup restore handles all of the above.\n");
+    text
+}
+
+//written right after the descriptor by archives with extended portability
+pub fn append_readme<W: Write>(archive: &mut Builder<W>, settings: &BackupSettings, mtime: u64) -> io::Result<()> {
+    if settings.portability != Portability::Extended {
+        return Ok(());
+    }
+
+    let data = readme(settings);
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    archive.append_data(&mut header, README_ENTRY, data.as_bytes())
+}
@@ -0,0 +1,52 @@
+use crate::utils;
+use std::path::{Path, PathBuf};
+
+//a named bundle of exclusion patterns, merged into the exclusions like --exclude
+//name patterns leave out matching entries at any depth, absolute paths only that path
+#[derive(Debug, PartialEq)]
+pub struct Preset {
+    //what --preset takes
+    pub name: &'static str,
+    //what the menu shows
+    pub label: &'static str,
+    pub patterns: &'static [&'static str],
+}
+
+pub const PRESETS: [Preset; 3] = [
+    Preset {
+        name: "media",
+        label: "Media (videos, disc images, lossless audio)",
+        patterns: &["*.mp4", "*.mkv", "*.avi", "*.mov", "*.webm", "*.m4v", "*.mpg", "*.mpeg", "*.wmv", "*.iso", "*.flac", "*.wav", "*.ape"],
+    },
+    Preset {
+        name: "build",
+        label: "Build artifacts (target/, node_modules/, *.o, __pycache__)",
+        patterns: &["target", "node_modules", "__pycache__", ".gradle", ".tox", "*.o", "*.obj", "*.pyc", "*.class"],
+    },
+    Preset {
+        name: "vm",
+        label: "VM & containers (disk images, docker and podman layers)",
+        patterns: &["*.qcow2", "*.vdi", "*.vmdk", "*.vhd", "*.vhdx", "*.ova", "/var/lib/docker/overlay2", "/var/lib/containers/storage/overlay"],
+    },
+];
+
+impl Preset {
+    //whether an exclusion that matched came from this preset, absolute patterns may sit below a --root
+    pub fn covers(&self, exclusion: &Path) -> bool {
+        self.patterns.iter().any(|pattern| exclusion == Path::new(pattern) || (pattern.starts_with('/') && exclusion.ends_with(pattern.trim_start_matches('/'))))
+    }
+}
+
+//the value parser of --preset
+pub fn parse(name: &str) -> Result<&'static Preset, String> {
+    PRESETS.iter().find(|preset| preset.name == name).ok_or_else(|| {
+        let names: Vec<&str> = PRESETS.iter().map(|preset| preset.name).collect();
+        format!("unknown preset {}, choose from {}", name, names.join(", "))
+    })
+}
+
+//the patterns of every preset, ready to join the exclusions
+pub fn patterns(presets: &[&'static Preset]) -> Vec<PathBuf> {
+    let patterns: Vec<PathBuf> = presets.iter().flat_map(|preset| preset.patterns.iter().map(PathBuf::from)).collect();
+    utils::exclusion_patterns(&patterns)
+}
@@ -0,0 +1,363 @@
+use crate::hangup;
+use colored::*;
+use dialoguer::theme::{ColorfulTheme, SimpleTheme, Theme};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+//the prompts the menus use, drawn by dialoguer on a capable terminal
+//dumb terminals, IDE task windows and piped input get numbered questions answered with a line of text instead
+
+//dialoguer redraws its prompts with cursor movement, which a dumb terminal prints as escape codes
+pub fn is_plain() -> bool {
+    static PLAIN: OnceLock<bool> = OnceLock::new();
+    *PLAIN.get_or_init(|| {
+        let dumb = std::env::var("TERM").map_or(true, |term| term.is_empty() || term == "dumb");
+        dumb || !io::stdin().is_terminal() || !io::stdout().is_terminal()
+    })
+}
+
+//no colors where the escape codes would show up as text, NO_COLOR is honored by colored itself
+pub fn init() {
+    if std::env::var("TERM").is_ok_and(|term| term == "dumb") {
+        colored::control::set_override(false);
+    }
+}
+
+pub fn clear_screen() {
+    if !is_plain() {
+        print!("\x1B[2J\x1B[1;1H");
+    }
+}
+
+//the colorful theme only when colors are on, its check marks and arrows are colored text too
+fn with_theme<R>(prompt: impl FnOnce(&dyn Theme) -> R) -> R {
+    if colored::control::SHOULD_COLORIZE.should_colorize() {
+        prompt(&ColorfulTheme::default())
+    } else {
+        prompt(&SimpleTheme)
+    }
+}
+
+//one line from stdin, running out of input is an error so a script can't loop forever
+fn read_line(question: &str) -> dialoguer::Result<String> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        println!();
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no more input to answer the question").into());
+    }
+    Ok(line.trim_end_matches(['\n', '\r']).to_string())
+}
+
+fn read_answer(question: &str) -> dialoguer::Result<String> {
+    let answer = read_line(question)?;
+    //piped answers aren't echoed by a terminal, show them so the output reads like a session
+    if !io::stdin().is_terminal() {
+        println!("{}", answer);
+    }
+    Ok(answer)
+}
+
+//never echoed, a piped passphrase would end up in the output and every log of it
+fn read_hidden(question: &str) -> dialoguer::Result<String> {
+    let stdin = io::stdin();
+    let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+    let echo_off = stdin.is_terminal() && unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } == 0;
+    if echo_off {
+        let mut silent = original;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) };
+    }
+
+    let answer = read_line(question);
+    if echo_off {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original) };
+    }
+    //the Enter that ended the line wasn't shown either
+    if answer.is_ok() {
+        println!();
+    }
+    answer
+}
+
+#[derive(Default)]
+pub struct Confirm {
+    prompt: String,
+    default: Option<bool>,
+}
+
+impl Confirm {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    pub fn default(mut self, default: bool) -> Self {
+        self.default = Some(default);
+        self
+    }
+
+    pub fn interact(self) -> dialoguer::Result<bool> {
+        hangup::answerable()?;
+        if !is_plain() {
+            return with_theme(|theme| {
+                let mut confirm = dialoguer::Confirm::with_theme(theme).with_prompt(&self.prompt);
+                if let Some(default) = self.default {
+                    confirm = confirm.default(default);
+                }
+                confirm.interact()
+            });
+        }
+
+        let hint = match self.default {
+            Some(true) => "[Y/n]",
+            Some(false) => "[y/N]",
+            None => "[y/n]",
+        };
+        loop {
+            let answer = read_answer(&format!("{} {} ", self.prompt, hint))?.trim().to_ascii_lowercase();
+            match (answer.as_str(), self.default) {
+                ("y" | "yes", _) => return Ok(true),
+                ("n" | "no", _) => return Ok(false),
+                ("", Some(default)) => return Ok(default),
+                _ => println!("{}", "Answer y or n.".red()),
+            }
+        }
+    }
+}
+
+pub struct Input<T> {
+    prompt: String,
+    default: Option<T>,
+    allow_empty: bool,
+}
+
+impl<T> Default for Input<T> {
+    fn default() -> Self {
+        Input { prompt: String::new(), default: None, allow_empty: false }
+    }
+}
+
+impl<T> Input<T>
+where
+    T: Clone + ToString + FromStr,
+    <T as FromStr>::Err: ToString,
+{
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    pub fn default(mut self, default: T) -> Self {
+        self.default = Some(default);
+        self
+    }
+
+    pub fn allow_empty(mut self, allow_empty: bool) -> Self {
+        self.allow_empty = allow_empty;
+        self
+    }
+
+    pub fn interact_text(self) -> dialoguer::Result<T> {
+        hangup::answerable()?;
+        if !is_plain() {
+            return with_theme(|theme| {
+                let mut input = dialoguer::Input::with_theme(theme).with_prompt(&self.prompt).allow_empty(self.allow_empty);
+                if let Some(default) = self.default.clone() {
+                    input = input.default(default);
+                }
+                input.interact_text()
+            });
+        }
+
+        let question = match &self.default {
+            Some(default) => format!("{} [{}]: ", self.prompt, default.to_string()),
+            None => format!("{}: ", self.prompt),
+        };
+        loop {
+            let answer = read_answer(&question)?;
+            if answer.trim().is_empty() {
+                if let Some(default) = &self.default {
+                    return Ok(default.clone());
+                }
+                if !self.allow_empty {
+                    continue;
+                }
+            }
+            match answer.parse() {
+                Ok(value) => return Ok(value),
+                Err(e) => println!("{}", e.to_string().red()),
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Select {
+    prompt: String,
+    items: Vec<String>,
+    default: usize,
+}
+
+impl Select {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    pub fn items<T: ToString>(mut self, items: &[T]) -> Self {
+        self.items.extend(items.iter().map(ToString::to_string));
+        self
+    }
+
+    pub fn default(mut self, default: usize) -> Self {
+        self.default = default;
+        self
+    }
+
+    pub fn interact(self) -> dialoguer::Result<usize> {
+        hangup::answerable()?;
+        if !is_plain() {
+            return with_theme(|theme| dialoguer::Select::with_theme(theme).with_prompt(&self.prompt).items(&self.items).default(self.default).interact());
+        }
+
+        println!("{}", self.prompt);
+        for (i, item) in self.items.iter().enumerate() {
+            println!("  {}) {}", i + 1, item);
+        }
+        loop {
+            let answer = read_answer(&format!("Enter a number [{}]: ", self.default + 1))?;
+            if answer.trim().is_empty() {
+                return Ok(self.default);
+            }
+            match answer.trim().parse::<usize>() {
+                Ok(number) if (1..=self.items.len()).contains(&number) => return Ok(number - 1),
+                _ => println!("{}", format!("Enter a number from 1 to {}.", self.items.len()).red()),
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MultiSelect {
+    prompt: String,
+    items: Vec<String>,
+    defaults: Vec<bool>,
+}
+
+impl MultiSelect {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    pub fn items<T: ToString>(mut self, items: &[T]) -> Self {
+        self.items.extend(items.iter().map(ToString::to_string));
+        self
+    }
+
+    pub fn defaults(mut self, defaults: &[bool]) -> Self {
+        self.defaults = defaults.to_vec();
+        self
+    }
+
+    pub fn interact(self) -> dialoguer::Result<Vec<usize>> {
+        hangup::answerable()?;
+        if !is_plain() {
+            let prompt = format!("{} (SPACEBAR to select, ENTER to confirm)", self.prompt);
+            return with_theme(|theme| dialoguer::MultiSelect::with_theme(theme).with_prompt(prompt).items(&self.items).defaults(&self.defaults).interact());
+        }
+
+        let checked = |i: usize| self.defaults.get(i).copied().unwrap_or(false);
+        println!("{}", self.prompt);
+        for (i, item) in self.items.iter().enumerate() {
+            println!("  {}) [{}] {}", i + 1, if checked(i) { "x" } else { " " }, item);
+        }
+        loop {
+            let answer = read_answer("Enter the numbers to select, separated by spaces or commas (empty keeps the [x] ones, - for none): ")?;
+            match answer.trim() {
+                "" => return Ok((0..self.items.len()).filter(|i| checked(*i)).collect()),
+                "-" => return Ok(Vec::new()),
+                _ => {}
+            }
+
+            let numbers: Result<Vec<usize>, _> = answer.split([' ', ',']).filter(|part| !part.is_empty()).map(str::parse::<usize>).collect();
+            match numbers {
+                Ok(mut numbers) if numbers.iter().all(|number| (1..=self.items.len()).contains(number)) => {
+                    numbers.sort_unstable();
+                    numbers.dedup();
+                    return Ok(numbers.into_iter().map(|number| number - 1).collect());
+                }
+                _ => println!("{}", format!("Enter numbers from 1 to {}.", self.items.len()).red()),
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Password {
+    prompt: String,
+    //(prompt, mismatch error) for asking a second time
+    confirmation: Option<(String, String)>,
+}
+
+impl Password {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    pub fn with_confirmation(mut self, prompt: impl Into<String>, mismatch: impl Into<String>) -> Self {
+        self.confirmation = Some((prompt.into(), mismatch.into()));
+        self
+    }
+
+    pub fn interact(self) -> dialoguer::Result<String> {
+        hangup::answerable()?;
+        if !is_plain() {
+            return with_theme(|theme| {
+                let password = dialoguer::Password::with_theme(theme).with_prompt(&self.prompt);
+                match &self.confirmation {
+                    Some((prompt, mismatch)) => password.with_confirmation(prompt, mismatch).interact(),
+                    None => password.interact(),
+                }
+            });
+        }
+
+        loop {
+            let password = read_hidden(&format!("{}: ", self.prompt))?;
+            if password.is_empty() {
+                continue;
+            }
+            let Some((prompt, mismatch)) = &self.confirmation else {
+                return Ok(password);
+            };
+            if read_hidden(&format!("{}: ", prompt))? == password {
+                return Ok(password);
+            }
+            println!("{}", mismatch.red());
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use crate::compression::{self, ArchiveCompression, ArchiveEncoder, CompressionFormat};
+use crate::encryption;
+use crate::restore::{self, RestoreOptions};
+use crate::sidecars;
+use crate::sysinfo::{self, SystemInfo};
+use crate::utils;
+use chrono::{Datelike, Timelike};
+use colored::*;
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tar::{Archive, Builder, Entry, EntryType};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+
+//* stays within one directory, ** crosses them
+const MATCH_OPTIONS: MatchOptions = MatchOptions { case_sensitive: true, require_literal_separator: true, require_literal_leading_dot: false };
+
+//pax records the writer derives from the entry itself, everything else is carried over
+const DERIVED_PAX_KEYS: [&str; 3] = ["path", "linkpath", "size"];
+
+//the archive a repacked one was cut from, recorded in its descriptor
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RepackOrigin {
+    pub archive: String,
+    //empty when every entry was taken
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<String>,
+}
+
+//archived names to take, a pattern naming a directory takes everything below it
+pub struct Selection {
+    patterns: Vec<Pattern>,
+}
+
+impl Selection {
+    //archived names have no leading slash, patterns copied from a path may
+    pub fn new(includes: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let patterns = includes
+            .iter()
+            .map(|include| {
+                let relative = include.trim_start_matches('/').trim_start_matches("./").trim_end_matches('/');
+                Pattern::new(relative).map_err(|e| format!("Invalid include pattern {}: {}", include, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Selection { patterns })
+    }
+
+    pub fn matches(&self, name: &Path) -> bool {
+        self.patterns.is_empty() || name.ancestors().any(|ancestor| self.patterns.iter().any(|pattern| pattern.matches_path_with(ancestor, MATCH_OPTIONS)))
+    }
+}
+
+//the new archive, the format follows the output's file name
+enum Repacked {
+    Tar(Builder<ArchiveEncoder<File>>),
+    //opened for reading too, hard links are stored as second copies read back from the zip
+    Zip(ZipWriter<File>),
+}
+
+//what a repack wrote and left out
+#[derive(Default)]
+struct Repacking {
+    entries: u64,
+    bytes: u64,
+    written: HashSet<PathBuf>,
+    skipped: Vec<(String, String)>,
+    //entries whose xattrs and ACLs a zip can't hold
+    dropped_attributes: u64,
+}
+
+//copy the matching entries of an archive into a new tar or zip archive, nothing is extracted on the way
+pub fn repack_backup(backup_file: &str, output: &str, includes: &[String], options: &RestoreOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(backup_file);
+    if !path.exists() {
+        return Err(format!("Backup file does not exist: {}", backup_file).into());
+    }
+    let output_path = Path::new(output);
+    if output_path.exists() {
+        return Err(format!("{} already exists, repack doesn't overwrite archives", output).into());
+    }
+    let format = output_format(output)?;
+    let selection = Selection::new(includes)?;
+
+    let key = if encryption::is_encrypted(path) {
+        match restore::unlock_archive(path, options)? {
+            Some(key) => Some(key),
+            None => return Ok(()),
+        }
+    } else {
+        None
+    };
+
+    println!("{}", "\n---- Repack Backup ----".blue().bold());
+    println!("Repacking {} into {}", backup_file, output);
+
+    //a descriptor of its own, with the origin of the data and where it was cut from
+    let origin = sysinfo::read_from_archive(path, key.as_ref()).unwrap_or_default();
+    let info = SystemInfo {
+        sources: if includes.is_empty() { origin.sources.clone() } else { includes.to_vec() },
+        compression: Some(format.map(|format| ArchiveCompression::new(format).label()).unwrap_or_else(|| "zip deflate".to_string())),
+        disk_layout: Vec::new(),
+        portability: None,
+        manifest: false,
+        repacked_from: Some(RepackOrigin { archive: utils::absolute_path(path).display().to_string(), includes: includes.to_vec() }),
+        ..origin
+    };
+
+    let partial_path = sidecars::partial(output_path);
+    let result = write_repacked(path, key.as_ref(), &partial_path, format, &selection, &info);
+    let repacking = match result {
+        Ok(repacking) if repacking.entries > 0 => repacking,
+        Ok(_) => {
+            let _ = fs::remove_file(&partial_path);
+            return Err(format!("No entries of {} match the include patterns, nothing was written", backup_file).into());
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial_path);
+            return Err(e);
+        }
+    };
+    fs::rename(&partial_path, output_path)?;
+
+    println!(
+        "{}",
+        format!("Repacked {} entries ({}) into {} ({}).", repacking.entries, utils::format_size(repacking.bytes), output, utils::format_size(fs::metadata(output_path)?.len())).green().bold()
+    );
+    if repacking.dropped_attributes > 0 {
+        println!("{}", format!("Warning: zip keeps no extended attributes or ACLs, {} entries lost theirs. Repack into a .tar.gz to keep them and the owners.", repacking.dropped_attributes).yellow());
+    }
+    if !repacking.skipped.is_empty() {
+        println!("{}", format!("{} entries were left out:", repacking.skipped.len()).yellow());
+        restore::print_some(&repacking.skipped);
+    }
+    Ok(())
+}
+
+//None for zip, the tar formats as their compression
+fn output_format(output: &str) -> Result<Option<CompressionFormat>, Box<dyn std::error::Error>> {
+    if output.ends_with(".zip") {
+        return Ok(None);
+    }
+    match CompressionFormat::from_name(output) {
+        Some(format) => Ok(Some(format)),
+        None => Err(format!("Can't tell the format of {}, name it .zip, .tar, .tar.gz, .tar.zst or .tar.xz", output).into()),
+    }
+}
+
+fn write_repacked(
+    backup_file: &Path,
+    key: Option<&encryption::ArchiveKey>,
+    partial_path: &Path,
+    format: Option<CompressionFormat>,
+    selection: &Selection,
+    info: &SystemInfo,
+) -> Result<Repacking, Box<dyn std::error::Error>> {
+    let mtime = utils::entry_mtime(false);
+    let mut repacked = match format {
+        Some(format) => {
+            let mut archive = Builder::new(ArchiveEncoder::new(File::create(partial_path)?, ArchiveCompression::new(format))?);
+            sysinfo::append_to_archive(&mut archive, info, mtime)?;
+            Repacked::Tar(archive)
+        }
+        None => {
+            let mut zip = ZipWriter::new(File::options().read(true).write(true).create(true).truncate(true).open(partial_path)?);
+            zip.start_file(sysinfo::SYSINFO_ENTRY, zip_options(0o644, mtime))?;
+            zip.write_all(&serde_json::to_vec_pretty(info)?)?;
+            Repacked::Zip(zip)
+        }
+    };
+
+    let mut repacking = Repacking::default();
+    let mut archive = Archive::new(compression::open_decoder(backup_file, key)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        if restore::is_bookkeeping(&name) || !selection.matches(&name) {
+            continue;
+        }
+
+        let size = entry.header().size()?;
+        let copied = match &mut repacked {
+            Repacked::Tar(archive) => copy_to_tar(archive, &mut entry, &name, &repacking.written)?,
+            Repacked::Zip(zip) => copy_to_zip(zip, &mut entry, &name, &repacking.written, &mut repacking.dropped_attributes)?,
+        };
+        match copied {
+            Ok(()) => {
+                repacking.entries += 1;
+                repacking.bytes += size;
+                repacking.written.insert(name);
+            }
+            Err(reason) => repacking.skipped.push((name.display().to_string(), reason)),
+        }
+    }
+
+    match repacked {
+        Repacked::Tar(archive) => archive.into_inner()?.finish()?.sync_all()?,
+        Repacked::Zip(zip) => zip.finish()?.sync_all()?,
+    }
+    Ok(repacking)
+}
+
+//the inner Err is why the entry was left out
+type Copied = Result<(), String>;
+
+//the header is kept as it is, long names and pax records are written again for the new position
+fn copy_to_tar<R: io::Read>(archive: &mut Builder<ArchiveEncoder<File>>, entry: &mut Entry<R>, name: &Path, written: &HashSet<PathBuf>) -> io::Result<Copied> {
+    let mut header = entry.header().clone();
+    let link = entry.link_name()?.map(|link| link.into_owned());
+    if let (EntryType::Link, Some(target)) = (header.entry_type(), &link) {
+        if !written.contains(target) {
+            return Ok(Err(format!("hard link to {}, which isn't included", target.display())));
+        }
+    }
+
+    let records: Vec<(String, Vec<u8>)> = match entry.pax_extensions()? {
+        Some(records) => records
+            .filter_map(|record| record.ok())
+            .filter_map(|record| Some((record.key().ok()?.to_string(), record.value_bytes().to_vec())))
+            .filter(|(key, _)| !DERIVED_PAX_KEYS.contains(&key.as_str()))
+            .collect(),
+        None => Vec::new(),
+    };
+    if !records.is_empty() {
+        archive.append_pax_extensions(records.iter().map(|(key, value)| (key.as_str(), value.as_slice())))?;
+    }
+
+    match (header.entry_type(), link) {
+        (EntryType::Link | EntryType::Symlink, Some(target)) => archive.append_link(&mut header, name, target)?,
+        _ => archive.append_data(&mut header, name, entry)?,
+    }
+    Ok(Ok(()))
+}
+
+fn copy_to_zip<R: io::Read>(zip: &mut ZipWriter<File>, entry: &mut Entry<R>, name: &Path, written: &HashSet<PathBuf>, dropped_attributes: &mut u64) -> Result<Copied, Box<dyn std::error::Error>> {
+    let header = entry.header().clone();
+    let zip_name = name.to_string_lossy().to_string();
+    let options = zip_options(header.mode()?, header.mtime()?);
+    if entry.pax_extensions()?.is_some_and(|mut records| records.any(|record| record.is_ok_and(|record| record.key().is_ok_and(|key| key.starts_with("SCHILY."))))) {
+        *dropped_attributes += 1;
+    }
+
+    match header.entry_type() {
+        EntryType::Directory => zip.add_directory(zip_name, options)?,
+        EntryType::Symlink => match entry.link_name()? {
+            Some(target) => zip.add_symlink(zip_name, target.to_string_lossy(), options)?,
+            None => return Ok(Err("symlink without a target".to_string())),
+        },
+        //zip has no hard links, the file is stored again under the link's name
+        EntryType::Link => match entry.link_name()? {
+            Some(target) if written.contains(target.as_ref()) => zip.deep_copy_file(&target.to_string_lossy(), &zip_name)?,
+            Some(target) => return Ok(Err(format!("hard link to {}, which isn't included", target.display()))),
+            None => return Ok(Err("hard link without a target".to_string())),
+        },
+        EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+            zip.start_file(zip_name, options.large_file(header.size()? >= u32::MAX as u64))?;
+            io::copy(entry, zip)?;
+        }
+        _ => return Ok(Err("zip can't hold devices, fifos or sockets".to_string())),
+    }
+    Ok(Ok(()))
+}
+
+//zip times are local and in two-second steps, anything before 1980 is stored as 1980
+fn zip_options(mode: u32, mtime: u64) -> SimpleFileOptions {
+    let local = chrono::DateTime::from_timestamp(mtime as i64, 0).unwrap_or_default().with_timezone(&chrono::Local);
+    let time = u16::try_from(local.year())
+        .ok()
+        .and_then(|year| DateTime::from_date_and_time(year, local.month() as u8, local.day() as u8, local.hour() as u8, local.minute() as u8, local.second() as u8).ok())
+        .unwrap_or_default();
+    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated).unix_permissions(mode).last_modified_time(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_take_directories_and_their_contents() {
+        let selection = Selection::new(&["/home/dave/projects/clientX/**".to_string(), "etc/fstab".to_string(), "srv/*.conf".to_string()]).unwrap();
+        assert!(selection.matches(Path::new("home/dave/projects/clientX/src/main.rs")));
+        assert!(!selection.matches(Path::new("home/dave/projects/clientY/src/main.rs")));
+        assert!(selection.matches(Path::new("etc/fstab")));
+        assert!(!selection.matches(Path::new("etc/fstab.bak")));
+        assert!(selection.matches(Path::new("srv/web.conf")));
+        assert!(!selection.matches(Path::new("srv/sites/web.conf")));
+
+        //a directory without a wildcard takes everything below it
+        let directory = Selection::new(&["home/dave/Documents/".to_string()]).unwrap();
+        assert!(directory.matches(Path::new("home/dave/Documents/taxes/2025.pdf")));
+        assert!(!directory.matches(Path::new("home/dave/Documents2/a")));
+        assert!(Selection::new(&[]).unwrap().matches(Path::new("anything")));
+    }
+
+    #[test]
+    fn repacks_keep_modes_links_and_their_parent() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("backup.tar.gz");
+        let mut archive = Builder::new(ArchiveEncoder::new(File::create(&source).unwrap(), ArchiveCompression::new(CompressionFormat::Gzip)).unwrap());
+        sysinfo::append_to_archive(&mut archive, &SystemInfo { hostname: "laptop".to_string(), ..SystemInfo::default() }, 0).unwrap();
+        for (name, data, mode) in [("home/dave/clientX/notes.txt", "notes", 0o600), ("home/dave/other.txt", "other", 0o644)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(mode);
+            header.set_mtime(1_700_000_000);
+            archive.append_data(&mut header, name, data.as_bytes()).unwrap();
+        }
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(EntryType::Link);
+        link.set_mode(0o600);
+        link.set_size(0);
+        link.set_mtime(1_700_000_000);
+        archive.append_link(&mut link, "home/dave/clientX/notes-link.txt", "home/dave/clientX/notes.txt").unwrap();
+        let mut outside = tar::Header::new_gnu();
+        outside.set_entry_type(EntryType::Link);
+        outside.set_size(0);
+        outside.set_mode(0o644);
+        outside.set_mtime(1_700_000_000);
+        archive.append_link(&mut outside, "home/dave/clientX/other-link.txt", "home/dave/other.txt").unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let includes = ["/home/dave/clientX/**".to_string()];
+        let zip_path = temp.path().join("clientX.zip");
+        repack_backup(source.to_str().unwrap(), zip_path.to_str().unwrap(), &includes, &RestoreOptions::default()).unwrap();
+        let mut zip = zip::ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(zip.len(), 3);
+        let mut notes = String::new();
+        io::Read::read_to_string(&mut zip.by_name("home/dave/clientX/notes-link.txt").unwrap(), &mut notes).unwrap();
+        assert_eq!(notes, "notes");
+        assert_eq!(zip.by_name("home/dave/clientX/notes.txt").unwrap().unix_mode().unwrap() & 0o777, 0o600);
+        assert!(zip.by_name("home/dave/other.txt").is_err());
+
+        let tar_path = temp.path().join("clientX.tar.xz");
+        repack_backup(source.to_str().unwrap(), tar_path.to_str().unwrap(), &includes, &RestoreOptions::default()).unwrap();
+        let info = sysinfo::read_from_archive(&tar_path, None).unwrap();
+        assert_eq!(info.hostname, "laptop");
+        assert_eq!(info.repacked_from.unwrap().archive, source.display().to_string());
+        let names: Vec<String> = Archive::new(compression::open_decoder(&tar_path, None).unwrap())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(names, [sysinfo::SYSINFO_ENTRY, "home/dave/clientX/notes.txt", "home/dave/clientX/notes-link.txt"]);
+
+        //an existing archive is never replaced
+        assert!(repack_backup(source.to_str().unwrap(), tar_path.to_str().unwrap(), &includes, &RestoreOptions::default()).is_err());
+    }
+}
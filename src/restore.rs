@@ -1,21 +1,214 @@
+use crate::acl;
+use crate::casefold::{self, CasePlan, CaseCollisionPolicy, Destination};
+use crate::checksums;
+use crate::compression::{self, CompressionFormat};
+use crate::config;
+use crate::conflicts::{self, Conflict, ConflictPlan, ConflictPolicy, Decision};
+use crate::disks;
+use crate::encryption::{self, ArchiveKey};
+use crate::entry_index;
+use crate::manifest::{self, Manifest, ManifestFile};
+use crate::mounts::{self, Filesystem};
+use crate::portability;
+use crate::prompt::{Confirm, Input, Select};
+use crate::services::{self, ServiceRecord};
+use crate::sidecars::{self, Role};
+use crate::status;
+use crate::sysinfo::{self, SystemInfo};
+use crate::throttle::{self, RateLimiter, SyncBatcher, Throttling};
+use crate::users::Accounts;
+use crate::userns::{Namespace, OwnershipLedger, RecordedOwner};
+use crate::utils;
+use crate::xattrs::{self, XattrRestore};
 use colored::*;
 use indicatif::{ProgressBar, ProgressStyle};
-use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+use tar::{Archive, EntryType};
 
-pub fn restore_backup(backup_file: &str, target_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+//an archive found while scanning a directory for backups
+struct ArchiveCandidate {
+    path: PathBuf,
+    modified: SystemTime,
+    size: u64,
+    origin: Option<SystemInfo>,
+    problem: Option<String>,
+    encrypted: bool,
+}
+
+//how a restore treats the target system, gathered from the prompts or the command line
+pub struct RestoreOptions {
+    //skip the check that the backup was made on a similar system
+    pub ignore_origin: bool,
+    //apply archived mode, owner and mtime to directories that already exist
+    pub touch_existing_dirs: bool,
+    //chown entries to the ids their archived user and group names have on this system
+    pub owner_by_name: bool,
+    //owner for entries whose names don't exist here, instead of the archived ids
+    pub fallback_owner: Option<String>,
+    //the target is a system mounted here, owner names and the origin check use its files
+    pub root: Option<PathBuf>,
+    //age identity file for archives encrypted to a public key, passphrases are asked for
+    pub identity: Option<PathBuf>,
+    //extract with the tar binary instead of the built-in extractor
+    pub system_tar: bool,
+    //what happens to archived files that exist in the target already
+    pub conflicts: ConflictPolicy,
+    //give entries their archived owner and exact mode, on by default for root like tar -x
+    pub preserve_owner: bool,
+    //rate limit, background priority and sync batching for restores onto a drive in use
+    pub throttling: Throttling,
+    //archived names that differ only in case, when the target ignores case
+    pub case_collisions: CaseCollisionPolicy,
+    //files an incremental or differential backup recorded as deleted
+    pub deletions: DeletionPolicy,
+    //check the restored files against the archive's checksums afterwards
+    pub verify: bool,
+}
+
+//what happens to files the archive lists as deleted since the backup it builds on
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DeletionPolicy {
+    //leave them in the target, a note says how many there are
+    #[default]
+    Keep,
+    //remove them, so restoring a chain in order doesn't bring back files that were gone
+    Remove,
+    //ask once the files are restored
+    Ask,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        RestoreOptions {
+            ignore_origin: false,
+            touch_existing_dirs: true,
+            owner_by_name: false,
+            fallback_owner: None,
+            root: None,
+            identity: None,
+            system_tar: false,
+            conflicts: ConflictPolicy::default(),
+            preserve_owner: unsafe { libc::geteuid() == 0 },
+            throttling: Throttling::default(),
+            case_collisions: CaseCollisionPolicy::default(),
+            deletions: DeletionPolicy::default(),
+            verify: false,
+        }
+    }
+}
+
+pub fn restore_backup(backup_file: &str, target_dir: &str, options: &RestoreOptions) -> Result<(), Box<dyn std::error::Error>> {
     println!("{}", "\n---- Restore Backup ----".blue().bold());
     println!("Restoring from backup: {} to {}", backup_file, target_dir);
+
+    if options.conflicts == ConflictPolicy::Ask && options.system_tar {
+        return Err("The ask conflict policy needs the built-in extractor, it can't be combined with --use-system-tar".into());
+    }
     
+    //tar would fail on every owner outside the namespace's mapping
+    if options.preserve_owner && options.system_tar && Namespace::current().is_some() {
+        return Err("Restoring owners in a user namespace needs the built-in extractor, drop --use-system-tar or pass --no-preserve-owner".into());
+    }
+
     //validate backup file exists
     if !Path::new(backup_file).exists() {
-        println!("{}", format!("Error: Backup file does not exist: {}", backup_file).red());
-        return Ok(());
+        return Err(format!("Backup file does not exist: {}", backup_file).into());
+    }
+
+    //encrypted archives are unlocked once, every later read decrypts with the same key
+    let key = if encryption::is_encrypted(Path::new(backup_file)) {
+        match unlock_archive(Path::new(backup_file), options)? {
+            Some(key) => Some(key),
+            None => return Err("The archive could not be unlocked, nothing was restored".into()),
+        }
+    } else {
+        None
+    };
+
+    if key.is_none() && CompressionFormat::detect(Path::new(backup_file)).is_none() {
+        return Err(format!("Not a tar archive (plain, gzip, zstd or xz): {}", backup_file).into());
+    }
+
+    let (accounts, current) = match &options.root {
+        Some(root) => (Accounts::of_root(root), SystemInfo::of_root(root, &[])),
+        None => (Accounts::System, SystemInfo::current(&[])),
+    };
+
+    //warn when the archive was made on a different machine
+    let origin = sysinfo::read_from_archive(Path::new(backup_file), key.as_ref());
+    match &origin {
+        Some(origin) => {
+            if !options.ignore_origin && !confirm_origin(origin, &current)? {
+                return Err("Restore cancelled, nothing was restored".into());
+            }
+        }
+        None => println!("{}", "Notice: This archive was not made by linux_backup, restoring from the tar headers only.".yellow()),
     }
     
+    //resolve the fallback owner up front, a typo should stop the restore before anything is written
+    let fallback = match &options.fallback_owner {
+        Some(name) => match fallback_ids(&accounts, name) {
+            Some(ids) => Some(ids),
+            None => return Err(format!("Fallback owner {} does not exist on this system", name).into()),
+        },
+        None => None,
+    };
+    if options.owner_by_name && unsafe { libc::geteuid() } != 0 {
+        println!("{}", "Warning: Mapping owners by name needs root, ownership is left as extracted.".yellow());
+    }
+
+    //tar leaves directories that already exist with whatever create_dir_all or a previous restore gave them
+    //remember their archived metadata so it can be applied after extraction, the built-in extractor sets it as it goes
+    let existing_dirs = if options.touch_existing_dirs && options.system_tar {
+        existing_directories(Path::new(backup_file), Path::new(target_dir), key.as_ref())
+    } else {
+        Vec::new()
+    };
+
+    if let Some(origin) = &origin {
+        warn_filesystem_features(origin, Path::new(target_dir));
+    }
+
     //create target directory if it doesn't exist
     fs::create_dir_all(target_dir)?;
+
+    //Notes.txt and notes.txt would end up as one file, find such names before anything is written
+    let case_plan = match target_ignores_case(Path::new(target_dir)) {
+        true => CasePlan::new(&archived_names(Path::new(backup_file), key.as_ref())?, options.case_collisions, Path::new(target_dir)),
+        false => CasePlan::default(),
+    };
+    if !case_plan.collisions.is_empty() {
+        if options.case_collisions == CaseCollisionPolicy::Abort {
+            println!("{}", format!("The target ignores the case of names, {} archived entries would overwrite an earlier one:", case_plan.collisions.len()).red());
+            for collision in &case_plan.collisions {
+                println!("  {} (collides with {})", collision.name.display(), collision.kept.display());
+            }
+            return Err("Restore aborted, nothing was restored. Use --on-case-collision rename or skip to restore anyway".into());
+        }
+        if options.system_tar {
+            return Err("Case collisions can only be renamed or skipped by the built-in extractor, leave out --use-system-tar".into());
+        }
+        let resolution = match options.case_collisions {
+            CaseCollisionPolicy::Rename => "restored under a new name",
+            _ => "left out",
+        };
+        println!("{}", format!("Note: The target ignores the case of names, {} archived entries collide with an earlier one and are {}.", case_plan.collisions.len(), resolution).yellow());
+    }
+
+    //asking needs every conflict known up front, the other policies decide file by file while extracting
+    let mut plan = ConflictPlan::new(options.conflicts);
+    if options.conflicts == ConflictPolicy::Ask {
+        let conflicts = conflicting_entries(Path::new(backup_file), Path::new(target_dir), key.as_ref(), &case_plan)?;
+        let limit = config::load_config()?.ask_conflict_limit.unwrap_or(conflicts::DEFAULT_ASK_LIMIT);
+        plan.ask(&conflicts, limit, &|name| read_archived_entry(Path::new(backup_file), key.as_ref(), name))?;
+    }
     
     //create progress bar
     let progress = ProgressBar::new_spinner();
@@ -24,22 +217,1890 @@ pub fn restore_backup(backup_file: &str, target_dir: &str) -> Result<(), Box<dyn
         .unwrap());
     progress.set_message("Extracting files...");
     
-    //use tar command for extraction as it handles permissions better than rust libraries
-    let status = Command::new("tar")
-        .arg("-xzf")
-        .arg(backup_file)
-        .arg("-C")
-        .arg(target_dir)
-        .status()?;
-    
-    progress.finish();
-    
-    if status.success() {
-        println!("{}", "Restore completed successfully!".green().bold());
-        println!("Files restored to: {}", target_dir);
+    if options.throttling.background {
+        throttle::run_in_background();
+        println!("Running at background priority.");
+    }
+
+    let started = Instant::now();
+    let mut decoder = RateLimiter::new(compression::open_decoder(Path::new(backup_file), key.as_ref())?, options.throttling.limit_rate);
+    let mut extraction = if options.system_tar {
+        extract_with_tar(decoder, Path::new(target_dir), options)?
     } else {
+        let mut extraction = extract_entries(&mut decoder, Path::new(target_dir), options, &mut plan, &case_plan, &progress);
+        extraction.bytes = decoder.bytes;
+        extraction
+    };
+    let elapsed = started.elapsed();
+
+    progress.finish_and_clear();
+
+    //the tar binary skips the bookkeeping entries, the list is only read again when it is going to be used
+    let deleted = match (options.system_tar, options.deletions) {
+        (true, DeletionPolicy::Keep) => Vec::new(),
+        (true, _) => read_archived_entry(Path::new(backup_file), key.as_ref(), &Path::new(utils::STATE_DIR).join(utils::DELETED_FILES)).unwrap_or_default(),
+        (false, _) => extraction.deleted.clone(),
+    };
+
+    let (dirs_applied, dir_failures) = apply_directory_metadata(&existing_dirs, options.preserve_owner);
+    let dirs_applied = dirs_applied + extraction.existing_dirs;
+
+    //the built-in extractor maps owners as it writes each entry, tar needs a second pass over the archive
+    let owners = if options.owner_by_name && options.system_tar && unsafe { libc::geteuid() } == 0 {
+        Some(apply_owners_by_name(Path::new(backup_file), Path::new(target_dir), key.as_ref(), &accounts, fallback))
+    } else {
+        extraction.owners.take()
+    };
+
+    //the rest of the summary is still printed, the restore fails once it is out
+    let mut failed = None;
+    if let Some(error) = &extraction.read_error {
+        println!("{}", format!("Restore incomplete, the archive could not be read to the end: {}", error).red());
+        println!("Files read before the error were restored to: {}", target_dir);
+        failed = Some(format!("Restore incomplete, the archive could not be read to the end: {}", error));
+    } else if !extraction.failures.is_empty() {
+        println!("{}", format!("Restore finished, but {} entries could not be restored:", extraction.failures.len()).yellow().bold());
+        for (path, reason) in &extraction.failures {
+            println!("  {} ({})", path, reason);
+        }
+        println!("Other files were restored to: {}", target_dir);
+        failed = Some(format!("Restore incomplete, {} entries could not be restored", extraction.failures.len()));
+    } else if let Some((stderr, status)) = &extraction.tar_error {
+        println!("{}", stderr.trim_end());
         println!("{}", format!("Restore failed with exit code: {}", status).red());
+        failed = Some(format!("Restore failed, tar exited with code {}", status));
+    } else {
+        println!("{}", "Restore completed successfully!".green().bold());
+        println!("Files restored to: {}", target_dir);
+    }
+    //the tar binary skips the bookkeeping entries, so only the built-in extractor sees the manifest
+    if let Some(manifest) = &extraction.manifest {
+        println!("Archive: {}", manifest.describe());
+        manifest::warn_partial(&extraction.partial);
+    } else if origin.as_ref().is_some_and(|origin| origin.manifest) && !options.system_tar && extraction.read_error.is_none() {
+        println!("{}", "Warning: The archive ends before its manifest, the backup was cut short and files may be missing from it.".yellow());
+    }
+
+    //effective throughput, so a rate limit can be seen working
+    match options.throttling.limit_rate {
+        Some(rate) => println!("Read {}, limited to {}/s", throttle::describe_throughput(extraction.bytes, elapsed), utils::format_size(rate)),
+        None => println!("Read {}", throttle::describe_throughput(extraction.bytes, elapsed)),
+    }
+    if extraction.syncs > 0 {
+        println!("Synced to disk {} times", extraction.syncs);
+    }
+    if let Some(error) = &extraction.sync_error {
+        println!("{}", format!("Warning: Syncing the restored files to disk failed: {}", error).yellow());
+    }
+
+    //not being allowed to chown is expected without root, the files themselves are there
+    if !extraction.owner_failures.is_empty() {
+        println!("{}", format!("Warning: The archived owner of {} entries could not be restored, they belong to you instead:", extraction.owner_failures.len()).yellow());
+        print_some(&extraction.owner_failures);
+    }
+    if let Some(ledger) = &mut extraction.ownership {
+        ledger.save();
+        ledger.report();
+    }
+
+    if extraction.xattrs.skipped > 0 {
+        println!("{}", format!("Note: {} security and trusted attributes (file capabilities, SELinux labels) were left out, only root can set them.", extraction.xattrs.skipped).yellow());
+    }
+    if !extraction.xattrs.failures.is_empty() {
+        println!("{}", format!("Warning: {} extended attributes could not be restored:", extraction.xattrs.failures.len()).yellow());
+        print_some(&extraction.xattrs.failures);
+    }
+    if extraction.acls_applied > 0 {
+        println!("ACLs restored on {} entries", extraction.acls_applied);
+    }
+    if extraction.acls_unsupported > 0 {
+        println!("{}", format!("Warning: {} ACLs were not restored, the target filesystem doesn't support them.", extraction.acls_unsupported).yellow());
+    }
+    for (path, reason) in &extraction.acl_failures {
+        println!("{}", format!("Warning: Could not restore the ACL of {}: {}", path, reason).yellow());
+    }
+
+    if dirs_applied > 0 {
+        println!("Directory metadata applied to {} existing directories", dirs_applied);
+    }
+    for (path, reason) in &dir_failures {
+        println!("{}", format!("Warning: Could not apply directory metadata to {}: {}", path.display(), reason).yellow());
+    }
+
+    plan.report();
+    case_plan.report();
+    services::describe_for_restore(&extraction.services, Path::new(target_dir));
+    //a restore that stopped early leaves the target as it was for the files that weren't reached
+    if extraction.read_error.is_none() && extraction.tar_error.is_none() {
+        apply_deletions(&deleted, Path::new(target_dir), options.deletions)?;
+    }
+
+    if let Some(owners) = owners {
+        owners.report();
+    }
+    if let Some(failed) = failed {
+        return Err(failed.into());
+    }
+
+    if options.verify {
+        let sums = match (options.system_tar, extraction.sums) {
+            (false, sums) => sums,
+            (true, _) => read_archived_entry(Path::new(backup_file), key.as_ref(), &Path::new(utils::STATE_DIR).join(checksums::SUMS_FILE)).ok(),
+        };
+        match sums {
+            Some(sums) => match checksums::verify_restored(&checksums::parse_sums(&sums), Path::new(target_dir)) {
+                0 => {}
+                failed => return Err(format!("{} restored files don't match the backup", failed).into()),
+            },
+            None => println!("{}", "Warning: This archive has no checksums, the restored files could not be verified.".yellow()),
+        }
     }
     
     Ok(())
-} 
\ No newline at end of file
+}
+
+//what an extraction left behind, from the built-in extractor or the tar binary
+#[derive(Default)]
+struct Extraction {
+    //the archive could not be read to the end, entries before that point were restored
+    read_error: Option<String>,
+    //entries that could not be written, as (path, reason)
+    failures: Vec<(String, String)>,
+    //entries written fine whose archived owner could not be set
+    owner_failures: Vec<(String, String)>,
+    //extended attributes from the archive's PAX records
+    xattrs: XattrRestore,
+    acls_applied: u64,
+    //ACLs left out because the target filesystem has no ACL support
+    acls_unsupported: u64,
+    acl_failures: Vec<(String, String)>,
+    //tar failed without naming entries: its error output and exit status
+    tar_error: Option<(String, String)>,
+    //tar stream bytes read from the archive
+    bytes: u64,
+    syncs: u64,
+    sync_error: Option<String>,
+    //what the backup did with running database services
+    services: Vec<ServiceRecord>,
+    //the archive's list of deleted files, as stored
+    deleted: Vec<u8>,
+    //the archive's file checksums, as stored
+    sums: Option<Vec<u8>>,
+    //owners set, recorded or dropped in a user namespace
+    ownership: Option<OwnershipLedger>,
+    //the last entry, missing when the backup was cut short
+    manifest: Option<Manifest>,
+    //files the manifest lists as only partially read by the backup
+    partial: Vec<ManifestFile>,
+    //directories that were in the target before the restore and got their archived metadata
+    existing_dirs: u64,
+    //how owners were resolved with --owner-by-name
+    owners: Option<OwnerMapping>,
+}
+
+//remove what the archive lists as deleted, only files that are still in the target
+fn apply_deletions(list: &[u8], target_dir: &Path, policy: DeletionPolicy) -> Result<(), Box<dyn std::error::Error>> {
+    //a symlinked directory in the target could lead the removal somewhere else
+    let target = target_dir.canonicalize()?;
+    let present: Vec<PathBuf> = deleted_names(list)
+        .into_iter()
+        .map(|name| target_dir.join(name))
+        .filter(|path| path.symlink_metadata().is_ok_and(|metadata| !metadata.is_dir()))
+        .filter(|path| path.parent().and_then(|parent| parent.canonicalize().ok()).is_some_and(|parent| parent.starts_with(&target)))
+        .collect();
+    if present.is_empty() {
+        return Ok(());
+    }
+
+    let remove = match policy {
+        DeletionPolicy::Keep => {
+            println!("{}", format!("Note: Files deleted before this backup was made, left in the target: {} (restore with --apply-deletions to remove them)", present.len()).yellow());
+            false
+        }
+        DeletionPolicy::Remove => true,
+        DeletionPolicy::Ask => Confirm::new()
+            .with_prompt(format!("Remove the files deleted before this backup was made ({} in the target)?", present.len()))
+            .default(false)
+            .interact()?,
+    };
+    if !remove {
+        return Ok(());
+    }
+
+    let mut failures = Vec::new();
+    for path in &present {
+        if let Err(e) = fs::remove_file(path) {
+            failures.push((path.display().to_string(), e.to_string()));
+        }
+    }
+    println!("Files removed as deleted: {}", present.len() - failures.len());
+    if !failures.is_empty() {
+        println!("{}", format!("Warning: {} files recorded as deleted could not be removed:", failures.len()).yellow());
+        print_some(&failures);
+    }
+    Ok(())
+}
+
+//archived names, one per line, anything that could point outside the target is dropped
+fn deleted_names(list: &[u8]) -> Vec<PathBuf> {
+    list.split(|byte| *byte == b'\n')
+        .map(|line| PathBuf::from(std::ffi::OsStr::from_bytes(line)))
+        .filter(|name| name.components().next().is_some() && name.components().all(|component| matches!(component, std::path::Component::Normal(_))))
+        .collect()
+}
+
+//warnings listed by name before the rest is only counted
+const WARNINGS_SHOWN: usize = 10;
+
+//the descriptor, the state directory and the README are linux_backup's own, never restored into the target
+pub fn is_bookkeeping(name: &Path) -> bool {
+    name.starts_with(sysinfo::SYSINFO_DIR) || name.starts_with(utils::STATE_DIR) || name == Path::new(portability::README_ENTRY)
+}
+
+pub fn print_some(warnings: &[(String, String)]) {
+    for (path, reason) in warnings.iter().take(WARNINGS_SHOWN) {
+        println!("  {} ({})", path, reason);
+    }
+    if warnings.len() > WARNINGS_SHOWN {
+        println!("  ...and {} more", warnings.len() - WARNINGS_SHOWN);
+    }
+}
+
+//a directory's archived mode and mtime, applied once everything inside it is written
+struct PendingDirectory {
+    path: PathBuf,
+    mode: u32,
+    mtime: u64,
+    //in the target before the restore, counted apart from the ones it created
+    existed: bool,
+}
+
+//unpack entry by entry the way tar -x does: owners and setuid bits when preserving owners, the umask otherwise,
+//directory modes and mtimes last so read-only directories and their timestamps survive their contents
+fn extract_entries<R: Read>(decoder: R, target_dir: &Path, options: &RestoreOptions, plan: &mut ConflictPlan, case_plan: &CasePlan, progress: &ProgressBar) -> Extraction {
+    let preserve_owner = options.preserve_owner;
+    let is_root = unsafe { libc::geteuid() == 0 };
+    let accounts = match &options.root {
+        Some(root) => Accounts::of_root(root),
+        None => Accounts::System,
+    };
+    let acls_supported = mounts::filesystem_of(target_dir).is_none_or(|filesystem| filesystem.supports_acls());
+    let umask = if preserve_owner { 0 } else { current_umask() };
+    //--owner-by-name takes the place of the archived ids, chowning to them needs root
+    let by_name = options.owner_by_name && is_root;
+    let fallback = options.fallback_owner.as_deref().and_then(|name| fallback_ids(&accounts, name));
+
+    //owners are set here rather than by the tar crate, so a refused chown doesn't fail the entry
+    let mut archive = Archive::new(decoder);
+    archive.set_preserve_permissions(preserve_owner);
+    archive.set_preserve_ownerships(false);
+    archive.set_mask(umask);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+
+    let mut extraction = Extraction::default();
+    if by_name {
+        extraction.owners = Some(OwnerMapping::default());
+    }
+    if preserve_owner && !by_name {
+        extraction.ownership = Namespace::current().map(|namespace| OwnershipLedger::new(namespace, target_dir));
+    }
+    let mut directories = Vec::new();
+    //unpack_in checks its own paths, everything written elsewhere is checked against this
+    let target = target_dir.canonicalize().unwrap_or_else(|_| target_dir.to_path_buf());
+    let mut batcher = options.throttling.sync.map(|batch| SyncBatcher::new(target_dir, batch));
+
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            extraction.read_error = Some(e.to_string());
+            return extraction;
+        }
+    };
+
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                extraction.read_error = Some(root_cause(&e));
+                break;
+            }
+        };
+
+        let name = match entry.path() {
+            Ok(name) => name.to_path_buf(),
+            Err(e) => {
+                extraction.failures.push((String::from_utf8_lossy(&entry.path_bytes()).to_string(), e.to_string()));
+                continue;
+            }
+        };
+        if name == Path::new(utils::STATE_DIR).join(services::SERVICES_FILE) {
+            extraction.services = serde_json::from_reader(&mut entry).unwrap_or_default();
+        }
+        if name == Path::new(utils::STATE_DIR).join(utils::DELETED_FILES) {
+            let _ = entry.read_to_end(&mut extraction.deleted);
+        }
+        if name == Path::new(utils::STATE_DIR).join(checksums::SUMS_FILE) {
+            let mut data = Vec::new();
+            extraction.sums = entry.read_to_end(&mut data).ok().map(|_| data);
+        }
+        if name == Path::new(utils::STATE_DIR).join(manifest::MANIFEST_FILE) {
+            let mut data = Vec::new();
+            extraction.manifest = entry.read_to_end(&mut data).ok().and_then(|_| manifest::parse(&data));
+            extraction.partial = manifest::partial_files(&data);
+        }
+        if is_bookkeeping(&name) {
+            continue;
+        }
+
+        let renamed = match case_plan.destination(&name) {
+            Destination::Skip => continue,
+            Destination::Renamed(renamed) => Some(contained_path(target_dir, &renamed)),
+            Destination::Unchanged => None,
+        };
+        let path = renamed.clone().unwrap_or_else(|| contained_path(target_dir, &name));
+        let is_dir = entry.header().entry_type().is_dir();
+        //like --no-overwrite-dir, directories already in the target keep their metadata
+        if is_dir && !options.touch_existing_dirs && path.is_dir() {
+            continue;
+        }
+        let existed = is_dir && fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir());
+
+        //unpack doesn't create parents the way unpack_in does, nor check that they stay in the target
+        if let Some(renamed) = &renamed {
+            if !inside_target(&target, renamed) {
+                extraction.failures.push((name.display().to_string(), "outside of the target directory".to_string()));
+                continue;
+            }
+            if let Some(parent) = renamed.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+        }
+
+        //unpacked here instead of at its archived name: a case collision's new name, or a copy next to a local file
+        let mut destination = renamed;
+
+        //files already in the target go by the conflict policy, directories are merged into
+        if !is_dir && fs::symlink_metadata(&path).is_ok_and(|local| !local.is_dir()) {
+            match plan.decide(&name, &path, entry.header().mtime().unwrap_or(0)) {
+                Decision::KeepLocal => continue,
+                Decision::RestoreAsCopy => destination = Some(conflicts::copy_path(&path)),
+                Decision::Restore => {}
+            }
+        }
+
+        //a hard link to a renamed entry, unpack_in would link it to the name that was kept
+        if destination.is_none() && entry.header().entry_type() == EntryType::Link {
+            if let Ok(Some(first)) = entry.link_name() {
+                if case_plan.rename(&first) != first {
+                    destination = Some(path.clone());
+                }
+            }
+        }
+
+        //read before unpacking, the records belong to the entry's header
+        let attrs = match entry.pax_extensions() {
+            Ok(Some(records)) => xattrs::from_pax(records),
+            _ => Vec::new(),
+        };
+        let acls = match entry.pax_extensions() {
+            Ok(Some(records)) => acl::from_pax(records),
+            _ => Vec::new(),
+        };
+
+        progress.set_message(format!("Extracting {}", name.display()));
+        let entry_type = entry.header().entry_type();
+        let special = entry_type.is_fifo() || entry_type.is_character_special() || entry_type.is_block_special();
+        //a directory the archive planted as a symlink earlier could lead these out of the target
+        let escapes = (special || destination.is_some()) && !inside_target(&target, destination.as_ref().unwrap_or(&path));
+        let unpacked = match &destination {
+            _ if escapes => Ok(false),
+            _ if (entry_type.is_character_special() || entry_type.is_block_special()) && !is_root => {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "only root can create device nodes"))
+            }
+            //the tar crate would write these as empty regular files
+            _ if special => {
+                make_special(destination.as_ref().unwrap_or(&path), entry.header(), umask).map(|_| true)
+            }
+            //a hard link's target is relative to the archive root, which unpack alone doesn't know
+            Some(destination) if entry.header().entry_type() == EntryType::Link => match entry.link_name() {
+                Ok(Some(first)) => {
+                    let source = contained_path(target_dir, &case_plan.rename(&first));
+                    if inside_target(&target, &source) {
+                        fs::hard_link(source, destination).map(|_| true)
+                    } else {
+                        Ok(false)
+                    }
+                }
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "hard link without a target")),
+            },
+            Some(destination) => entry.unpack(destination).map(|_| true),
+            None => entry.unpack_in(target_dir),
+        };
+        match unpacked {
+            Ok(true) => {}
+            Ok(false) => {
+                extraction.failures.push((name.display().to_string(), "outside of the target directory".to_string()));
+                continue;
+            }
+            Err(e) => {
+                extraction.failures.push((name.display().to_string(), root_cause(&e)));
+                continue;
+            }
+        }
+
+        if let (Some(batcher), true) = (batcher.as_mut(), entry.header().entry_type().is_file()) {
+            if let Err(e) = batcher.record(entry.size()) {
+                extraction.sync_error = Some(e.to_string());
+            }
+        }
+
+        let header = entry.header();
+        let mode = header.mode().unwrap_or(0o755) & if preserve_owner { 0o7777 } else { 0o777 & !umask };
+        if let Some(mapping) = extraction.owners.as_mut() {
+            let written = destination.as_ref().unwrap_or(&path);
+            let user = resolve_id(header.username().ok().flatten(), header.uid().unwrap_or(0), |name| accounts.uid_by_name(name), fallback.map(|f| f.0));
+            let group = resolve_id(header.groupname().ok().flatten(), header.gid().unwrap_or(0), |name| accounts.gid_by_name(name), fallback.map(|f| f.1));
+            mapping.count(&user, &group);
+            let result = std::os::unix::fs::lchown(written, Some(user.id()), Some(group.id())).and_then(|_| {
+                //chown clears setuid and setgid, so the archived mode goes back on for those
+                let archived = header.mode().unwrap_or(0);
+                match archived & 0o6000 != 0 && header.entry_type().is_file() {
+                    true => fs::set_permissions(written, fs::Permissions::from_mode(archived & 0o7777)),
+                    false => Ok(()),
+                }
+            });
+            if let Err(e) = result {
+                mapping.failures.push((written.clone(), e.to_string()));
+            }
+        } else if preserve_owner {
+            let written = destination.as_ref().unwrap_or(&path);
+            let (uid, gid) = (header.uid().unwrap_or(0), header.gid().unwrap_or(0));
+            //in a user namespace, ids outside its mapping can't be given out and are recorded for --apply-ownership instead
+            let owner = match &extraction.ownership {
+                Some(ledger) if !ledger.namespace.maps(uid, gid) => None,
+                _ => Some(std::os::unix::fs::lchown(written, Some(uid as u32), Some(gid as u32))),
+            };
+            match (owner, &mut extraction.ownership) {
+                //chown drops setuid and setgid bits, so the mode goes back on afterwards
+                (Some(Ok(())), ledger) => {
+                    if header.entry_type().is_file() {
+                        let _ = fs::set_permissions(written, fs::Permissions::from_mode(mode));
+                    }
+                    if let Some(ledger) = ledger {
+                        ledger.mapped += 1;
+                    }
+                }
+                (_, Some(ledger)) => ledger.record(written, RecordedOwner {
+                    path: String::new(),
+                    uid,
+                    gid,
+                    user: header.username().ok().flatten().filter(|user| !user.is_empty()).map(str::to_string),
+                    group: header.groupname().ok().flatten().filter(|group| !group.is_empty()).map(str::to_string),
+                    mode,
+                }),
+                (Some(Err(e)), None) => extraction.owner_failures.push((name.display().to_string(), e.to_string())),
+                (None, None) => {}
+            }
+        }
+
+        //after the chown, which would drop file capabilities again
+        if !attrs.is_empty() && !header.entry_type().is_symlink() {
+            extraction.xattrs.apply(destination.as_ref().unwrap_or(&path), &attrs, is_root);
+        }
+        for (attribute, text) in &acls {
+            if !acls_supported {
+                extraction.acls_unsupported += 1;
+                continue;
+            }
+            match acl::apply(destination.as_ref().unwrap_or(&path), attribute, text, &accounts) {
+                Ok(()) => extraction.acls_applied += 1,
+                Err(e) => extraction.acl_failures.push((name.display().to_string(), e.to_string())),
+            }
+        }
+
+        //a symlink in the directory's place is followed by chmod
+        if is_dir && fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+            //writable for now, whatever the archive says, so the directory's contents can be unpacked
+            let _ = fs::set_permissions(&path, fs::Permissions::from_mode(mode | 0o700));
+            directories.push(PendingDirectory { path, mode, mtime: header.mtime().unwrap_or(0), existed });
+        }
+    }
+
+    //innermost first, setting a parent's mode can't lock out its children anymore
+    for directory in directories.iter().rev() {
+        let result = fs::set_permissions(&directory.path, fs::Permissions::from_mode(directory.mode))
+            .and_then(|_| File::open(&directory.path)?.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(directory.mtime)));
+        match result {
+            Ok(()) if directory.existed => extraction.existing_dirs += 1,
+            Ok(()) => {}
+            Err(e) => extraction.failures.push((directory.path.display().to_string(), e.to_string())),
+        }
+    }
+
+    if let Some(mut batcher) = batcher {
+        if let Err(e) = batcher.finish() {
+            extraction.sync_error = Some(e.to_string());
+        }
+        extraction.syncs = batcher.syncs;
+    }
+
+    extraction
+}
+
+//--use-system-tar: tar works relative to the -C directory, so a long target prefix doesn't count against PATH_MAX
+//the archive is decompressed here and fed to tar on stdin, so zstd and xz archives don't need the tools
+fn extract_with_tar<R: Read + Send + 'static>(mut decoder: R, target_dir: &Path, options: &RestoreOptions) -> Result<Extraction, Box<dyn std::error::Error>> {
+    let conflict_flag = match options.conflicts {
+        ConflictPolicy::Skip => Some("--skip-old-files"),
+        ConflictPolicy::Newer => Some("--keep-newer-files"),
+        ConflictPolicy::Overwrite | ConflictPolicy::Ask => None,
+    };
+
+    let mut child = Command::new("tar")
+        .arg("-xf")
+        .arg("-")
+        .arg("-C")
+        .arg(target_dir)
+        .arg(format!("--exclude={}", sysinfo::SYSINFO_DIR))
+        .arg(format!("--exclude={}", utils::STATE_DIR))
+        //anchored, a file of the same name further down is the user's own
+        .arg("--anchored")
+        .arg(format!("--exclude={}", portability::README_ENTRY))
+        .arg("--no-anchored")
+        .args(if options.touch_existing_dirs { None } else { Some("--no-overwrite-dir") })
+        .args(conflict_flag)
+        .args(if options.preserve_owner { ["--same-owner", "--same-permissions"].as_slice() } else { ["--no-same-owner"].as_slice() })
+        //tar restores only user.* attributes unless told otherwise, the rest needs root anyway
+        .args(["--xattrs", "--acls"])
+        .args(if unsafe { libc::geteuid() == 0 } { Some("--xattrs-include=*") } else { None })
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().ok_or("Could not open tar's standard input")?;
+    let feeder = std::thread::spawn(move || io::copy(&mut decoder, &mut stdin));
+    let output = child.wait_with_output()?;
+
+    //a corrupt compressed stream only shows up on our side of the pipe, tar just sees it end early
+    let (read_error, bytes) = match feeder.join() {
+        Ok(Ok(bytes)) => (None, bytes),
+        Ok(Err(e)) if e.kind() != io::ErrorKind::BrokenPipe => (Some(e.to_string()), 0),
+        Err(_) => (Some("decompression stopped unexpectedly".to_string()), 0),
+        _ => (None, 0),
+    };
+
+    //tar writes everything before it exits, so there is only the final sync to do
+    let (syncs, sync_error) = match options.throttling.sync {
+        Some(batch) => {
+            let mut batcher = SyncBatcher::new(target_dir, batch);
+            (1, batcher.finish().err().map(|e| e.to_string()))
+        }
+        None => (0, None),
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    let (owner_failures, failures) = owner_and_other_failures(&stderr);
+    let tar_error = if output.status.success() || !failures.is_empty() || !owner_failures.is_empty() {
+        None
+    } else {
+        Some((stderr, output.status.to_string()))
+    };
+
+    Ok(Extraction { read_error, failures, owner_failures, tar_error, bytes, syncs, sync_error, ..Extraction::default() })
+}
+
+//mknod like tar -x does for FIFOs and device nodes, replacing whatever non-directory is in the way
+fn make_special(path: &Path, header: &tar::Header, umask: u32) -> io::Result<()> {
+    let entry_type = header.entry_type();
+    let kind = if entry_type.is_fifo() {
+        libc::S_IFIFO
+    } else if entry_type.is_character_special() {
+        libc::S_IFCHR
+    } else {
+        libc::S_IFBLK
+    };
+    let device = libc::makedev(header.device_major()?.unwrap_or(0), header.device_minor()?.unwrap_or(0));
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::symlink_metadata(path).is_ok_and(|existing| !existing.is_dir()) {
+        fs::remove_file(path)?;
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mode = header.mode().unwrap_or(0o644) & 0o7777 & !umask;
+    if unsafe { libc::mknod(c_path.as_ptr(), kind | mode, device) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    //mknod applies the process umask on top
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+
+    //opening a FIFO to set its mtime would block, so it goes by path
+    let mtime = libc::timespec { tv_sec: header.mtime().unwrap_or(0) as libc::time_t, tv_nsec: 0 };
+    if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), [mtime, mtime].as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+//where unpack_in puts an entry: only its normal components count, below the target
+fn contained_path(target_dir: &Path, name: &Path) -> PathBuf {
+    name.components()
+        .filter(|component| matches!(component, std::path::Component::Normal(_)))
+        .fold(target_dir.to_path_buf(), |path, component| path.join(component))
+}
+
+//the tar crate wraps the interesting error, "Permission denied" beats "failed to unpack `...`"
+fn root_cause(error: &io::Error) -> String {
+    let mut cause: &dyn std::error::Error = error;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+//umask can only be read by setting it, so it is put right back
+fn current_umask() -> u32 {
+    unsafe {
+        let mask = libc::umask(0o022);
+        libc::umask(mask);
+        mask as u32
+    }
+}
+
+//a directory entry from the archive that is already present in the target
+struct ExistingDirectory {
+    path: PathBuf,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    mtime: u64,
+}
+
+//archived files whose paths are taken in the target, with what is needed to ask about them
+fn conflicting_entries(backup_file: &Path, target_dir: &Path, key: Option<&ArchiveKey>, case_plan: &CasePlan) -> Result<Vec<Conflict>, Box<dyn std::error::Error>> {
+    let mut archive = Archive::new(compression::open_decoder(backup_file, key)?);
+    let mut conflicts = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+
+        let name = entry.path()?.to_path_buf();
+        if is_bookkeeping(&name) {
+            continue;
+        }
+
+        let path = match case_plan.destination(&name) {
+            Destination::Skip => continue,
+            Destination::Renamed(renamed) => contained_path(target_dir, &renamed),
+            Destination::Unchanged => contained_path(target_dir, &name),
+        };
+        if !fs::symlink_metadata(&path).is_ok_and(|local| !local.is_dir()) {
+            continue;
+        }
+
+        let mut start = Vec::new();
+        (&mut entry).take(8192).read_to_end(&mut start)?;
+        conflicts.push(Conflict {
+            name,
+            path,
+            archived_size: entry.header().size().unwrap_or(0),
+            archived_mtime: entry.header().mtime().unwrap_or(0),
+            archived_text: entry.header().entry_type().is_file() && conflicts::looks_like_text(&start),
+        });
+    }
+
+    Ok(conflicts)
+}
+
+//every archived name with whether it is a directory, in archive order
+fn archived_names(backup_file: &Path, key: Option<&ArchiveKey>) -> Result<Vec<(PathBuf, bool)>, Box<dyn std::error::Error>> {
+    let mut archive = Archive::new(compression::open_decoder(backup_file, key)?);
+    let mut names = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let name = entry.path()?.to_path_buf();
+        if !is_bookkeeping(&name) {
+            names.push((name, entry.header().entry_type().is_dir()));
+        }
+    }
+    Ok(names)
+}
+
+//probed in the target itself, mount types miss case-folding ext4 directories, read-only targets fall back to them
+fn target_ignores_case(target_dir: &Path) -> bool {
+    casefold::is_case_insensitive(target_dir)
+        .unwrap_or_else(|_| mounts::filesystem_of(target_dir).is_some_and(|filesystem| filesystem.case_insensitive()))
+}
+
+//the content of one archived file, for comparing it with the local one
+pub fn read_archived_entry(backup_file: &Path, key: Option<&ArchiveKey>, name: &Path) -> io::Result<Vec<u8>> {
+    //with an entry index the entry is read from where it starts
+    if key.is_none() {
+        if let Some(content) = entry_index::read_entry(backup_file, name) {
+            return content;
+        }
+    }
+    let mut archive = Archive::new(compression::open_decoder(backup_file, key)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == name {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            return Ok(content);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the archive", name.display())))
+}
+
+//directory entries of the archive that already exist below the target directory
+fn existing_directories(backup_file: &Path, target_dir: &Path, key: Option<&ArchiveKey>) -> Vec<ExistingDirectory> {
+    let decoder = match compression::open_decoder(backup_file, key) {
+        Ok(decoder) => decoder,
+        Err(_) => return Vec::new(),
+    };
+
+    //an archive from elsewhere must not get a mode or owner onto a directory outside the target
+    let Ok(target) = target_dir.canonicalize() else {
+        return Vec::new();
+    };
+    let mut archive = Archive::new(decoder);
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut existing = Vec::new();
+    for entry in entries.flatten() {
+        let header = entry.header();
+        if !header.entry_type().is_dir() {
+            continue;
+        }
+
+        let name = match entry.path() {
+            Ok(name) => name.to_path_buf(),
+            Err(_) => continue,
+        };
+        if is_bookkeeping(&name) {
+            continue;
+        }
+
+        let path = contained_path(&target, &name);
+        if !fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) || !inside_target(&target, &path) {
+            continue;
+        }
+
+        existing.push(ExistingDirectory {
+            path,
+            mode: header.mode().unwrap_or(0o755) & 0o7777,
+            uid: header.uid().unwrap_or(0),
+            gid: header.gid().unwrap_or(0),
+            mtime: header.mtime().unwrap_or(0),
+        });
+    }
+
+    existing
+}
+
+//contained_path keeps absolute and `..` names below the target, a symlinked directory on the way could still lead out of it
+//the nearest part of the path that exists has to resolve to somewhere below the target, which is given canonical
+fn inside_target(target: &Path, path: &Path) -> bool {
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir()) && path.canonicalize().is_ok_and(|path| path == target) {
+        return true;
+    }
+    path.ancestors()
+        .skip(1)
+        .find(|ancestor| fs::symlink_metadata(ancestor).is_ok())
+        .and_then(|ancestor| ancestor.canonicalize().ok())
+        .is_some_and(|ancestor| ancestor.starts_with(target))
+}
+
+//set mode, owner (when preserving owners) and mtime on directories that existed before the restore
+fn apply_directory_metadata(dirs: &[ExistingDirectory], preserve_owner: bool) -> (u64, Vec<(PathBuf, String)>) {
+    let mut applied = 0;
+    let mut failures = Vec::new();
+
+    for dir in dirs {
+        let result = (|| -> std::io::Result<()> {
+            if preserve_owner {
+                std::os::unix::fs::chown(&dir.path, Some(dir.uid as u32), Some(dir.gid as u32))?;
+            }
+            fs::set_permissions(&dir.path, fs::Permissions::from_mode(dir.mode))?;
+            File::open(&dir.path)?.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(dir.mtime))
+        })();
+
+        match result {
+            Ok(()) => applied += 1,
+            Err(e) => failures.push((dir.path.clone(), e.to_string())),
+        }
+    }
+
+    (applied, failures)
+}
+
+//how the owners of restored entries were resolved with --owner-by-name
+#[derive(Default, Debug)]
+struct OwnerMapping {
+    by_name: u64,
+    by_number: u64,
+    fallback: u64,
+    failures: Vec<(PathBuf, String)>,
+}
+
+impl OwnerMapping {
+    //an entry counts as mapped by name only when both its user and group were
+    fn count(&mut self, user: &ResolvedId, group: &ResolvedId) {
+        match (user, group) {
+            (ResolvedId::Fallback(_), _) | (_, ResolvedId::Fallback(_)) => self.fallback += 1,
+            (ResolvedId::ByNumber(_), _) | (_, ResolvedId::ByNumber(_)) => self.by_number += 1,
+            _ => self.by_name += 1,
+        }
+    }
+
+    fn report(&self) {
+        println!(
+            "Owners mapped by name: {}, by number: {}, fell back: {}",
+            self.by_name, self.by_number, self.fallback
+        );
+        for (path, reason) in &self.failures {
+            println!("{}", format!("Warning: Could not set the owner of {}: {}", path.display(), reason).yellow());
+        }
+    }
+}
+
+//an archived id, resolved through its name when this system knows that name
+enum ResolvedId {
+    ByName(u32),
+    ByNumber(u32),
+    Fallback(u32),
+}
+
+impl ResolvedId {
+    fn id(&self) -> u32 {
+        match self {
+            ResolvedId::ByName(id) | ResolvedId::ByNumber(id) | ResolvedId::Fallback(id) => *id,
+        }
+    }
+}
+
+//the ids of --fallback-owner, its group defaults to the one with the same number
+fn fallback_ids(accounts: &Accounts, name: &str) -> Option<(u32, u32)> {
+    accounts.uid_by_name(name).map(|uid| (uid, accounts.gid_by_name(name).unwrap_or(uid)))
+}
+
+fn resolve_id(name: Option<&str>, id: u64, lookup: impl Fn(&str) -> Option<u32>, fallback: Option<u32>) -> ResolvedId {
+    match name.filter(|name| !name.is_empty()) {
+        None => ResolvedId::ByNumber(id as u32),
+        Some(name) => match lookup(name) {
+            Some(id) => ResolvedId::ByName(id),
+            None => ResolvedId::Fallback(fallback.unwrap_or(id as u32)),
+        },
+    }
+}
+
+//chown every entry tar restored to the ids its archived user and group names have here, run as root
+fn apply_owners_by_name(backup_file: &Path, target_dir: &Path, key: Option<&ArchiveKey>, accounts: &Accounts, fallback: Option<(u32, u32)>) -> OwnerMapping {
+    let mut mapping = OwnerMapping::default();
+
+    //names come from the archive, root's chown must not reach anything outside the target
+    let Ok(target) = target_dir.canonicalize() else {
+        return mapping;
+    };
+    let mut archive = match compression::open_decoder(backup_file, key) {
+        Ok(decoder) => Archive::new(decoder),
+        Err(_) => return mapping,
+    };
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(_) => return mapping,
+    };
+
+    for mut entry in entries.flatten() {
+        let capability: Vec<_> = match entry.pax_extensions() {
+            Ok(Some(records)) => xattrs::from_pax(records).into_iter().filter(|(name, _)| name == xattrs::CAPABILITY).collect(),
+            _ => Vec::new(),
+        };
+        let header = entry.header();
+        let name = match entry.path() {
+            Ok(name) => name.to_path_buf(),
+            Err(_) => continue,
+        };
+        if is_bookkeeping(&name) {
+            continue;
+        }
+
+        let path = contained_path(&target, &name);
+        if !inside_target(&target, &path) || fs::symlink_metadata(&path).is_err() {
+            continue;
+        }
+
+        let user = resolve_id(header.username().ok().flatten(), header.uid().unwrap_or(0), |name| accounts.uid_by_name(name), fallback.map(|f| f.0));
+        let group = resolve_id(header.groupname().ok().flatten(), header.gid().unwrap_or(0), |name| accounts.gid_by_name(name), fallback.map(|f| f.1));
+
+        mapping.count(&user, &group);
+
+        let result = std::os::unix::fs::lchown(&path, Some(user.id()), Some(group.id())).and_then(|_| {
+            //chown clears setuid and setgid, so put the archived mode back for those
+            let mode = header.mode().unwrap_or(0);
+            if mode & 0o6000 != 0 && !header.entry_type().is_symlink() {
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))?;
+            }
+            //and file capabilities, which live in an attribute
+            capability.iter().try_for_each(|(name, value)| xattr::set(&path, name, value))
+        });
+        if let Err(e) = result {
+            mapping.failures.push((path, e.to_string()));
+        }
+    }
+
+    mapping
+}
+
+//pick out "tar: <path>: Cannot <action>: <reason>" lines as (path, reason)
+fn failed_entries(stderr: &str) -> Vec<(String, String)> {
+    stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("tar: "))
+        .filter_map(|line| line.split_once(": Cannot "))
+        .map(|(path, rest)| {
+            let reason = rest.split_once(": ").map(|(_, reason)| reason).unwrap_or(rest);
+            (path.to_string(), reason.to_string())
+        })
+        .collect()
+}
+
+type Failures = Vec<(String, String)>;
+
+//tar's "Cannot change ownership" complaints apart from entries that weren't restored at all
+fn owner_and_other_failures(stderr: &str) -> (Failures, Failures) {
+    let (owner_lines, other_lines): (Vec<&str>, Vec<&str>) = stderr.lines().partition(|line| line.contains(": Cannot change ownership"));
+    (failed_entries(&owner_lines.join("\n")), failed_entries(&other_lines.join("\n")))
+}
+
+//compare the archive's embedded sysinfo with this machine, returns false if the user backs out
+//ask for the passphrase, or read the identity file, and check it before anything is extracted
+pub fn unlock_archive(backup_file: &Path, options: &RestoreOptions) -> Result<Option<ArchiveKey>, Box<dyn std::error::Error>> {
+    let key = if encryption::is_gpg(backup_file) {
+        println!("This archive is encrypted with GPG, gpg asks for the secret key's passphrase if it needs one.");
+        ArchiveKey::Gpg
+    } else if encryption::uses_passphrase(backup_file)? {
+        println!("This archive is encrypted with a passphrase.");
+        ArchiveKey::Passphrase(encryption::prompt_passphrase(false)?)
+    } else {
+        let identity = match &options.identity {
+            Some(identity) => identity.clone(),
+            None => {
+                println!("This archive is encrypted to an age public key.");
+                let path: String = Input::new()
+                    .with_prompt("Path to the matching age identity file")
+                    .interact_text()?;
+                PathBuf::from(utils::expand_tilde(path.trim()))
+            }
+        };
+        ArchiveKey::Identities(encryption::read_identities(&identity)?)
+    };
+
+    //gpg only fails once it has read the key packets, so a first byte is asked for
+    match encryption::decrypt_file(backup_file, &key).and_then(|mut plain| plain.read(&mut [0u8; 1])) {
+        Ok(_) => Ok(Some(key)),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied && matches!(key, ArchiveKey::Gpg) => {
+            println!("{}", format!("Error: gpg could not decrypt {}, nothing was restored:", backup_file.display()).red());
+            println!("{}", e);
+            Ok(None)
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            println!("{}", format!("Error: Wrong passphrase or key for {}, nothing was restored.", backup_file.display()).red());
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+//"restore lost my ACLs" is usually the target's filesystem, say so before extracting
+fn warn_filesystem_features(origin: &SystemInfo, target_dir: &Path) {
+    let target = match mounts::filesystem_of(target_dir) {
+        Some(target) => target,
+        None => return,
+    };
+
+    let source_with = |feature: fn(&Filesystem) -> bool| origin.filesystems.iter().find(|source| feature(source));
+    let missing = [
+        (source_with(Filesystem::supports_xattrs), target.supports_xattrs(), "extended attributes"),
+        (source_with(Filesystem::supports_acls), target.supports_acls(), "ACLs"),
+    ];
+    for (source, supported, feature) in missing {
+        if let (Some(source), false) = (source, supported) {
+            println!(
+                "{}",
+                format!("Warning: {} came from {} with {}, but the target is on {}, which doesn't keep them.", source.path, source.fs_type, feature, target.describe()).yellow()
+            );
+        }
+    }
+}
+
+//the descriptor embedded in an archive: where, how and from which filesystems it was made
+pub fn show_archive_info(backup_file: &str, options: &RestoreOptions, list_files: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(backup_file);
+    if !path.exists() {
+        return Err(format!("Backup file does not exist: {}", backup_file).into());
+    }
+
+    let key = if encryption::is_encrypted(path) {
+        match unlock_archive(path, options)? {
+            Some(key) => Some(key),
+            None => return Ok(()),
+        }
+    } else {
+        None
+    };
+
+    println!("{}", "\n---- Archive Info ----".blue().bold());
+    println!("File: {} ({})", backup_file, utils::format_size(fs::metadata(path)?.len()));
+
+    let info = match sysinfo::read_from_archive(path, key.as_ref()) {
+        Some(info) => info,
+        None => {
+            println!("{}", "This archive was not made by linux_backup, it has no descriptor.".yellow());
+            return Ok(());
+        }
+    };
+
+    println!("Made on: {} ({}, {})", info.hostname, info.os_name, info.architecture);
+    if let Some(root) = &info.offline_root {
+        println!("Offline system mounted at: {}", root);
+    }
+    if let Some(compression) = &info.compression {
+        println!("Compression: {}", compression);
+    }
+    if let Some(portability) = info.portability {
+        println!("Portability: {}", portability.describe());
+    }
+    if let Some(parent) = &info.repacked_from {
+        if parent.includes.is_empty() {
+            println!("Repacked from: {}", parent.archive);
+        } else {
+            println!("Repacked from: {} (only {})", parent.archive, parent.includes.join(", "));
+        }
+    }
+    if !info.disk_layout.is_empty() {
+        println!("Disk layout: {} (bare-metal capable, see restore --show-disk-layout)", info.disk_layout.join(", "));
+    }
+    println!("Sources:");
+    for source in &info.sources {
+        match info.filesystems.iter().find(|filesystem| &filesystem.path == source) {
+            Some(filesystem) => println!("  {}: {}", source, filesystem.describe()),
+            None => println!("  {}", source),
+        }
+        if let Some(link) = info.symlinked_sources.iter().find(|link| &link.path == source) {
+            println!("    a symlink to {} when backed up, its contents are stored under the link's path", link.target);
+        }
+    }
+
+    //the manifest is the last entry, every header before it is read but nothing is extracted
+    let data = manifest::read_entry(path, key.as_ref());
+    match data.as_deref().and_then(manifest::parse) {
+        Some(manifest) => {
+            println!("Backup: {}", manifest.describe());
+            if let Some(since) = manifest.changes_since {
+                println!("Changes since: {}", status::local_time(since));
+            }
+            if !manifest.exclusions.is_empty() {
+                println!("Exclusions: {}", manifest.exclusions.join(", "));
+            }
+            println!("Made with: linux_backup {}", manifest.tool_version);
+        }
+        None if info.manifest => println!("{}", "The archive ends before its manifest, the backup was cut short and files may be missing from it.".red()),
+        None => {}
+    }
+    manifest::warn_partial(&data.as_deref().map(manifest::partial_files).unwrap_or_default());
+    if list_files && (data.is_some() || !info.manifest) {
+        match data.as_deref().and_then(manifest::parse_files) {
+            Some(files) => {
+                for file in files {
+                    println!("{:>10}  {}", utils::format_size(file.size), file.path);
+                }
+            }
+            None => println!("{}", "This archive has no manifest to list the files from.".yellow()),
+        }
+    }
+
+    Ok(())
+}
+
+//write the disk layout saved with the archive into the target and explain it, nothing is restored or applied
+pub fn show_saved_disk_layout(backup_file: &str, target_dir: &Path, options: &RestoreOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(backup_file);
+    if !path.exists() {
+        return Err(format!("Backup file does not exist: {}", backup_file).into());
+    }
+
+    let key = if encryption::is_encrypted(path) {
+        match unlock_archive(path, options)? {
+            Some(key) => Some(key),
+            None => return Ok(()),
+        }
+    } else {
+        None
+    };
+
+    let files = disks::extract(&mut Archive::new(compression::open_decoder(path, key.as_ref())?), &target_dir.join("disk_layout"))?;
+    if files.is_empty() {
+        return Err(format!("{} has no saved disk layout, full backups save one with --disk-layout", backup_file).into());
+    }
+
+    println!("{}", "\n---- Saved Disk Layout ----".blue().bold());
+    disks::describe(&files);
+    Ok(())
+}
+
+fn confirm_origin(origin: &SystemInfo, current: &SystemInfo) -> Result<bool, Box<dyn std::error::Error>> {
+    let differences = origin.differences(current);
+    if differences.is_empty() {
+        return Ok(true);
+    }
+
+    println!("{}", "\nThis backup was created on a different system:".yellow().bold());
+    println!("  {:<14} {:<32} This system", "", "Backup");
+    for (field, theirs, ours) in &differences {
+        println!("  {:<14} {:<32} {}", field, theirs, ours);
+    }
+    println!();
+
+    if !origin.has_system_paths() {
+        println!("{}", "Notice: The backup only contains home directories, continuing.".yellow());
+        return Ok(true);
+    }
+
+    let proceed = Confirm::new()
+        .with_prompt("The backup contains system directories from another system. Restore anyway?")
+        .default(false)
+        .interact()?;
+
+    Ok(proceed)
+}
+
+//scan a directory for backups and let the user pick one to restore
+pub fn choose_archive(dir: &Path) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut candidates: Vec<ArchiveCandidate> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| inspect_archive(&e.path()))
+        .collect();
+
+    if candidates.is_empty() {
+        println!("{}", format!("No backup archives found in {}", dir.display()).yellow());
+        return Ok(None);
+    }
+
+    //newest first
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.modified));
+
+    let items: Vec<String> = candidates.iter().map(describe_candidate).collect();
+
+    loop {
+        let selection = Select::new()
+            .with_prompt("Select a backup to restore")
+            .default(0)
+            .items(&items)
+            .interact()?;
+
+        let candidate = &candidates[selection];
+        match &candidate.problem {
+            Some(problem) => println!("{}", format!("Cannot restore {}: {}", candidate.path.display(), problem).red()),
+            None => return Ok(Some(candidate.path.to_string_lossy().to_string())),
+        }
+    }
+}
+
+fn describe_candidate(candidate: &ArchiveCandidate) -> String {
+    let name = candidate.path.file_name().unwrap_or_default().to_string_lossy();
+    let date = chrono::DateTime::<chrono::Local>::from(candidate.modified).format("%Y-%m-%d %H:%M");
+    let label = match (&candidate.problem, &candidate.origin) {
+        (Some(problem), _) => problem.clone(),
+        (None, Some(origin)) => {
+            let mut label = format!("{}: {}", origin.hostname, origin.sources.join(", "));
+            if let Some(compression) = &origin.compression {
+                label.push_str(&format!(" ({})", compression));
+            }
+            //archives that can partition a new disk stand out when picking one for a bare-metal restore
+            if !origin.disk_layout.is_empty() {
+                label.push_str(" [disk layout]");
+            }
+            label
+        }
+        (None, None) if candidate.encrypted => "encrypted archive".to_string(),
+        (None, None) => "external archive".to_string(),
+    };
+
+    let line = format!("{:<40} {}  {:>10}  {}", name, date, utils::format_size(candidate.size), label);
+    if candidate.problem.is_some() {
+        line.dimmed().to_string()
+    } else {
+        line
+    }
+}
+
+//recognize backups by extension or gzip/zstd/xz magic bytes, and note why unusable ones can't be restored
+fn inspect_archive(path: &Path) -> Option<ArchiveCandidate> {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+
+    //checksums, indexes, scripts and metadata copies sit next to the archives but aren't any
+    let name = path.file_name()?.to_string_lossy().to_string();
+    let role = sidecars::role_of(&name);
+    if matches!(role, Some(Role::MetadataStub | Role::Checksum | Role::Index | Role::RestoreScript)) {
+        return None;
+    }
+
+    //encrypted archives can't be looked into without their key
+    if encryption::is_encrypted(path) {
+        return Some(ArchiveCandidate {
+            path: path.to_path_buf(),
+            modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            size: metadata.len(),
+            origin: None,
+            problem: (role == Some(Role::Partial)).then(|| Role::Partial.describe().to_string()),
+            encrypted: true,
+        });
+    }
+
+    let has_extension = CompressionFormat::from_name(&name).is_some();
+    let has_magic = CompressionFormat::detect(path).is_some();
+
+    if !has_extension && !has_magic {
+        return None;
+    }
+
+    let problem = if role == Some(Role::Partial) {
+        Some(Role::Partial.describe().to_string())
+    } else if !has_magic {
+        Some("not a tar, gzip, zstd or xz stream".to_string())
+    } else {
+        first_entry_problem(path)
+    };
+
+    Some(ArchiveCandidate {
+        path: path.to_path_buf(),
+        modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        size: metadata.len(),
+        origin: if problem.is_none() { sysinfo::read_from_archive(path, None) } else { None },
+        problem,
+        encrypted: false,
+    })
+}
+
+//a quick sanity check that the archive starts with a readable tar entry
+fn first_entry_problem(path: &Path) -> Option<String> {
+    let decoder = match compression::open_decoder(path, None) {
+        Ok(decoder) => decoder,
+        Err(e) => return Some(format!("unreadable: {}", e)),
+    };
+
+    let mut archive = Archive::new(decoder);
+    let mut entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(e) => return Some(format!("corrupt: {}", e)),
+    };
+
+    match entries.next() {
+        Some(Ok(_)) => None,
+        Some(Err(e)) => Some(format!("corrupt: {}", e)),
+        None => Some("empty archive".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{ArchiveCompression, ArchiveEncoder};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::os::unix::fs::MetadataExt;
+    use tar::EntryType;
+
+    //a gzipped tar with names written into the header as given, the tar crate's own setters refuse absolute and `..` names
+    fn gzip_with_raw_names(path: &Path, entries: &[(&str, EntryType, u32)]) {
+        let mut archive = tar::Builder::new(GzEncoder::new(File::create(path).unwrap(), Compression::default()));
+        for (name, kind, mode) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*kind);
+            header.set_size(0);
+            header.set_mode(*mode);
+            header.set_uid(65534);
+            header.set_gid(65534);
+            header.set_mtime(1_000_000);
+            header.set_cksum();
+            archive.append(&header, std::io::empty()).unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn scanned_directories_list_archives_and_why_some_cant_be_restored() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+
+        let good = dir.join("backup_1.tar.gz");
+        let mut archive = tar::Builder::new(GzEncoder::new(File::create(&good).unwrap(), Compression::default()));
+        let info = SystemInfo { hostname: "laptop".to_string(), sources: vec!["/home/dave".to_string()], ..SystemInfo::default() };
+        sysinfo::append_to_archive(&mut archive, &info, utils::entry_mtime(false)).unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+
+        //gzip magic in front of something that isn't a tar stream, and a name alone without the magic
+        let corrupt = dir.join("backup_2.tar.gz");
+        let mut encoder = GzEncoder::new(File::create(&corrupt).unwrap(), Compression::default());
+        std::io::Write::write_all(&mut encoder, &[0xAB; 700]).unwrap();
+        encoder.finish().unwrap();
+        let renamed = dir.join("backup_3.tar.gz");
+        fs::write(&renamed, "plain text").unwrap();
+
+        //the restore script and unrelated files aren't offered at all
+        fs::write(dir.join("backup_1.restore.sh"), "#!/bin/sh\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not a backup").unwrap();
+        fs::create_dir_all(dir.join("old.tar.gz")).unwrap();
+
+        let mut candidates: Vec<ArchiveCandidate> = fs::read_dir(dir).unwrap().filter_map(|e| inspect_archive(&e.unwrap().path())).collect();
+        candidates.sort_by(|a, b| a.path.cmp(&b.path));
+        let names: Vec<PathBuf> = candidates.iter().map(|c| c.path.clone()).collect();
+        assert_eq!(names, vec![good, corrupt, renamed]);
+
+        assert!(candidates[0].problem.is_none());
+        assert!(describe_candidate(&candidates[0]).contains("laptop: /home/dave"));
+        assert!(candidates[1].problem.as_deref().unwrap().starts_with("corrupt"), "{:?}", candidates[1].problem);
+        assert_eq!(candidates[2].problem.as_deref(), Some("not a tar, gzip, zstd or xz stream"));
+        assert!(describe_candidate(&candidates[2]).contains("not a tar, gzip, zstd or xz stream"));
+    }
+
+    #[test]
+    fn tar_errors_name_the_entries_that_failed() {
+        let stderr = "tar: home/user/deep/file.txt: Cannot open: File name too long\n\
+            tar: home/user/deep/other.txt: Cannot mkdir: No space left on device\n\
+            tar: Exiting with failure status due to previous errors\n";
+        assert_eq!(failed_entries(stderr), vec![
+            ("home/user/deep/file.txt".to_string(), "File name too long".to_string()),
+            ("home/user/deep/other.txt".to_string(), "No space left on device".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn existing_target_directories_get_their_archived_metadata() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source/etc");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("hostname"), "box").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o750)).unwrap();
+        File::open(&source).unwrap().set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)).unwrap();
+
+        let archive_path = temp.path().join("backup.tar.gz");
+        let encoder = GzEncoder::new(File::create(&archive_path).unwrap(), Compression::default());
+        let mut archive = tar::Builder::new(encoder);
+        archive.append_dir_all("etc", &source).unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let target = temp.path().join("target");
+        fs::create_dir_all(target.join("etc")).unwrap();
+        fs::set_permissions(target.join("etc"), fs::Permissions::from_mode(0o777)).unwrap();
+
+        restore_backup(archive_path.to_str().unwrap(), target.to_str().unwrap(), &RestoreOptions { ignore_origin: true, ..RestoreOptions::default() }).unwrap();
+
+        let restored = fs::metadata(target.join("etc")).unwrap();
+        assert_eq!(restored.mode() & 0o7777, 0o750);
+        assert_eq!(restored.mtime(), 1_000_000);
+        assert_eq!(fs::read_to_string(target.join("etc/hostname")).unwrap(), "box");
+
+        //the built-in extractor counts them as it unpacks, tar needs the separate pass
+        fs::set_permissions(target.join("etc"), fs::Permissions::from_mode(0o777)).unwrap();
+        let extraction = extract_entries(flate2::read::GzDecoder::new(File::open(&archive_path).unwrap()), &target, &RestoreOptions::default(), &mut ConflictPlan::default(), &CasePlan::default(), &ProgressBar::hidden());
+        assert_eq!(extraction.existing_dirs, 1);
+        assert_eq!(fs::metadata(target.join("etc")).unwrap().mode() & 0o7777, 0o750);
+
+        fs::set_permissions(target.join("etc"), fs::Permissions::from_mode(0o777)).unwrap();
+        restore_backup(archive_path.to_str().unwrap(), target.to_str().unwrap(), &RestoreOptions { ignore_origin: true, system_tar: true, ..RestoreOptions::default() }).unwrap();
+        assert_eq!(fs::metadata(target.join("etc")).unwrap().mode() & 0o7777, 0o750);
+    }
+
+    #[test]
+    fn existing_directories_outside_the_target_are_left_alone() {
+        let temp = tempfile::tempdir().unwrap();
+        let victim = temp.path().join("outside/victim");
+        fs::create_dir_all(&victim).unwrap();
+        fs::set_permissions(&victim, fs::Permissions::from_mode(0o700)).unwrap();
+        let target = temp.path().join("target");
+        fs::create_dir_all(target.join("docs")).unwrap();
+        std::os::unix::fs::symlink(temp.path().join("outside"), target.join("link")).unwrap();
+
+        let absolute = victim.display().to_string();
+        let archive_path = temp.path().join("crafted.tar.gz");
+        gzip_with_raw_names(&archive_path, &[
+            (&absolute, EntryType::Directory, 0o777),
+            ("../outside/victim", EntryType::Directory, 0o777),
+            ("link/victim", EntryType::Directory, 0o777),
+            ("link", EntryType::Directory, 0o777),
+            ("docs", EntryType::Directory, 0o750),
+        ]);
+
+        let existing = existing_directories(&archive_path, &target, None);
+        assert_eq!(existing.len(), 1);
+        assert!(existing[0].path.ends_with("target/docs"));
+        apply_directory_metadata(&existing, false);
+        assert_eq!(fs::metadata(&victim).unwrap().mode() & 0o7777, 0o700);
+        assert_eq!(fs::metadata(target.join("docs")).unwrap().mode() & 0o7777, 0o750);
+    }
+
+    #[test]
+    fn tar_ownership_errors_are_kept_apart_from_failures() {
+        let stderr = "tar: etc/shadow: Cannot change ownership to uid 0, gid 42: Operation not permitted\n\
+            tar: etc/motd: Cannot open: Permission denied\n\
+            tar: Exiting with failure status due to previous errors\n";
+        let (owner_failures, failures) = owner_and_other_failures(stderr);
+        assert_eq!(owner_failures, vec![("etc/shadow".to_string(), "Operation not permitted".to_string())]);
+        assert_eq!(failures, vec![("etc/motd".to_string(), "Permission denied".to_string())]);
+    }
+
+    #[test]
+    fn read_only_directories_are_filled_and_failed_entries_reported() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o555);
+        header.set_mtime(2_000_000);
+        header.set_uid(unsafe { libc::getuid() } as u64);
+        header.set_gid(unsafe { libc::getgid() } as u64);
+        header.set_size(0);
+        builder.append_data(&mut header, "locked", io::empty()).unwrap();
+        for (name, content) in [("locked/inside.txt", "kept"), ("taken", "lost")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_uid(unsafe { libc::getuid() } as u64);
+            header.set_gid(unsafe { libc::getgid() } as u64);
+            header.set_size(content.len() as u64);
+            builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+        }
+        let tarball = builder.into_inner().unwrap();
+
+        //a non-empty directory where the archive has a file can't be replaced, not even by root
+        let temp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(temp.path().join("taken/child")).unwrap();
+
+        let extraction = extract_entries(tarball.as_slice(), temp.path(), &RestoreOptions::default(), &mut ConflictPlan::default(), &CasePlan::default(), &ProgressBar::hidden());
+        assert!(extraction.read_error.is_none());
+        assert_eq!(extraction.failures.len(), 1);
+        assert_eq!(extraction.failures[0].0, "taken");
+
+        let locked = fs::metadata(temp.path().join("locked")).unwrap();
+        assert_eq!(locked.mode() & 0o777, 0o555);
+        assert_eq!(locked.mtime(), 2_000_000);
+        assert_eq!(fs::read_to_string(temp.path().join("locked/inside.txt")).unwrap(), "kept");
+        fs::set_permissions(temp.path().join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn entries_are_not_written_through_a_planted_symlink() {
+        let temp = tempfile::tempdir().unwrap();
+        let outside = temp.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::set_permissions(&outside, fs::Permissions::from_mode(0o700)).unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "link", &outside).unwrap();
+        //a directory entry of the same name, its mode would go onto what the symlink points to
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_mode(0o777);
+        header.set_size(0);
+        builder.append_data(&mut header, "link", io::empty()).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Fifo);
+        header.set_mode(0o644);
+        header.set_size(0);
+        builder.append_data(&mut header, "link/pipe", io::empty()).unwrap();
+        //the second one is renamed, renamed entries are written without unpack_in's checks
+        for name in ["link/Note.txt", "link/note.txt"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(4);
+            builder.append_data(&mut header, name, "note".as_bytes()).unwrap();
+        }
+        let tarball = builder.into_inner().unwrap();
+
+        let target = temp.path().join("target");
+        fs::create_dir_all(&target).unwrap();
+        let names: Vec<(PathBuf, bool)> = Archive::new(tarball.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .map(|entry| (entry.path().unwrap().to_path_buf(), entry.header().entry_type().is_dir()))
+            .collect();
+        let case_plan = CasePlan::new(&names, CaseCollisionPolicy::Rename, &target);
+        let extraction = extract_entries(tarball.as_slice(), &target, &RestoreOptions::default(), &mut ConflictPlan::default(), &case_plan, &ProgressBar::hidden());
+
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        assert_eq!(fs::metadata(&outside).unwrap().mode() & 0o7777, 0o700);
+        let failed: Vec<&str> = extraction.failures.iter().map(|(name, _)| name.as_str()).collect();
+        assert!(failed.contains(&"link/pipe") && failed.contains(&"link/note.txt"), "{:?}", extraction.failures);
+    }
+
+    #[test]
+    fn archived_owners_and_modes_are_kept_when_preserving() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, mode) in [("bin", 0o4755), ("secret", 0o640)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(mode);
+            header.set_uid(1234);
+            header.set_gid(5678);
+            header.set_mtime(3_000_000);
+            header.set_size(2);
+            builder.append_data(&mut header, name, "ok".as_bytes()).unwrap();
+        }
+        let tarball = builder.into_inner().unwrap();
+
+        let temp = tempfile::tempdir().unwrap();
+        let options = RestoreOptions { preserve_owner: true, ..RestoreOptions::default() };
+        let extraction = extract_entries(tarball.as_slice(), temp.path(), &options, &mut ConflictPlan::default(), &CasePlan::default(), &ProgressBar::hidden());
+        assert!(extraction.failures.is_empty());
+
+        let bin = fs::metadata(temp.path().join("bin")).unwrap();
+        let secret = fs::metadata(temp.path().join("secret")).unwrap();
+        assert_eq!(secret.mode() & 0o7777, 0o640);
+        assert_eq!(secret.mtime(), 3_000_000);
+        if unsafe { libc::geteuid() } == 0 {
+            assert_eq!((secret.uid(), secret.gid()), (1234, 5678));
+            //the setuid bit survives the chown
+            assert_eq!(bin.mode() & 0o7777, 0o4755);
+        } else {
+            //without root the files are written anyway and the refused chowns are collected
+            assert_eq!(extraction.owner_failures.len(), 2);
+        }
+    }
+
+    #[test]
+    fn extended_attributes_survive_a_round_trip() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("tagged.txt");
+        fs::write(&source, "tagged").unwrap();
+        //some temp filesystems don't take user attributes, there is nothing to test there
+        if xattr::set(&source, "user.origin", b"camera").is_err() {
+            return;
+        }
+
+        let mut archive = tar::Builder::new(Vec::new());
+        let mut appender = crate::appender::FileAppender::new(false).with_xattrs(true);
+        appender.append_file(&mut archive, Path::new("tagged.txt"), &source, &mut File::open(&source).unwrap()).unwrap();
+        let tarball = archive.into_inner().unwrap();
+
+        let target = temp.path().join("target");
+        fs::create_dir_all(&target).unwrap();
+        let extraction = extract_entries(tarball.as_slice(), &target, &RestoreOptions::default(), &mut ConflictPlan::default(), &CasePlan::default(), &ProgressBar::hidden());
+        assert!(extraction.failures.is_empty() && extraction.xattrs.failures.is_empty());
+        assert_eq!(extraction.xattrs.applied, 1);
+        assert_eq!(xattr::get(target.join("tagged.txt"), "user.origin").unwrap(), Some(b"camera".to_vec()));
+    }
+
+    #[test]
+    fn hard_links_are_stored_once_and_restored_as_links() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("cur")).unwrap();
+        fs::write(source.join("cur/a"), "message").unwrap();
+        fs::hard_link(source.join("cur/a"), source.join("cur/b")).unwrap();
+        fs::hard_link(source.join("cur/a"), source.join("c")).unwrap();
+
+        let mut archive = tar::Builder::new(Vec::new());
+        let mut appender = crate::appender::FileAppender::new(false);
+        for name in ["cur/a", "cur/b", "c"] {
+            let path = source.join(name);
+            appender.append_file(&mut archive, Path::new(name), &path, &mut File::open(&path).unwrap()).unwrap();
+        }
+        let tarball = archive.into_inner().unwrap();
+
+        let types: Vec<EntryType> = Archive::new(tarball.as_slice()).entries().unwrap().map(|e| e.unwrap().header().entry_type()).collect();
+        assert_eq!(types, vec![EntryType::Regular, EntryType::Link, EntryType::Link]);
+
+        let target = temp.path().join("target");
+        fs::create_dir_all(&target).unwrap();
+        let extraction = extract_entries(tarball.as_slice(), &target, &RestoreOptions::default(), &mut ConflictPlan::default(), &CasePlan::default(), &ProgressBar::hidden());
+        assert!(extraction.failures.is_empty());
+
+        use std::os::unix::fs::MetadataExt;
+        let first = fs::metadata(target.join("cur/a")).unwrap();
+        assert_eq!(first.nlink(), 3);
+        assert_eq!(fs::metadata(target.join("c")).unwrap().ino(), first.ino());
+        assert_eq!(fs::read_to_string(target.join("cur/b")).unwrap(), "message");
+    }
+
+    #[test]
+    fn case_collisions_are_restored_under_their_new_names() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, content) in [("docs/Notes.txt", "first"), ("docs/notes.txt", "second"), ("Photos", "file")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(content.len() as u64);
+            builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        builder.append_data(&mut header, "photos", io::empty()).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(3);
+        builder.append_data(&mut header, "photos/cat.jpg", "cat".as_bytes()).unwrap();
+        //a second name of the renamed file
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Link);
+        header.set_mode(0o644);
+        header.set_size(0);
+        builder.append_link(&mut header, "docs/copy.txt", "docs/notes.txt").unwrap();
+        let tarball = builder.into_inner().unwrap();
+
+        //planned as if the target ignored case, extracted onto one that doesn't so both names can be checked
+        let temp = tempfile::tempdir().unwrap();
+        let names: Vec<(PathBuf, bool)> = Archive::new(tarball.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .map(|entry| (entry.path().unwrap().to_path_buf(), entry.header().entry_type().is_dir()))
+            .collect();
+        let case_plan = CasePlan::new(&names, CaseCollisionPolicy::Rename, temp.path());
+        assert_eq!(case_plan.collisions.len(), 2);
+
+        let extraction = extract_entries(tarball.as_slice(), temp.path(), &RestoreOptions::default(), &mut ConflictPlan::default(), &case_plan, &ProgressBar::hidden());
+        assert!(extraction.failures.is_empty());
+        assert_eq!(fs::read_to_string(temp.path().join("docs/Notes.txt")).unwrap(), "first");
+        assert_eq!(fs::read_to_string(temp.path().join("docs/notes (case 2).txt")).unwrap(), "second");
+        assert_eq!(fs::read_to_string(temp.path().join("docs/copy.txt")).unwrap(), "second");
+        assert_eq!(fs::read_to_string(temp.path().join("photos (case 2)/cat.jpg")).unwrap(), "cat");
+        assert!(!temp.path().join("docs/notes.txt").exists() && !temp.path().join("photos").exists());
+    }
+
+    #[test]
+    fn fifos_and_device_nodes_are_recreated() {
+        let temp = tempfile::tempdir().unwrap();
+        let pipe = temp.path().join("pipe");
+        assert!(Command::new("mkfifo").arg("-m").arg("0640").arg(&pipe).status().unwrap().success());
+
+        //nobody writes to the FIFO, opening it would hang the test
+        let mut archive = tar::Builder::new(Vec::new());
+        let mut appender = crate::appender::FileAppender::new(false);
+        assert!(appender.append_special(&mut archive, Path::new("pipe"), &pipe).unwrap());
+        assert!(appender.append_special(&mut archive, Path::new("null"), Path::new("/dev/null")).unwrap());
+        let tarball = archive.into_inner().unwrap();
+
+        let target = temp.path().join("target");
+        fs::create_dir_all(&target).unwrap();
+        let extraction = extract_entries(tarball.as_slice(), &target, &RestoreOptions::default(), &mut ConflictPlan::default(), &CasePlan::default(), &ProgressBar::hidden());
+
+        use std::os::unix::fs::FileTypeExt;
+        let restored = fs::symlink_metadata(target.join("pipe")).unwrap();
+        assert!(restored.file_type().is_fifo());
+        assert_eq!(restored.mode() & 0o777, 0o640 & !current_umask());
+        if unsafe { libc::geteuid() } == 0 {
+            assert!(extraction.failures.is_empty());
+            let null = fs::symlink_metadata(target.join("null")).unwrap();
+            assert!(null.file_type().is_char_device());
+            assert_eq!(null.rdev(), fs::metadata("/dev/null").unwrap().rdev());
+        } else {
+            assert_eq!(extraction.failures, vec![("null".to_string(), "only root can create device nodes".to_string())]);
+        }
+    }
+
+    #[test]
+    fn existing_files_follow_the_conflict_policy() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("kept.txt"), "archived").unwrap();
+        fs::write(source.join("new.txt"), "archived").unwrap();
+
+        let archive_path = temp.path().join("backup.tar.gz");
+        let encoder = GzEncoder::new(File::create(&archive_path).unwrap(), Compression::default());
+        let mut archive = tar::Builder::new(encoder);
+        archive.append_dir_all("docs", &source).unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let target = temp.path().join("target");
+        fs::create_dir_all(target.join("docs")).unwrap();
+        fs::write(target.join("docs/kept.txt"), "local").unwrap();
+
+        let options = RestoreOptions { ignore_origin: true, conflicts: ConflictPolicy::Skip, ..RestoreOptions::default() };
+        restore_backup(archive_path.to_str().unwrap(), target.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read_to_string(target.join("docs/kept.txt")).unwrap(), "local");
+        assert_eq!(fs::read_to_string(target.join("docs/new.txt")).unwrap(), "archived");
+
+        let conflicts = conflicting_entries(&archive_path, &target, None, &CasePlan::default()).unwrap();
+        assert_eq!(conflicts.len(), 2);
+        assert!(conflicts.iter().all(|conflict| conflict.archived_text && conflict.archived_size == 8));
+        assert_eq!(read_archived_entry(&archive_path, None, Path::new("docs/kept.txt")).unwrap(), b"archived");
+    }
+
+    //archives made with plain "tar czf" have no sysinfo entry and may use ./ prefixes
+    #[test]
+    fn gnu_tar_archives_are_listed_and_restored() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs/notes.txt"), "notes").unwrap();
+
+        let archive_path = temp.path().join("foreign.tar.gz");
+        let status = Command::new("tar")
+            .arg("czf")
+            .arg(&archive_path)
+            .arg("-C")
+            .arg(&source)
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let candidate = inspect_archive(&archive_path).unwrap();
+        assert!(candidate.problem.is_none());
+        assert!(candidate.origin.is_none());
+        assert!(describe_candidate(&candidate).contains("external archive"));
+
+        let target = temp.path().join("target");
+        fs::create_dir_all(target.join("docs")).unwrap();
+        assert_eq!(existing_directories(&archive_path, &target, None).len(), 2);
+
+        restore_backup(archive_path.to_str().unwrap(), target.to_str().unwrap(), &RestoreOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(target.join("docs/notes.txt")).unwrap(), "notes");
+    }
+
+    #[test]
+    fn owners_are_mapped_by_name_with_numeric_fallback() {
+        //changing owners needs root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let temp = tempfile::tempdir().unwrap();
+        let archive_path = temp.path().join("owners.tar.gz");
+        let mut archive = tar::Builder::new(GzEncoder::new(File::create(&archive_path).unwrap(), Compression::default()));
+        for (name, user, uid) in [("known", Some("root"), 4242), ("unknown", Some("no-such-user-for-linux-backup"), 4343), ("numeric", None, 4444)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_uid(uid);
+            header.set_gid(uid);
+            if let Some(user) = user {
+                header.set_username(user).unwrap();
+                header.set_groupname(user).unwrap();
+            }
+            archive.append_data(&mut header, name, std::io::empty()).unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let target = temp.path().join("target");
+        fs::create_dir_all(&target).unwrap();
+        Command::new("tar").arg("-xzf").arg(&archive_path).arg("-C").arg(&target).status().unwrap();
+
+        let mapping = apply_owners_by_name(&archive_path, &target, None, &Accounts::System, None);
+        assert_eq!((mapping.by_name, mapping.by_number, mapping.fallback), (1, 1, 1));
+        assert_eq!(fs::metadata(target.join("known")).unwrap().uid(), 0);
+        assert_eq!(fs::metadata(target.join("unknown")).unwrap().uid(), 4343);
+        assert_eq!(fs::metadata(target.join("numeric")).unwrap().uid(), 4444);
+
+        let mapping = apply_owners_by_name(&archive_path, &target, None, &Accounts::System, Some((1, 1)));
+        assert_eq!(mapping.fallback, 1);
+        assert_eq!(fs::metadata(target.join("unknown")).unwrap().uid(), 1);
+
+        //the built-in extractor maps them while it writes the entries
+        let extracted = temp.path().join("extracted");
+        let options = RestoreOptions { ignore_origin: true, owner_by_name: true, fallback_owner: Some("root".to_string()), ..RestoreOptions::default() };
+        fs::create_dir_all(&extracted).unwrap();
+        let mut extraction = extract_entries(flate2::read::GzDecoder::new(File::open(&archive_path).unwrap()), &extracted, &options, &mut ConflictPlan::default(), &CasePlan::default(), &ProgressBar::hidden());
+        let mapping = extraction.owners.take().unwrap();
+        assert_eq!((mapping.by_name, mapping.by_number, mapping.fallback), (1, 1, 1));
+        assert_eq!(fs::metadata(extracted.join("known")).unwrap().uid(), 0);
+        assert_eq!(fs::metadata(extracted.join("unknown")).unwrap().uid(), 0);
+        assert_eq!(fs::metadata(extracted.join("numeric")).unwrap().uid(), 4444);
+    }
+
+    #[test]
+    fn zstd_archives_are_listed_and_restored() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("notes.txt"), "notes").unwrap();
+
+        let archive_path = temp.path().join("backup.tar.zst");
+        let compression = ArchiveCompression::new(CompressionFormat::Zstd);
+        let mut archive = tar::Builder::new(ArchiveEncoder::new(File::create(&archive_path).unwrap(), compression).unwrap());
+        archive.append_dir_all("docs", &source).unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+
+        assert!(inspect_archive(&archive_path).unwrap().problem.is_none());
+
+        let target = temp.path().join("target");
+        restore_backup(archive_path.to_str().unwrap(), target.to_str().unwrap(), &RestoreOptions { ignore_origin: true, ..RestoreOptions::default() }).unwrap();
+        assert_eq!(fs::read_to_string(target.join("docs/notes.txt")).unwrap(), "notes");
+    }
+
+    #[test]
+    fn owners_by_name_stay_inside_the_target() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let temp = tempfile::tempdir().unwrap();
+        let outside = temp.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("victim"), "keep").unwrap();
+        fs::set_permissions(outside.join("victim"), fs::Permissions::from_mode(0o644)).unwrap();
+        let target = temp.path().join("target");
+        fs::create_dir_all(&target).unwrap();
+        std::os::unix::fs::symlink(&outside, target.join("link")).unwrap();
+
+        let absolute = outside.join("victim").display().to_string();
+        let archive_path = temp.path().join("crafted.tar.gz");
+        gzip_with_raw_names(&archive_path, &[(&absolute, EntryType::Regular, 0o4755), ("../outside/victim", EntryType::Regular, 0o4755), ("link/victim", EntryType::Regular, 0o4755)]);
+
+        let mapping = apply_owners_by_name(&archive_path, &target, None, &Accounts::System, None);
+        assert_eq!(mapping.by_name + mapping.by_number + mapping.fallback, 0);
+        let victim = fs::metadata(outside.join("victim")).unwrap();
+        assert_eq!((victim.uid(), victim.mode() & 0o7777), (0, 0o644));
+    }
+
+    #[test]
+    fn encrypted_archives_restore_with_their_identity_only() {
+        use crate::encryption::{ArchiveOutput, Encryption};
+        use age::secrecy::ExposeSecret;
+
+        let temp = tempfile::tempdir().unwrap();
+        let identity = age::x25519::Identity::generate();
+        let identity_file = temp.path().join("key.txt");
+        fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+
+        let archive_path = temp.path().join("backup.tar.gz.age");
+        let output = ArchiveOutput::new(File::create(&archive_path).unwrap(), Some(&Encryption::Recipients(vec![identity.to_public()]))).unwrap();
+        let mut archive = tar::Builder::new(ArchiveEncoder::new(output, ArchiveCompression::default()).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(6);
+        header.set_mode(0o644);
+        archive.append_data(&mut header, "docs/secret.txt", "secret".as_bytes()).unwrap();
+        archive.into_inner().unwrap().finish().unwrap().finish().unwrap();
+
+        assert!(inspect_archive(&archive_path).unwrap().encrypted);
+
+        //a key that doesn't match stops the restore before anything is extracted
+        let other_key = temp.path().join("other.txt");
+        fs::write(&other_key, age::x25519::Identity::generate().to_string().expose_secret()).unwrap();
+        let wrong_target = temp.path().join("wrong");
+        assert!(restore_backup(archive_path.to_str().unwrap(), wrong_target.to_str().unwrap(), &RestoreOptions { identity: Some(other_key), ..RestoreOptions::default() }).is_err());
+        assert!(!wrong_target.exists());
+
+        let target = temp.path().join("target");
+        restore_backup(archive_path.to_str().unwrap(), target.to_str().unwrap(), &RestoreOptions { identity: Some(identity_file), ..RestoreOptions::default() }).unwrap();
+        assert_eq!(fs::read_to_string(target.join("docs/secret.txt")).unwrap(), "secret");
+    }
+}
@@ -0,0 +1,300 @@
+use crate::compression::CompressionFormat;
+use crate::sidecars;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+//everything the script needs to know about how the archive was written
+pub struct ScriptOptions {
+    //shell command that writes the decompressed tar stream of "$ARCHIVE" to stdout
+    pub decompress: String,
+    //tools the recovery environment must provide
+    pub requires: Vec<String>,
+}
+
+impl ScriptOptions {
+    pub fn gzip() -> Self {
+        ScriptOptions {
+            decompress: "gzip -dc \"$ARCHIVE\"".to_string(),
+            requires: vec!["tar".to_string(), "gzip".to_string(), "sha256sum".to_string()],
+        }
+    }
+
+    pub fn zstd() -> Self {
+        ScriptOptions {
+            decompress: "zstd -dc \"$ARCHIVE\"".to_string(),
+            requires: vec!["tar".to_string(), "zstd".to_string(), "sha256sum".to_string()],
+        }
+    }
+
+    pub fn xz() -> Self {
+        ScriptOptions {
+            decompress: "xz -dc \"$ARCHIVE\"".to_string(),
+            requires: vec!["tar".to_string(), "xz".to_string(), "sha256sum".to_string()],
+        }
+    }
+
+    //a plain tar is piped through unchanged
+    pub fn store() -> Self {
+        ScriptOptions {
+            decompress: "cat \"$ARCHIVE\"".to_string(),
+            requires: vec!["tar".to_string(), "sha256sum".to_string()],
+        }
+    }
+
+    pub fn for_format(format: CompressionFormat) -> Self {
+        match format {
+            CompressionFormat::Gzip => ScriptOptions::gzip(),
+            CompressionFormat::Zstd => ScriptOptions::zstd(),
+            CompressionFormat::Xz => ScriptOptions::xz(),
+            CompressionFormat::Store => ScriptOptions::store(),
+        }
+    }
+
+    //decrypt with the age tool first, it asks for the passphrase, AGE_IDENTITY=key.txt picks an identity file
+    pub fn encrypted(self) -> Self {
+        self.decrypted_with("age -d ${AGE_IDENTITY:+-i} ${AGE_IDENTITY:+\"$AGE_IDENTITY\"} \"$ARCHIVE\"", "age")
+    }
+
+    //gpg picks the secret key from the keyring and asks for its passphrase
+    pub fn gpg_encrypted(self) -> Self {
+        self.decrypted_with("gpg --quiet --decrypt \"$ARCHIVE\"", "gpg")
+    }
+
+    fn decrypted_with(self, decrypt: &str, tool: &str) -> Self {
+        let decompress = match self.decompress.replace(" \"$ARCHIVE\"", "").as_str() {
+            "cat" => decrypt.to_string(),
+            stdin_decompress => format!("{} | {}", decrypt, stdin_decompress),
+        };
+
+        let mut requires = self.requires;
+        requires.insert(1, tool.to_string());
+        ScriptOptions { decompress, requires }
+    }
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+//quote a value for use inside a POSIX shell script
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+//names shown in comments, a newline in one would end the comment and run the rest
+fn comment_safe(value: &str) -> String {
+    value.chars().map(|c| if c.is_control() { '?' } else { c }).collect()
+}
+
+pub fn render_restore_script(archive_name: &str, checksum: &str, options: &ScriptOptions) -> String {
+    format!(
+r#"#!/bin/sh
+# Restore script for {archive_comment}
+# Generated by linux_backup on {date}.
+# Works without linux_backup installed, it only needs: {requires}
+#
+# Usage: ./{script_name} [list|verify|extract] [target-dir]
+#   list     show the archive contents
+#   verify   check the archive against the checksum recorded at backup time
+#   extract  verify, then extract into target-dir (default: current directory)
+#
+# Set ARCHIVE=/path/to/{archive_comment} if the archive is not next to this script.
+set -eu
+# a failed decryption or decompression fails the pipeline, shells without pipefail (dash) rely on from_archive's check
+if (set -o pipefail) 2>/dev/null; then
+    set -o pipefail
+fi
+
+ARCHIVE_NAME={archive_quoted}
+SHA256={checksum_quoted}
+SCRIPT_DIR=$(CDPATH= cd -- "$(dirname -- "$0")" && pwd)
+ARCHIVE="${{ARCHIVE:-$SCRIPT_DIR/$ARCHIVE_NAME}}"
+
+decompress() {{
+    {decompress}
+}}
+
+# feeds the tar stream to a command, tar alone would call a cut off stream a success
+from_archive() {{
+    FAILED=$(mktemp)
+    trap 'rm -f "$FAILED"' EXIT
+    {{ decompress || echo "$?" > "$FAILED"; }} | "$@"
+    if [ -s "$FAILED" ]; then
+        echo "Could not read all of $ARCHIVE" >&2
+        exit 1
+    fi
+}}
+
+# the file is read from stdin, so no name reaches sha256sum's own parsing
+verify() {{
+    echo "Verifying $ARCHIVE"
+    ACTUAL=$(sha256sum < "$ARCHIVE")
+    ACTUAL=${{ACTUAL%% *}}
+    if [ "$ACTUAL" != "$SHA256" ]; then
+        echo "$ARCHIVE: checksum mismatch, expected $SHA256 but got $ACTUAL" >&2
+        exit 1
+    fi
+    echo "$ARCHIVE: OK"
+}}
+
+case "${{1:-extract}}" in
+    list)
+        from_archive tar -tvf -
+        ;;
+    verify)
+        verify
+        ;;
+    extract)
+        TARGET="${{2:-.}}"
+        verify
+        mkdir -p -- "$TARGET"
+        from_archive tar -xpf - -C "$TARGET"
+        echo "Restored to $TARGET"
+        ;;
+    *)
+        echo "Usage: $0 [list|verify|extract] [target-dir]" >&2
+        exit 2
+        ;;
+esac
+"#,
+        archive_comment = comment_safe(archive_name),
+        date = chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        requires = options.requires.join(", "),
+        script_name = comment_safe(&sidecars::restore_script(Path::new(archive_name)).display().to_string()),
+        archive_quoted = shell_quote(archive_name),
+        checksum_quoted = shell_quote(checksum),
+        decompress = options.decompress,
+    )
+}
+
+//write the restore script next to the archive and make it executable
+pub fn write_restore_script(archive_path: &Path, options: &ScriptOptions) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let archive_name = archive_path
+        .file_name()
+        .ok_or("Archive path has no file name")?
+        .to_string_lossy()
+        .to_string();
+    let checksum = sha256_file(archive_path)?;
+
+    let script_path = sidecars::restore_script(archive_path);
+    fs::write(&script_path, render_restore_script(&archive_name, &checksum, options))?;
+    fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))?;
+
+    Ok(script_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::process::Command;
+
+    #[test]
+    fn script_path_replaces_archive_extension() {
+        assert_eq!(sidecars::restore_script(Path::new("/b/backup_1.tar.gz")), PathBuf::from("/b/backup_1.restore.sh"));
+        assert_eq!(sidecars::restore_script(Path::new("/b/backup_2.tar.zst")), PathBuf::from("/b/backup_2.restore.sh"));
+        assert_eq!(sidecars::restore_script(Path::new("/b/backup_3.tar")), PathBuf::from("/b/backup_3.restore.sh"));
+        assert_eq!(sidecars::restore_script(Path::new("/b/backup_4.tar.gz.age")), PathBuf::from("/b/backup_4.restore.sh"));
+        assert_eq!(ScriptOptions::gzip().encrypted().decompress, "age -d ${AGE_IDENTITY:+-i} ${AGE_IDENTITY:+\"$AGE_IDENTITY\"} \"$ARCHIVE\" | gzip -dc");
+        assert_eq!(sidecars::restore_script(Path::new("/b/backup_5.tar.zst.gpg")), PathBuf::from("/b/backup_5.restore.sh"));
+        assert_eq!(ScriptOptions::store().gpg_encrypted().decompress, "gpg --quiet --decrypt \"$ARCHIVE\"");
+        assert_eq!(sidecars::restore_script(Path::new("odd name")), PathBuf::from("odd name.restore.sh"));
+    }
+
+    #[test]
+    fn generated_script_round_trips_an_archive() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("nested")).unwrap();
+        fs::write(source.join("hello.txt"), "hello").unwrap();
+        fs::write(source.join("nested/it's.txt"), "quoted").unwrap();
+
+        let archive_path = temp.path().join("backup_test.tar.gz");
+        let encoder = GzEncoder::new(File::create(&archive_path).unwrap(), Compression::default());
+        let mut archive = tar::Builder::new(encoder);
+        archive.append_dir_all("source", &source).unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let script = write_restore_script(&archive_path, &ScriptOptions::gzip()).unwrap();
+        let target = temp.path().join("restored");
+
+        let status = Command::new("sh")
+            .arg(&script)
+            .arg("extract")
+            .arg(&target)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        assert_eq!(fs::read_to_string(target.join("source/hello.txt")).unwrap(), "hello");
+        assert_eq!(fs::read_to_string(target.join("source/nested/it's.txt")).unwrap(), "quoted");
+    }
+
+    fn gzip_fixture(archive_path: &Path, source: &Path) {
+        let encoder = GzEncoder::new(File::create(archive_path).unwrap(), Compression::default());
+        let mut archive = tar::Builder::new(encoder);
+        archive.append_dir_all("source", source).unwrap();
+        archive.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn odd_archive_names_stay_quoted() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("hello.txt"), "hello").unwrap();
+
+        //a newline would end the comment it is shown in, the rest would run as a command
+        let archive_path = temp.path().join("it's $HOME `x`\ntouch pwned; backup.tar.gz");
+        gzip_fixture(&archive_path, &source);
+        let script = write_restore_script(&archive_path, &ScriptOptions::gzip()).unwrap();
+        let target = temp.path().join("-restored here");
+
+        let status = Command::new("sh").arg(&script).arg("extract").arg(&target).current_dir(temp.path()).status().unwrap();
+        assert!(status.success());
+        assert_eq!(fs::read_to_string(target.join("source/hello.txt")).unwrap(), "hello");
+        assert!(!temp.path().join("pwned").exists());
+    }
+
+    #[test]
+    fn a_cut_off_archive_fails_the_extraction() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("hello.txt"), "hello").unwrap();
+
+        //without the gzip trailer the whole tar stream still comes out, only gzip notices
+        let archive_path = temp.path().join("backup_cut.tar.gz");
+        gzip_fixture(&archive_path, &source);
+        let data = fs::read(&archive_path).unwrap();
+        fs::write(&archive_path, &data[..data.len() - 8]).unwrap();
+        let script = write_restore_script(&archive_path, &ScriptOptions::gzip()).unwrap();
+
+        let status = Command::new("sh").arg(&script).arg("extract").arg(temp.path().join("restored")).status().unwrap();
+        assert!(!status.success());
+    }
+
+    #[test]
+    fn generated_script_rejects_a_modified_archive() {
+        let temp = tempfile::tempdir().unwrap();
+        let archive_path = temp.path().join("backup_test.tar.gz");
+        fs::write(&archive_path, "not the original").unwrap();
+
+        let script_path = sidecars::restore_script(&archive_path);
+        fs::write(&script_path, render_restore_script("backup_test.tar.gz", &"0".repeat(64), &ScriptOptions::gzip())).unwrap();
+
+        let status = Command::new("sh")
+            .arg(&script_path)
+            .arg("verify")
+            .status()
+            .unwrap();
+        assert!(!status.success());
+    }
+}
@@ -0,0 +1,1206 @@
+use crate::filter::FileFilter;
+use crate::ignore::IgnoreStack;
+use crate::presets::Preset;
+use crate::services::StoppedService;
+use crate::utils::{self, ChangeDetection, FileChange, FileHash};
+use colored::*;
+use glob::{MatchOptions, Pattern};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+//an empty file that marks a directory's contents as not worth backing up, handled like a CACHEDIR.TAG
+pub const NOBACKUP_MARKER: &str = ".nobackup";
+
+//cargo, borg, ccache and others tag their cache directories with this file, see https://bford.info/cachedir/
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    //stored as a link to its target, dangling ones included
+    Symlink,
+    //a FIFO, or a character or block device when running as root, stored as a tar special entry and never opened
+    Special,
+}
+
+//an entry the archiving layer should write
+#[derive(Debug)]
+pub struct SelectedEntry {
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    pub size: u64,
+    //why an incremental or differential run picked the file up, Content for full runs
+    pub change: FileChange,
+}
+
+//the rule that left an entry out, counted per rule for the summary
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExclusionRule {
+    //an excluded path prefix or glob pattern, a matching directory is skipped with everything below it
+    Exclusion(PathBuf),
+    //the same for a pattern of an exclusion preset, with the preset's name
+    Preset(&'static str, PathBuf),
+    //the --exclude-older-than limit, with the text the user entered
+    Age(String),
+    //the --max-file-size limit, with the text the user entered
+    Size(String),
+    //listed in a .backupignore file, counted per ignore file
+    IgnoreFile(PathBuf),
+    //below a directory marked with a .nobackup file, counted per marked directory
+    NoBackupMarker,
+    //below a directory tagged with a valid CACHEDIR.TAG, counted per cache directory
+    CacheDir,
+    //a mount point of another filesystem, counted per mount point when staying on one filesystem
+    OtherFilesystem,
+    //not changed since the last backup of an incremental or differential chain
+    Unchanged,
+    //unix sockets, the program that listens on one creates it again
+    Socket,
+    //device nodes, only root can create them on restore so only root archives them
+    Device,
+    //entries whose metadata can't be read
+    Unsupported,
+}
+
+impl ExclusionRule {
+    //the selection stage the rule belongs to, as shown by --why
+    pub fn stage(&self) -> &'static str {
+        match self {
+            ExclusionRule::Exclusion(_) | ExclusionRule::Preset(..) => "exclusions",
+            ExclusionRule::IgnoreFile(_) => "ignore files",
+            ExclusionRule::Age(_) | ExclusionRule::Size(_) => "filters",
+            ExclusionRule::NoBackupMarker | ExclusionRule::CacheDir => "markers",
+            ExclusionRule::OtherFilesystem => "filesystems",
+            ExclusionRule::Unchanged => "changes",
+            ExclusionRule::Socket | ExclusionRule::Device | ExclusionRule::Unsupported => "metadata",
+        }
+    }
+}
+
+impl fmt::Display for ExclusionRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExclusionRule::Exclusion(rule) => write!(f, "exclusion {}", rule.display()),
+            ExclusionRule::Preset(preset, rule) => write!(f, "exclusion {} of the {} preset", rule.display(), preset),
+            ExclusionRule::IgnoreFile(file) => write!(f, "listed in {}", file.display()),
+            ExclusionRule::Age(label) => write!(f, "older than {}", label),
+            ExclusionRule::Size(label) => write!(f, "larger than {}", label),
+            ExclusionRule::NoBackupMarker => write!(f, "directory marked with {}, contents left out", NOBACKUP_MARKER),
+            ExclusionRule::CacheDir => write!(f, "cache directory tagged with {}, contents left out", CACHEDIR_TAG),
+            ExclusionRule::OtherFilesystem => write!(f, "mount point of another filesystem, contents left out"),
+            ExclusionRule::Unchanged => write!(f, "unchanged since the last backup"),
+            ExclusionRule::Socket => write!(f, "unix socket, recreated by the program listening on it"),
+            ExclusionRule::Device => write!(f, "device node, only archived when running as root"),
+            ExclusionRule::Unsupported => write!(f, "metadata unreadable"),
+        }
+    }
+}
+
+//the decision for one path, stage by stage
+pub struct Trace {
+    pub steps: Vec<(&'static str, String)>,
+    pub included: bool,
+}
+
+//incremental and differential runs only select what changed after this time
+struct ChangedSince {
+    since: u64,
+    policy: ChangeDetection,
+}
+
+//the files the chain's last run saw, and the ones seen during this run
+//content hashes are only taken with hash checking, otherwise an entry is just size and mtime
+#[derive(Default)]
+struct History {
+    previous: HashMap<String, FileHash>,
+    current: HashMap<String, FileHash>,
+    hash_check: bool,
+}
+
+impl History {
+    //files whose size and mtime match the cache are trusted without reading them
+    fn change(&mut self, path: &Path, metadata: &std::fs::Metadata) -> FileChange {
+        let key = hash_key(path);
+        let previous = self.previous.get(&key);
+
+        let current = match previous {
+            Some(cached) if cached.size == metadata.len() && cached.mtime == metadata.mtime() && !cached.blake3.is_empty() => cached.clone(),
+            _ => match utils::hash_file(path) {
+                Ok(hash) => FileHash { size: metadata.len(), mtime: metadata.mtime(), blake3: hash },
+                //unreadable files are left to the archiving step to report
+                Err(_) => {
+                    self.record(path, metadata);
+                    return FileChange::Content;
+                }
+            },
+        };
+
+        let changed = previous.is_none_or(|cached| cached.blake3 != current.blake3);
+        self.current.insert(key, current);
+
+        if changed {
+            FileChange::Content
+        } else {
+            FileChange::Unchanged
+        }
+    }
+
+    //a hash from an earlier run stays as long as the file looks the same
+    fn record(&mut self, path: &Path, metadata: &std::fs::Metadata) {
+        let key = hash_key(path);
+        let current = match self.previous.get(&key) {
+            Some(cached) if cached.size == metadata.len() && cached.mtime == metadata.mtime() => cached.clone(),
+            _ => FileHash { size: metadata.len(), mtime: metadata.mtime(), blake3: String::new() },
+        };
+        self.current.insert(key, current);
+    }
+}
+
+//decides which entries below the sources end up in the archive
+pub struct FileSelector {
+    exclusions: Vec<Exclusion>,
+    filter: FileFilter,
+    changed_since: Option<ChangedSince>,
+    history: History,
+    //archive what symlinks point to instead of the links, and walk into linked directories
+    follow_symlinks: bool,
+    //archive device nodes, only root could create them again
+    devices: bool,
+    //leave out the contents of directories with a .nobackup file
+    nobackup_markers: bool,
+    //leave out the contents of directories with a CACHEDIR.TAG
+    cache_dirs: bool,
+    //don't descend into filesystems mounted below a source, like tar --one-file-system
+    one_file_system: bool,
+    //(data directory, unit) of services stopped while the walk is inside their directory
+    stop_services: Vec<(PathBuf, String)>,
+    //how long each of them was down, or why it couldn't be stopped
+    stops: Vec<(String, Result<Duration, String>)>,
+    //entries left out so far, per rule
+    excluded: BTreeMap<ExclusionRule, u64>,
+    //directories whose contents a .nobackup file kept out, listed in the summary in case one is there by mistake
+    marked: Vec<PathBuf>,
+    //mount points whose filesystems weren't crossed, listed in the summary since nothing on them was backed up
+    mount_points: Vec<PathBuf>,
+    //files over the size limit with their sizes, for the total in the summary and --list-skipped
+    oversized: Vec<(PathBuf, u64)>,
+    //active exclusion presets, their patterns are among the exclusions
+    presets: Vec<&'static Preset>,
+    //entries and bytes each preset left out, directories with everything below them
+    preset_totals: BTreeMap<&'static str, (u64, u64)>,
+}
+
+impl FileSelector {
+    pub fn new(exclusions: &[PathBuf], filter: FileFilter) -> Self {
+        FileSelector {
+            exclusions: exclusions.iter().map(|ex| Exclusion::new(ex)).collect(),
+            filter,
+            changed_since: None,
+            history: History::default(),
+            follow_symlinks: false,
+            devices: unsafe { libc::geteuid() == 0 },
+            nobackup_markers: true,
+            cache_dirs: true,
+            one_file_system: false,
+            stop_services: Vec::new(),
+            stops: Vec::new(),
+            excluded: BTreeMap::new(),
+            marked: Vec::new(),
+            mount_points: Vec::new(),
+            oversized: Vec::new(),
+            presets: Vec::new(),
+            preset_totals: BTreeMap::new(),
+        }
+    }
+
+    //decide what changed by content hash instead of timestamps, against the hashes of an earlier run
+    pub fn verify_hashes(mut self, previous: HashMap<String, FileHash>) -> Self {
+        self.history = History { previous, current: HashMap::new(), hash_check: true };
+        self
+    }
+
+    //the files of an earlier run, to tell which of them are gone
+    pub fn previous_history(mut self, previous: HashMap<String, FileHash>) -> Self {
+        self.history.previous = previous;
+        self
+    }
+
+    //every file seen so far, to compare the next run against
+    pub fn take_history(&mut self) -> HashMap<String, FileHash> {
+        std::mem::take(&mut self.history.current)
+    }
+
+    //files of the earlier run below the sources that the walk didn't see and that are gone from the disk
+    //an excluded or filtered file is still there, so only real deletions are left
+    pub fn deleted(&self, sources: &[PathBuf]) -> Vec<PathBuf> {
+        let mut deleted: Vec<PathBuf> = self
+            .history
+            .previous
+            .keys()
+            .filter(|key| !self.history.current.contains_key(*key))
+            .filter_map(|key| path_of_key(key))
+            .filter(|path| sources.iter().any(|source| path.starts_with(source)))
+            .filter(|path| matching_exclusion(&self.exclusions, path).is_none() && path.symlink_metadata().is_err())
+            .collect();
+        deleted.sort();
+        deleted
+    }
+
+    //only select files changed after `since`, as decided by the chain's detection policy
+    pub fn changed_since(mut self, since: u64, policy: ChangeDetection) -> Self {
+        self.changed_since = Some(ChangedSince { since, policy });
+        self
+    }
+
+    //dereference symlinks like tar -h, walkdir stops at links that loop back to a parent
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    //keep the directory and its marker but nothing else below a .nobackup file, on by default
+    pub fn nobackup_markers(mut self, honor: bool) -> Self {
+        self.nobackup_markers = honor;
+        self
+    }
+
+    //the same for cache directories, their tag is kept like a .nobackup file, on by default like tar --exclude-caches
+    pub fn cache_dirs(mut self, honor: bool) -> Self {
+        self.cache_dirs = honor;
+        self
+    }
+
+    //keep mount points below a source as empty directories instead of walking the filesystems mounted on them
+    pub fn one_file_system(mut self, stay: bool) -> Self {
+        self.one_file_system = stay;
+        self
+    }
+
+    //credit the exclusions that came from these presets to them in the summary
+    pub fn presets(mut self, presets: &[&'static Preset]) -> Self {
+        self.presets = presets.to_vec();
+        self
+    }
+
+    //stop these units while their data directories are walked, so the copy is taken at rest
+    pub fn stop_services(mut self, services: Vec<(PathBuf, String)>) -> Self {
+        self.stop_services = services;
+        self
+    }
+
+    //the outcome of every stop so far, per unit
+    pub fn take_stops(&mut self) -> Vec<(String, Result<Duration, String>)> {
+        std::mem::take(&mut self.stops)
+    }
+
+    //walk a source in file name order, the source directory itself is not selected
+    pub fn select<'a>(&'a mut self, source: &Path) -> impl Iterator<Item = SelectedEntry> + 'a {
+        let exclusions = &self.exclusions;
+        let filter = &self.filter;
+        let changed_since = &self.changed_since;
+        let history = &mut self.history;
+        let follow = self.follow_symlinks;
+        let devices = self.devices;
+        let markers = self.nobackup_markers;
+        let caches = self.cache_dirs;
+        let excluded = &mut self.excluded;
+        let marked = &mut self.marked;
+        let mount_points = &mut self.mount_points;
+        let oversized = &mut self.oversized;
+        let presets = &self.presets;
+        let preset_totals = &mut self.preset_totals;
+        //the device every entry has to be on, recorded per source so sources on their own partitions still work
+        let device = if self.one_file_system { device_of(source) } else { None };
+        let stop_services = &self.stop_services;
+        let stops = &mut self.stops;
+        let mut walker = WalkDir::new(source).follow_links(follow).sort_by_file_name().into_iter();
+        let mut pending_marker: Option<PathBuf> = None;
+        let mut ignores = IgnoreStack::default();
+        //a dropped walk starts the service again through the guard
+        let mut stopped: Option<(&Path, String, StoppedService)> = None;
+
+        std::iter::from_fn(move || loop {
+            //the marker of a directory whose contents were just skipped
+            if let Some(marker) = pending_marker.take() {
+                match select_entry(&marker, filter, changed_since, history, follow, devices) {
+                    Ok(selected) => return Some(selected),
+                    Err(rule) => *excluded.entry(rule).or_insert(0) += 1,
+                }
+            }
+
+            let next = walker.next();
+
+            //the walk left a stopped service's directory, or is over
+            let left = match (&stopped, &next) {
+                (Some((dir, _, _)), Some(Ok(entry))) => !entry.path().starts_with(dir),
+                (Some(_), None) => true,
+                _ => false,
+            };
+            if let Some((_, unit, service)) = stopped.take_if(|_| left) {
+                stops.push((unit, Ok(service.start())));
+            }
+
+            let entry = match next? {
+                Ok(entry) => entry,
+                Err(e) => {
+                    println!("{}", format!("Warning: Error accessing entry: {}", e).yellow());
+                    continue;
+                }
+            };
+
+            //excluded directories are not descended into
+            if let Some(rule) = matching_exclusion(exclusions, entry.path()) {
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                let rule = match presets.iter().find(|preset| preset.covers(rule)) {
+                    Some(preset) => {
+                        let totals = preset_totals.entry(preset.name).or_insert((0, 0));
+                        totals.0 += 1;
+                        totals.1 += size_below(entry.path(), follow);
+                        ExclusionRule::Preset(preset.name, rule.to_path_buf())
+                    }
+                    None => ExclusionRule::Exclusion(rule.to_path_buf()),
+                };
+                *excluded.entry(rule).or_insert(0) += 1;
+                continue;
+            }
+
+            //.backupignore files apply below their directory, nested ones stack like .gitignore files
+            ignores.leave(entry.path());
+            if entry.depth() > 0 {
+                if let Some(file) = ignores.ignoring(entry.path(), entry.file_type().is_dir()) {
+                    if entry.file_type().is_dir() {
+                        walker.skip_current_dir();
+                    }
+                    *excluded.entry(ExclusionRule::IgnoreFile(file.to_path_buf())).or_insert(0) += 1;
+                    continue;
+                }
+            }
+            if entry.file_type().is_dir() {
+                ignores.enter(entry.path());
+            }
+
+            if let Some((dir, unit)) = stop_services.iter().find(|(dir, _)| entry.file_type().is_dir() && entry.path() == dir) {
+                match StoppedService::stop(unit) {
+                    Ok(service) => stopped = Some((dir, unit.clone(), service)),
+                    //dropping the guard starts it again, in case the stop got halfway
+                    Err((_, e)) => {
+                        println!("{}", format!("Error: Could not stop {}, copying {} live: {}", unit, dir.display(), e).red());
+                        stops.push((unit.clone(), Err(e)));
+                    }
+                }
+            }
+            if entry.depth() == 0 {
+                //naming a source is an explicit include, it wins over a marker
+                if let Some((marked, (_, marker))) = entry.path().ancestors().find_map(|ancestor| marker_in(ancestor, markers, caches).map(|found| (ancestor, found))) {
+                    println!(
+                        "{}",
+                        format!("Note: {} has a {} file, backing up {} anyway since it was named as a source", marked.display(), marker, entry.path().display()).yellow()
+                    );
+                }
+                continue;
+            }
+
+            if !on_device(&entry, device) {
+                mount_points.push(entry.path().to_path_buf());
+                *excluded.entry(ExclusionRule::OtherFilesystem).or_insert(0) += 1;
+                //a mounted directory is kept empty like tar does, so a restore has somewhere to mount it again
+                if !entry.file_type().is_dir() {
+                    continue;
+                }
+                walker.skip_current_dir();
+            } else if let Some((rule, marker)) = marker_in(entry.path(), markers, caches).filter(|_| entry.file_type().is_dir()) {
+                walker.skip_current_dir();
+                if rule == ExclusionRule::NoBackupMarker {
+                    marked.push(entry.path().to_path_buf());
+                }
+                *excluded.entry(rule).or_insert(0) += 1;
+                pending_marker = Some(entry.path().join(marker));
+            }
+
+            match select_entry(entry.path(), filter, changed_since, history, follow, devices) {
+                Ok(selected) => return Some(selected),
+                Err(rule) => {
+                    if let ExclusionRule::Size(_) = rule {
+                        oversized.push((entry.path().to_path_buf(), entry.metadata().map_or(0, |metadata| metadata.len())));
+                    }
+                    *excluded.entry(rule).or_insert(0) += 1;
+                }
+            }
+        })
+    }
+
+    //follow a single path through the same stages the walk uses
+    pub fn trace(&mut self, sources: &[PathBuf], path: &Path) -> Trace {
+        let mut steps = Vec::new();
+
+        //the innermost source, a source below a marked directory is walked on its own
+        let source = match sources.iter().filter(|source| path.starts_with(source)).max_by_key(|source| source.as_os_str().len()) {
+            Some(source) => source,
+            None => {
+                steps.push(("sources", "not below any of the backed up directories".to_string()));
+                return Trace { steps, included: false };
+            }
+        };
+        steps.push(("sources", format!("below {}", source.display())));
+
+        //the walk stops at the first excluded directory on the way down
+        let mut ancestors: Vec<&Path> = path.ancestors().take_while(|a| a.starts_with(source)).collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            if let Some(rule) = matching_exclusion(&self.exclusions, ancestor) {
+                let rule = match self.presets.iter().find(|preset| preset.covers(rule)) {
+                    Some(preset) => format!("{} of the {} preset", rule.display(), preset.name),
+                    None => rule.display().to_string(),
+                };
+                let message = if ancestor == path {
+                    format!("matches exclusion {}", rule)
+                } else {
+                    format!("{} matches exclusion {}, nothing below it is archived", ancestor.display(), rule)
+                };
+                steps.push(("exclusions", message));
+                return Trace { steps, included: false };
+            }
+        }
+        steps.push(("exclusions", format!("none of the {} exclusions match", self.exclusions.len())));
+
+        //the ignore files on the way down, each applies below its own directory
+        let mut ignores = IgnoreStack::default();
+        for ancestor in path.ancestors().skip(1).take_while(|a| a.starts_with(source)).collect::<Vec<_>>().into_iter().rev() {
+            if let Some(file) = ignores.ignoring(ancestor, true).filter(|_| ancestor != source.as_path()) {
+                steps.push(("ignore files", format!("{} is listed in {}, nothing below it is archived", ancestor.display(), file.display())));
+                return Trace { steps, included: false };
+            }
+            ignores.enter(ancestor);
+        }
+        if let Some(file) = ignores.ignoring(path, path.is_dir()).filter(|_| path != source.as_path()) {
+            steps.push(("ignore files", format!("listed in {}", file.display())));
+            return Trace { steps, included: false };
+        }
+
+        //a marked directory keeps itself and its marker, the source itself was named explicitly
+        let marked = path
+            .ancestors()
+            .skip(1)
+            .take_while(|ancestor| ancestor.starts_with(source) && *ancestor != source.as_path())
+            .find_map(|ancestor| marker_in(ancestor, self.nobackup_markers, self.cache_dirs).map(|(_, marker)| (ancestor, marker)))
+            .filter(|(ancestor, marker)| path != ancestor.join(marker));
+        if let Some((marked, marker)) = marked {
+            steps.push(("markers", format!("{} has a {} file, nothing else below it is archived", marked.display(), marker)));
+            return Trace { steps, included: false };
+        }
+
+        //the first mount point on the way down, the walk keeps it but doesn't descend into it
+        if let Some(device) = device_of(source).filter(|_| self.one_file_system) {
+            let mut ancestors: Vec<&Path> = path.ancestors().skip(1).take_while(|ancestor| ancestor.starts_with(source) && *ancestor != source.as_path()).collect();
+            ancestors.reverse();
+            let mounted = ancestors.into_iter().find(|ancestor| device_of(ancestor).is_some_and(|dev| dev != device));
+            if let Some(mount_point) = mounted {
+                steps.push(("filesystems", format!("{} is on another filesystem, nothing below it is archived", mount_point.display())));
+                return Trace { steps, included: false };
+            }
+        }
+
+        if path == source.as_path() {
+            steps.push(("selection", "the backed up directory itself, archived through its contents".to_string()));
+            return Trace { steps, included: true };
+        }
+
+        match select_entry(path, &self.filter, &self.changed_since, &mut self.history, self.follow_symlinks, self.devices) {
+            Ok(entry) => {
+                let reason = match (&self.changed_since, entry.change) {
+                    (None, _) => "a full backup takes everything that passed the filters",
+                    (Some(_), FileChange::MetadataOnly) => "only its metadata changed since the last backup",
+                    (Some(_), _) => "new or changed since the last backup",
+                };
+                let kind = match entry.kind {
+                    EntryKind::File => "file",
+                    EntryKind::Directory => "directory",
+                    EntryKind::Symlink => "symlink",
+                    EntryKind::Special => "special file",
+                };
+                steps.push(("selection", format!("archived as a {}, {}", kind, reason)));
+                Trace { steps, included: true }
+            }
+            Err(rule) => {
+                steps.push((rule.stage(), rule.to_string()));
+                Trace { steps, included: false }
+            }
+        }
+    }
+
+    pub fn report(&self) {
+        if !self.presets.is_empty() {
+            println!("Exclusion presets:");
+            for preset in &self.presets {
+                let (entries, bytes) = self.preset_totals.get(preset.name).copied().unwrap_or_default();
+                println!("  {}: {} entries left out, {}", preset.name, entries, utils::format_size(bytes));
+            }
+        }
+        if self.excluded.is_empty() {
+            return;
+        }
+
+        println!("Entries left out, by rule:");
+        for (rule, count) in &self.excluded {
+            println!("  {}: {}", rule, count);
+        }
+        if !self.marked.is_empty() {
+            println!("{}", format!("Directories with a {} file, only the marker was archived:", NOBACKUP_MARKER).yellow());
+            for dir in &self.marked {
+                println!("  {}", dir.display());
+            }
+        }
+        if !self.oversized.is_empty() {
+            let bytes: u64 = self.oversized.iter().map(|(_, size)| size).sum();
+            println!("{}", format!("{} files over the size limit were left out, {} in total", self.oversized.len(), utils::format_size(bytes)).yellow());
+        }
+        if !self.mount_points.is_empty() {
+            println!("{}", "Mount points not crossed, back up the filesystems on them separately if they matter:".yellow());
+            for mount_point in &self.mount_points {
+                println!("  {}", mount_point.display());
+            }
+        }
+    }
+
+    //the files left out for their size, one path per line, appended to what a per-user run wrote for earlier users
+    pub fn write_oversized(&self, list: &Path, append: bool) -> std::io::Result<()> {
+        let mut file = OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(list)?;
+        for (path, _) in &self.oversized {
+            file.write_all(path.as_os_str().as_bytes())?;
+            file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+fn select_entry(
+    path: &Path,
+    filter: &FileFilter,
+    changed_since: &Option<ChangedSince>,
+    history: &mut History,
+    follow_symlinks: bool,
+    devices: bool
+) -> Result<SelectedEntry, ExclusionRule> {
+    let metadata = if follow_symlinks { path.metadata() } else { path.symlink_metadata() };
+    let metadata = metadata.map_err(|_| ExclusionRule::Unsupported)?;
+
+    let (kind, change) = if metadata.is_file() {
+        if !filter.allows(&metadata) {
+            return Err(ExclusionRule::Age(filter.max_age_label.clone().unwrap_or_default()));
+        }
+        if filter.too_large(&metadata) {
+            return Err(ExclusionRule::Size(filter.max_size_label.clone().unwrap_or_default()));
+        }
+
+        //full runs still hash everything, so the next run has something to compare against
+        let change = match (history.hash_check, changed_since) {
+            (true, Some(_)) => history.change(path, &metadata),
+            (true, None) => {
+                history.change(path, &metadata);
+                FileChange::Content
+            }
+            (false, Some(changed)) => {
+                history.record(path, &metadata);
+                utils::detect_change(&metadata, changed.since, &changed.policy)
+            }
+            (false, None) => {
+                history.record(path, &metadata);
+                FileChange::Content
+            }
+        };
+        if change == FileChange::Unchanged {
+            return Err(ExclusionRule::Unchanged);
+        }
+
+        (EntryKind::File, change)
+    } else if metadata.is_dir() {
+        //changed-only runs just add directories that are new
+        if let Some(changed) = changed_since {
+            let created = metadata.created().ok().and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok());
+            if created.is_none_or(|created| created.as_secs() <= changed.since) {
+                return Err(ExclusionRule::Unchanged);
+            }
+        }
+
+        (EntryKind::Directory, FileChange::Content)
+    } else if metadata.file_type().is_socket() {
+        return Err(ExclusionRule::Socket);
+    } else if !devices && (metadata.file_type().is_char_device() || metadata.file_type().is_block_device()) {
+        return Err(ExclusionRule::Device);
+    } else {
+        //symlinks and special files by their own timestamps, retargeting a link counts as a change
+        if !filter.allows(&metadata) {
+            return Err(ExclusionRule::Age(filter.max_age_label.clone().unwrap_or_default()));
+        }
+        history.record(path, &metadata);
+        let change = match changed_since {
+            Some(changed) => utils::detect_change(&metadata, changed.since, &changed.policy),
+            None => FileChange::Content,
+        };
+        if change == FileChange::Unchanged {
+            return Err(ExclusionRule::Unchanged);
+        }
+
+        (if metadata.is_symlink() { EntryKind::Symlink } else { EntryKind::Special }, change)
+    };
+
+    Ok(SelectedEntry {
+        path: path.to_path_buf(),
+        kind,
+        size: if kind == EntryKind::File { metadata.len() } else { 0 },
+        change,
+    })
+}
+
+//the filesystem a path is on, symlinks followed like walkdir does for a walk's root
+fn device_of(path: &Path) -> Option<u64> {
+    path.metadata().ok().map(|metadata| metadata.dev())
+}
+
+//an entry whose metadata can't be read is left to select_entry to report
+fn on_device(entry: &walkdir::DirEntry, device: Option<u64>) -> bool {
+    device.is_none_or(|device| entry.metadata().map_or(true, |metadata| metadata.dev() == device))
+}
+
+fn has_marker(dir: &Path) -> bool {
+    dir.join(NOBACKUP_MARKER).symlink_metadata().is_ok_and(|metadata| metadata.is_file())
+}
+
+//only a tag that starts with the signature counts, so an unrelated file of the same name doesn't hide anything
+fn is_cache_dir(dir: &Path) -> bool {
+    let mut signature = [0u8; CACHEDIR_SIGNATURE.len()];
+    let tag = dir.join(CACHEDIR_TAG);
+    tag.symlink_metadata().is_ok_and(|metadata| metadata.is_file()) && File::open(&tag).and_then(|mut file| file.read_exact(&mut signature)).is_ok() && signature == CACHEDIR_SIGNATURE
+}
+
+//the marker file that keeps a directory's contents out, with the rule it is counted under
+fn marker_in(dir: &Path, nobackup: bool, caches: bool) -> Option<(ExclusionRule, &'static str)> {
+    if nobackup && has_marker(dir) {
+        Some((ExclusionRule::NoBackupMarker, NOBACKUP_MARKER))
+    } else if caches && is_cache_dir(dir) {
+        Some((ExclusionRule::CacheDir, CACHEDIR_TAG))
+    } else {
+        None
+    }
+}
+
+//the marked or cache directory below `outer` that hides `inner` from a walk of `outer`
+pub fn marked_between<'a>(outer: &Path, inner: &'a Path) -> Option<&'a Path> {
+    inner.ancestors().take_while(|ancestor| *ancestor != outer && ancestor.starts_with(outer)).find(|ancestor| marker_in(ancestor, true, true).is_some())
+}
+
+//the bytes an excluded entry would have added, everything below it for a directory
+fn size_below(path: &Path, follow: bool) -> u64 {
+    WalkDir::new(path)
+        .follow_links(follow)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+//an excluded directory, or a glob pattern when it contains a *, compiled once before the walk
+//a bare name (target, node_modules/, *.log) leaves out entries with that name at any depth
+pub struct Exclusion {
+    path: PathBuf,
+    pattern: Option<Pattern>,
+    by_name: bool,
+}
+
+//a * stays within one path component, so /home/*/.cache doesn't reach into /home/a/b/.cache
+const PATTERN_OPTIONS: MatchOptions = MatchOptions { case_sensitive: true, require_literal_separator: true, require_literal_leading_dot: false };
+
+impl Exclusion {
+    //a pattern that doesn't compile is matched as a plain path, trailing slashes are dropped like Path does
+    pub fn new(path: &Path) -> Self {
+        let pattern = path
+            .to_str()
+            .filter(|ex| ex.contains('*'))
+            .and_then(|ex| Pattern::new(ex.trim_end_matches('/')).ok());
+        Exclusion { path: path.to_path_buf(), pattern, by_name: is_name_pattern(path) }
+    }
+
+    //an exclusion covers the path it names and everything below it, whole components only
+    //so /var/log leaves /var/logical_volumes alone
+    //a name pattern only looks at the entry's own name, the walk doesn't descend into a matching directory
+    pub fn matches(&self, path: &Path) -> bool {
+        match (&self.pattern, self.by_name) {
+            (Some(pattern), true) => path.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy())),
+            (None, true) => path.file_name().is_some_and(|name| Some(name) == self.path.file_name()),
+            //names that aren't valid UTF-8 only ever meet a * here, the literal parts of a pattern are UTF-8
+            (Some(pattern), false) => Path::new(path.to_string_lossy().as_ref()).ancestors().any(|ancestor| pattern.matches_path_with(ancestor, PATTERN_OPTIONS)),
+            //compared by component, so names that aren't valid UTF-8 match exactly like any other
+            (None, false) => path.starts_with(&self.path),
+        }
+    }
+}
+
+//a single name without any directory part, ~ is the home directory rather than a name
+pub fn is_name_pattern(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(name)), None) if name != "~")
+}
+
+//returns the first exclusion that matches
+pub fn matching_exclusion<'a>(exclusions: &'a [Exclusion], path: &Path) -> Option<&'a Path> {
+    exclusions.iter().find(|ex| ex.matches(path)).map(|ex| ex.path.as_path())
+}
+
+//the hash cache is stored as JSON, names that aren't valid UTF-8 are kept as hex so two of them can't share a key
+//a NUL can't appear in a path, so the hex form can't collide with a real one
+fn hash_key(path: &Path) -> String {
+    match path.to_str() {
+        Some(path) => path.to_string(),
+        None => format!("\0{}", path.as_os_str().as_bytes().iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+    }
+}
+
+//the path a key stands for, None for a damaged hex form
+fn path_of_key(key: &str) -> Option<PathBuf> {
+    let Some(hex) = key.strip_prefix('\0') else {
+        return Some(PathBuf::from(key));
+    };
+    let bytes = (0..hex.len()).step_by(2).map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok())).collect::<Option<Vec<u8>>>()?;
+    Some(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::fs::{self, File};
+    use std::time::Duration;
+
+    fn fixture() -> tempfile::TempDir {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("docs/drafts")).unwrap();
+        fs::create_dir_all(root.join("cache/thumbs")).unwrap();
+        fs::write(root.join("docs/report.txt"), "report").unwrap();
+        fs::write(root.join("docs/drafts/idea.txt"), "idea").unwrap();
+        fs::write(root.join("cache/thumbs/a.png"), "png").unwrap();
+        fs::write(root.join("top.txt"), "top").unwrap();
+        temp
+    }
+
+    fn set_mtime(path: &Path, secs_ago: u64) {
+        let time = SystemTime::now() - Duration::from_secs(secs_ago);
+        File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn selected(selector: &mut FileSelector, root: &Path) -> Vec<(String, EntryKind)> {
+        selector
+            .select(root)
+            .map(|e| (e.path.strip_prefix(root).unwrap().display().to_string(), e.kind))
+            .collect()
+    }
+
+    #[test]
+    fn full_selection_walks_everything_in_name_order() {
+        let temp = fixture();
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+
+        assert_eq!(
+            selected(&mut selector, temp.path()),
+            vec![
+                ("cache".to_string(), EntryKind::Directory),
+                ("cache/thumbs".to_string(), EntryKind::Directory),
+                ("cache/thumbs/a.png".to_string(), EntryKind::File),
+                ("docs".to_string(), EntryKind::Directory),
+                ("docs/drafts".to_string(), EntryKind::Directory),
+                ("docs/drafts/idea.txt".to_string(), EntryKind::File),
+                ("docs/report.txt".to_string(), EntryKind::File),
+                ("top.txt".to_string(), EntryKind::File),
+            ]
+        );
+    }
+
+    #[test]
+    fn symlinks_are_selected_as_links_unless_followed() {
+        let temp = fixture();
+        let root = temp.path();
+        std::os::unix::fs::symlink("top.txt", root.join("file-link")).unwrap();
+        std::os::unix::fs::symlink("docs/drafts", root.join("dir-link")).unwrap();
+        std::os::unix::fs::symlink("missing", root.join("dangling")).unwrap();
+        //a loop back to the root must not be walked forever
+        std::os::unix::fs::symlink(".", root.join("docs/loop")).unwrap();
+
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+        let links: Vec<String> = selected(&mut selector, root)
+            .into_iter()
+            .filter(|(_, kind)| *kind == EntryKind::Symlink)
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(links, vec!["dangling", "dir-link", "docs/loop", "file-link"]);
+
+        let mut following = FileSelector::new(&[], FileFilter::default()).follow_symlinks(true);
+        let entries = selected(&mut following, root);
+        assert!(entries.contains(&("file-link".to_string(), EntryKind::File)));
+        assert!(entries.contains(&("dir-link/idea.txt".to_string(), EntryKind::File)));
+        assert!(!entries.iter().any(|(name, _)| name == "dangling" || name.starts_with("docs/loop/")));
+    }
+
+    #[test]
+    fn fifos_are_selected_and_sockets_counted() {
+        let temp = fixture();
+        let root = temp.path();
+        assert!(std::process::Command::new("mkfifo").arg(root.join("pipe")).status().unwrap().success());
+        let _listener = std::os::unix::net::UnixListener::bind(root.join("app.sock")).unwrap();
+
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+        let entries = selected(&mut selector, root);
+        assert!(entries.contains(&("pipe".to_string(), EntryKind::Special)));
+        assert!(!entries.iter().any(|(name, _)| name == "app.sock"));
+        assert_eq!(selector.excluded.get(&ExclusionRule::Socket), Some(&1));
+
+        //device nodes only as root
+        let dev_null = select_entry(Path::new("/dev/null"), &FileFilter::default(), &None, &mut History::default(), false, false);
+        assert_eq!(dev_null.err(), Some(ExclusionRule::Device));
+        let dev_null = select_entry(Path::new("/dev/null"), &FileFilter::default(), &None, &mut History::default(), false, true);
+        assert_eq!(dev_null.unwrap().kind, EntryKind::Special);
+    }
+
+    #[test]
+    fn nobackup_markers_keep_only_the_directory_and_marker() {
+        let temp = fixture();
+        let root = temp.path();
+        fs::create_dir_all(root.join("docs/drafts/old")).unwrap();
+        fs::write(root.join("docs/drafts/old/v1.txt"), "v1").unwrap();
+        fs::write(root.join("docs/drafts/.nobackup"), "").unwrap();
+
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+        let entries: Vec<String> = selected(&mut selector, root).into_iter().map(|(name, _)| name).collect();
+        assert!(entries.contains(&"docs/drafts".to_string()) && entries.contains(&"docs/drafts/.nobackup".to_string()));
+        assert!(entries.contains(&"docs/report.txt".to_string()));
+        assert!(!entries.iter().any(|name| name.starts_with("docs/drafts/") && !name.ends_with(NOBACKUP_MARKER)));
+        assert_eq!(selector.excluded.get(&ExclusionRule::NoBackupMarker), Some(&1));
+
+        //a source inside the marked directory was named explicitly and is walked as usual
+        let nested = root.join("docs/drafts/old");
+        assert_eq!(utils::normalize_sources(&[root.to_path_buf(), nested.clone()]).len(), 2);
+        assert!(selector.trace(&[root.to_path_buf(), nested], &root.join("docs/drafts/old/v1.txt")).included);
+        let explicit: Vec<String> = selected(&mut selector, &root.join("docs/drafts/old")).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(explicit, vec!["v1.txt".to_string()]);
+
+        let sources = vec![root.to_path_buf()];
+        let trace = selector.trace(&sources, &root.join("docs/drafts/idea.txt"));
+        assert!(!trace.included && trace.steps.last().unwrap().0 == "markers");
+        assert!(selector.trace(&sources, &root.join("docs/drafts/.nobackup")).included);
+
+        let mut ignoring = FileSelector::new(&[], FileFilter::default()).nobackup_markers(false);
+        assert!(selected(&mut ignoring, root).iter().any(|(name, _)| name == "docs/drafts/old/v1.txt"));
+    }
+
+    #[test]
+    fn nested_markers_are_listed_once_per_pruned_directory() {
+        let temp = fixture();
+        let root = temp.path();
+        fs::create_dir_all(root.join("videos/raw/day1")).unwrap();
+        fs::write(root.join("videos/raw/day1/clip.mov"), "mov").unwrap();
+        fs::write(root.join("videos/raw/.nobackup"), "").unwrap();
+        fs::write(root.join("videos/raw/day1/.nobackup"), "").unwrap();
+        fs::write(root.join("cache/.nobackup"), "").unwrap();
+
+        let mut selector = FileSelector::new(&[], FileFilter::default());
+        let entries: Vec<String> = selected(&mut selector, root).into_iter().map(|(name, _)| name).collect();
+        assert!(entries.contains(&"videos/raw/.nobackup".to_string()) && !entries.iter().any(|name| name.starts_with("videos/raw/day1")));
+        assert!(entries.contains(&"docs/drafts/idea.txt".to_string()));
+
+        //the inner marker is never reached, the outer one already pruned it
+        assert_eq!(selector.marked, vec![root.join("cache"), root.join("videos/raw")]);
+        assert_eq!(selector.excluded.get(&ExclusionRule::NoBackupMarker), Some(&2));
+
+        //a marked directory named as a source is walked, the marker nested in it still applies
+        let mut explicit = FileSelector::new(&[], FileFilter::default());
+        let entries: Vec<String> = selected(&mut explicit, &root.join("videos/raw")).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(entries, vec![".nobackup", "day1", "day1/.nobackup"]);
+        assert_eq!(explicit.marked, vec![root.join("videos/raw/day1")]);
+    }
+
+    #[test]
+    fn other_filesystems_are_kept_as_empty_mount_points() {
+        let temp = fixture();
+        let root = temp.path();
+        //a followed link into /proc stands in for a mount, it is always on a filesystem of its own
+        std::os::unix::fs::symlink("/proc/self/fdinfo", root.join("docs/mounted")).unwrap();
+
+        let mut crossing = FileSelector::new(&[], FileFilter::default()).follow_symlinks(true);
+        assert!(selected(&mut crossing, root).iter().any(|(name, _)| name.starts_with("docs/mounted/")));
+
+        let mut staying = FileSelector::new(&[], FileFilter::default()).follow_symlinks(true).one_file_system(true);
+        let entries = selected(&mut staying, root);
+        assert!(entries.contains(&("docs/mounted".to_string(), EntryKind::Directory)));
+        assert!(!entries.iter().any(|(name, _)| name.starts_with("docs/mounted/")));
+        assert!(entries.iter().any(|(name, _)| name == "docs/drafts/idea.txt"));
+        assert_eq!(staying.mount_points, vec![root.join("docs/mounted")]);
+        assert_eq!(staying.excluded.get(&ExclusionRule::OtherFilesystem), Some(&1));
+
+        let trace = staying.trace(&[root.to_path_buf()], &root.join("docs/mounted/0"));
+        assert!(!trace.included);
+        assert_eq!(trace.steps.last().unwrap().0, "filesystems");
+    }
+
+    #[test]
+    fn tagged_cache_directories_keep_only_their_tag() {
+        let temp = fixture();
+        let root = temp.path();
+        fs::write(root.join("cache/CACHEDIR.TAG"), "Signature: 8a477f597d28d172789f06886806bc55\n# This file is a cache directory tag.\n").unwrap();
+        //a file that only has the name isn't a tag
+        fs::write(root.join("docs/drafts/CACHEDIR.TAG"), "not a tag\n").unwrap();
+
+        //incremental runs prune the same way, the tag itself is new so it goes in
+        let exclusions = vec![root.join("top.txt")];
+        let mut selector = FileSelector::new(&exclusions, FileFilter::default()).changed_since(0, ChangeDetection::default());
+        let entries: Vec<String> = selected(&mut selector, root).into_iter().map(|(name, _)| name).collect();
+        assert!(entries.contains(&"cache/CACHEDIR.TAG".to_string()) && !entries.contains(&"cache/thumbs/a.png".to_string()));
+        assert!(entries.contains(&"docs/drafts/idea.txt".to_string()) && !entries.contains(&"top.txt".to_string()));
+        assert_eq!(selector.excluded.get(&ExclusionRule::CacheDir), Some(&1));
+
+        let trace = selector.trace(&[root.to_path_buf()], &root.join("cache/thumbs/a.png"));
+        assert!(!trace.included && trace.steps.last().unwrap().1.contains(CACHEDIR_TAG));
+
+        let mut including = FileSelector::new(&[], FileFilter::default()).cache_dirs(false);
+        assert!(selected(&mut including, root).iter().any(|(name, _)| name == "cache/thumbs/a.png"));
+    }
+
+    #[test]
+    fn presets_are_credited_with_what_they_left_out() {
+        let temp = fixture();
+        let root = temp.path();
+        fs::create_dir_all(root.join("docs/site/node_modules/lib")).unwrap();
+        fs::write(root.join("docs/site/node_modules/lib/index.js"), "x".repeat(1000)).unwrap();
+        fs::write(root.join("docs/clip.mkv"), "y".repeat(200)).unwrap();
+
+        let active = [crate::presets::parse("build").unwrap(), crate::presets::parse("media").unwrap()];
+        let mut exclusions = crate::presets::patterns(&active);
+        exclusions.push(PathBuf::from("*.png"));
+        let mut selector = FileSelector::new(&exclusions, FileFilter::default()).presets(&active);
+        let entries: Vec<String> = selected(&mut selector, root).into_iter().map(|(name, _)| name).collect();
+        assert!(entries.contains(&"docs/site".to_string()) && !entries.iter().any(|name| name.contains("node_modules") || name.ends_with(".mkv")));
+
+        assert_eq!(selector.preset_totals.get("build"), Some(&(1, 1000)));
+        assert_eq!(selector.preset_totals.get("media"), Some(&(1, 200)));
+        assert_eq!(selector.excluded.get(&ExclusionRule::Preset("media", PathBuf::from("*.mkv"))), Some(&1));
+        //a user's own pattern stays an exclusion of its own
+        assert_eq!(selector.excluded.get(&ExclusionRule::Exclusion(PathBuf::from("*.png"))), Some(&1));
+
+        let trace = selector.trace(&[root.to_path_buf()], &root.join("docs/clip.mkv"));
+        assert!(trace.steps.last().unwrap().1.contains("of the media preset"));
+    }
+
+    #[test]
+    fn exclusions_prune_whole_subtrees() {
+        let temp = fixture();
+        let root = temp.path();
+        let exclusions = vec![root.join("cache"), root.join("docs/*")];
+        let mut selector = FileSelector::new(&exclusions, FileFilter::default());
+
+        assert_eq!(
+            selected(&mut selector, root),
+            vec![
+                ("docs".to_string(), EntryKind::Directory),
+                ("top.txt".to_string(), EntryKind::File),
+            ]
+        );
+        //pruned directories count once for the rule that pruned them
+        assert_eq!(
+            selector.excluded.iter().map(|(rule, count)| (rule.to_string(), *count)).collect::<Vec<_>>(),
+            vec![
+                (format!("exclusion {}", exclusions[0].display()), 1),
+                (format!("exclusion {}", exclusions[1].display()), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn exclusions_match_patterns_and_whole_components_without_touching_the_filesystem() {
+        let cases = [
+            //(exclusion, path, excluded)
+            ("/var/log", "/var/log", true),
+            ("/var/log", "/var/log/syslog", true),
+            ("/var/log", "/var/logical_volumes", false),
+            ("/var/log", "/var/log.old/syslog", false),
+            ("/var/log/", "/var/log/syslog", true),
+            ("/var/log/", "/var/logs", false),
+            ("/home/*/.cache", "/home/alice/.cache", true),
+            ("/home/*/.cache", "/home/alice/.cache/thumbnails/x.png", true),
+            ("/home/*/.cache/", "/home/alice/.cache/x", true),
+            ("/home/*/.cache", "/home/alice/.cache-backup", false),
+            ("/home/*/.cache", "/home/alice/work/.cache", false),
+            ("/home/*/.cache", "/home/.cache", false),
+            ("/srv/*.tmp", "/srv/build.tmp/out", true),
+            ("/srv/*.tmp", "/srv/build.tmpl", false),
+            ("/srv/[x", "/srv/[x/y", true),
+            ("target", "/home/alice/projects/app/target", true),
+            ("target/", "/home/alice/target", true),
+            ("target", "/home/alice/targets", false),
+            ("node_modules", "/srv/web/node_modules", true),
+            ("*.log", "/var/www/error.log", true),
+            ("*.log", "/var/www/error.log.gz", false),
+        ];
+        for (exclusion, path, excluded) in cases {
+            assert_eq!(Exclusion::new(Path::new(exclusion)).matches(Path::new(path)), excluded, "{} against {}", exclusion, path);
+        }
+
+        //a * matches a name that isn't valid UTF-8
+        let latin1 = Path::new(OsStr::from_bytes(b"/home/m\xfcller/.cache/x"));
+        assert!(Exclusion::new(Path::new("/home/*/.cache")).matches(latin1));
+    }
+
+    #[test]
+    fn trace_names_the_rule_and_stage_for_a_path() {
+        let temp = fixture();
+        let root = temp.path();
+        let sources = vec![root.to_path_buf()];
+        let exclusions = vec![root.join("cache")];
+        set_mtime(&root.join("top.txt"), 200 * 86400);
+
+        let filter = FileFilter::new(Some((90 * 86400, "90d".to_string())), now());
+        let mut selector = FileSelector::new(&exclusions, filter);
+
+        let pruned = selector.trace(&sources, &root.join("cache/thumbs/a.png"));
+        assert!(!pruned.included);
+        assert_eq!(pruned.steps.last().unwrap().0, "exclusions");
+        assert!(pruned.steps.last().unwrap().1.contains("nothing below it is archived"));
+
+        let old = selector.trace(&sources, &root.join("top.txt"));
+        assert!(!old.included);
+        assert_eq!(old.steps.last().unwrap(), &("filters", "older than 90d".to_string()));
+
+        assert!(selector.trace(&sources, &root.join("docs/report.txt")).included);
+        assert!(!selector.trace(&sources, Path::new("/elsewhere/file")).included);
+    }
+
+    #[test]
+    fn age_filter_skips_and_counts_old_files() {
+        let temp = fixture();
+        let root = temp.path();
+        set_mtime(&root.join("docs/report.txt"), 200 * 86400);
+        set_mtime(&root.join("top.txt"), 200 * 86400);
+
+        let filter = FileFilter::new(Some((90 * 86400, "90d".to_string())), now());
+        let mut selector = FileSelector::new(&[], filter);
+        let files: Vec<String> = selected(&mut selector, root)
+            .into_iter()
+            .filter(|(_, kind)| *kind == EntryKind::File)
+            .map(|(path, _)| path)
+            .collect();
+
+        assert_eq!(files, vec!["cache/thumbs/a.png", "docs/drafts/idea.txt"]);
+        assert_eq!(selector.excluded.get(&ExclusionRule::Age("90d".to_string())), Some(&2));
+    }
+
+    #[test]
+    fn size_filter_skips_counts_and_lists_large_files() {
+        let temp = fixture();
+        let root = temp.path();
+        fs::write(root.join("docs/disk.iso"), vec![0u8; 3000]).unwrap();
+        fs::write(root.join("cache/vm.qcow2"), vec![0u8; 2049]).unwrap();
+        fs::write(root.join("cache/limit.bin"), vec![0u8; 2048]).unwrap();
+
+        let filter = FileFilter::default().with_max_size(Some((2048, "2K".to_string())));
+        let mut selector = FileSelector::new(&[], filter);
+        let files: Vec<String> = selected(&mut selector, root).into_iter().map(|(path, _)| path).collect();
+        assert!(files.contains(&"cache/limit.bin".to_string()) && files.contains(&"docs".to_string()));
+        assert!(!files.contains(&"docs/disk.iso".to_string()) && !files.contains(&"cache/vm.qcow2".to_string()));
+        assert_eq!(selector.excluded.get(&ExclusionRule::Size("2K".to_string())), Some(&2));
+        assert_eq!(selector.oversized, vec![(root.join("cache/vm.qcow2"), 2049), (root.join("docs/disk.iso"), 3000)]);
+
+        let list = root.join("skipped.txt");
+        selector.write_oversized(&list, false).unwrap();
+        selector.write_oversized(&list, true).unwrap();
+        let expected = format!("{}\n{}\n", root.join("cache/vm.qcow2").display(), root.join("docs/disk.iso").display());
+        assert_eq!(fs::read_to_string(&list).unwrap(), expected.repeat(2));
+        selector.write_oversized(&list, false).unwrap();
+        assert_eq!(fs::read_to_string(&list).unwrap(), expected);
+
+        let trace = selector.trace(&[root.to_path_buf()], &root.join("docs/disk.iso"));
+        assert_eq!(trace.steps.last().unwrap(), &("filters", "larger than 2K".to_string()));
+    }
+
+    #[test]
+    fn change_detection_policy_decides_what_changed() {
+        let temp = fixture();
+        let root = temp.path();
+        let since = now() - 100;
+
+        //content untouched since long before the last backup, but the inode changed just now
+        set_mtime(&root.join("docs/report.txt"), 1000);
+        set_mtime(&root.join("docs/drafts/idea.txt"), 1000);
+        set_mtime(&root.join("cache/thumbs/a.png"), 1000);
+
+        let changed_files = |policy: ChangeDetection| -> Vec<(String, FileChange)> {
+            let mut selector = FileSelector::new(&[], FileFilter::default()).changed_since(since, policy);
+            selector
+                .select(root)
+                .filter(|e| e.kind == EntryKind::File)
+                .map(|e| (e.path.strip_prefix(root).unwrap().display().to_string(), e.change))
+                .collect()
+        };
+
+        assert_eq!(changed_files(ChangeDetection::Mtime), vec![("top.txt".to_string(), FileChange::Content)]);
+        assert_eq!(
+            changed_files(ChangeDetection::Either),
+            vec![
+                ("cache/thumbs/a.png".to_string(), FileChange::MetadataOnly),
+                ("docs/drafts/idea.txt".to_string(), FileChange::MetadataOnly),
+                ("docs/report.txt".to_string(), FileChange::MetadataOnly),
+                ("top.txt".to_string(), FileChange::Content),
+            ]
+        );
+    }
+
+    #[test]
+    fn hash_check_looks_at_content_not_timestamps() {
+        let temp = fixture();
+        let root = temp.path();
+        let report = root.join("docs/report.txt");
+        let idea = root.join("docs/drafts/idea.txt");
+
+        let changed_files = |hashes: HashMap<String, FileHash>| -> (Vec<String>, HashMap<String, FileHash>) {
+            let mut selector = FileSelector::new(&[], FileFilter::default())
+                .changed_since(now(), ChangeDetection::Either)
+                .verify_hashes(hashes);
+            let files = selector
+                .select(root)
+                .filter(|e| e.kind == EntryKind::File)
+                .map(|e| e.path.strip_prefix(root).unwrap().display().to_string())
+                .collect();
+            (files, selector.take_history())
+        };
+
+        //a full run selects everything and records a hash for each file
+        let mut full = FileSelector::new(&[], FileFilter::default()).verify_hashes(HashMap::new());
+        assert_eq!(full.select(root).filter(|e| e.kind == EntryKind::File).count(), 4);
+        let hashes = full.take_history();
+        assert_eq!(hashes.len(), 4);
+
+        //touched without a content change, and changed but put back to an old mtime
+        set_mtime(&report, 10);
+        fs::write(&idea, "IDEA").unwrap();
+        set_mtime(&idea, 1000 * 86400);
+
+        let (files, hashes) = changed_files(hashes);
+        assert_eq!(files, vec!["docs/drafts/idea.txt"]);
+        assert_eq!(hashes[&report.display().to_string()].mtime, fs::metadata(&report).unwrap().mtime());
+
+        let (files, _) = changed_files(hashes);
+        assert!(files.is_empty());
+    }
+}
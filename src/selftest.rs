@@ -0,0 +1,349 @@
+use crate::acl;
+use crate::backup::{self, BackupJob, BackupSettings, ExistingOutput};
+use crate::compression::{self, ArchiveCompression, CompressionFormat};
+use crate::conflicts::ConflictPolicy;
+use crate::restore::{restore_backup, RestoreOptions};
+use crate::users::Accounts;
+use crate::utils::BackupType;
+use colored::*;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tar::Archive;
+
+//directories below the fixture root, deeper than most real trees
+const NESTING_DEPTH: usize = 40;
+
+//large enough that a restored file without holes clearly takes more blocks than one with them
+const SPARSE_SIZE: u64 = 32 * 1024 * 1024;
+
+//the mtime the permissions check expects back
+const FIXED_MTIME: u64 = 1_500_000_000;
+
+//an extra reader on top of the mode bits, what setfacl -m u:1000:r would give
+const TEST_ACL: &str = "user::rw-,user:1000:r--,group::r--,mask::r--,other::r--";
+
+//the result of one capability check
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    //this machine or filesystem can't do it, which is worth knowing but not a failure
+    Unsupported(String),
+}
+
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+}
+
+//the files the fixture was made of, relative to its root
+struct Fixture {
+    root: PathBuf,
+    unicode: PathBuf,
+    non_utf8: PathBuf,
+    deep: PathBuf,
+    xattrs: bool,
+    acls: bool,
+}
+
+//back up, change, back up incrementally and restore a generated tree below `base`, then compare
+//returns the number of failed checks
+pub fn run_self_test(base: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    println!("{}", "\n---- Self-Test ----".blue().bold());
+
+    let work = base.join(format!("linux_backup-selftest-{}", std::process::id()));
+    fs::create_dir_all(&work)?;
+    let work = fs::canonicalize(&work)?;
+
+    //the test chain must not touch the user's real backup metadata or settings
+    let saved: Vec<_> = ["LINUX_BACKUP_STATE_DIR", "LINUX_BACKUP_CONFIG_DIR"].iter().map(|var| (*var, std::env::var_os(var))).collect();
+    std::env::set_var("LINUX_BACKUP_STATE_DIR", work.join("state"));
+    std::env::set_var("LINUX_BACKUP_CONFIG_DIR", work.join("config"));
+
+    let result = run_checks(&work);
+
+    for (var, value) in saved {
+        match value {
+            Some(value) => std::env::set_var(var, value),
+            None => std::env::remove_var(var),
+        }
+    }
+    let _ = fs::remove_dir_all(&work);
+
+    let checks = result?;
+    println!("{}", "\n---- Self-Test Report ----".blue().bold());
+    println!("Tested in: {}", base.display());
+    for check in &checks {
+        let (label, detail) = match &check.outcome {
+            Outcome::Pass(detail) => ("PASS".green().bold(), detail),
+            Outcome::Fail(detail) => ("FAIL".red().bold(), detail),
+            Outcome::Unsupported(detail) => ("N/A ".yellow().bold(), detail),
+        };
+        println!("  {}  {:<24} {}", label, check.name, detail);
+    }
+
+    let failed = checks.iter().filter(|check| matches!(check.outcome, Outcome::Fail(_))).count();
+    if failed == 0 {
+        println!("{}", "\nAll checks passed, backups and restores work on this machine.".green().bold());
+    } else {
+        println!("{}", format!("\n{} checks failed.", failed).red().bold());
+    }
+    Ok(failed)
+}
+
+fn run_checks(work: &Path) -> Result<Vec<Check>, Box<dyn std::error::Error>> {
+    let fixture = create_fixture(&work.join("source"))?;
+    let archives = work.join("archives");
+    fs::create_dir_all(&archives)?;
+    let mut checks = Vec::new();
+
+    let full = archives.join("full.tar.gz");
+    run_backup(&fixture.root, &full, BackupType::Full)?;
+    let full_entries = archive_entries(&full)?;
+    checks.push(Check {
+        name: "full backup",
+        outcome: match full_entries.iter().any(|name| name.ends_with("plain.txt")) {
+            true => Outcome::Pass(format!("{} entries archived", full_entries.len())),
+            false => Outcome::Fail("the archive is missing fixture files".to_string()),
+        },
+    });
+
+    //mtimes have a resolution of a second, changes in the backup's own second would be missed
+    std::thread::sleep(Duration::from_millis(1100));
+    fs::write(fixture.root.join("changing.txt"), "changed after the full backup")?;
+    fs::write(fixture.root.join("added.txt"), "added after the full backup")?;
+
+    let incremental = archives.join("incremental.tar.gz");
+    run_backup(&fixture.root, &incremental, BackupType::Incremental)?;
+    let incremental_entries = archive_entries(&incremental)?;
+    let picked = |name: &str| incremental_entries.iter().any(|entry| entry.ends_with(name));
+    checks.push(Check {
+        name: "incremental backup",
+        outcome: match (picked("changing.txt") && picked("added.txt"), picked("plain.txt")) {
+            (true, false) => Outcome::Pass("only the changed and added files were archived".to_string()),
+            (false, _) => Outcome::Fail("changed or added files are missing".to_string()),
+            (true, true) => Outcome::Fail("unchanged files were archived again".to_string()),
+        },
+    });
+
+    //the chain is restored the way a user would, full first, then the incremental over it
+    let target = work.join("restored");
+    let options = RestoreOptions { ignore_origin: true, conflicts: ConflictPolicy::Overwrite, ..RestoreOptions::default() };
+    restore_backup(&full.display().to_string(), &target.display().to_string(), &options)?;
+    restore_backup(&incremental.display().to_string(), &target.display().to_string(), &options)?;
+    let restored = target.join(fixture.root.strip_prefix("/")?);
+
+    checks.push(compare_tree(&fixture.root, &restored));
+    checks.push(same_content("unicode names", &fixture.root.join(&fixture.unicode), &restored.join(&fixture.unicode)));
+    checks.push(same_content("non-UTF-8 names", &fixture.root.join(&fixture.non_utf8), &restored.join(&fixture.non_utf8)));
+    checks.push(same_content("deep nesting", &fixture.root.join(&fixture.deep), &restored.join(&fixture.deep)));
+    checks.push(check_symlink(&fixture.root, &restored));
+    checks.push(check_sparse(&restored.join("sparse.img")));
+    checks.push(check_permissions(&restored.join("plain.txt")));
+    checks.push(Check {
+        name: "FIFOs",
+        outcome: match fs::symlink_metadata(restored.join("pipe")) {
+            Ok(metadata) if metadata.file_type().is_fifo() => Outcome::Pass("stored as a FIFO without reading from it, recreated on restore".to_string()),
+            Ok(_) => Outcome::Fail("restored as something other than a FIFO".to_string()),
+            Err(e) => Outcome::Fail(e.to_string()),
+        },
+    });
+    checks.push(check_xattrs(&fixture, &restored.join("tagged.txt")));
+    checks.push(check_acls(&fixture, &restored.join("shared.txt")));
+
+    Ok(checks)
+}
+
+fn run_backup(source: &Path, output: &Path, backup_type: BackupType) -> Result<(), Box<dyn std::error::Error>> {
+    backup::run_backup(BackupJob {
+        sources: vec![source.to_path_buf()],
+        exclusions: Vec::new(),
+        settings: BackupSettings {
+            backup_type,
+            compression: ArchiveCompression::new(CompressionFormat::Gzip),
+            reproducible: false,
+            max_age: None,
+            max_size: None,
+            list_skipped: None,
+            presets: Vec::new(),
+            hash_check: false,
+            encryption: None,
+            xattrs: true,
+            acls: true,
+            follow_symlinks: false,
+            nobackup_markers: true,
+            cache_dirs: true,
+            one_file_system: false,
+            portability: Default::default(),
+            wait_for_lock: None,
+            min_free_after: None,
+            check_archive: false,
+            entry_index: false,
+            survive_hangup: false,
+            low_space: None,
+        },
+        output_path: output.to_path_buf(),
+        existing_output: ExistingOutput::Replace,
+        interactive: false,
+        skip_empty: false,
+        profile: None,
+        skipped_sources: Vec::new(),
+        root: None,
+        services: Vec::new(),
+        disk_layout: false,
+    })
+    .map(|_| ())
+}
+
+//a small tree with the kinds of entries that tend to break archivers
+fn create_fixture(root: &Path) -> Result<Fixture, Box<dyn std::error::Error>> {
+    fs::create_dir_all(root)?;
+
+    let plain = root.join("plain.txt");
+    fs::write(&plain, "plain content\n")?;
+    fs::set_permissions(&plain, fs::Permissions::from_mode(0o640))?;
+    File::open(&plain)?.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(FIXED_MTIME))?;
+    fs::write(root.join("changing.txt"), "before the full backup")?;
+
+    let unicode = PathBuf::from("naïve café 日本語 🗂.txt");
+    fs::write(root.join(&unicode), "unicode")?;
+    let non_utf8 = PathBuf::from(OsStr::from_bytes(b"latin1-\xe9t\xe9.txt"));
+    fs::write(root.join(&non_utf8), "latin-1")?;
+
+    let deep_dir: PathBuf = (0..NESTING_DEPTH).map(|level| format!("level{:02}", level)).collect();
+    fs::create_dir_all(root.join(&deep_dir))?;
+    let deep = deep_dir.join("bottom.txt");
+    fs::write(root.join(&deep), "deep")?;
+
+    std::os::unix::fs::symlink("plain.txt", root.join("link.txt"))?;
+    std::os::unix::fs::symlink("nowhere.txt", root.join("dangling.txt"))?;
+
+    //a hole followed by a few bytes at the end
+    let mut sparse = File::create(root.join("sparse.img"))?;
+    sparse.set_len(SPARSE_SIZE)?;
+    sparse.seek(SeekFrom::End(-4))?;
+    sparse.write_all(b"end\n")?;
+
+    let fifo = CString::new(root.join("pipe").as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) } != 0 {
+        return Err(format!("Could not create a FIFO: {}", std::io::Error::last_os_error()).into());
+    }
+
+    let tagged = root.join("tagged.txt");
+    fs::write(&tagged, "tagged")?;
+    let xattrs = xattr::set(&tagged, "user.linux_backup.selftest", b"kept").is_ok();
+
+    let shared = root.join("shared.txt");
+    fs::write(&shared, "shared")?;
+    let acls = acl::apply(&shared, acl::XATTR_ACCESS, TEST_ACL, &Accounts::System).is_ok();
+
+    Ok(Fixture { root: root.to_path_buf(), unicode, non_utf8, deep, xattrs, acls })
+}
+
+fn archive_entries(path: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut archive = Archive::new(compression::open_decoder(path, None)?);
+    let mut names = Vec::new();
+    for entry in archive.entries()? {
+        names.push(entry?.path()?.to_path_buf());
+    }
+    Ok(names)
+}
+
+//every regular file of the source, with the same content after the restore
+fn compare_tree(source: &Path, restored: &Path) -> Check {
+    let mut compared = 0;
+    for entry in walkdir::WalkDir::new(source).into_iter().flatten() {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(source).unwrap_or(entry.path());
+        match (fs::read(entry.path()), fs::read(restored.join(relative))) {
+            (Ok(expected), Ok(actual)) if expected == actual => compared += 1,
+            (_, Err(e)) => return Check { name: "chain restore", outcome: Outcome::Fail(format!("{}: {}", relative.display(), e)) },
+            _ => return Check { name: "chain restore", outcome: Outcome::Fail(format!("{} differs", relative.display())) },
+        }
+    }
+    Check { name: "chain restore", outcome: Outcome::Pass(format!("{} files identical after full + incremental", compared)) }
+}
+
+fn same_content(name: &'static str, expected: &Path, restored: &Path) -> Check {
+    let outcome = match (fs::read(expected), fs::read(restored)) {
+        (Ok(expected), Ok(actual)) if expected == actual => Outcome::Pass("restored intact".to_string()),
+        (_, Err(e)) => Outcome::Fail(e.to_string()),
+        _ => Outcome::Fail("content differs".to_string()),
+    };
+    Check { name, outcome }
+}
+
+//links come back as links with the same target, the dangling one too
+fn check_symlink(source: &Path, restored: &Path) -> Check {
+    let outcome = ["link.txt", "dangling.txt"]
+        .iter()
+        .find_map(|name| match (fs::read_link(source.join(name)), fs::read_link(restored.join(name))) {
+            (Ok(expected), Ok(actual)) if expected == actual => None,
+            (_, Err(e)) => Some(Outcome::Fail(format!("{}: {}", name, e))),
+            _ => Some(Outcome::Fail(format!("{} points somewhere else", name))),
+        })
+        .unwrap_or_else(|| Outcome::Pass("stored as links, dangling ones included".to_string()));
+    Check { name: "symlinks", outcome }
+}
+
+fn check_sparse(restored: &Path) -> Check {
+    let outcome = match fs::metadata(restored) {
+        Err(e) => Outcome::Fail(e.to_string()),
+        Ok(metadata) if metadata.len() != SPARSE_SIZE => Outcome::Fail(format!("restored with {} instead of {}", metadata.len(), SPARSE_SIZE)),
+        Ok(metadata) if metadata.blocks() * 512 < SPARSE_SIZE / 2 => Outcome::Pass("size and holes kept".to_string()),
+        Ok(metadata) => Outcome::Unsupported(format!("content intact, but holes are filled ({} on disk)", crate::utils::format_size(metadata.blocks() * 512))),
+    };
+    Check { name: "sparse files", outcome }
+}
+
+fn check_permissions(restored: &Path) -> Check {
+    let outcome = match fs::metadata(restored) {
+        Err(e) => Outcome::Fail(e.to_string()),
+        Ok(metadata) if metadata.mode() & 0o777 != 0o640 => Outcome::Fail(format!("mode {:o} instead of 640", metadata.mode() & 0o777)),
+        Ok(metadata) if metadata.mtime() != FIXED_MTIME as i64 => Outcome::Fail("modification time not kept".to_string()),
+        Ok(_) => Outcome::Pass("mode and modification time kept".to_string()),
+    };
+    Check { name: "permissions and mtimes", outcome }
+}
+
+fn check_xattrs(fixture: &Fixture, restored: &Path) -> Check {
+    let outcome = if !fixture.xattrs {
+        Outcome::Unsupported("this filesystem doesn't take user extended attributes".to_string())
+    } else {
+        match xattr::get(restored, "user.linux_backup.selftest") {
+            Ok(Some(value)) if value == b"kept" => Outcome::Pass("user attributes kept".to_string()),
+            Ok(_) => Outcome::Fail("the attribute was lost".to_string()),
+            Err(e) => Outcome::Fail(e.to_string()),
+        }
+    };
+    Check { name: "extended attributes", outcome }
+}
+
+fn check_acls(fixture: &Fixture, restored: &Path) -> Check {
+    let outcome = if !fixture.acls {
+        Outcome::Unsupported("this filesystem doesn't support POSIX ACLs".to_string())
+    } else if acl::read(restored) == vec![(acl::PAX_ACCESS, TEST_ACL.to_string())] {
+        Outcome::Pass("access ACL kept".to_string())
+    } else {
+        Outcome::Fail("the ACL was lost".to_string())
+    };
+    Check { name: "POSIX ACLs", outcome }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_test_passes_in_a_temp_directory() {
+        let temp = tempfile::tempdir().unwrap();
+        assert_eq!(run_self_test(temp.path()).unwrap(), 0);
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 0);
+    }
+}
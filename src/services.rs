@@ -0,0 +1,403 @@
+use crate::prompt::Select;
+use crate::utils;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tar::{Builder, Header};
+
+//archive directory holding service dumps, restored like any other files
+pub const DUMPS_DIR: &str = ".linux_backup_dumps";
+
+//what was done for each running service, stored in the archive's state directory
+pub const SERVICES_FILE: &str = "services.json";
+
+//a dump of a large database takes a while, stopping and starting a service shouldn't
+const DUMP_TIMEOUT: Duration = Duration::from_secs(4 * 60 * 60);
+const SYSTEMCTL_TIMEOUT: Duration = Duration::from_secs(120);
+const BGSAVE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+//how a running service's data gets into the archive
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    //its own dump tool writes a consistent copy into the archive, the directory is copied as usual
+    Dump,
+    //stopped while the walk is inside its data directory, started again right after
+    Stop,
+    //copied while running, what happened before this handling existed
+    Live,
+}
+
+//how a consistent copy of a service's data is made
+enum DumpMethod {
+    //a tool that writes every database to stdout, run as the given user
+    Command { user: &'static str, program: &'static str, args: &'static [&'static str], file: &'static str },
+    //BGSAVE writes dump.rdb in the data directory, the walk picks it up afterwards
+    RedisBgsave,
+}
+
+//a service whose live data directory doesn't restore into a usable state
+pub struct Service {
+    pub name: &'static str,
+    //unit names differ between distributions, the first active one is used
+    units: &'static [&'static str],
+    pub data_dir: &'static str,
+    dump: DumpMethod,
+}
+
+pub const SERVICES: &[Service] = &[
+    Service {
+        name: "postgresql",
+        units: &["postgresql"],
+        data_dir: "/var/lib/postgresql",
+        dump: DumpMethod::Command { user: "postgres", program: "pg_dumpall", args: &[], file: "postgresql.sql" },
+    },
+    Service {
+        name: "mysql",
+        units: &["mariadb", "mysql", "mysqld"],
+        data_dir: "/var/lib/mysql",
+        dump: DumpMethod::Command {
+            user: "root",
+            program: "mysqldump",
+            args: &["--all-databases", "--single-transaction", "--routines", "--events"],
+            file: "mysql.sql",
+        },
+    },
+    Service {
+        name: "redis",
+        units: &["redis-server", "redis"],
+        data_dir: "/var/lib/redis",
+        dump: DumpMethod::RedisBgsave,
+    },
+];
+
+impl Service {
+    fn dump_label(&self) -> String {
+        match &self.dump {
+            DumpMethod::Command { program, .. } => format!("{} into the archive", program),
+            DumpMethod::RedisBgsave => "redis-cli BGSAVE, then copy dump.rdb".to_string(),
+        }
+    }
+}
+
+//a running service below the sources and what to do about it
+pub struct Quiesce {
+    pub service: &'static Service,
+    pub unit: String,
+    pub strategy: Strategy,
+}
+
+//one service's outcome, as recorded in the archive
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ServiceRecord {
+    pub service: String,
+    pub unit: String,
+    pub strategy: Strategy,
+    //the dump's name in the archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dump: Option<String>,
+    //how long the service was down for the stop strategy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stopped_seconds: Option<f64>,
+    //the strategy failed, the data directory was copied live
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ServiceRecord {
+    fn new(plan: &Quiesce) -> Self {
+        ServiceRecord { service: plan.service.name.to_string(), unit: plan.unit.clone(), strategy: plan.strategy, dump: None, stopped_seconds: None, error: None }
+    }
+}
+
+//running services with a data directory below one of the sources, only root can dump or stop them
+pub fn detect(sources: &[PathBuf]) -> Vec<(&'static Service, String)> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Vec::new();
+    }
+
+    SERVICES
+        .iter()
+        .filter(|service| Path::new(service.data_dir).is_dir() && sources.iter().any(|source| Path::new(service.data_dir).starts_with(source)))
+        .filter_map(|service| {
+            let unit = service.units.iter().find(|unit| is_active(unit))?;
+            Some((service, unit.to_string()))
+        })
+        .collect()
+}
+
+fn is_active(unit: &str) -> bool {
+    Command::new("systemctl")
+        .args(["is-active", "--quiet", unit])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+//the strategy per service: asked for in the menu, from the [services] table of config.toml otherwise
+//anything not configured is copied live, with a warning saying how to change that
+pub fn plan(sources: &[PathBuf], configured: &HashMap<String, Strategy>, interactive: bool) -> Result<Vec<Quiesce>, Box<dyn std::error::Error>> {
+    let mut plans = Vec::new();
+
+    for (service, unit) in detect(sources) {
+        let strategy = match configured.get(service.name) {
+            Some(strategy) => *strategy,
+            None if interactive => prompt_strategy(service, &unit)?,
+            None => {
+                println!(
+                    "{}",
+                    format!(
+                        "Warning: {} is running, {} is copied live and may not restore into a working database. Set {} = \"dump\" or \"stop\" under [services] in config.toml.",
+                        unit, service.data_dir, service.name
+                    )
+                    .yellow()
+                );
+                Strategy::Live
+            }
+        };
+        plans.push(Quiesce { service, unit, strategy });
+    }
+
+    Ok(plans)
+}
+
+fn prompt_strategy(service: &Service, unit: &str) -> Result<Strategy, Box<dyn std::error::Error>> {
+    println!("{}", format!("{} is running, a live copy of {} may not restore into a working database.", unit, service.data_dir).yellow());
+    let options = vec![
+        format!("Dump it ({})", service.dump_label()),
+        format!("Stop {} while {} is copied, then start it again", unit, service.data_dir),
+        "Copy it live anyway".to_string(),
+    ];
+    let selection = Select::new()
+        .with_prompt(format!("How should {} be backed up?", service.name))
+        .default(0)
+        .items(&options)
+        .interact()?;
+
+    Ok(match selection {
+        0 => Strategy::Dump,
+        1 => Strategy::Stop,
+        _ => Strategy::Live,
+    })
+}
+
+//run a command to completion or until the timeout, then kill it
+pub fn run_with_timeout(command: &mut Command, timeout: Duration) -> Result<(), String> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut child = command.spawn().map_err(|e| format!("could not run {}: {}", program, e))?;
+    let started = Instant::now();
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return Ok(()),
+            Ok(Some(status)) => return Err(format!("{} failed ({})", program, status)),
+            Ok(None) if started.elapsed() < timeout => std::thread::sleep(POLL_INTERVAL),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{} did not finish within {} s and was stopped", program, timeout.as_secs()));
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+//a command's output, for short answers like those of redis-cli or lsblk
+pub fn output_with_timeout(command: &mut Command, timeout: Duration) -> Result<String, String> {
+    let path = std::env::temp_dir().join(format!("linux_backup-{}-command.out", std::process::id()));
+    let file = File::create(&path).map_err(|e| e.to_string())?;
+    let result = run_with_timeout(command.stdout(file), timeout).and_then(|_| fs::read_to_string(&path).map_err(|e| e.to_string()));
+    let _ = fs::remove_file(&path);
+    result.map(|output| output.trim().to_string())
+}
+
+//run a dump tool into a private temporary file and store that as DUMPS_DIR/file, returns its size
+fn dump_into<W: Write>(archive: &mut Builder<W>, command: &mut Command, file: &str, mtime: u64) -> Result<u64, String> {
+    let path = std::env::temp_dir().join(format!("linux_backup-{}-{}", std::process::id(), file));
+    //dumps hold every row of every database, nobody else gets to read them on the way
+    let output = File::options().write(true).create_new(true).mode(0o600).open(&path).map_err(|e| e.to_string())?;
+
+    let result = run_with_timeout(command.stdout(output).stderr(Stdio::inherit()), DUMP_TIMEOUT).and_then(|_| {
+        let mut dump = File::open(&path).map_err(|e| e.to_string())?;
+        let size = dump.metadata().map_err(|e| e.to_string())?.len();
+        let mut header = Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o600);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        archive.append_data(&mut header, format!("{}/{}", DUMPS_DIR, file), &mut dump as &mut dyn Read).map_err(|e| e.to_string())?;
+        Ok(size)
+    });
+    let _ = fs::remove_file(&path);
+    result
+}
+
+//dump a service before the walk reaches its directory, a failed dump leaves the live copy as the fallback
+pub fn dump<W: Write>(plan: &Quiesce, archive: &mut Builder<W>, mtime: u64) -> ServiceRecord {
+    let mut record = ServiceRecord::new(plan);
+    println!("{}", format!("Dumping {} ({})...", plan.unit, plan.service.dump_label()).bold());
+    let started = Instant::now();
+
+    let result = match &plan.service.dump {
+        DumpMethod::Command { user, program, args, file } => {
+            let mut command = Command::new("runuser");
+            command.args(["-u", user, "--", program]).args(*args);
+            dump_into(archive, &mut command, file, mtime).map(|size| {
+                record.dump = Some(format!("{}/{}", DUMPS_DIR, file));
+                format!("{} written", utils::format_size(size))
+            })
+        }
+        DumpMethod::RedisBgsave => redis_bgsave().map(|_| format!("{}/dump.rdb saved", plan.service.data_dir)),
+    };
+
+    match result {
+        Ok(summary) => println!("{}", format!("Dumped {} in {:.1} s, {}", plan.unit, started.elapsed().as_secs_f64(), summary).green()),
+        Err(e) => {
+            println!("{}", format!("Error: Dumping {} failed: {}. Its data directory is still copied, live.", plan.unit, e).red());
+            record.error = Some(e);
+        }
+    }
+    record
+}
+
+//BGSAVE returns right away, the save is done once LASTSAVE moves
+fn redis_bgsave() -> Result<(), String> {
+    let last_save = || output_with_timeout(Command::new("redis-cli").arg("LASTSAVE"), SYSTEMCTL_TIMEOUT);
+    let before = last_save()?;
+    output_with_timeout(Command::new("redis-cli").arg("BGSAVE"), SYSTEMCTL_TIMEOUT)?;
+
+    let started = Instant::now();
+    while started.elapsed() < BGSAVE_TIMEOUT {
+        if last_save()? != before {
+            return Ok(());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Err(format!("the background save did not finish within {} s", BGSAVE_TIMEOUT.as_secs()))
+}
+
+//a service stopped for the walk, started again when dropped, on errors and early returns too
+pub struct StoppedService {
+    unit: String,
+    since: Instant,
+    running: bool,
+}
+
+impl StoppedService {
+    //a stop that fails or times out still starts the unit again on drop, systemd may have stopped it halfway
+    pub fn stop(unit: &str) -> Result<Self, (Self, String)> {
+        println!("{}", format!("Stopping {} while its data directory is copied...", unit).yellow().bold());
+        let stopped = StoppedService { unit: unit.to_string(), since: Instant::now(), running: false };
+        match run_with_timeout(Command::new("systemctl").args(["stop", unit]), SYSTEMCTL_TIMEOUT) {
+            Ok(()) => Ok(stopped),
+            Err(e) => Err((stopped, e)),
+        }
+    }
+
+    //start the unit again, returns how long it was down
+    //the copy is consistent either way, a failed start is for the user to sort out right away
+    pub fn start(mut self) -> Duration {
+        self.start_unit()
+    }
+
+    fn start_unit(&mut self) -> Duration {
+        self.running = true;
+        let down = self.since.elapsed();
+        match run_with_timeout(Command::new("systemctl").args(["start", &self.unit]), SYSTEMCTL_TIMEOUT) {
+            Ok(()) => println!("{}", format!("Started {} again, it was stopped for {:.1} s", self.unit, down.as_secs_f64()).green()),
+            Err(e) => println!("{}", format!("Error: Could not start {} again: {}. Start it with systemctl start {}", self.unit, e, self.unit).red().bold()),
+        }
+        down
+    }
+}
+
+impl Drop for StoppedService {
+    fn drop(&mut self) {
+        if !self.running {
+            let _ = self.start_unit();
+        }
+    }
+}
+
+//records for the stopped and live services, next to the dumps
+//a stop the walk never got to, say a low space abort, leaves the directory uncopied
+pub fn records_for(plans: &[Quiesce], mut dumped: Vec<ServiceRecord>, stops: &[(String, Result<Duration, String>)]) -> Vec<ServiceRecord> {
+    for plan in plans.iter().filter(|plan| plan.strategy != Strategy::Dump) {
+        let mut record = ServiceRecord::new(plan);
+        if plan.strategy == Strategy::Stop {
+            match stops.iter().find(|(unit, _)| *unit == plan.unit).map(|(_, result)| result) {
+                Some(Ok(down)) => record.stopped_seconds = Some(down.as_secs_f64()),
+                Some(Err(e)) => record.error = Some(e.clone()),
+                None => record.error = Some("its data directory was not reached".to_string()),
+            }
+        }
+        dumped.push(record);
+    }
+    dumped
+}
+
+//the records, next to the chain state, and a summary for the end of the backup
+pub fn append_to_archive<W: Write>(archive: &mut Builder<W>, records: &[ServiceRecord], mtime: u64) -> io::Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    utils::append_json_entry(archive, &format!("{}/{}", utils::STATE_DIR, SERVICES_FILE), &records, mtime)
+}
+
+//what a restore says about the services in an archive
+pub fn describe_for_restore(records: &[ServiceRecord], target_dir: &Path) {
+    for record in records {
+        match (&record.strategy, &record.dump, &record.error) {
+            (_, _, Some(error)) => println!("{}", format!("Note: {} was copied live, its {} failed during the backup: {}", record.service, strategy_name(record.strategy), error).yellow()),
+            (Strategy::Dump, Some(dump), None) => println!("{}", format!("Note: {} was dumped during the backup, the dump is at {}", record.service, target_dir.join(dump).display()).yellow()),
+            (Strategy::Dump, None, None) => println!("{}", format!("Note: {} saved its data to disk before it was copied", record.service).yellow()),
+            (Strategy::Stop, _, None) => println!("Note: {} was stopped while its data was copied, the copy is consistent", record.service),
+            (Strategy::Live, _, None) => println!("{}", format!("Note: {} was copied while running and may need recovery before it starts", record.service).yellow()),
+        }
+    }
+}
+
+fn strategy_name(strategy: Strategy) -> &'static str {
+    match strategy {
+        Strategy::Dump => "dump",
+        Strategy::Stop => "stop",
+        Strategy::Live => "live copy",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dumps_are_stored_privately_and_slow_commands_stopped() {
+        let mut archive = Builder::new(Vec::new());
+        let size = dump_into(&mut archive, Command::new("sh").args(["-c", "echo 'CREATE TABLE t (id int);'"]), "test.sql", 1_000).unwrap();
+        assert_eq!(size, 25);
+
+        let data = archive.into_inner().unwrap();
+        let mut reader = tar::Archive::new(data.as_slice());
+        let mut entry = reader.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new(DUMPS_DIR).join("test.sql"));
+        assert_eq!(entry.header().mode().unwrap(), 0o600);
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "CREATE TABLE t (id int);\n");
+
+        let started = Instant::now();
+        assert!(run_with_timeout(Command::new("sleep").arg("10"), Duration::from_millis(300)).unwrap_err().contains("did not finish"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(dump_into(&mut Builder::new(Vec::new()), &mut Command::new("false"), "failed.sql", 0).is_err());
+
+        let configured: HashMap<String, Strategy> = toml::from_str("postgresql = \"dump\"\nredis = \"stop\"").unwrap();
+        assert_eq!(configured["redis"], Strategy::Stop);
+    }
+}
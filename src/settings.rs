@@ -0,0 +1,63 @@
+use crate::config;
+use crate::paths;
+use crate::prompt::{Input, MultiSelect, Select};
+use crate::utils;
+use colored::*;
+
+pub fn settings_menu() -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", "\n---- Settings ----".blue().bold());
+
+    loop {
+        let options = vec!["Edit quick-pick directories", "Back"];
+        let selection = Select::new()
+            .with_prompt("What would you like to change?")
+            .default(0)
+            .items(&options)
+            .interact()?;
+
+        match selection {
+            0 => edit_quick_dirs()?,
+            _ => break,
+        }
+    }
+
+    Ok(())
+}
+
+fn edit_quick_dirs() -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = config::load_config()?;
+    let current = config::configured_quick_dirs(&config);
+
+    //everything starts checked, unchecking removes an entry
+    let defaults = vec![true; current.len()];
+    let keep = MultiSelect::new()
+        .with_prompt("Quick-pick directories, unselect the ones to remove")
+        .items(&current)
+        .defaults(&defaults)
+        .interact()?;
+
+    let mut quick_dirs: Vec<String> = keep.iter().map(|&i| current[i].clone()).collect();
+
+    loop {
+        let dir: String = Input::new()
+            .with_prompt("Add a directory (leave empty to finish)")
+            .allow_empty(true)
+            .interact_text()?;
+
+        let dir = dir.trim();
+        if dir.is_empty() {
+            break;
+        }
+
+        if !std::path::Path::new(&utils::expand_tilde(dir)).exists() {
+            println!("{}", format!("Note: {} does not exist right now, it will be hidden until it does", dir).yellow());
+        }
+        quick_dirs.push(dir.to_string());
+    }
+
+    config.quick_dirs = Some(quick_dirs);
+    config::save_config(&config)?;
+    println!("{}", format!("Saved to {}", paths::config_dir().join(config::CONFIG_FILE).display()).green());
+
+    Ok(())
+}
@@ -0,0 +1,267 @@
+use crate::compression::CompressionFormat;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//the files the tool writes around an archive, backup, restore and verify all name and recognize them through here
+pub const PARTIAL: &str = ".partial";
+pub const METADATA_STUB: &str = ".metadata.json";
+pub const CHECKSUM: &str = ".sha256";
+pub const INDEX: &str = ".index.json";
+pub const ENTRY_INDEX: &str = ".index";
+pub const RESTORE_SCRIPT: &str = ".restore.sh";
+
+//what a file next to the archives is to the tool
+#[derive(Debug, PartialEq)]
+pub enum Role {
+    Partial,
+    MetadataStub,
+    Checksum,
+    Index,
+    EntryIndex,
+    RestoreScript,
+    Volume,
+    Rotated,
+}
+
+impl Role {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Role::Partial => "the unfinished archive of a backup that is running or was interrupted",
+            Role::MetadataStub => "the copy of a backup's metadata kept next to its archive",
+            Role::Checksum => "a checksum file",
+            Role::Index => "the index of a volume set",
+            Role::EntryIndex => "the entry index of an archive",
+            Role::RestoreScript => "a restore script",
+            Role::Volume => "a volume of a split backup",
+            Role::Rotated => "an earlier backup kept by rotation",
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+//archives are written to backup.tar.gz.partial and only renamed once complete
+pub fn partial(archive: &Path) -> PathBuf {
+    with_suffix(archive, PARTIAL)
+}
+
+//backup.tar.gz -> backup.tar.gz.N
+pub fn rotated(archive: &Path, n: u32) -> PathBuf {
+    with_suffix(archive, &format!(".{}", n))
+}
+
+//backup.tar.gz -> backup.tar.gz.metadata.json
+pub fn metadata_stub(archive: &Path) -> PathBuf {
+    with_suffix(archive, METADATA_STUB)
+}
+
+//backup.tar.gz -> backup.tar.gz.sha256, the checksums of its files, or of the volume itself for photos.vol001.tar.gz
+pub fn checksum(archive: &Path) -> PathBuf {
+    with_suffix(archive, CHECKSUM)
+}
+
+//backup.tar.gz -> backup.tar.gz.index, where each entry starts, for reading one without the rest
+pub fn entry_index(archive: &Path) -> PathBuf {
+    with_suffix(archive, ENTRY_INDEX)
+}
+
+//backup_XXXX.tar.gz, .tar.zst, .tar.xz or .tar, optionally with .age or .gpg -> backup_XXXX.restore.sh
+pub fn restore_script(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let name = [".age", ".gpg", ".pgp"]
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(&name);
+    let stem = name
+        .strip_suffix(".tar.gz")
+        .or_else(|| name.strip_suffix(".tar.zst"))
+        .or_else(|| name.strip_suffix(".tar.xz"))
+        .or_else(|| name.strip_suffix(".tar"))
+        .unwrap_or(name);
+
+    archive.with_file_name(format!("{}{}", stem, RESTORE_SCRIPT))
+}
+
+//photos.tar.gz -> (photos, .tar.gz), the volumes are photos.vol001.tar.gz and so on
+pub fn set_name(output: &Path, extension: &str) -> String {
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    name.strip_suffix(extension).unwrap_or(&name).to_string()
+}
+
+pub fn volume_name(set: &str, number: usize, extension: &str) -> String {
+    format!("{}.vol{:03}{}", set, number, extension)
+}
+
+pub fn index_name(set: &str) -> String {
+    format!("{}{}", set, INDEX)
+}
+
+//the set a volume file belongs to, None for anything that isn't a volume
+pub fn set_of(file_name: &str) -> Option<&str> {
+    let (set, rest) = file_name.rsplit_once(".vol")?;
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    if digits < 3 || !rest[digits..].starts_with(".tar") || rest.ends_with(CHECKSUM) || rest.ends_with(PARTIAL) || rest.ends_with(ENTRY_INDEX) {
+        return None;
+    }
+    Some(set)
+}
+
+//None for archives and for files the tool doesn't write
+pub fn role_of(file_name: &str) -> Option<Role> {
+    if file_name.ends_with(PARTIAL) {
+        return Some(Role::Partial);
+    }
+    if file_name.ends_with(METADATA_STUB) {
+        return Some(Role::MetadataStub);
+    }
+    if file_name.ends_with(CHECKSUM) {
+        return Some(Role::Checksum);
+    }
+    if file_name.ends_with(INDEX) {
+        return Some(Role::Index);
+    }
+    if file_name.ends_with(ENTRY_INDEX) {
+        return Some(Role::EntryIndex);
+    }
+    if file_name.ends_with(RESTORE_SCRIPT) {
+        return Some(Role::RestoreScript);
+    }
+    if set_of(file_name).is_some() {
+        return Some(Role::Volume);
+    }
+
+    //backup.tar.gz.3, encrypted archives keep their .age or .gpg before the number
+    let (archive, number) = file_name.rsplit_once('.')?;
+    let archive = [".age", ".gpg", ".pgp"].iter().find_map(|suffix| archive.strip_suffix(suffix)).unwrap_or(archive);
+    if !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()) && CompressionFormat::from_name(archive).is_some() {
+        return Some(Role::Rotated);
+    }
+    None
+}
+
+//an output name the tool would mistake for one of its own files, later backups would rotate over it or restore would skip it
+pub fn check_output_name(output: &Path) -> Result<(), String> {
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    match role_of(&name) {
+        Some(role) => Err(format!("{} is named like {}, choose a name that doesn't end in {}", name, role.describe(), suffix_of(&name, &role))),
+        None => Ok(()),
+    }
+}
+
+fn suffix_of<'a>(name: &'a str, role: &Role) -> &'a str {
+    let start = match role {
+        Role::Partial => name.len() - PARTIAL.len(),
+        Role::MetadataStub => name.len() - METADATA_STUB.len(),
+        Role::Checksum => name.len() - CHECKSUM.len(),
+        Role::Index => name.len() - INDEX.len(),
+        Role::EntryIndex => name.len() - ENTRY_INDEX.len(),
+        Role::RestoreScript => name.len() - RESTORE_SCRIPT.len(),
+        Role::Volume => set_of(name).map_or(0, str::len),
+        Role::Rotated => name.rfind('.').unwrap_or(0),
+    };
+    &name[start..]
+}
+
+//files next to the archive that the backup would write over without them being its own,
+//a metadata copy or checksums left behind by an archive that is gone, or the restore script of a differently compressed backup of the same name
+pub fn conflicts(archive: &Path) -> Vec<PathBuf> {
+    let mut conflicts = Vec::new();
+    for sidecar in [metadata_stub(archive), checksum(archive), entry_index(archive)] {
+        if sidecar.exists() && !archive.exists() {
+            conflicts.push(sidecar);
+        }
+    }
+
+    let script = restore_script(archive);
+    let archive_name = archive.file_name().unwrap_or_default().to_string_lossy().to_string();
+    if let Ok(contents) = fs::read_to_string(&script) {
+        if !contents.contains(&archive_name) {
+            conflicts.push(script);
+        }
+    } else if script.exists() {
+        conflicts.push(script);
+    }
+    conflicts
+}
+
+//the volumes and the index of a set that would be written over
+pub fn set_conflicts(output: &Path, extension: &str) -> Vec<PathBuf> {
+    let set = set_name(output, extension);
+    let dir = output.parent().unwrap_or(Path::new("/"));
+    let mut conflicts: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_str().and_then(set_of) == Some(set.as_str()))
+                .map(|entry| entry.path())
+                .collect()
+        })
+        .unwrap_or_default();
+    conflicts.sort();
+
+    let index = dir.join(index_name(&set));
+    if index.exists() {
+        conflicts.push(index);
+    }
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecar_names_are_recognized() {
+        assert_eq!(set_of("photos.vol002.tar.gz.partial"), None);
+        assert_eq!(role_of("backup.tar.gz"), None);
+        assert_eq!(role_of("notes.txt.1"), None);
+        assert_eq!(role_of("backup.tar.gz.partial"), Some(Role::Partial));
+        assert_eq!(role_of("backup.tar.gz.metadata.json"), Some(Role::MetadataStub));
+        assert_eq!(role_of("photos.vol001.tar.gz.sha256"), Some(Role::Checksum));
+        assert_eq!(role_of("photos.index.json"), Some(Role::Index));
+        assert_eq!(role_of("backup.tar.gz.index"), Some(Role::EntryIndex));
+        assert_eq!(role_of("backup.restore.sh"), Some(Role::RestoreScript));
+        assert_eq!(role_of("photos.vol001.tar.zst"), Some(Role::Volume));
+        assert_eq!(role_of("backup.tar.gz.2"), Some(Role::Rotated));
+        assert_eq!(role_of("backup.tar.zst.age.12"), Some(Role::Rotated));
+
+        assert!(check_output_name(Path::new("/b/backup.tar.gz")).is_ok());
+        assert_eq!(
+            check_output_name(Path::new("/b/photos.vol001.tar.gz")).unwrap_err(),
+            "photos.vol001.tar.gz is named like a volume of a split backup, choose a name that doesn't end in .vol001.tar.gz"
+        );
+        assert!(check_output_name(Path::new("/b/backup.tar.gz.1")).unwrap_err().ends_with("doesn't end in .1"));
+    }
+
+    #[test]
+    fn sidecars_of_other_backups_are_conflicts() {
+        let temp = tempfile::tempdir().unwrap();
+        let gzip = temp.path().join("backup.tar.gz");
+        let zstd = temp.path().join("backup.tar.zst");
+        assert!(conflicts(&gzip).is_empty());
+
+        //both would write backup.restore.sh
+        fs::write(restore_script(&gzip), "ARCHIVE_NAME='backup.tar.gz'\n").unwrap();
+        assert!(conflicts(&gzip).is_empty());
+        assert_eq!(conflicts(&zstd), vec![temp.path().join("backup.restore.sh")]);
+
+        //a metadata copy without its archive belongs to nothing the backup replaces
+        fs::write(metadata_stub(&zstd), "{}").unwrap();
+        assert_eq!(conflicts(&zstd).len(), 2);
+        fs::write(&zstd, "").unwrap();
+        assert_eq!(conflicts(&zstd).len(), 1);
+
+        fs::write(temp.path().join("photos.vol001.tar.gz"), "").unwrap();
+        fs::write(temp.path().join("photos.vol001.tar.gz.sha256"), "").unwrap();
+        fs::write(temp.path().join("photos.index.json"), "").unwrap();
+        fs::write(temp.path().join("other.vol001.tar.gz"), "").unwrap();
+        assert_eq!(set_conflicts(&temp.path().join("photos.tar.gz"), ".tar.gz"), vec![temp.path().join("photos.vol001.tar.gz"), temp.path().join("photos.index.json")]);
+    }
+}
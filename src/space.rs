@@ -0,0 +1,279 @@
+use crate::mounts;
+use crate::prompt::Select;
+use crate::utils;
+use colored::*;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+//free space to keep on the destination before the low-space policy kicks in: 5% of the filesystem, at most this
+//a fixed 512M stopped every backup to a small USB stick or partition before it started
+const MAX_LOW_WATER_MARK: u64 = 512 * 1024 * 1024;
+
+//re-read the real free space after this many files or bytes
+const CHECK_EVERY_FILES: u64 = 100;
+const CHECK_EVERY_BYTES: u64 = 64 * 1024 * 1024;
+
+const MIB: u64 = 1024 * 1024;
+const GIB: u64 = 1024 * MIB;
+const TIB: u64 = 1024 * GIB;
+
+//largest file each filesystem can hold, by statfs magic number
+const FILE_SIZE_LIMITS: &[(i64, &str, u64)] = &[
+    (0x4d44, "FAT", 4 * GIB - 1),
+    (0x9660, "ISO 9660", 4 * GIB - 1),
+    (0x517B, "smbfs", 2 * GIB - 1),
+    (0x4244, "HFS", 2 * GIB - 1),
+    (0x137F, "Minix", 64 * MIB),
+    (0x2468, "Minix v2", 2 * GIB - 1),
+    (0xEF53, "ext4", 16 * TIB),
+];
+
+//object stores mounted through FUSE, where the object size is the limit (S3 allows 5 TB)
+const OBJECT_STORE_LIMITS: &[(&str, &str, u64)] = &[
+    ("fuse.s3fs", "S3 (s3fs)", 5 * TIB),
+    ("fuse.goofys", "S3 (goofys)", 5 * TIB),
+    ("fuse.mountpoint-s3", "S3 (mountpoint-s3)", 5 * TIB),
+];
+
+//a filesystem that can't hold files past a certain size
+#[derive(Debug, PartialEq)]
+pub struct FileSizeLimit {
+    pub filesystem: &'static str,
+    pub max_file_size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LowSpacePolicy {
+    //ask the user what to do (interactive runs)
+    Prompt,
+    //stop archiving and close the archive cleanly
+    Abort,
+    //skip files larger than the remaining space and keep going with smaller ones
+    SkipLarger,
+    //ignore the low-water mark for the rest of the run
+    Continue,
+}
+
+pub struct SpaceMonitor {
+    destination: PathBuf,
+    pub low_water_mark: u64,
+    pub policy: LowSpacePolicy,
+    pub aborted: bool,
+    pub skipped: Vec<(PathBuf, u64)>,
+    free_estimate: u64,
+    files_since_check: u64,
+    bytes_since_check: u64,
+}
+
+impl SpaceMonitor {
+    pub fn new(destination: &Path, policy: LowSpacePolicy) -> Self {
+        let mut monitor = SpaceMonitor {
+            destination: destination.to_path_buf(),
+            low_water_mark: default_low_water_mark(filesystem_size(destination)),
+            policy,
+            aborted: false,
+            skipped: Vec::new(),
+            free_estimate: u64::MAX,
+            files_since_check: 0,
+            bytes_since_check: 0,
+        };
+        monitor.refresh();
+        monitor
+    }
+
+    //low_water_mark from config.toml replaces the one scaled to the filesystem
+    pub fn with_low_water_mark(mut self, mark: Option<u64>) -> Self {
+        if let Some(mark) = mark {
+            self.low_water_mark = mark;
+        }
+        self
+    }
+
+    //min_free_after replaces the default low-water mark
+    pub fn with_floor(mut self, floor: Option<&(u64, String)>) -> Self {
+        if let Some((floor, _)) = floor {
+            self.low_water_mark = *floor;
+        }
+        self
+    }
+
+    fn refresh(&mut self) {
+        self.free_estimate = free_space(&self.destination).unwrap_or(u64::MAX);
+        self.files_since_check = 0;
+        self.bytes_since_check = 0;
+    }
+
+    //decide whether a file of the given size may still be archived
+    pub fn allow(&mut self, path: &Path, size: u64) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.aborted {
+            return Ok(false);
+        }
+
+        if self.files_since_check >= CHECK_EVERY_FILES || self.bytes_since_check >= CHECK_EVERY_BYTES {
+            self.refresh();
+        }
+
+        if self.policy != LowSpacePolicy::Continue && self.free_estimate.saturating_sub(size) < self.low_water_mark {
+            if self.policy == LowSpacePolicy::Prompt {
+                //make sure the prompt is based on the real number, not the estimate
+                self.refresh();
+                if self.free_estimate.saturating_sub(size) < self.low_water_mark {
+                    self.policy = prompt_policy(self.free_estimate)?;
+                }
+            }
+
+            match self.policy {
+                LowSpacePolicy::Abort => {
+                    self.aborted = true;
+                    return Ok(false);
+                }
+                LowSpacePolicy::SkipLarger => {
+                    if size > self.free_estimate.saturating_sub(self.low_water_mark) {
+                        self.skipped.push((path.to_path_buf(), size));
+                        return Ok(false);
+                    }
+                }
+                LowSpacePolicy::Prompt | LowSpacePolicy::Continue => {}
+            }
+        }
+
+        self.files_since_check += 1;
+        self.bytes_since_check += size;
+        self.free_estimate = self.free_estimate.saturating_sub(size);
+        Ok(true)
+    }
+
+    pub fn report_skipped(&self) {
+        if self.skipped.is_empty() {
+            return;
+        }
+
+        let total: u64 = self.skipped.iter().map(|(_, size)| size).sum();
+        println!("{}", format!("Skipped {} files ({}) because the destination was low on space:", self.skipped.len(), utils::format_size(total)).yellow());
+        for (path, size) in &self.skipped {
+            println!("  {} ({})", path.display(), utils::format_size(*size));
+        }
+    }
+}
+
+fn prompt_policy(free: u64) -> Result<LowSpacePolicy, Box<dyn std::error::Error>> {
+    println!("{}", format!("\nWarning: Destination is running low on space ({} free)", utils::format_size(free)).yellow().bold());
+
+    let options = vec![
+        "Abort cleanly (keep the files archived so far)",
+        "Skip files larger than the remaining space",
+        "Continue anyway",
+    ];
+    let selection = Select::new()
+        .with_prompt("What would you like to do?")
+        .default(0)
+        .items(&options)
+        .interact()?;
+
+    Ok(match selection {
+        0 => LowSpacePolicy::Abort,
+        1 => LowSpacePolicy::SkipLarger,
+        _ => LowSpacePolicy::Continue,
+    })
+}
+
+pub fn default_low_water_mark(filesystem_size: Option<u64>) -> u64 {
+    filesystem_size.map_or(MAX_LOW_WATER_MARK, |size| (size / 20).min(MAX_LOW_WATER_MARK))
+}
+
+//the whole size of the filesystem holding a path, free or not
+fn filesystem_size(path: &Path) -> Option<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    Some(stat.f_blocks as u64 * stat.f_frsize as u64)
+}
+
+//bytes available to unprivileged users on the filesystem holding a path
+pub fn free_space(path: &Path) -> Option<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+//the file size limit of the filesystem a backup is written to, None where files can grow as large as the disk
+pub fn file_size_limit(path: &Path) -> Option<FileSizeLimit> {
+    let fs_type = mounts::filesystem_of(path).map(|filesystem| filesystem.fs_type).unwrap_or_default();
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    limit_for(stat.f_type as i64, &fs_type)
+}
+
+fn limit_for(magic: i64, fs_type: &str) -> Option<FileSizeLimit> {
+    //FUSE mounts all share one magic number, the mount table tells them apart
+    if let Some((_, filesystem, max_file_size)) = OBJECT_STORE_LIMITS.iter().find(|(name, _, _)| *name == fs_type) {
+        return Some(FileSizeLimit { filesystem, max_file_size: *max_file_size });
+    }
+    FILE_SIZE_LIMITS
+        .iter()
+        .find(|(limited, _, _)| *limited == magic)
+        .map(|(_, filesystem, max_file_size)| FileSizeLimit { filesystem, max_file_size: *max_file_size })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a monitor that believes this much is free, re-reading the real number is at least 100 files away
+    fn monitor(policy: LowSpacePolicy, free: u64, mark: u64) -> SpaceMonitor {
+        let mut monitor = SpaceMonitor::new(Path::new("/"), policy).with_low_water_mark(Some(mark));
+        monitor.free_estimate = free;
+        monitor
+    }
+
+    #[test]
+    fn low_space_policies_decide_what_is_still_archived() {
+        let mut abort = monitor(LowSpacePolicy::Abort, 1000, 100);
+        assert!(abort.allow(Path::new("a"), 500).unwrap());
+        //would leave 0 of the 100 to keep, the run stops and nothing more gets in
+        assert!(!abort.allow(Path::new("b"), 500).unwrap());
+        assert!(abort.aborted);
+        assert!(!abort.allow(Path::new("c"), 1).unwrap());
+
+        let mut skip = monitor(LowSpacePolicy::SkipLarger, 1000, 100);
+        assert!(skip.allow(Path::new("a"), 800).unwrap());
+        assert!(!skip.allow(Path::new("big"), 150).unwrap());
+        assert!(skip.allow(Path::new("small"), 50).unwrap());
+        assert!(!skip.aborted);
+        assert_eq!(skip.skipped, vec![(PathBuf::from("big"), 150)]);
+
+        let mut carry_on = monitor(LowSpacePolicy::Continue, 1000, 100);
+        assert!(carry_on.allow(Path::new("a"), 5000).unwrap());
+        assert!(!carry_on.aborted);
+    }
+
+    #[test]
+    fn the_default_low_water_mark_scales_with_small_filesystems() {
+        assert_eq!(default_low_water_mark(Some(100 * MIB)), 5 * MIB);
+        assert_eq!(default_low_water_mark(Some(2 * 1024 * 1024 * MIB)), 512 * MIB);
+        assert_eq!(default_low_water_mark(None), 512 * MIB);
+    }
+
+    #[test]
+    fn file_size_limits_come_from_the_magic_number_or_the_fuse_type() {
+        assert_eq!(limit_for(0x4d44, "vfat"), Some(FileSizeLimit { filesystem: "FAT", max_file_size: 4 * GIB - 1 }));
+        assert_eq!(limit_for(0x65735546, "fuse.s3fs").unwrap().max_file_size, 5 * TIB);
+        assert_eq!(limit_for(0x65735546, "fuse.sshfs"), None);
+        assert_eq!(limit_for(0x9123683E, "btrfs"), None);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::utils;
+use colored::*;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tar::Builder;
+
+//the first bytes of every SQLite database file
+const MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+//a database file is at least its 100 byte header
+const HEADER_SIZE: u64 = 100;
+
+//how often a busy database is retried before it is copied raw
+const BUSY_RETRIES: u32 = 10;
+const BUSY_PAUSE: Duration = Duration::from_millis(200);
+
+//files SQLite keeps next to a database while it is open
+const SIDECAR_SUFFIXES: &[&str] = &["-wal", "-shm", "-journal"];
+
+//which databases were archived from a snapshot, stored in the archive's state directory
+pub const DATABASES_FILE: &str = "databases.json";
+
+//databases found during a backup and how each was stored
+#[derive(Serialize, Default, Debug)]
+pub struct DatabaseSnapshots {
+    pub snapshotted: Vec<PathBuf>,
+    //copied as they were on disk, with the reason the snapshot failed
+    pub raw_copies: Vec<(PathBuf, String)>,
+    //-wal, -shm and -journal files left out because their database's snapshot holds their contents
+    #[serde(skip)]
+    pub sidecars_skipped: u64,
+}
+
+impl DatabaseSnapshots {
+    pub fn is_empty(&self) -> bool {
+        self.snapshotted.is_empty() && self.raw_copies.is_empty()
+    }
+
+    //true for databases archived from a snapshot, false for raw copies, None if not archived yet
+    pub fn snapshotted(&self, database: &Path) -> Option<bool> {
+        if self.snapshotted.iter().any(|snapshotted| snapshotted == database) {
+            Some(true)
+        } else if self.raw_copies.iter().any(|(copied, _)| copied == database) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    pub fn report(&self) {
+        if !self.snapshotted.is_empty() {
+            println!(
+                "SQLite databases archived from a consistent snapshot: {} ({} -wal/-shm/-journal files left out)",
+                self.snapshotted.len(),
+                self.sidecars_skipped
+            );
+        }
+        if self.raw_copies.is_empty() {
+            return;
+        }
+
+        println!("{}", format!("{} SQLite databases were copied while in use and are possibly inconsistent:", self.raw_copies.len()).yellow());
+        for (path, reason) in &self.raw_copies {
+            println!("  {} ({})", path.display(), reason);
+        }
+    }
+
+    //a note next to the chain state, so a restore can tell which copies to trust
+    pub fn append_to_archive<W: io::Write>(&self, archive: &mut Builder<W>, mtime: u64) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        utils::append_json_entry(archive, &format!("{}/{}", utils::STATE_DIR, DATABASES_FILE), self, mtime)
+    }
+}
+
+//the database a -wal, -shm or -journal file belongs to, both as a path and as its archive name
+pub fn sidecar_of(path: &Path, name: &Path) -> Option<(PathBuf, PathBuf)> {
+    let strip = |path: &Path| {
+        let text = path.as_os_str().as_bytes();
+        SIDECAR_SUFFIXES
+            .iter()
+            .find_map(|suffix| text.strip_suffix(suffix.as_bytes()))
+            .map(|database| PathBuf::from(OsStr::from_bytes(database)))
+    };
+    Some((strip(path)?, strip(name)?))
+}
+
+//checked on the already opened file, so the walk doesn't open every file twice
+pub fn is_database(file: &File, size: u64) -> bool {
+    let mut start = [0; 16];
+    size >= HEADER_SIZE && file.read_exact_at(&mut start, 0).is_ok() && &start == MAGIC
+}
+
+//copy a live database through SQLite's backup API, which sees it between transactions
+//a database held by an exclusive lock for longer than the retries fails with the reason
+pub fn snapshot(path: &Path, destination: &Path) -> Result<(), String> {
+    let source = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+        .map_err(|e| e.to_string())?;
+    let mut target = Connection::open(destination).map_err(|e| e.to_string())?;
+
+    let backup = Backup::new(&source, &mut target).map_err(|e| e.to_string())?;
+    for _ in 0..BUSY_RETRIES {
+        //all pages in one step, so the copy comes from a single read transaction
+        match backup.step(-1).map_err(|e| e.to_string())? {
+            StepResult::Done => return Ok(()),
+            StepResult::More | StepResult::Busy | StepResult::Locked => std::thread::sleep(BUSY_PAUSE),
+            _ => {}
+        }
+    }
+    Err("locked by another process".to_string())
+}
+
+//where snapshots are written before they go into the archive
+pub fn snapshot_path() -> PathBuf {
+    std::env::temp_dir().join(format!("linux_backup-{}-snapshot.db", std::process::id()))
+}
+
+//the snapshot file is only needed until its entry is written
+pub fn remove_snapshot(path: &Path) {
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(path.with_extension("db-journal"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn databases_are_recognized_and_snapshotted() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("places.sqlite");
+        let live = Connection::open(&path).unwrap();
+        live.execute_batch("PRAGMA journal_mode=WAL; CREATE TABLE visits (url TEXT); INSERT INTO visits VALUES ('https://example.com');").unwrap();
+
+        let size = fs::metadata(&path).unwrap().len();
+        assert!(is_database(&File::open(&path).unwrap(), size));
+        let text = temp.path().join("notes.txt");
+        fs::write(&text, "SQLite format 2 and some more text to fill a header, ".repeat(4)).unwrap();
+        assert!(!is_database(&File::open(&text).unwrap(), 200));
+
+        //the open connection keeps the row in the WAL, the snapshot still has it
+        let copy = temp.path().join("copy.db");
+        snapshot(&path, &copy).unwrap();
+        let count: i64 = Connection::open(&copy).unwrap().query_row("SELECT count(*) FROM visits", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+
+        live.execute_batch("PRAGMA locking_mode=EXCLUSIVE; BEGIN EXCLUSIVE;").unwrap();
+        assert!(snapshot(&path, &temp.path().join("locked.db")).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::config::Config;
+use crate::paths;
+use crate::users;
+use crate::utils;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+//the reason the last run of a chain failed, removed again by the next run that completes
+pub const FAILURE_FILE: &str = "last_failure.json";
+
+//how old the last backup may get before it shows yellow, then red, unless config.toml says otherwise
+const DEFAULT_WARN_AFTER: &str = "2d";
+const DEFAULT_STALE_AFTER: &str = "7d";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Failure {
+    pub time: u64,
+    pub reason: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Freshness {
+    Fresh,
+    Aging,
+    Stale,
+}
+
+//a backup chain with something to report, the current user's or one of the users root backs up
+pub struct ChainStatus {
+    pub name: String,
+    pub last_backup: Option<u64>,
+    //only set when it happened after the last successful backup
+    pub failure: Option<Failure>,
+}
+
+pub struct Thresholds {
+    pub warn_after: u64,
+    pub stale_after: u64,
+}
+
+impl Thresholds {
+    pub fn from_config(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let warn_after = utils::parse_duration(config.status_warn_after.as_deref().unwrap_or(DEFAULT_WARN_AFTER))?;
+        let stale_after = utils::parse_duration(config.status_stale_after.as_deref().unwrap_or(DEFAULT_STALE_AFTER))?;
+        if warn_after > stale_after {
+            return Err("status_warn_after is longer than status_stale_after in config.toml".into());
+        }
+        Ok(Thresholds { warn_after, stale_after })
+    }
+
+    //a chain that never completed a backup, or whose last run failed, is judged by its last success all the same
+    pub fn judge(&self, chain: &ChainStatus, now: u64) -> Freshness {
+        match chain.last_backup.map(|time| now.saturating_sub(time)) {
+            Some(age) if age <= self.warn_after => Freshness::Fresh,
+            Some(age) if age <= self.stale_after => Freshness::Aging,
+            _ => Freshness::Stale,
+        }
+    }
+}
+
+//a failed run is remembered next to the chain's metadata, a write error only costs the status line
+pub fn record_failure(metadata_dir: &Path, reason: &str) {
+    let failure = Failure { time: now(), reason: reason.to_string() };
+    if let Ok(data) = serde_json::to_vec_pretty(&failure) {
+        let _ = fs::write(metadata_dir.join(FAILURE_FILE), data);
+    }
+}
+
+pub fn clear_failure(metadata_dir: &Path) {
+    let _ = fs::remove_file(metadata_dir.join(FAILURE_FILE));
+}
+
+fn read_failure(metadata_dir: &Path) -> Option<Failure> {
+    serde_json::from_slice(&fs::read(metadata_dir.join(FAILURE_FILE)).ok()?).ok()
+}
+
+//None for a chain that has neither a backup nor a failure to show
+pub fn chain_status(name: &str, metadata_dir: &Path) -> Option<ChainStatus> {
+    let last_backup = if metadata_dir.join(utils::METADATA_FILE).exists() {
+        utils::load_metadata_store(metadata_dir).ok().and_then(|store| store.last_backup_time())
+    } else {
+        None
+    };
+    let failure = read_failure(metadata_dir).filter(|failure| last_backup.is_none_or(|time| failure.time > time));
+    if last_backup.is_none() && failure.is_none() {
+        return None;
+    }
+    Some(ChainStatus { name: name.to_string(), last_backup, failure })
+}
+
+//the invoking user's chain, and for root the chains of the per-user backups too
+pub fn chains() -> Vec<ChainStatus> {
+    let is_root = unsafe { libc::geteuid() == 0 };
+    let mut dirs: Vec<(String, PathBuf)> = vec![(if is_root { "system".to_string() } else { "your backups".to_string() }, paths::state_dir())];
+    if is_root {
+        dirs.extend(users::regular_users().into_iter().map(|user| (format!("user {}", user.name), paths::user_state_dir_lookup(&user))));
+    }
+    dirs.iter().filter_map(|(name, dir)| chain_status(name, dir)).collect()
+}
+
+//"3 hours", "6 weeks", rounded down to the largest unit that fits
+pub fn format_age(seconds: u64) -> String {
+    if seconds < 60 {
+        return "less than a minute".to_string();
+    }
+    let (count, unit) = match seconds {
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 2 * 24 * 60 * 60 => (s / (60 * 60), "hour"),
+        s if s < 3 * 7 * 24 * 60 * 60 => (s / (24 * 60 * 60), "day"),
+        s => (s / (7 * 24 * 60 * 60), "week"),
+    };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+fn describe(chain: &ChainStatus, freshness: Freshness, now: u64) -> String {
+    let text = match chain.last_backup {
+        Some(time) => format!("last backup {} ago", format_age(now.saturating_sub(time))),
+        None => "never backed up".to_string(),
+    };
+    match freshness {
+        Freshness::Fresh => text.green().to_string(),
+        Freshness::Aging => text.yellow().to_string(),
+        Freshness::Stale => text.red().to_string(),
+    }
+}
+
+//the status subcommand, returns how many chains are stale
+pub fn print_status(config: &Config) -> Result<usize, Box<dyn std::error::Error>> {
+    let thresholds = Thresholds::from_config(config)?;
+    let now = now();
+    let chains = chains();
+
+    println!("{}", "\n---- Backup Status ----".blue().bold());
+    if chains.is_empty() {
+        println!("No backups have been made yet.");
+        return Ok(0);
+    }
+
+    let mut stale = 0;
+    for chain in &chains {
+        let freshness = thresholds.judge(chain, now);
+        if freshness == Freshness::Stale {
+            stale += 1;
+        }
+        let when = chain.last_backup.map(|time| format!(" ({})", local_time(time))).unwrap_or_default();
+        println!("{}: {}{}", chain.name.bold(), describe(chain, freshness, now), when);
+        if let Some(failure) = &chain.failure {
+            println!("  {}", format!("The last run failed {} ago: {}", format_age(now.saturating_sub(failure.time)), failure.reason).red());
+        }
+    }
+    println!(
+        "Backups older than {} are shown yellow, older than {} red (status_warn_after and status_stale_after in config.toml).",
+        format_age(thresholds.warn_after),
+        format_age(thresholds.stale_after)
+    );
+    Ok(stale)
+}
+
+//one line for the main menu, about the invoking user's own chain
+pub fn menu_line(config: &Config) -> Option<String> {
+    let thresholds = Thresholds::from_config(config).ok()?;
+    let now = now();
+    let chain = chain_status("", &paths::state_dir())?;
+    let mut line = format!("Backup status: {}", describe(&chain, thresholds.judge(&chain, now), now));
+    if let Some(failure) = &chain.failure {
+        line.push_str(&format!(", {}", format!("the last run failed: {}", failure.reason).red()));
+    }
+    Some(line)
+}
+
+pub fn local_time(time: u64) -> String {
+    chrono::DateTime::from_timestamp(time as i64, 0).unwrap_or_default().with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_are_judged_by_their_last_successful_backup() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        assert!(chain_status("empty", dir).is_none());
+
+        let now = now();
+        let metadata = utils::BackupMetadata { last_backup_time: Some(now - 3 * 86400), ..Default::default() };
+        fs::write(dir.join(utils::METADATA_FILE), serde_json::to_string(&metadata).unwrap()).unwrap();
+        let thresholds = Thresholds { warn_after: 2 * 86400, stale_after: 7 * 86400 };
+        let chain = chain_status("home", dir).unwrap();
+        assert_eq!(thresholds.judge(&chain, now), Freshness::Aging);
+        assert_eq!(thresholds.judge(&chain, now + 5 * 86400), Freshness::Stale);
+        assert_eq!(thresholds.judge(&chain, now - 2 * 86400), Freshness::Fresh);
+        assert!(chain.failure.is_none());
+
+        //a failure after the last success is shown until the next run completes
+        record_failure(dir, "No space left on device");
+        assert_eq!(chain_status("home", dir).unwrap().failure.unwrap().reason, "No space left on device");
+        clear_failure(dir);
+        assert!(chain_status("home", dir).unwrap().failure.is_none());
+
+        //one that never succeeded is stale
+        let failed = tempfile::tempdir().unwrap();
+        record_failure(failed.path(), "permission denied");
+        assert_eq!(thresholds.judge(&chain_status("new", failed.path()).unwrap(), now), Freshness::Stale);
+    }
+
+    #[test]
+    fn ages_use_the_largest_unit_that_fits() {
+        assert_eq!(format_age(59), "less than a minute");
+        assert_eq!(format_age(60), "1 minute");
+        assert_eq!(format_age(3 * 3600), "3 hours");
+        assert_eq!(format_age(47 * 3600), "47 hours");
+        assert_eq!(format_age(2 * 86400), "2 days");
+        assert_eq!(format_age(42 * 86400), "6 weeks");
+    }
+}
@@ -0,0 +1,245 @@
+use crate::compression;
+use crate::encryption::ArchiveKey;
+use crate::mounts::{self, Filesystem};
+use crate::portability::Portability;
+use crate::repack::RepackOrigin;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tar::{Archive, Builder};
+
+//directory inside the archive holding information about the machine that made it
+pub const SYSINFO_DIR: &str = ".linux_backup_sysinfo";
+pub const SYSINFO_ENTRY: &str = ".linux_backup_sysinfo/sysinfo.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SystemInfo {
+    pub hostname: String,
+    pub os_id: String,
+    pub os_version: String,
+    pub os_name: String,
+    pub architecture: String,
+    pub sources: Vec<String>,
+    //sources that were symlinks, walked through their target but stored under the link's path
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub symlinked_sources: Vec<SymlinkedSource>,
+    //set when the backup was taken from a system mounted at this path instead of the running one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offline_root: Option<String>,
+    //format and level the archive was written with, "zstd level 19"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    //type, device, relevant mount options and free space of each source's filesystem
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filesystems: Vec<Filesystem>,
+    //files of the saved disk layout, set when the archive can partition a new disk for a bare-metal restore
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disk_layout: Vec<String>,
+    //how far the archive strays from what plain tar extracts, unset for archives from before the setting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub portability: Option<Portability>,
+    //set on archives cut out of another one by restore --repack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repacked_from: Option<RepackOrigin>,
+    //the archive ends with a manifest, one without it was cut short
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub manifest: bool,
+}
+
+//a source like ~/Documents pointing at /data/docs, the archive holds /data/docs under ~/Documents
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SymlinkedSource {
+    pub path: String,
+    pub target: String,
+}
+
+impl SystemInfo {
+    //sources are recorded for display, a name that isn't valid UTF-8 shows with replacement characters
+    pub fn current(sources: &[PathBuf]) -> Self {
+        let os_release = fs::read_to_string("/etc/os-release")
+            .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+            .unwrap_or_default();
+
+        SystemInfo {
+            hostname: hostname(),
+            os_id: os_release_field(&os_release, "ID"),
+            os_version: os_release_field(&os_release, "VERSION_ID"),
+            os_name: os_release_field(&os_release, "PRETTY_NAME"),
+            architecture: std::env::consts::ARCH.to_string(),
+            sources: sources.iter().map(|source| source.display().to_string()).collect(),
+            symlinked_sources: symlinked_sources(sources),
+            offline_root: None,
+            compression: None,
+            filesystems: sources.iter().filter_map(|source| mounts::filesystem_of(source)).collect(),
+            disk_layout: Vec::new(),
+            portability: None,
+            repacked_from: None,
+            manifest: false,
+        }
+    }
+
+    //a system mounted at `root`, described by its own files, the architecture is assumed to match ours
+    pub fn of_root(root: &Path, sources: &[PathBuf]) -> Self {
+        let os_release = fs::read_to_string(root.join("etc/os-release"))
+            .or_else(|_| fs::read_to_string(root.join("usr/lib/os-release")))
+            .unwrap_or_default();
+        let hostname = fs::read_to_string(root.join("etc/hostname")).unwrap_or_default();
+
+        SystemInfo {
+            hostname: hostname.trim().to_string(),
+            os_id: os_release_field(&os_release, "ID"),
+            os_version: os_release_field(&os_release, "VERSION_ID"),
+            os_name: os_release_field(&os_release, "PRETTY_NAME"),
+            architecture: std::env::consts::ARCH.to_string(),
+            sources: sources.iter().map(|source| source.display().to_string()).collect(),
+            offline_root: Some(root.display().to_string()),
+            //links below the root point into the mounted system, not into ours
+            symlinked_sources: Vec::new(),
+            compression: None,
+            filesystems: sources
+                .iter()
+                .filter_map(|source| {
+                    let filesystem = mounts::filesystem_of(&utils::under_root(root, source))?;
+                    Some(Filesystem { path: source.display().to_string(), ..filesystem })
+                })
+                .collect(),
+            disk_layout: Vec::new(),
+            portability: None,
+            repacked_from: None,
+            manifest: false,
+        }
+    }
+
+    //fields that differ from another system as (field, ours, theirs)
+    pub fn differences(&self, other: &SystemInfo) -> Vec<(&'static str, String, String)> {
+        let mut differences = Vec::new();
+
+        if self.hostname != other.hostname {
+            differences.push(("Hostname", self.hostname.clone(), other.hostname.clone()));
+        }
+        if self.os_id != other.os_id || self.os_version != other.os_version {
+            differences.push(("OS", self.os_name.clone(), other.os_name.clone()));
+        }
+        if self.architecture != other.architecture {
+            differences.push(("Architecture", self.architecture.clone(), other.architecture.clone()));
+        }
+
+        differences
+    }
+
+    //free space changes from run to run, reproducible archives leave it out
+    pub fn without_free_space(mut self) -> Self {
+        for filesystem in &mut self.filesystems {
+            filesystem.free_bytes = None;
+        }
+        self
+    }
+
+    //true when the backup covered anything outside of /home
+    pub fn has_system_paths(&self) -> bool {
+        self.sources.iter().any(|s| !Path::new(s).starts_with("/home"))
+    }
+}
+
+//each source that is itself a symlink, with what it resolved to
+pub fn symlinked_sources(sources: &[PathBuf]) -> Vec<SymlinkedSource> {
+    sources
+        .iter()
+        .filter(|source| fs::symlink_metadata(source).is_ok_and(|metadata| metadata.file_type().is_symlink()))
+        .filter_map(|source| {
+            let target = fs::canonicalize(source).ok()?;
+            Some(SymlinkedSource { path: source.display().to_string(), target: target.display().to_string() })
+        })
+        .collect()
+}
+
+pub fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+        return String::new();
+    }
+
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).to_string()
+}
+
+fn os_release_field(os_release: &str, key: &str) -> String {
+    os_release
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.trim_matches('"').to_string())
+        .unwrap_or_default()
+}
+
+//written as the first entry so restore can read it without scanning the whole archive
+pub fn append_to_archive<W: Write>(archive: &mut Builder<W>, info: &SystemInfo, mtime: u64) -> io::Result<()> {
+    utils::append_json_entry(archive, SYSINFO_ENTRY, info, mtime)
+}
+
+pub fn read_from_archive(archive_path: &Path, key: Option<&ArchiveKey>) -> Option<SystemInfo> {
+    let mut archive = Archive::new(compression::open_decoder(archive_path, key).ok()?);
+
+    //only look at the first few entries, older archives simply don't have it
+    for entry in archive.entries().ok()?.take(4) {
+        let mut entry = entry.ok()?;
+        if entry.path().ok()?.as_ref() == Path::new(SYSINFO_ENTRY) {
+            let mut data = String::new();
+            entry.read_to_string(&mut data).ok()?;
+            return serde_json::from_str(&data).ok();
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{ArchiveCompression, ArchiveEncoder, CompressionFormat};
+    use std::fs::File;
+
+    #[test]
+    fn sysinfo_round_trips_through_an_archive() {
+        let temp = tempfile::tempdir().unwrap();
+        let info = SystemInfo::current(&[PathBuf::from("/etc")]);
+
+        for format in [CompressionFormat::Gzip, CompressionFormat::Zstd] {
+            let archive_path = temp.path().join(format!("backup{}", format.extension()));
+            let encoder = ArchiveEncoder::new(File::create(&archive_path).unwrap(), ArchiveCompression::new(format)).unwrap();
+            let mut archive = Builder::new(encoder);
+            append_to_archive(&mut archive, &info, utils::entry_mtime(false)).unwrap();
+            archive.into_inner().unwrap().finish().unwrap();
+
+            assert_eq!(read_from_archive(&archive_path, None), Some(info.clone()));
+        }
+    }
+
+    #[test]
+    fn offline_systems_are_described_by_their_own_files() {
+        let temp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(temp.path().join("etc")).unwrap();
+        fs::write(temp.path().join("etc/hostname"), "broken-box\n").unwrap();
+        fs::write(temp.path().join("etc/os-release"), "ID=debian\nVERSION_ID=\"12\"\nPRETTY_NAME=\"Debian 12\"\n").unwrap();
+
+        let info = SystemInfo::of_root(temp.path(), &[PathBuf::from("/etc")]);
+        assert_eq!(info.hostname, "broken-box");
+        assert_eq!(info.os_version, "12");
+        assert_eq!(info.offline_root, Some(temp.path().display().to_string()));
+        assert_eq!(serde_json::from_str::<SystemInfo>(&serde_json::to_string(&info).unwrap()).unwrap(), info);
+    }
+
+    #[test]
+    fn differences_ignore_sources_and_flag_system_paths() {
+        let home = SystemInfo { sources: vec!["/home/dave/Documents".to_string()], ..SystemInfo::default() };
+        let system = SystemInfo { sources: vec!["/home".to_string(), "/etc".to_string()], ..SystemInfo::default() };
+        let other_arch = SystemInfo { architecture: "aarch64".to_string(), ..SystemInfo::default() };
+
+        assert!(home.differences(&system).is_empty());
+        assert_eq!(home.differences(&other_arch).len(), 1);
+        assert!(!home.has_system_paths());
+        assert!(system.has_system_paths());
+    }
+}
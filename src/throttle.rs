@@ -0,0 +1,240 @@
+use crate::prompt::{Confirm, Input};
+use crate::utils;
+use colored::*;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+//ioprio_set(2) constants, libc has the syscall number but not these
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+//the lowest CPU priority, what nice -n 19 gives
+const BACKGROUND_NICENESS: libc::c_int = 19;
+
+//batch sizes when only one of the two limits is given
+pub const DEFAULT_SYNC_FILES: u64 = 1000;
+pub const DEFAULT_SYNC_BYTES: u64 = 256 * 1024 * 1024;
+
+//flush restored data to disk every so many files or bytes, whichever comes first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SyncBatch {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+//how gently a restore treats a machine that is in use
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Throttling {
+    //bytes per second read from the archive, None for no limit
+    pub limit_rate: Option<u64>,
+    //idle I/O class and lowest CPU priority
+    pub background: bool,
+    pub sync: Option<SyncBatch>,
+}
+
+impl Throttling {
+    //the batch from --fsync-every and --fsync-every-mb, either one turns syncing on
+    pub fn sync_batch(files: Option<u64>, megabytes: Option<u64>) -> Option<SyncBatch> {
+        if files.is_none() && megabytes.is_none() {
+            return None;
+        }
+        Some(SyncBatch {
+            files: files.unwrap_or(DEFAULT_SYNC_FILES).max(1),
+            bytes: megabytes.map(|mb| mb * 1024 * 1024).unwrap_or(DEFAULT_SYNC_BYTES).max(1),
+        })
+    }
+}
+
+//"20M", "512K", "1.5G" or plain bytes, per second
+pub fn parse_rate(input: &str) -> Result<u64, String> {
+    let text = input.trim().trim_end_matches("/s").trim_end_matches(['B', 'b']);
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid rate '{}', expected something like 20M", input))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" => 1.0,
+        "K" => 1024.0,
+        "M" => 1024.0 * 1024.0,
+        "G" => 1024.0 * 1024.0 * 1024.0,
+        other => return Err(format!("Unknown rate unit '{}', use K, M or G", other)),
+    };
+
+    let rate = (number * multiplier) as u64;
+    if rate == 0 {
+        return Err("The rate limit must be above zero".to_string());
+    }
+    Ok(rate)
+}
+
+//lower this process's CPU and I/O priority for the rest of its life, child processes like tar inherit it
+pub fn run_in_background() {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, BACKGROUND_NICENESS) } != 0 {
+        println!("{}", format!("Warning: Could not lower the CPU priority: {}", io::Error::last_os_error()).yellow());
+    }
+    let idle = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, idle) } != 0 {
+        println!("{}", format!("Warning: Could not switch to the idle I/O class: {}", io::Error::last_os_error()).yellow());
+    }
+}
+
+//reads at no more than `rate` bytes per second on average, and counts what went through
+pub struct RateLimiter<R> {
+    inner: R,
+    rate: Option<u64>,
+    started: Instant,
+    pub bytes: u64,
+}
+
+impl<R: Read> RateLimiter<R> {
+    pub fn new(inner: R, rate: Option<u64>) -> Self {
+        RateLimiter { inner, rate, started: Instant::now(), bytes: 0 }
+    }
+}
+
+impl<R: Read> Read for RateLimiter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        //a tenth of a second's worth at most, so the limit holds over short stretches too
+        let limit = match self.rate {
+            Some(rate) => buf.len().min((rate / 10).max(512) as usize),
+            None => buf.len(),
+        };
+        let n = self.inner.read(&mut buf[..limit])?;
+        self.bytes += n as u64;
+
+        if let Some(rate) = self.rate {
+            let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+            let elapsed = self.started.elapsed();
+            if due > elapsed {
+                std::thread::sleep(due - elapsed);
+            }
+        }
+        Ok(n)
+    }
+}
+
+//syncs the target's filesystem after every batch instead of after every file
+pub struct SyncBatcher {
+    batch: SyncBatch,
+    target: Option<File>,
+    files: u64,
+    bytes: u64,
+    pub syncs: u64,
+}
+
+impl SyncBatcher {
+    pub fn new(target_dir: &Path, batch: SyncBatch) -> Self {
+        SyncBatcher { batch, target: File::open(target_dir).ok(), files: 0, bytes: 0, syncs: 0 }
+    }
+
+    pub fn record(&mut self, size: u64) -> io::Result<()> {
+        self.files += 1;
+        self.bytes += size;
+        if self.files >= self.batch.files || self.bytes >= self.batch.bytes {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.files = 0;
+        self.bytes = 0;
+        self.syncs += 1;
+        match &self.target {
+            Some(target) if unsafe { libc::syncfs(target.as_raw_fd()) } != 0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    //the last partial batch, then the target directory itself so its new entries are durable too
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.sync()?;
+        match &self.target {
+            Some(target) => target.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
+
+//"412.0 MB in 10.3 s (40.0 MB/s)"
+pub fn describe_throughput(bytes: u64, elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64().max(0.001);
+    format!(
+        "{} in {:.1} s ({}/s)",
+        utils::format_size(bytes),
+        seconds,
+        utils::format_size((bytes as f64 / seconds) as u64)
+    )
+}
+
+//the restore flow's advanced options, defaults keep a restore at full speed
+pub fn prompt_throttling() -> Result<Throttling, Box<dyn std::error::Error>> {
+    let configure = Confirm::new()
+        .with_prompt("Configure advanced options? (speed limit, background priority, syncing)")
+        .default(false)
+        .interact()?;
+    if !configure {
+        return Ok(Throttling::default());
+    }
+
+    let limit_rate = loop {
+        let input: String = Input::new()
+            .with_prompt("Limit the restore to (e.g. 20M per second, empty for no limit)")
+            .allow_empty(true)
+            .interact_text()?;
+        if input.trim().is_empty() {
+            break None;
+        }
+        match parse_rate(&input) {
+            Ok(rate) => break Some(rate),
+            Err(e) => println!("{}", e.red()),
+        }
+    };
+
+    let background = Confirm::new()
+        .with_prompt("Run at background priority? (idle I/O class and lowest CPU priority, slower while the machine is busy)")
+        .default(false)
+        .interact()?;
+
+    let sync = Confirm::new()
+        .with_prompt(format!(
+            "Sync restored files to disk as it goes? (every {} files or {})",
+            DEFAULT_SYNC_FILES,
+            utils::format_size(DEFAULT_SYNC_BYTES)
+        ))
+        .default(false)
+        .interact()?;
+
+    Ok(Throttling {
+        limit_rate,
+        background,
+        sync: if sync { Some(SyncBatch { files: DEFAULT_SYNC_FILES, bytes: DEFAULT_SYNC_BYTES }) } else { None },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_parse_and_limit_reads() {
+        assert_eq!(parse_rate("20M"), Ok(20 * 1024 * 1024));
+        assert_eq!(parse_rate("512KB/s"), Ok(512 * 1024));
+        assert_eq!(parse_rate("1.5g"), Ok(1536 * 1024 * 1024));
+        assert!(parse_rate("fast").is_err() && parse_rate("0").is_err());
+
+        //100 KB at 400 KB/s takes about a quarter of a second
+        let data = vec![0u8; 100 * 1024];
+        let mut limiter = RateLimiter::new(data.as_slice(), Some(400 * 1024));
+        let start = Instant::now();
+        io::copy(&mut limiter, &mut io::sink()).unwrap();
+        assert_eq!(limiter.bytes, 100 * 1024);
+        assert!(start.elapsed() >= Duration::from_millis(230));
+    }
+}
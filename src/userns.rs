@@ -0,0 +1,259 @@
+use crate::xattrs;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+//owners a restore in a user namespace could not set, replayed later by restore --apply-ownership run as root
+pub const OWNERSHIP_FILE: &str = ".lb-ownership.json";
+
+//the inside ids of /proc/self/uid_map or gid_map, as (first, count)
+#[derive(Debug, PartialEq)]
+pub struct IdMap {
+    ranges: Vec<(u64, u64)>,
+}
+
+impl IdMap {
+    //"<inside> <outside> <count>" per line
+    pub fn parse(text: &str) -> IdMap {
+        let ranges = text
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<u64> = line.split_whitespace().filter_map(|field| field.parse().ok()).collect();
+                match fields[..] {
+                    [inside, _, count] => Some((inside, count)),
+                    _ => None,
+                }
+            })
+            .collect();
+        IdMap { ranges }
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.ranges.iter().any(|&(first, count)| id >= first && id - first < count)
+    }
+
+    //the initial namespace maps all 2^32 - 1 ids onto themselves
+    fn is_initial(&self) -> bool {
+        self.ranges == [(0, 4294967295)]
+    }
+
+    //"0-0, 1-65536", the ids that can be given out here
+    fn describe(&self) -> String {
+        self.ranges.iter().map(|&(first, count)| format!("{}-{}", first, first + count.saturating_sub(1))).collect::<Vec<_>>().join(", ")
+    }
+}
+
+//the uid and gid mappings of the user namespace this process runs in, rootless Podman or unshare -r
+pub struct Namespace {
+    pub uids: IdMap,
+    pub gids: IdMap,
+}
+
+impl Namespace {
+    //None in the initial namespace, or when /proc can't tell
+    pub fn current() -> Option<Namespace> {
+        let uids = IdMap::parse(&fs::read_to_string("/proc/self/uid_map").ok()?);
+        let gids = IdMap::parse(&fs::read_to_string("/proc/self/gid_map").ok()?);
+        if uids.is_initial() && gids.is_initial() {
+            return None;
+        }
+        Some(Namespace { uids, gids })
+    }
+
+    pub fn maps(&self, uid: u64, gid: u64) -> bool {
+        self.uids.contains(uid) && self.gids.contains(gid)
+    }
+}
+
+//one entry whose archived owner is waiting to be applied, the path is relative to the target
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecordedOwner {
+    pub path: String,
+    pub uid: u64,
+    pub gid: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    //chown clears setuid and setgid, the mode goes back on afterwards
+    pub mode: u32,
+}
+
+//what a restore in a user namespace did with the archived owners
+pub struct OwnershipLedger {
+    pub namespace: Namespace,
+    target: PathBuf,
+    pub mapped: u64,
+    recorded: Vec<RecordedOwner>,
+    //(path, reason) of owners neither set nor recorded
+    pub dropped: Vec<(String, String)>,
+    saved: bool,
+}
+
+impl OwnershipLedger {
+    pub fn new(namespace: Namespace, target: &Path) -> Self {
+        OwnershipLedger { namespace, target: target.to_path_buf(), mapped: 0, recorded: Vec::new(), dropped: Vec::new(), saved: false }
+    }
+
+    //`written` is where the entry ended up below the target
+    pub fn record(&mut self, written: &Path, owner: RecordedOwner) {
+        match written.strip_prefix(&self.target).ok().and_then(|relative| relative.to_str()) {
+            Some(relative) => self.recorded.push(RecordedOwner { path: relative.to_string(), ..owner }),
+            None => self.dropped.push((written.display().to_string(), "the name isn't valid UTF-8 and can't be recorded".to_string())),
+        }
+    }
+
+    //added to what an earlier restore into the same target recorded, a later entry for a path replaces the earlier one
+    pub fn save(&mut self) {
+        if self.recorded.is_empty() {
+            return;
+        }
+        let path = self.target.join(OWNERSHIP_FILE);
+        let result = load_recorded(&self.target).and_then(|mut entries| {
+            entries.retain(|entry| !self.recorded.iter().any(|recorded| recorded.path == entry.path));
+            entries.extend(self.recorded.iter().cloned());
+            fs::write(&path, serde_json::to_vec_pretty(&entries)?)
+        });
+        match result {
+            Ok(()) => self.saved = true,
+            Err(e) => {
+                let reason = format!("{} could not be written: {}", path.display(), e);
+                self.dropped.extend(self.recorded.drain(..).map(|owner| (owner.path, reason.clone())));
+            }
+        }
+    }
+
+    pub fn report(&self) {
+        println!("{}", "\n---- Ownership ----".blue().bold());
+        println!("Running in a user namespace, it maps uids {} and gids {}.", self.namespace.uids.describe(), self.namespace.gids.describe());
+        println!("Owners restored: {}", self.mapped);
+        if self.saved {
+            let path = self.target.join(OWNERSHIP_FILE);
+            println!("{}", format!("Owners outside the mapping, recorded in {}: {}", path.display(), self.recorded.len()).yellow());
+            println!("Run as root outside the namespace to apply them: linux_backup restore --apply-ownership --target {}", self.target.display());
+        }
+        if !self.dropped.is_empty() {
+            println!("{}", format!("Owners dropped: {}", self.dropped.len()).red());
+            for (path, reason) in self.dropped.iter().take(10) {
+                println!("  {} ({})", path, reason);
+            }
+            if self.dropped.len() > 10 {
+                println!("  ...and {} more", self.dropped.len() - 10);
+            }
+        }
+    }
+}
+
+fn load_recorded(target: &Path) -> io::Result<Vec<RecordedOwner>> {
+    match fs::read(target.join(OWNERSHIP_FILE)) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+//restore --apply-ownership: chown what a namespaced restore recorded, entries that still fail stay in the file
+pub fn apply_ownership(target: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    if let Some(namespace) = Namespace::current() {
+        return Err(format!("This runs in a user namespace too (uids {}), apply the recorded owners as root on the host", namespace.uids.describe()).into());
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        return Err("Applying recorded owners needs root".into());
+    }
+    let entries = load_recorded(target).map_err(|e| format!("{} can't be read: {}", target.join(OWNERSHIP_FILE).display(), e))?;
+    if entries.is_empty() {
+        return Err(format!("No recorded owners in {}", target.join(OWNERSHIP_FILE).display()).into());
+    }
+
+    //the file was written inside the container, it must not hand root's chown anything outside the target
+    let target = fs::canonicalize(target)?;
+    let mut applied = 0;
+    let mut missing = 0;
+    let mut failed = Vec::new();
+    for entry in entries {
+        let path = target.join(&entry.path);
+        let contained = Path::new(&entry.path).components().all(|component| matches!(component, Component::Normal(_)))
+            && path.parent().and_then(|parent| fs::canonicalize(parent).ok()).is_some_and(|parent| parent.starts_with(&target));
+        let Some(metadata) = fs::symlink_metadata(&path).ok().filter(|_| contained) else {
+            missing += 1;
+            continue;
+        };
+        //chown drops file capabilities along with setuid, both go back on afterwards
+        let capability = xattr::get(&path, xattrs::CAPABILITY).ok().flatten();
+        let result = std::os::unix::fs::lchown(&path, Some(entry.uid as u32), Some(entry.gid as u32)).and_then(|_| {
+            if metadata.is_symlink() {
+                return Ok(());
+            }
+            if entry.mode & 0o6000 != 0 {
+                fs::set_permissions(&path, fs::Permissions::from_mode(entry.mode & 0o7777))?;
+            }
+            capability.as_ref().map_or(Ok(()), |value| xattr::set(&path, xattrs::CAPABILITY, value))
+        });
+        match result {
+            Ok(()) => applied += 1,
+            Err(e) => {
+                println!("{}", format!("Warning: Could not give {} its owner {}:{}: {}", path.display(), entry.uid, entry.gid, e).yellow());
+                failed.push(entry);
+            }
+        }
+    }
+
+    println!("Recorded owners applied: {}", applied);
+    if missing > 0 {
+        println!("{}", format!("Recorded entries not found in the target: {}", missing).yellow());
+    }
+    if failed.is_empty() {
+        fs::remove_file(target.join(OWNERSHIP_FILE))?;
+    } else {
+        fs::write(target.join(OWNERSHIP_FILE), serde_json::to_vec_pretty(&failed)?)?;
+    }
+    Ok(failed.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_maps_are_read_from_proc_format() {
+        let initial = IdMap::parse("         0          0 4294967295\n");
+        assert!(initial.is_initial());
+
+        //rootless Podman: root is the invoking user, 1-65536 come from /etc/subuid
+        let podman = IdMap::parse("         0       1000          1\n         1     100000      65536\n");
+        assert!(!podman.is_initial());
+        assert!(podman.contains(0) && podman.contains(33) && podman.contains(65536));
+        assert!(!podman.contains(65537));
+        assert_eq!(podman.describe(), "0-0, 1-65536");
+    }
+
+    #[test]
+    fn recorded_owners_are_merged_and_applied() {
+        let temp = tempfile::tempdir().unwrap();
+        let target = temp.path();
+        fs::write(target.join("a"), "a").unwrap();
+        fs::write(target.join("b"), "b").unwrap();
+        let namespace = || Namespace { uids: IdMap::parse("0 1000 1\n"), gids: IdMap::parse("0 1000 1\n") };
+        let (uid, gid) = unsafe { (libc::geteuid() as u64, libc::getegid() as u64) };
+        let owner = |path: &str| RecordedOwner { path: path.to_string(), uid, gid, user: None, group: None, mode: 0o644 };
+
+        let mut first = OwnershipLedger::new(namespace(), target);
+        first.record(&target.join("a"), owner(""));
+        first.save();
+        let mut second = OwnershipLedger::new(namespace(), target);
+        second.record(&target.join("a"), owner(""));
+        second.record(&target.join("b"), owner(""));
+        second.record(&target.join("gone"), owner(""));
+        second.save();
+        assert_eq!(load_recorded(target).unwrap().iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>(), vec!["a", "b", "gone"]);
+
+        //applied outside a namespace as root, the file goes once everything is applied
+        if unsafe { libc::geteuid() } == 0 && Namespace::current().is_none() {
+            assert_eq!(apply_ownership(target).unwrap(), 0);
+            assert!(!target.join(OWNERSHIP_FILE).exists());
+        }
+    }
+}
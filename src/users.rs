@@ -0,0 +1,259 @@
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+//uids below this belong to system accounts
+const FIRST_REGULAR_UID: u32 = 1000;
+//the overflow uid used by "nobody"
+const NOBODY_UID: u32 = 65534;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserAccount {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+//regular users with an existing home directory, from /etc/passwd or /home as a fallback
+pub fn regular_users() -> Vec<UserAccount> {
+    let users: Vec<UserAccount> = fs::read_to_string("/etc/passwd")
+        .map(|contents| parse_passwd(&contents))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|user| user.home.is_dir())
+        .collect();
+
+    if users.is_empty() {
+        home_directory_users(Path::new("/home"))
+    } else {
+        users
+    }
+}
+
+fn parse_passwd(contents: &str) -> Vec<UserAccount> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() < 7 {
+                return None;
+            }
+
+            Some(UserAccount {
+                name: fields[0].to_string(),
+                uid: fields[2].parse().ok()?,
+                gid: fields[3].parse().ok()?,
+                home: PathBuf::from(fields[5]),
+            })
+        })
+        .filter(|user| user.uid >= FIRST_REGULAR_UID && user.uid != NOBODY_UID)
+        .collect()
+}
+
+//every directory under /home, owned by whoever owns it
+fn home_directory_users(base: &Path) -> Vec<UserAccount> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut users: Vec<UserAccount> = fs::read_dir(base)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            if !metadata.is_dir() {
+                return None;
+            }
+
+            Some(UserAccount {
+                name: entry.file_name().to_string_lossy().to_string(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                home: entry.path(),
+            })
+        })
+        .collect();
+
+    users.sort_by(|a, b| a.name.cmp(&b.name));
+    users
+}
+
+//hand a file or directory over to a user, only works when running as root
+pub fn chown(path: &Path, user: &UserAccount) -> std::io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    if unsafe { libc::chown(c_path.as_ptr(), user.uid, user.gid) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+//passwd and group lookups go through libc, so NSS sources like LDAP are honoured too
+const LOOKUP_BUFFER_SIZE: usize = 16 * 1024;
+
+pub fn user_name(uid: u32) -> Option<String> {
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+
+    let found = unsafe { libc::getpwuid_r(uid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if found != 0 || result.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(entry.pw_name) }.to_string_lossy().to_string())
+}
+
+pub fn group_name(gid: u32) -> Option<String> {
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+
+    let found = unsafe { libc::getgrgid_r(gid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if found != 0 || result.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(entry.gr_name) }.to_string_lossy().to_string())
+}
+
+pub fn uid_by_name(name: &str) -> Option<u32> {
+    let c_name = CString::new(name).ok()?;
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+
+    let found = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if found != 0 || result.is_null() {
+        return None;
+    }
+    Some(entry.pw_uid)
+}
+
+pub fn gid_by_name(name: &str) -> Option<u32> {
+    let c_name = CString::new(name).ok()?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+
+    let found = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if found != 0 || result.is_null() {
+        return None;
+    }
+    Some(entry.gr_gid)
+}
+
+//where owner names come from: this system through libc, or the files of a system mounted elsewhere
+#[derive(Default)]
+pub enum Accounts {
+    #[default]
+    System,
+    //(name, id) pairs from the passwd and group files below an alternate root
+    Root {
+        users: Vec<(String, u32)>,
+        groups: Vec<(String, u32)>,
+    },
+}
+
+impl Accounts {
+    //an offline system's accounts, NSS sources configured there can't be consulted
+    pub fn of_root(root: &Path) -> Self {
+        let read = |file: &str| fs::read_to_string(root.join(file)).map(|contents| parse_ids(&contents)).unwrap_or_default();
+        Accounts::Root { users: read("etc/passwd"), groups: read("etc/group") }
+    }
+
+    pub fn user_name(&self, uid: u32) -> Option<String> {
+        match self {
+            Accounts::System => user_name(uid),
+            Accounts::Root { users, .. } => name_of(users, uid),
+        }
+    }
+
+    pub fn group_name(&self, gid: u32) -> Option<String> {
+        match self {
+            Accounts::System => group_name(gid),
+            Accounts::Root { groups, .. } => name_of(groups, gid),
+        }
+    }
+
+    pub fn uid_by_name(&self, name: &str) -> Option<u32> {
+        match self {
+            Accounts::System => uid_by_name(name),
+            Accounts::Root { users, .. } => id_of(users, name),
+        }
+    }
+
+    pub fn gid_by_name(&self, name: &str) -> Option<u32> {
+        match self {
+            Accounts::System => gid_by_name(name),
+            Accounts::Root { groups, .. } => id_of(groups, name),
+        }
+    }
+}
+
+//passwd and group lines both have the name first and the id third
+fn parse_ids(contents: &str) -> Vec<(String, u32)> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            Some((fields.first()?.to_string(), fields.get(2)?.parse().ok()?))
+        })
+        .collect()
+}
+
+fn name_of(entries: &[(String, u32)], id: u32) -> Option<String> {
+    entries.iter().find(|(_, entry_id)| *entry_id == id).map(|(name, _)| name.clone())
+}
+
+fn id_of(entries: &[(String, u32)], name: &str) -> Option<u32> {
+    entries.iter().find(|(entry_name, _)| entry_name == name).map(|(_, id)| *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwd_keeps_only_regular_users() {
+        let contents = "\
+root:x:0:0:root:/root:/bin/bash
+daemon:x:1:1:daemon:/usr/sbin:/usr/sbin/nologin
+# comment
+alice:x:1000:1000:Alice,,,:/home/alice:/bin/bash
+bob:x:1001:100::/home/bob:/bin/zsh
+nobody:x:65534:65534:nobody:/nonexistent:/usr/sbin/nologin
+broken:x:1002
+";
+
+        let users = parse_passwd(contents);
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0], UserAccount { name: "alice".to_string(), uid: 1000, gid: 1000, home: PathBuf::from("/home/alice") });
+        assert_eq!(users[1].name, "bob");
+        assert_eq!(users[1].gid, 100);
+    }
+
+    #[test]
+    fn root_resolves_both_ways() {
+        assert_eq!(user_name(0).as_deref(), Some("root"));
+        assert_eq!(uid_by_name("root"), Some(0));
+        assert_eq!(gid_by_name(&group_name(0).unwrap()), Some(0));
+        assert_eq!(uid_by_name("no-such-user-for-linux-backup"), None);
+    }
+
+    #[test]
+    fn accounts_of_an_alternate_root_come_from_its_files() {
+        let temp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(temp.path().join("etc")).unwrap();
+        fs::write(temp.path().join("etc/passwd"), "root:x:0:0:root:/root:/bin/bash\ndave:x:1500:1500::/home/dave:/bin/sh\n").unwrap();
+        fs::write(temp.path().join("etc/group"), "root:x:0:\nstaff:x:1600:dave\n").unwrap();
+
+        let accounts = Accounts::of_root(temp.path());
+        assert_eq!(accounts.user_name(1500).as_deref(), Some("dave"));
+        assert_eq!(accounts.uid_by_name("dave"), Some(1500));
+        assert_eq!(accounts.group_name(1600).as_deref(), Some("staff"));
+        assert_eq!(accounts.gid_by_name("dave"), None);
+    }
+}
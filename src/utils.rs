@@ -1,43 +1,647 @@
+use crate::selector;
+use colored::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tar::{Builder, Header};
 
 //path for storing backup metadata
 pub const METADATA_DIR: &str = ".linux_backup_metadata";
 
-#[derive(Clone, Debug)]
+//directory inside every archive holding a snapshot of the tool's own state
+pub const STATE_DIR: &str = ".linux_backup_state";
+
+//statfs magic numbers of network filesystems (nfs, smb, cifs, smb2, afs, ceph)
+const NETWORK_FS_MAGIC: [i64; 6] = [0x6969, 0x517B, 0xFF534D42, 0xFE534D42, 0x5346414F, 0x00C36400];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum BackupType {
     Full,
     Incremental,
     Differential,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+//which inode timestamps count as a change for incremental and differential backups
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum ChangeDetection {
+    //content changes only
+    Mtime,
+    //also catches chmod/chown/xattr changes, but fires on hard link count changes too
+    Ctime,
+    //either timestamp moved
+    #[default]
+    Either,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FileChange {
+    Unchanged,
+    Content,
+    MetadataOnly,
+}
+
+//size, mtime and content hash of a file as of the last backup, the hash is empty unless that one was hash-checked
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileHash {
+    pub size: u64,
+    pub mtime: i64,
+    pub blake3: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct BackupMetadata {
     pub last_backup_time: Option<u64>,
     pub original_backup_time: Option<u64>,
-    pub backup_history: HashMap<String, u64>, //path -> timestamp
+    #[serde(default)]
+    pub backup_history: HashMap<String, FileHash>, //path -> state at the last backup
+    #[serde(default)]
+    pub change_detection: Option<ChangeDetection>,
+}
+
+//every backup chain kept in a state directory, by job, so an incremental of one set of directories
+//never measures against the last backup of another
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct MetadataStore {
+    pub jobs: BTreeMap<String, BackupMetadata>,
+}
+
+//the single chain of a file from before chains were kept per job
+pub const LEGACY_JOB: &str = "legacy";
+
+impl MetadataStore {
+    //a job without a chain of its own continues the legacy one, if there is one
+    pub fn job(&self, key: &str) -> BackupMetadata {
+        self.jobs.get(key).or_else(|| self.jobs.get(LEGACY_JOB)).cloned().unwrap_or_default()
+    }
+
+    //the legacy chain is used up by the first job that records a run
+    pub fn record(&mut self, key: &str, metadata: BackupMetadata) {
+        self.jobs.remove(LEGACY_JOB);
+        self.jobs.insert(key.to_string(), metadata);
+    }
+
+    //the latest backup of any job
+    pub fn last_backup_time(&self) -> Option<u64> {
+        self.jobs.values().filter_map(|metadata| metadata.last_backup_time).max()
+    }
+}
+
+//a profile's chain is named after it, anything else by a hash of the set of directories it backs up
+pub fn job_key(profile: Option<&str>, sources: &[PathBuf]) -> String {
+    if let Some(name) = profile {
+        return format!("profile:{}", name);
+    }
+    let mut sources: Vec<&PathBuf> = sources.iter().collect();
+    sources.sort();
+    sources.dedup();
+    let mut hasher = blake3::Hasher::new();
+    for source in sources {
+        hasher.update(source.as_os_str().as_bytes());
+        hasher.update(b"\0");
+    }
+    format!("dirs:{}", &hasher.finalize().to_hex()[..16])
+}
+
+//the chains' metadata, the version before the last save and where a reset moves a broken file
+pub const METADATA_FILE: &str = "backup_metadata.json";
+pub const PREVIOUS_METADATA_FILE: &str = "backup_metadata.json.1";
+const CORRUPT_METADATA_FILE: &str = "backup_metadata.json.corrupt";
+
+//archived names of the files deleted since the backup an incremental or differential one builds on, one per line
+pub const DELETED_FILES: &str = "deleted_files.txt";
+
+//a metadata save that takes longer than this is given up on and tried again, a few times
+const METADATA_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+const METADATA_WRITE_ATTEMPTS: u32 = 3;
+const METADATA_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+//saves slower than this are reported, it explains a backup that seemed stuck after the archive was done
+const SLOW_METADATA_WRITE: Duration = Duration::from_secs(2);
+
+//the file held a single BackupMetadata before, it is read as the legacy chain
+fn read_metadata_store(path: &Path) -> Result<MetadataStore, Box<dyn std::error::Error>> {
+    let value: serde_json::Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    if value.get("jobs").is_some() {
+        return Ok(serde_json::from_value(value)?);
+    }
+    let metadata: BackupMetadata = serde_json::from_value(value)?;
+    match metadata.last_backup_time {
+        Some(_) => Ok(MetadataStore { jobs: BTreeMap::from([(LEGACY_JOB.to_string(), metadata)]) }),
+        None => Ok(MetadataStore::default()),
+    }
 }
 
-pub fn load_backup_metadata(metadata_dir: &Path) -> Result<BackupMetadata, Box<dyn std::error::Error>> {
-    let metadata_file = metadata_dir.join("backup_metadata.json");
+//a damaged file falls back to the previous version instead of stopping every backup
+pub fn load_metadata_store(metadata_dir: &Path) -> Result<MetadataStore, Box<dyn std::error::Error>> {
+    let metadata_file = metadata_dir.join(METADATA_FILE);
     
-    if metadata_file.exists() {
-        let file = File::open(metadata_file)?;
-        let reader = BufReader::new(file);
-        let metadata: BackupMetadata = serde_json::from_reader(reader)?;
-        Ok(metadata)
+    if !metadata_file.exists() {
+        return Ok(MetadataStore::default());
+    }
+
+    let error = match read_metadata_store(&metadata_file) {
+        Ok(metadata) => return Ok(metadata),
+        Err(e) => e,
+    };
+    println!("{}", format!("Warning: {} is damaged: {}", metadata_file.display(), error).yellow());
+
+    let previous_file = metadata_dir.join(PREVIOUS_METADATA_FILE);
+    match read_metadata_store(&previous_file) {
+        Ok(metadata) => {
+            println!("{}", format!("Using the previous version from {}, changes since then are backed up again.", previous_file.display()).yellow());
+            Ok(metadata)
+        }
+        Err(_) => Err(format!(
+            "Backup metadata in {} is damaged and no usable previous version exists, pass --reset-metadata to start a new chain",
+            metadata_dir.display()
+        ).into()),
+    }
+}
+
+//write to a temporary file and rename it over the old one, so a crash never leaves a half-written file
+fn write_metadata_file(metadata_dir: &Path, contents: &[u8]) -> io::Result<()> {
+    let metadata_file = metadata_dir.join(METADATA_FILE);
+    let temp_file = metadata_dir.join(format!("{}.tmp", METADATA_FILE));
+
+    let mut writer = BufWriter::new(File::create(&temp_file)?);
+    writer.write_all(contents)?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    //only a version that still parses is worth keeping as the fallback
+    if read_metadata_store(&metadata_file).is_ok() {
+        fs::copy(&metadata_file, metadata_dir.join(PREVIOUS_METADATA_FILE))?;
+    }
+    fs::rename(&temp_file, &metadata_file)?;
+    File::open(metadata_dir)?.sync_all()
+}
+
+//save the metadata at the end of a backup without letting a hung NFS home freeze the run
+//the write happens on its own thread, one that never returns is left behind and ends with the process
+pub fn persist_metadata_store(metadata_dir: &Path, store: &MetadataStore) -> Result<(), String> {
+    let contents = Arc::new(serde_json::to_vec_pretty(store).map_err(|e| e.to_string())?);
+    let mut error = String::new();
+
+    for attempt in 1..=METADATA_WRITE_ATTEMPTS {
+        let (sender, receiver) = mpsc::channel();
+        let (dir, data) = (metadata_dir.to_path_buf(), Arc::clone(&contents));
+        thread::spawn(move || sender.send(write_metadata_file(&dir, &data)));
+
+        let started = Instant::now();
+        let result = loop {
+            match receiver.recv_timeout(METADATA_PROGRESS_INTERVAL) {
+                Ok(result) => break result.map_err(|e| e.to_string()),
+                Err(RecvTimeoutError::Timeout) if started.elapsed() < METADATA_WRITE_TIMEOUT => {
+                    println!("Still saving backup metadata to {} ({} s)...", metadata_dir.display(), started.elapsed().as_secs());
+                }
+                Err(_) => break Err(format!("no answer after {} s", started.elapsed().as_secs())),
+            }
+        };
+
+        let elapsed = started.elapsed();
+        match result {
+            Ok(()) => {
+                if elapsed >= SLOW_METADATA_WRITE {
+                    println!("{}", format!("Note: Saving backup metadata to {} took {:.1} s, the filesystem holding it is slow.", metadata_dir.display(), elapsed.as_secs_f64()).yellow());
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                println!("{}", format!("Warning: Saving backup metadata failed after {:.1} s (attempt {} of {}): {}", elapsed.as_secs_f64(), attempt, METADATA_WRITE_ATTEMPTS, e).yellow());
+                error = e;
+            }
+        }
+    }
+
+    Err(error)
+}
+
+//move the metadata aside so the next backup starts a new chain, returns where it went
+pub fn reset_backup_metadata(metadata_dir: &Path) -> io::Result<Option<PathBuf>> {
+    let metadata_file = metadata_dir.join(METADATA_FILE);
+    if !metadata_file.exists() {
+        return Ok(None);
+    }
+
+    let moved = metadata_dir.join(CORRUPT_METADATA_FILE);
+    fs::rename(&metadata_file, &moved)?;
+    let _ = fs::remove_file(metadata_dir.join(PREVIOUS_METADATA_FILE));
+    Ok(Some(moved))
+}
+
+//true when the file content was modified after the given unix time
+pub fn modified_after(metadata: &fs::Metadata, since: u64) -> bool {
+    metadata.mtime() > since as i64
+}
+
+//parse durations like 90d, 12w, 36h, 30m, 45s or 1y into seconds
+pub fn parse_duration(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}', expected something like 90d", input))?;
+
+    let multiplier = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" | "" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        other => return Err(format!("Unknown duration unit '{}', use s, m, h, d, w or y", other)),
+    };
+
+    Ok(number * multiplier)
+}
+
+//parse sizes like 500M, 2G, 1.5T, 2GB or 2GiB into bytes, binary units like format_size shows them
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size '{}', expected something like 500M or 2G", input))?;
+
+    let unit = unit.trim().to_ascii_uppercase();
+    let unit = unit.strip_suffix("IB").or_else(|| unit.strip_suffix('B')).unwrap_or(&unit);
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        other => return Err(format!("Unknown size unit '{}', use K, M, G or T", other)),
+    };
+
+    let bytes = (number * multiplier as f64) as u64;
+    if bytes == 0 {
+        return Err("The size must be above zero".to_string());
+    }
+    Ok(bytes)
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+//decide whether a file changed after the given unix time under a detection policy
+pub fn detect_change(metadata: &fs::Metadata, since: u64, policy: &ChangeDetection) -> FileChange {
+    let modified = modified_after(metadata, since);
+    let changed = metadata.ctime() > since as i64;
+
+    match (policy, modified, changed) {
+        (ChangeDetection::Mtime, true, _) => FileChange::Content,
+        (ChangeDetection::Ctime, true, true) => FileChange::Content,
+        (ChangeDetection::Ctime, _, true) => FileChange::MetadataOnly,
+        (ChangeDetection::Either, true, _) => FileChange::Content,
+        (ChangeDetection::Either, false, true) => FileChange::MetadataOnly,
+        _ => FileChange::Unchanged,
+    }
+}
+
+//the fixed timestamp tar's HeaderMode::Deterministic uses
+const DETERMINISTIC_MTIME: u64 = 1153704088;
+
+//timestamp for entries generated by the tool itself
+pub fn entry_mtime(reproducible: bool) -> u64 {
+    if reproducible {
+        return DETERMINISTIC_MTIME;
+    }
+
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+//write a serializable value as a json file entry into the archive
+pub fn append_json_entry<W: Write, T: Serialize>(archive: &mut Builder<W>, name: &str, value: &T, mtime: u64) -> io::Result<()> {
+    let data = serde_json::to_vec_pretty(value)?;
+
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+
+    archive.append_data(&mut header, name, data.as_slice())
+}
+
+//keep a copy of the metadata inside the archive so losing the metadata directory doesn't orphan history
+pub fn append_state_to_archive<W: Write>(archive: &mut Builder<W>, metadata: &BackupMetadata) -> io::Result<()> {
+    append_json_entry(archive, &format!("{}/{}", STATE_DIR, METADATA_FILE), metadata, entry_mtime(false))
+}
+
+//the deletions travel with the archive, a restore of the chain can remove them again
+//a name with a newline in it can't be told apart from two names, so it is left out
+pub fn append_deleted_files<W: Write>(archive: &mut Builder<W>, names: &[PathBuf], mtime: u64) -> io::Result<()> {
+    let mut data = Vec::new();
+    for name in names.iter().filter(|name| !name.as_os_str().as_bytes().contains(&b'\n')) {
+        data.extend_from_slice(name.as_os_str().as_bytes());
+        data.push(b'\n');
+    }
+
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+
+    archive.append_data(&mut header, format!("{}/{}", STATE_DIR, DELETED_FILES), data.as_slice())
+}
+
+//canonicalize selected sources so the same directory is never archived twice
+//exact duplicates are dropped, as are directories nested inside another selection
+//unless a .nobackup marker or a CACHEDIR.TAG between the two keeps the outer walk from reaching them
+pub fn normalize_sources(sources: &[PathBuf]) -> Vec<PathBuf> {
+    let mut unique: Vec<(PathBuf, PathBuf)> = Vec::new();
+
+    for source in sources {
+        let canonical = fs::canonicalize(source).unwrap_or_else(|_| source.clone());
+
+        if let Some((kept, _)) = unique.iter().find(|(_, c)| *c == canonical) {
+            println!("{}", format!("Notice: {} is the same directory as {}, skipping duplicate", source.display(), kept.display()).yellow());
+            continue;
+        }
+
+        unique.push((source.clone(), canonical));
+    }
+
+    unique.iter()
+        .filter(|(source, canonical)| {
+            match unique.iter().find(|(_, other)| other != canonical && canonical.starts_with(other)) {
+                Some((_, other)) if selector::marked_between(other, canonical).is_some() => true,
+                Some((ancestor, _)) => {
+                    println!("{}", format!("Notice: {} is inside {}, it will be backed up as part of it", source.display(), ancestor.display()).yellow());
+                    false
+                }
+                None => true,
+            }
+        })
+        .map(|(source, _)| source.clone())
+        .collect()
+}
+
+pub fn home_dir() -> PathBuf {
+    dirs::home_dir().expect("Could not find home directory")
+}
+
+//expand a leading ~ to the home directory
+pub fn expand_tilde(path: &str) -> String {
+    if path == "~" {
+        return home_dir().display().to_string();
+    }
+
+    match path.strip_prefix("~/") {
+        Some(rest) => home_dir().join(rest).display().to_string(),
+        None => path.to_string(),
+    }
+}
+
+//expand ~ and resolve relative paths against the current directory, the rest of the name is kept byte for byte
+pub fn absolute_path(path: &Path) -> PathBuf {
+    let expanded = match path.strip_prefix("~") {
+        Ok(rest) if rest.as_os_str().is_empty() => home_dir(),
+        Ok(rest) => home_dir().join(rest),
+        Err(_) => path.to_path_buf(),
+    };
+    if expanded.is_absolute() {
+        return expanded;
+    }
+
+    match std::env::current_dir() {
+        Ok(current) => current.join(expanded),
+        Err(_) => expanded,
+    }
+}
+
+//an absolute path as seen by a system mounted at `root`
+pub fn under_root(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+//exclusions as the user typed them: bare names match at any depth and stay as they are, paths are made absolute
+pub fn exclusion_patterns(patterns: &[PathBuf]) -> Vec<PathBuf> {
+    patterns
+        .iter()
+        .filter(|pattern| !pattern.as_os_str().is_empty())
+        .map(|pattern| if selector::is_name_pattern(pattern) { pattern.clone() } else { absolute_path(pattern) })
+        .collect()
+}
+
+//sources that don't exist on disk, in their original order
+pub fn missing_sources(sources: &[PathBuf]) -> Vec<PathBuf> {
+    sources
+        .iter()
+        .filter(|s| fs::symlink_metadata(s).is_err())
+        .cloned()
+        .collect()
+}
+
+//format a byte count as a human readable size
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
     } else {
-        Ok(BackupMetadata::default())
+        format!("{:.1} {}", size, units[unit])
     }
 }
 
-pub fn save_backup_metadata(metadata_dir: &Path, metadata: &BackupMetadata) -> Result<(), Box<dyn std::error::Error>> {
-    let metadata_file = metadata_dir.join("backup_metadata.json");
-    let file = File::create(metadata_file)?;
-    let writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, metadata)?;
-    Ok(())
-} 
\ No newline at end of file
+//check whether a path lives on a network filesystem
+pub fn is_network_filesystem(path: &Path) -> bool {
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => return false,
+    };
+
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+
+    NETWORK_FS_MAGIC.contains(&(stat.f_type as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_at(time: u64) -> MetadataStore {
+        let mut store = MetadataStore::default();
+        store.record("dirs:test", BackupMetadata { last_backup_time: Some(time), ..BackupMetadata::default() });
+        store
+    }
+
+    #[test]
+    fn overlapping_sources_are_backed_up_once() {
+        let temp = tempfile::tempdir().unwrap();
+        let home = temp.path().join("home");
+        let documents = home.join("Documents");
+        let elsewhere = temp.path().join("elsewhere");
+        fs::create_dir_all(&documents).unwrap();
+        fs::create_dir_all(&elsewhere).unwrap();
+        std::os::unix::fs::symlink(&documents, temp.path().join("docs-link")).unwrap();
+
+        //the descendant goes whichever order it was picked in, the same directory by another spelling counts once
+        let sources = [documents.clone(), home.clone(), home.join("."), elsewhere.clone(), temp.path().join("docs-link"), elsewhere.clone()];
+        assert_eq!(normalize_sources(&sources), vec![home.clone(), elsewhere.clone()]);
+
+        //paths that do not exist are passed through as given
+        let missing = temp.path().join("missing");
+        assert_eq!(normalize_sources(&[missing.clone(), elsewhere.clone()]), vec![missing, elsewhere]);
+    }
+
+    #[test]
+    fn archives_carry_a_copy_of_the_metadata() {
+        use std::io::Read;
+
+        let mut metadata = BackupMetadata { last_backup_time: Some(1_700_000_000), ..BackupMetadata::default() };
+        metadata.backup_history.insert("/home/dave/notes.txt".to_string(), FileHash { size: 12, mtime: 1_699_999_000, blake3: "00".repeat(32) });
+        let mut archive = Builder::new(Vec::new());
+        append_state_to_archive(&mut archive, &metadata).unwrap();
+        let data = archive.into_inner().unwrap();
+
+        //the same record the metadata directory keeps, enough to pick the chain up on a fresh machine
+        let mut reader = tar::Archive::new(data.as_slice());
+        let mut entry = reader.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new(STATE_DIR).join("backup_metadata.json"));
+        let mut snapshot = String::new();
+        entry.read_to_string(&mut snapshot).unwrap();
+        let snapshot: BackupMetadata = serde_json::from_str(&snapshot).unwrap();
+        assert_eq!(snapshot.last_backup_time, metadata.last_backup_time);
+        assert_eq!(snapshot.backup_history, metadata.backup_history);
+    }
+
+    #[test]
+    fn metadata_only_changes_follow_the_chains_detection_policy() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("report.txt");
+        fs::write(&path, "content").unwrap();
+        //content from before the last backup, a fresh inode the way a chmod or chown leaves one
+        let now = SystemTime::now();
+        File::options().write(true).open(&path).unwrap().set_modified(now - std::time::Duration::from_secs(2000)).unwrap();
+        let since = now.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() - 1000;
+        let metadata = path.metadata().unwrap();
+
+        assert_eq!(detect_change(&metadata, since, &ChangeDetection::default()), FileChange::MetadataOnly);
+        assert_eq!(detect_change(&metadata, since, &ChangeDetection::Ctime), FileChange::MetadataOnly);
+        assert_eq!(detect_change(&metadata, since, &ChangeDetection::Mtime), FileChange::Unchanged);
+    }
+
+    #[test]
+    fn damaged_metadata_falls_back_to_the_previous_version() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+
+        for time in [100, 200] {
+            persist_metadata_store(dir, &store_at(time)).unwrap();
+        }
+        assert_eq!(load_metadata_store(dir).unwrap().last_backup_time(), Some(200));
+
+        //a write cut short, then plain garbage
+        let contents = fs::read_to_string(dir.join(METADATA_FILE)).unwrap();
+        fs::write(dir.join(METADATA_FILE), &contents[..contents.len() / 2]).unwrap();
+        assert_eq!(load_metadata_store(dir).unwrap().last_backup_time(), Some(100));
+
+        fs::write(dir.join(METADATA_FILE), "\0\0garbage").unwrap();
+        assert_eq!(load_metadata_store(dir).unwrap().last_backup_time(), Some(100));
+
+        //saving over a damaged file keeps the good previous version
+        persist_metadata_store(dir, &store_at(300)).unwrap();
+        assert_eq!(read_metadata_store(&dir.join(PREVIOUS_METADATA_FILE)).unwrap().last_backup_time(), Some(100));
+
+        //with both versions unreadable only a reset helps
+        fs::write(dir.join(METADATA_FILE), "garbage").unwrap();
+        fs::write(dir.join(PREVIOUS_METADATA_FILE), "").unwrap();
+        assert!(load_metadata_store(dir).unwrap_err().to_string().contains("--reset-metadata"));
+
+        assert!(reset_backup_metadata(dir).unwrap().is_some());
+        assert_eq!(load_metadata_store(dir).unwrap().last_backup_time(), None);
+    }
+
+    #[test]
+    fn single_chain_files_become_the_legacy_chain() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join(METADATA_FILE), r#"{"last_backup_time": 500, "original_backup_time": 400}"#).unwrap();
+
+        let documents = job_key(None, &[PathBuf::from("/home/dave/Documents")]);
+        let etc = job_key(None, &[PathBuf::from("/etc")]);
+        assert_ne!(documents, etc);
+        assert_eq!(job_key(None, &[PathBuf::from("/etc"), PathBuf::from("/opt")]), job_key(None, &[PathBuf::from("/opt"), PathBuf::from("/etc")]));
+        assert_eq!(job_key(Some("nightly"), &[PathBuf::from("/etc")]), "profile:nightly");
+
+        //the first job to record takes the old chain over, the next one starts its own
+        let mut store = load_metadata_store(dir).unwrap();
+        let mut metadata = store.job(&documents);
+        assert_eq!((metadata.original_backup_time, metadata.last_backup_time), (Some(400), Some(500)));
+        metadata.last_backup_time = Some(600);
+        store.record(&documents, metadata);
+        persist_metadata_store(dir, &store).unwrap();
+
+        let store = load_metadata_store(dir).unwrap();
+        assert_eq!(store.job(&documents).last_backup_time, Some(600));
+        assert_eq!(store.job(&etc).last_backup_time, None);
+        assert!(!store.jobs.contains_key(LEGACY_JOB));
+
+        //a chain file that is damaged inside a job isn't mistaken for an old empty one
+        fs::write(dir.join(METADATA_FILE), r#"{"jobs": {"dirs:x": {"last_backup_time": "soon"}}}"#).unwrap();
+        fs::remove_file(dir.join(PREVIOUS_METADATA_FILE)).unwrap();
+        assert!(load_metadata_store(dir).is_err());
+    }
+
+    #[test]
+    fn durations_parse_with_suffixes() {
+        assert_eq!(parse_duration("45s"), Ok(45));
+        assert_eq!(parse_duration("30m"), Ok(30 * 60));
+        assert_eq!(parse_duration("36h"), Ok(36 * 3600));
+        assert_eq!(parse_duration("90d"), Ok(90 * 86400));
+        assert_eq!(parse_duration("90"), Ok(90 * 86400));
+        assert_eq!(parse_duration(" 2w "), Ok(14 * 86400));
+        assert_eq!(parse_duration("1y"), Ok(365 * 86400));
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("10x").is_err());
+    }
+
+    #[test]
+    fn sizes_parse_with_suffixes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("500M"), Ok(500 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert_eq!(parse_size(" 2GB "), Ok(2 << 30));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("2 G"), Ok(2 << 30));
+        assert_eq!(parse_size("1.5K"), Ok(1536));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert_eq!(parse_size("100B"), Ok(100));
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("2X").is_err());
+        assert!(parse_size("2GG").is_err());
+        assert!(parse_size("0").is_err());
+    }
+}
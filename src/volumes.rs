@@ -0,0 +1,384 @@
+use crate::appender::FileAppender;
+use crate::backup::{self, BackupSettings};
+use crate::compression::ArchiveEncoder;
+use crate::filter::FileFilter;
+use crate::parallel_gzip;
+use crate::portability;
+use crate::restore_script::sha256_file;
+use crate::selector::{EntryKind, FileSelector};
+use crate::sidecars;
+use crate::sysinfo::{self, SystemInfo};
+use crate::throttle;
+use crate::utils;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tar::Builder;
+
+//recordable capacities of common single-session discs
+const PRESETS: [(&str, u64); 6] = [
+    ("cd", 700 * 1000 * 1000),
+    ("dvd", 4_700_372_992),
+    ("dvd-dl", 8_543_666_176),
+    ("bd", 25_025_314_816),
+    ("bd-dl", 50_050_629_632),
+    ("bd-xl", 100_103_356_416),
+];
+
+//tar headers, PAX records and the end of the archive around an entry
+const ENTRY_RESERVE: u64 = 64 * 1024;
+
+//what zstd and xz may hold back before writing it out, gzip holds back a block per thread on top
+const COMPRESSOR_SLACK: u64 = 8 * 1024 * 1024;
+
+//what went into which volume, written next to the volumes and meant to be read by people as well
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VolumeIndex {
+    pub created: String,
+    pub hostname: String,
+    pub sources: Vec<String>,
+    pub volume_size: u64,
+    pub compression: String,
+    //every volume stands alone, this says how to get the files back without linux_backup
+    pub restore: String,
+    pub volumes: Vec<VolumeRecord>,
+    //files larger than a volume, they are in none of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub left_out: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VolumeRecord {
+    pub file: String,
+    pub size: u64,
+    pub sha256: String,
+    //files, symlinks and special files as stored in the volume, without the leading /
+    pub paths: Vec<String>,
+}
+
+//a preset name from PRESETS or a size like 4G or 700M
+pub fn parse_volume_size(input: &str) -> Result<u64, String> {
+    if let Some((_, size)) = PRESETS.iter().find(|(name, _)| name.eq_ignore_ascii_case(input.trim())) {
+        //the disc's filesystem, the .sha256 file and the index take some room next to the volume
+        return Ok(size - size / 100);
+    }
+    let names: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+    let size = throttle::parse_rate(input).map_err(|_| format!("Invalid volume size '{}', use a size like 4G or one of {}", input, names.join(", ")))?;
+    if size < 4 * ENTRY_RESERVE {
+        return Err(format!("A volume of {} bytes is too small to hold anything", size));
+    }
+    Ok(size)
+}
+
+//one volume being written, a complete archive of its own
+struct VolumeWriter {
+    archive: Builder<ArchiveEncoder<File>>,
+    //the same file, to see how much has reached the disk
+    file: File,
+    partial: PathBuf,
+    path: PathBuf,
+    appender: FileAppender,
+    slack: u64,
+    paths: Vec<String>,
+    mtime: u64,
+}
+
+impl VolumeWriter {
+    //every volume starts with the descriptor, so a single disc restores like any other archive
+    fn create(path: PathBuf, settings: &BackupSettings, origin: &SystemInfo) -> Result<Self, Box<dyn std::error::Error>> {
+        let partial = sidecars::partial(&path);
+
+        let file = File::create(&partial)?;
+        let mut archive = backup::create_archive(file.try_clone()?, settings.compression, settings.reproducible)?;
+        let mtime = utils::entry_mtime(settings.reproducible);
+        sysinfo::append_to_archive(&mut archive, origin, mtime)?;
+        portability::append_readme(&mut archive, settings, mtime)?;
+        println!("{}", format!("Writing {}", path.display()).bold());
+
+        Ok(VolumeWriter {
+            archive,
+            file,
+            partial,
+            path,
+            //hard links are only stored as links within a volume, each volume restores on its own
+            appender: FileAppender::new(settings.reproducible).with_xattrs(settings.xattrs).with_acls(settings.acls),
+            slack: settings.compression.threads as u64 * parallel_gzip::BLOCK_SIZE as u64 + COMPRESSOR_SLACK,
+            paths: Vec::new(),
+            mtime,
+        })
+    }
+
+    //whether `needed` more bytes still fit, the compressor is only flushed when it gets close
+    //flushing ends a gzip member or zstd block early, which costs a little compression
+    fn room_for(&mut self, needed: u64, capacity: u64) -> io::Result<bool> {
+        if self.file.metadata()?.len() + self.slack + needed <= capacity {
+            return Ok(true);
+        }
+        self.archive.get_mut().flush()?;
+        Ok(self.file.metadata()?.len() + needed <= capacity)
+    }
+
+    fn finish(mut self) -> Result<VolumeRecord, Box<dyn std::error::Error>> {
+        self.appender.databases.append_to_archive(&mut self.archive, self.mtime)?;
+        self.archive.into_inner()?.finish()?.sync_all()?;
+        fs::rename(&self.partial, &self.path)?;
+        self.appender.report();
+
+        let file = self.path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let sha256 = sha256_file(&self.path)?;
+        //the format sha256sum -c reads, run in the directory the volume was copied to
+        fs::write(sidecars::checksum(&self.path), format!("{}  {}\n", sha256, file))?;
+
+        Ok(VolumeRecord { file, size: fs::metadata(&self.path)?.len(), sha256, paths: self.paths })
+    }
+}
+
+//a full backup split into independent archives of at most `capacity` bytes, with a checksum for each and an index of all
+//volume sets stand apart from the backup chain, they neither read nor update its metadata
+pub fn write_volumes(sources: &[PathBuf], exclusions: &[PathBuf], settings: &BackupSettings, output: &Path, capacity: u64) -> Result<VolumeIndex, Box<dyn std::error::Error>> {
+    let extension = settings.compression.format.extension();
+    let set = sidecars::set_name(output, extension);
+    let dir = output.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+
+    let mut origin = SystemInfo { compression: Some(settings.compression.label()), portability: Some(settings.portability), ..SystemInfo::current(sources) };
+    if settings.reproducible {
+        origin = origin.without_free_space();
+    }
+    let mut index = VolumeIndex {
+        created: chrono::Local::now().format("%Y-%m-%d %H:%M:%S %z").to_string(),
+        hostname: origin.hostname.clone(),
+        sources: origin.sources.clone(),
+        volume_size: capacity,
+        compression: settings.compression.label(),
+        restore: format!("Each volume is a complete tar archive, extract any one of them with: tar -xf {} -C /target/dir", sidecars::volume_name(&set, 1, extension)),
+        volumes: Vec::new(),
+        left_out: Vec::new(),
+    };
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let mut selector = FileSelector::new(exclusions, FileFilter::new(settings.max_age.clone(), now).with_max_size(settings.max_size.clone())).follow_symlinks(settings.follow_symlinks).nobackup_markers(settings.nobackup_markers).cache_dirs(settings.cache_dirs).one_file_system(settings.one_file_system).presets(&settings.presets);
+    let next_volume = |number| VolumeWriter::create(dir.join(sidecars::volume_name(&set, number, extension)), settings, &origin);
+    let mut current: Option<VolumeWriter> = None;
+
+    for source in sources {
+        println!("Backing up directory: {}", source.display());
+        for entry in selector.select(source) {
+            let name = entry.path.strip_prefix("/").unwrap_or(&entry.path).to_path_buf();
+            //incompressible data grows a little in any of the formats
+            let needed = match entry.kind {
+                EntryKind::File => entry.size + entry.size / 100 + ENTRY_RESERVE,
+                _ => ENTRY_RESERVE,
+            };
+            if needed + ENTRY_RESERVE > capacity {
+                println!("{}", format!("Warning: {} is larger than a volume, leaving it out", entry.path.display()).yellow());
+                index.left_out.push(name.display().to_string());
+                continue;
+            }
+
+            let mut volume = match current.take() {
+                Some(mut volume) => {
+                    if volume.room_for(needed, capacity)? {
+                        volume
+                    } else {
+                        index.volumes.push(volume.finish()?);
+                        next_volume(index.volumes.len() + 1)?
+                    }
+                }
+                None => next_volume(1)?,
+            };
+
+            let appended = match entry.kind {
+                EntryKind::File => File::open(&entry.path).and_then(|mut file| volume.appender.append_file(&mut volume.archive, &name, &entry.path, &mut file)),
+                EntryKind::Directory => volume.appender.append_dir(&mut volume.archive, &name, &entry.path).map(|_| false),
+                EntryKind::Symlink => volume.appender.append_symlink(&mut volume.archive, &name, &entry.path),
+                EntryKind::Special => volume.appender.append_special(&mut volume.archive, &name, &entry.path),
+            };
+            match appended {
+                Ok(true) => volume.paths.push(name.display().to_string()),
+                Ok(false) => {}
+                Err(e) => println!("{}", format!("Warning: Could not archive {}, leaving it out: {}", entry.path.display(), e).yellow()),
+            }
+            current = Some(volume);
+        }
+    }
+    if let Some(volume) = current {
+        index.volumes.push(volume.finish()?);
+    }
+    selector.report();
+    backup::list_skipped(&selector, settings, false);
+
+    fs::write(dir.join(sidecars::index_name(&set)), serde_json::to_string_pretty(&index)? + "\n")?;
+    Ok(index)
+}
+
+pub fn print_volume_summary(index: &VolumeIndex, dir: &Path) {
+    println!("\n{}", "Backup completed!".green().bold());
+    for volume in &index.volumes {
+        println!("  {}: {} files, {}", volume.file, volume.paths.len(), utils::format_size(volume.size));
+    }
+    if !index.left_out.is_empty() {
+        println!("{}", format!("{} files were larger than a volume and are in none of them, see left_out in the index", index.left_out.len()).yellow());
+    }
+    let set = index.volumes.first().and_then(|volume| sidecars::set_of(&volume.file)).unwrap_or_default();
+    println!("Index saved to: {}", dir.join(sidecars::index_name(set)).display().to_string().green());
+    println!("Burn each volume with its .sha256 file and a copy of the index, then check the copies with: linux_backup verify --volume <dir>");
+}
+
+//the checksum recorded for a volume, from its .sha256 file or else from the index of its set next to it
+fn recorded_checksum(volume: &Path) -> Option<String> {
+    if let Ok(contents) = fs::read_to_string(sidecars::checksum(volume)) {
+        return contents.split_whitespace().next().map(str::to_string);
+    }
+
+    let file = volume.file_name()?.to_str()?;
+    let index_path = volume.with_file_name(sidecars::index_name(sidecars::set_of(file)?));
+    let index: VolumeIndex = serde_json::from_str(&fs::read_to_string(index_path).ok()?).ok()?;
+    index.volumes.into_iter().find(|record| record.file == file).map(|record| record.sha256)
+}
+
+//check a volume, or every volume in a directory, against the recorded checksums, returns how many failed
+pub fn verify(path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut volumes: Vec<PathBuf> = if path.is_dir() {
+        fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.file_name().and_then(|name| name.to_str()).and_then(sidecars::set_of).is_some())
+            .collect()
+    } else {
+        vec![path.to_path_buf()]
+    };
+    volumes.sort();
+    if volumes.is_empty() {
+        return Err(format!("No volumes found in {}", path.display()).into());
+    }
+
+    let mut failed = 0;
+    for volume in &volumes {
+        let name = volume.display();
+        let Some(expected) = recorded_checksum(volume) else {
+            println!("{}", format!("{}: no checksum recorded, the .sha256 file and the index are both missing", name).red());
+            failed += 1;
+            continue;
+        };
+        match sha256_file(volume) {
+            Ok(actual) if actual == expected => println!("{}", format!("{}: OK", name).green()),
+            Ok(_) => {
+                println!("{}", format!("{}: FAILED, the checksum doesn't match", name).red());
+                failed += 1;
+            }
+            Err(e) => {
+                println!("{}", format!("{}: FAILED, could not read it: {}", name, e).red());
+                failed += 1;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{self, ArchiveCompression, CompressionFormat};
+    use crate::utils::BackupType;
+    use tar::Archive;
+
+    //data that doesn't compress, so the volume sizes are predictable
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn settings() -> BackupSettings {
+        BackupSettings {
+            backup_type: BackupType::Full,
+            compression: ArchiveCompression::new(CompressionFormat::Gzip),
+            reproducible: false,
+            max_age: None,
+            max_size: None,
+            list_skipped: None,
+            presets: Vec::new(),
+            hash_check: false,
+            encryption: None,
+            xattrs: false,
+            acls: false,
+            follow_symlinks: false,
+            nobackup_markers: true,
+            cache_dirs: true,
+            one_file_system: false,
+            portability: Default::default(),
+            wait_for_lock: None,
+            min_free_after: None,
+            check_archive: false,
+            entry_index: false,
+            survive_hangup: false,
+            low_space: None,
+        }
+    }
+
+    #[test]
+    fn sizes_take_presets_and_units() {
+        assert_eq!(parse_volume_size("bd").unwrap(), 25_025_314_816 - 250_253_148);
+        assert_eq!(parse_volume_size("DVD").unwrap(), 4_700_372_992 - 47_003_729);
+        assert_eq!(parse_volume_size("700M").unwrap(), 700 * 1024 * 1024);
+        assert!(parse_volume_size("4K").is_err());
+        assert!(parse_volume_size("floppy").is_err());
+
+        assert_eq!(sidecars::set_of("photos.vol002.tar.gz"), Some("photos"));
+        assert_eq!(sidecars::set_of("photos.vol002.tar.gz.sha256"), None);
+        assert_eq!(sidecars::set_of("photos.index.json"), None);
+        assert_eq!(sidecars::set_of("my.volcano.tar.gz"), None);
+    }
+
+    #[test]
+    fn volumes_restore_on_their_own_and_verify_against_their_checksums() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("photos");
+        fs::create_dir(&source).unwrap();
+        for (i, name) in ["a.jpg", "b.jpg", "c.jpg"].iter().enumerate() {
+            fs::write(source.join(name), noise(400 * 1024, i as u64)).unwrap();
+        }
+        fs::write(source.join("huge.mov"), noise(2 * 1024 * 1024, 9)).unwrap();
+
+        let out = temp.path().join("out");
+        fs::create_dir(&out).unwrap();
+        let index = write_volumes(std::slice::from_ref(&source), &[], &settings(), &out.join("photos.tar.gz"), 1024 * 1024).unwrap();
+
+        //two of the files fit in a volume, the one larger than a volume is only listed
+        let files: Vec<Vec<&str>> = index.volumes.iter().map(|volume| volume.paths.iter().map(|path| path.rsplit('/').next().unwrap()).collect()).collect();
+        assert_eq!(files, vec![vec!["a.jpg", "b.jpg"], vec!["c.jpg"]]);
+        assert_eq!(index.left_out.len(), 1);
+        assert!(index.left_out[0].ends_with("photos/huge.mov"));
+        for volume in &index.volumes {
+            assert!(volume.size <= 1024 * 1024);
+        }
+
+        let saved: VolumeIndex = serde_json::from_str(&fs::read_to_string(out.join("photos.index.json")).unwrap()).unwrap();
+        assert_eq!(saved.volumes[1].file, "photos.vol002.tar.gz");
+
+        //the second disc alone gives back its file
+        let restored = temp.path().join("restored");
+        let decoder = compression::open_decoder(&out.join("photos.vol002.tar.gz"), None).unwrap();
+        Archive::new(decoder).unpack(&restored).unwrap();
+        let name = source.join("c.jpg");
+        assert_eq!(fs::read(restored.join(name.strip_prefix("/").unwrap())).unwrap(), noise(400 * 1024, 2));
+
+        assert_eq!(verify(&out).unwrap(), 0);
+
+        //a copy without its .sha256 file is checked against the index
+        fs::remove_file(out.join("photos.vol001.tar.gz.sha256")).unwrap();
+        assert_eq!(verify(&out.join("photos.vol001.tar.gz")).unwrap(), 0);
+
+        let mut damaged = fs::read(out.join("photos.vol002.tar.gz")).unwrap();
+        damaged[1000] ^= 1;
+        fs::write(out.join("photos.vol002.tar.gz"), damaged).unwrap();
+        assert_eq!(verify(&out).unwrap(), 1);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::acl;
+use std::io;
+use std::path::Path;
+
+//GNU tar and bsdtar store extended attributes as PAX records under this prefix
+pub const PAX_PREFIX: &str = "SCHILY.xattr.";
+
+//where file capabilities are stored, chown removes it
+pub const CAPABILITY: &str = "security.capability";
+
+//namespaces only root can write, restores without root leave them out
+const PRIVILEGED_NAMESPACES: &[&str] = &["security.", "trusted."];
+
+//name and value of one extended attribute
+pub type Xattr = (String, Vec<u8>);
+
+//the attributes of a file or directory, sorted by name so reproducible archives stay identical
+//filesystems without xattr support simply have none
+pub fn read(path: &Path) -> Vec<Xattr> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(_) => return Vec::new(),
+    };
+
+    //ACLs are stored in their own records, and only when asked for
+    let mut attrs: Vec<Xattr> = names
+        .filter(|name| name != acl::XATTR_ACCESS && name != acl::XATTR_DEFAULT)
+        .filter_map(|name| {
+            let value = xattr::get(path, &name).ok()??;
+            Some((name.into_string().ok()?, value))
+        })
+        .collect();
+    attrs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    attrs
+}
+
+//the PAX records an entry's attributes are stored as
+pub fn pax_records(attrs: &[Xattr]) -> Vec<(String, &[u8])> {
+    attrs.iter().map(|(name, value)| (format!("{}{}", PAX_PREFIX, name), value.as_slice())).collect()
+}
+
+//the attributes among an entry's PAX records
+pub fn from_pax<'a>(records: impl Iterator<Item = io::Result<tar::PaxExtension<'a>>>) -> Vec<Xattr> {
+    records
+        .flatten()
+        .filter_map(|record| {
+            let name = record.key().ok()?.strip_prefix(PAX_PREFIX)?;
+            Some((name.to_string(), record.value_bytes().to_vec()))
+        })
+        .collect()
+}
+
+//what became of the attributes of the restored entries
+#[derive(Default)]
+pub struct XattrRestore {
+    pub applied: u64,
+    //security.* and trusted.* attributes left out because the restore isn't running as root
+    pub skipped: u64,
+    pub failures: Vec<(String, String)>,
+}
+
+impl XattrRestore {
+    //set the archived attributes, after chown, which drops file capabilities
+    pub fn apply(&mut self, path: &Path, attrs: &[Xattr], is_root: bool) {
+        for (name, value) in attrs {
+            if !is_root && PRIVILEGED_NAMESPACES.iter().any(|namespace| name.starts_with(namespace)) {
+                self.skipped += 1;
+                continue;
+            }
+            match xattr::set(path, name, value) {
+                Ok(()) => self.applied += 1,
+                Err(e) => self.failures.push((format!("{} ({})", path.display(), name), e.to_string())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn privileged_namespaces_need_root() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("ping");
+        std::fs::write(&path, "").unwrap();
+
+        let mut restore = XattrRestore::default();
+        restore.apply(&path, &[("security.capability".to_string(), vec![1, 0, 0, 2])], false);
+        assert_eq!((restore.applied, restore.skipped), (0, 1));
+        assert!(read(&path).is_empty());
+    }
+}
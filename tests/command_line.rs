@@ -0,0 +1,287 @@
+use flate2::read::GzDecoder;
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn linux_backup(root: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_linux_backup"))
+        .args(args)
+        .current_dir(root)
+        .env("HOME", root)
+        .env("TERM", "dumb")
+        .env("LINUX_BACKUP_CONFIG_DIR", root.join("config"))
+        .env("LINUX_BACKUP_STATE_DIR", root.join("state"))
+        .output()
+        .unwrap()
+}
+
+//names of the files in a gzipped archive, without the tool's own bookkeeping
+fn archived_files(archive: &Path) -> Vec<String> {
+    let mut names: Vec<String> = tar::Archive::new(GzDecoder::new(File::open(archive).unwrap()))
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.header().entry_type().is_file())
+        .map(|entry| entry.path().unwrap().display().to_string())
+        .filter(|name| !name.starts_with(".linux_backup") && name != "README.linux_backup.txt")
+        .collect();
+    names.sort();
+    names
+}
+
+//the subcommands run start to finish with no terminal to prompt on
+#[test]
+fn subcommands_back_up_and_restore_without_prompting() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src/sub")).unwrap();
+    fs::write(root.join("src/a.txt"), "hello").unwrap();
+    fs::write(root.join("src/sub/b.txt"), "world").unwrap();
+    fs::create_dir_all(root.join("config")).unwrap();
+    fs::write(root.join("config/config.toml"), format!("quick_dirs = [\"{}\"]\n", root.join("src").display())).unwrap();
+
+    let help = linux_backup(root, &["--help"]);
+    let help = String::from_utf8_lossy(&help.stdout);
+    for command in ["selective", "full", "restore"] {
+        assert!(help.contains(command), "{}", help);
+    }
+
+    let output = linux_backup(root, &["selective", "--output", "full.tar.gz"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stored = root.strip_prefix("/").unwrap().join("src");
+    assert_eq!(archived_files(&root.join("full.tar.gz")), vec![
+        stored.join("a.txt").display().to_string(),
+        stored.join("sub/b.txt").display().to_string(),
+    ]);
+
+    //change times are kept to the second, a file from the same second as the full backup could be missed
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    fs::write(root.join("src/c.txt"), "new").unwrap();
+    let output = linux_backup(root, &["selective", "--output", "incremental.tar.gz", "--backup-type", "incremental"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(archived_files(&root.join("incremental.tar.gz")), vec![stored.join("c.txt").display().to_string()]);
+
+    //an existing archive is only touched when asked to
+    let output = linux_backup(root, &["selective", "--output", "full.tar.gz"]);
+    assert!(!output.status.success());
+    let output = linux_backup(root, &["selective", "--output", "full.tar.gz", "--rotate"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(root.join("full.tar.gz.1").exists());
+
+    let output = linux_backup(root, &["restore", "--file", "full.tar.gz.1", "--target", "restored"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(root.join("restored").join(&stored).join("sub/b.txt")).unwrap(), "world");
+}
+
+//a typo in --dirs fails the run before anything is written, with every missing directory named at once
+#[test]
+fn missing_directories_fail_the_run_before_the_archive() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src/a.txt"), "hello").unwrap();
+
+    let output = linux_backup(root, &["selective", "--dirs", "src", "--dirs", "Documnets", "--dirs", "Pictrues", "-o", "out.tar.gz"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(stderr.contains("These directories do not exist"), "{}", stderr);
+    assert!(stderr.contains(&root.join("Documnets").display().to_string()), "{}", stderr);
+    assert!(stderr.contains(&root.join("Pictrues").display().to_string()), "{}", stderr);
+    let left: Vec<_> = fs::read_dir(root).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    assert_eq!(left, vec!["src"]);
+
+    //the same for a scheduled profile, its backup doesn't quietly shrink either
+    fs::create_dir_all(root.join("config")).unwrap();
+    fs::write(root.join("config/config.toml"), "[profiles.nightly]\ndirs = [\"src\", \"Documnets\", \"Pictrues\"]\noutput_dir = \"out\"\n").unwrap();
+    let output = linux_backup(root, &["--profile", "nightly"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains(&root.join("Documnets").display().to_string()), "{}", stderr);
+    assert!(stderr.contains(&root.join("Pictrues").display().to_string()), "{}", stderr);
+    assert!(!root.join("out").exists());
+}
+
+//--dirs takes a comma-separated list as well as repeats, relative entries resolved against the current directory
+#[test]
+fn directories_can_be_listed_and_given_relative() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    let elsewhere = tempfile::tempdir().unwrap();
+    for dir in ["work/docs", "work/notes"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+        fs::write(root.join(dir).join("file.txt"), dir).unwrap();
+    }
+    fs::write(elsewhere.path().join("absolute.txt"), "absolute").unwrap();
+
+    let absolute = elsewhere.path().display().to_string();
+    let output = linux_backup(&root.join("work"), &["selective", "--dirs", "docs,notes", "--dirs", &absolute, "-o", "out.tar.gz"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stored = |path: &Path| path.strip_prefix("/").unwrap().display().to_string();
+    let mut expected = vec![
+        stored(&root.join("work/docs/file.txt")),
+        stored(&root.join("work/notes/file.txt")),
+        stored(&elsewhere.path().join("absolute.txt")),
+    ];
+    expected.sort();
+    assert_eq!(archived_files(&root.join("work/out.tar.gz")), expected);
+}
+
+//a restore that didn't get everything back fails, so a script or cron job can tell
+#[test]
+fn failed_restores_exit_with_an_error() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src")).unwrap();
+    //incompressible, so cutting the archive in half lands inside the file's data
+    let mut state = 0x2545_f491_u32;
+    let noise: Vec<u8> = (0..256 * 1024)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    fs::write(root.join("src/noise.bin"), noise).unwrap();
+    let output = linux_backup(root, &["selective", "--dirs", "src", "-o", "full.tar.gz"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let archive = fs::read(root.join("full.tar.gz")).unwrap();
+    fs::write(root.join("truncated.tar.gz"), &archive[..archive.len() / 2]).unwrap();
+    let output = linux_backup(root, &["restore", "--file", "truncated.tar.gz", "--target", "restored"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Restore incomplete"), "{}", String::from_utf8_lossy(&output.stdout));
+
+    //a directory in the way of a file keeps it from being written
+    let stored = root.strip_prefix("/").unwrap().join("src/noise.bin");
+    fs::create_dir_all(root.join("blocked").join(&stored).join("inside")).unwrap();
+    let output = linux_backup(root, &["restore", "--file", "full.tar.gz", "--target", "blocked"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 entries could not be restored"), "{}", String::from_utf8_lossy(&output.stdout));
+
+    fs::write(root.join("garbage.tar.gz"), "not an archive").unwrap();
+    for file in ["missing.tar.gz", "garbage.tar.gz"] {
+        let output = linux_backup(root, &["restore", "--file", file, "--target", "restored"]);
+        assert!(!output.status.success(), "{}", file);
+    }
+}
+
+//xz archives are written at the chosen preset and restore both ways
+#[test]
+fn xz_archives_round_trip() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src/a.txt"), "squeeze me ".repeat(1000)).unwrap();
+
+    let output = linux_backup(root, &["selective", "--dirs", "src", "--compression", "xz", "--compression-level", "9"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let archive = root.join("backup.tar.xz");
+    assert_eq!(fs::read(&archive).unwrap()[..6], [0xFD, b'7', b'z', b'X', b'Z', 0x00]);
+    let info = linux_backup(root, &["info", "--file", "backup.tar.xz"]);
+    assert!(String::from_utf8_lossy(&info.stdout).contains("Compression: xz level 9"));
+
+    let stored = root.strip_prefix("/").unwrap().join("src/a.txt");
+    for (target, system_tar) in [("restored", false), ("restored_by_tar", true)] {
+        let mut args = vec!["restore", "--file", "backup.tar.xz", "--target", target];
+        if system_tar {
+            args.push("--use-system-tar");
+        }
+        let output = linux_backup(root, &args);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(fs::read_to_string(root.join(target).join(&stored)).unwrap(), "squeeze me ".repeat(1000));
+    }
+}
+
+//a command line restore never asks about conflicts, it says so before touching anything
+#[test]
+fn asking_about_conflicts_is_left_to_the_menu() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src/a.txt"), "hello").unwrap();
+    let output = linux_backup(root, &["selective", "--dirs", "src", "-o", "out.tar.gz"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = linux_backup(root, &["restore", "--file", "out.tar.gz", "--target", "restored", "--on-conflict", "ask"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("The ask conflict policy is only offered in the menu"));
+    assert!(!root.join("restored").exists());
+}
+
+//store only writes a plain .tar, which restore tells from a compressed one by its contents
+#[test]
+fn store_only_archives_are_plain_tar() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src/photo.jpg"), "already compressed").unwrap();
+
+    let output = linux_backup(root, &["selective", "--dirs", "src", "--compression", "none"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!root.join("backup.tar.gz").exists());
+    let bytes = fs::read(root.join("backup.tar")).unwrap();
+    assert_eq!(&bytes[257..262], b"ustar");
+
+    //a misleading name doesn't send it through gzip
+    fs::rename(root.join("backup.tar"), root.join("renamed.tar.gz")).unwrap();
+    let output = linux_backup(root, &["restore", "--file", "renamed.tar.gz", "--target", "restored"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stored = root.strip_prefix("/").unwrap().join("src/photo.jpg");
+    assert_eq!(fs::read_to_string(root.join("restored").join(stored)).unwrap(), "already compressed");
+}
+
+//--exclude drops directories by name at any depth, paths by prefix and files by basename glob, each counted in the summary
+#[test]
+fn exclusions_match_names_paths_and_globs() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    for (file, content) in [
+        ("projects/app/src/main.rs", "fn main() {}"),
+        ("projects/app/target/debug/app", "binary"),
+        ("projects/web/node_modules/left-pad/index.js", "pad"),
+        ("projects/web/index.html", "<html>"),
+        ("projects/web/debug.log", "noise"),
+        ("projects/scratch/notes.txt", "draft"),
+    ] {
+        fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+        fs::write(root.join(file), content).unwrap();
+    }
+
+    let scratch = root.join("projects/scratch").display().to_string();
+    let output = linux_backup(root, &["selective", "--dirs", "projects", "--exclude", "target", "--exclude", "node_modules", "--exclude", "*.log", "--exclude", &scratch, "-o", "out.tar.gz"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stored = |file: &str| root.strip_prefix("/").unwrap().join(file).display().to_string();
+    assert_eq!(archived_files(&root.join("out.tar.gz")), vec![stored("projects/app/src/main.rs"), stored("projects/web/index.html")]);
+    for rule in ["exclusion target: 1", "exclusion node_modules: 1", "exclusion *.log: 1", &format!("exclusion {}: 1", scratch)] {
+        assert!(stdout.contains(rule), "{}", stdout);
+    }
+}
+
+//an estimate counts only what the backup would take, for a profile as for --dirs with the same flags
+#[test]
+fn estimates_leave_out_what_the_backup_would() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    for (file, size) in [("src/main.rs", 3000), ("target/app", 50_000), ("cache/CACHEDIR.TAG", 0), ("cache/blob", 20_000)] {
+        fs::create_dir_all(root.join("app").join(file).parent().unwrap()).unwrap();
+        fs::write(root.join("app").join(file), vec![b'x'; size]).unwrap();
+    }
+    fs::write(root.join("app/cache/CACHEDIR.TAG"), "Signature: 8a477f597d28d172789f06886806bc55").unwrap();
+    fs::create_dir_all(root.join("config")).unwrap();
+    fs::write(root.join("config/config.toml"), "[profiles.code]\ndirs = [\"app\"]\nexcludes = [\"target\"]\n").unwrap();
+
+    let bytes = |output: Output| {
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()["bytes"].as_u64().unwrap()
+    };
+    //the cache tag itself is kept, like in the archive
+    let tag = 43;
+    assert_eq!(bytes(linux_backup(root, &["estimate", "--profile", "code", "--json"])), 3000 + tag);
+    assert_eq!(bytes(linux_backup(root, &["estimate", "--dirs", "app", "--exclude", "target", "--json"])), 3000 + tag);
+    assert_eq!(bytes(linux_backup(root, &["estimate", "--dirs", "app", "--include-caches", "--json"])), 3000 + 50_000 + 20_000 + tag);
+}
@@ -0,0 +1,62 @@
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn wait_for(what: &str, done: impl Fn() -> bool) {
+    let started = Instant::now();
+    while !done() {
+        assert!(started.elapsed() < Duration::from_secs(30), "gave up waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+//a real SIGHUP to a running backup, paused so the hangup lands mid-run: the output moves to the log and the backup finishes
+#[test]
+fn a_backup_outlives_its_terminal() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src")).unwrap();
+    for i in 0..2000 {
+        fs::write(root.join("src").join(format!("{}.txt", i)), "some text ".repeat(100)).unwrap();
+    }
+
+    //--survive-hangup is the default already, scripts that pass it keep working
+    let mut child = Command::new(env!("CARGO_BIN_EXE_linux_backup"))
+        .args(["selective", "--dirs", "src", "-o", "out.tar.gz", "--survive-hangup"])
+        .current_dir(root)
+        .env("HOME", root)
+        .env("TERM", "dumb")
+        .env("LINUX_BACKUP_CONFIG_DIR", root.join("config"))
+        .env("LINUX_BACKUP_STATE_DIR", root.join("state"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let pid = child.id() as libc::pid_t;
+
+    //the pause and hangup handlers are in place once the walk starts
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    loop {
+        let line = lines.next().expect("the backup ended before it started walking").unwrap();
+        if line.starts_with("Backing up directory") {
+            break;
+        }
+    }
+    unsafe { libc::kill(pid, libc::SIGUSR1) };
+    unsafe { libc::kill(pid, libc::SIGHUP) };
+
+    let log = root.join("state/hangup.log");
+    wait_for("the hangup notice", || fs::read_to_string(&log).is_ok_and(|log| log.contains("The terminal hung up")));
+    unsafe { libc::kill(pid, libc::SIGUSR2) };
+    //what was printed before the hangup, the rest goes to the log
+    drop(lines);
+
+    let status = child.wait().unwrap();
+    assert!(status.success(), "{:?}", status);
+    assert!(root.join("out.tar.gz").exists());
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("Finished in the background, the terminal had hung up."), "{}", log);
+}
@@ -0,0 +1,234 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+//the interactive menu on a dumb terminal, every answer typed into piped stdin
+#[test]
+fn menu_backup_runs_through_numbered_prompts() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src/sub")).unwrap();
+    fs::write(root.join("src/a.txt"), "hello").unwrap();
+    fs::write(root.join("src/sub/b.txt"), "world").unwrap();
+    fs::create_dir_all(root.join("config")).unwrap();
+    fs::write(root.join("config/config.toml"), format!("quick_dirs = [{:?}]\n", root.join("src").display().to_string())).unwrap();
+    let archive = root.join("out.tar.gz");
+
+    let answers = [
+        "9", //not on the menu, asked again
+        "1", //Backup Selected Directories
+        "1", //the quick-pick directory
+        "", //don't review the largest directories
+        "", //no exclusion patterns
+        "", //full backup
+        "", //no advanced options
+        "", //gzip
+        "", //default level
+        "", //no encryption
+        &archive.display().to_string(),
+        "", //read the archive back
+        "n", //no restore script
+        "", //press Enter to continue
+        "9", //Exit, What Changed and View Backup History show up after the first backup
+    ];
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_linux_backup"))
+        .current_dir(root)
+        .env("HOME", root)
+        .env("TERM", "dumb")
+        .env("LINUX_BACKUP_CONFIG_DIR", root.join("config"))
+        .env("LINUX_BACKUP_STATE_DIR", root.join("state"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all((answers.join("\n") + "\n").as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "{}\n{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("  7) Exit"));
+    assert!(stdout.contains("Enter a number from 1 to 7."));
+    assert!(stdout.contains("  1) [ ] "));
+    assert!(stdout.contains("Backup completed!"));
+    assert!(stdout.contains("The archive is complete."));
+    assert!(stdout.contains("  3) What Changed"));
+    assert!(stdout.contains("  4) View Backup History"));
+    assert!(stdout.contains("Exiting..."));
+    //no colors, no screen clearing and no cursor movement
+    assert!(!stdout.contains('\x1B'), "{}", stdout);
+    assert!(archive.exists());
+}
+
+//directories left out after the size review are saved into a new profile for the next run
+#[test]
+fn reviewed_exclusions_are_saved_into_a_profile() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src/videos")).unwrap();
+    fs::create_dir_all(root.join("src/notes")).unwrap();
+    fs::write(root.join("src/videos/raw.mov"), vec![0u8; 64 * 1024]).unwrap();
+    fs::write(root.join("src/notes/todo.txt"), "milk").unwrap();
+    fs::create_dir_all(root.join("config")).unwrap();
+    fs::write(root.join("config/config.toml"), format!("quick_dirs = [{:?}]\n", root.join("src").display().to_string())).unwrap();
+    let archive = root.join("out.tar.gz");
+
+    let answers = [
+        "1", //Backup Selected Directories
+        "1", //the quick-pick directory
+        "y", //review the largest directories
+        "1", //videos, the largest
+        "1", //save them in a new profile
+        "media",
+        "", //no exclusion patterns
+        "", //full backup
+        "", //no advanced options
+        "", //gzip
+        "", //default level
+        "", //no encryption
+        &archive.display().to_string(),
+        "", //read the archive back
+        "n", //no restore script
+        "", //press Enter to continue
+        "10", //Exit, Run Profile, What Changed and View Backup History show up now
+    ];
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_linux_backup"))
+        .current_dir(root)
+        .env("HOME", root)
+        .env("TERM", "dumb")
+        .env("LINUX_BACKUP_CONFIG_DIR", root.join("config"))
+        .env("LINUX_BACKUP_STATE_DIR", root.join("state"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all((answers.join("\n") + "\n").as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "{}\n{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("Saved 1 exclusions to profile media"), "{}", stdout);
+    assert!(stdout.contains("  3) Run Profile"));
+    assert!(stdout.contains("Backup completed!"));
+    let config = fs::read_to_string(root.join("config/config.toml")).unwrap();
+    assert!(config.contains("[profiles.media]"), "{}", config);
+    assert!(config.contains(&root.join("src/videos").display().to_string()), "{}", config);
+
+    let listing = Command::new("tar").arg("-tzf").arg(&archive).output().unwrap();
+    let listing = String::from_utf8_lossy(&listing.stdout);
+    assert!(listing.contains("src/notes/todo.txt"), "{}", listing);
+    assert!(!listing.contains("raw.mov"), "{}", listing);
+}
+
+//restore and verify offer the archive and the target the session just made, nothing is typed twice
+#[test]
+fn later_menu_actions_reuse_the_last_backup_and_restore() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src/a.txt"), "hello").unwrap();
+    fs::create_dir_all(root.join("config")).unwrap();
+    fs::write(root.join("config/config.toml"), format!("quick_dirs = [{:?}]\n", root.join("src").display().to_string())).unwrap();
+    let archive = root.join("out.tar.gz");
+
+    let answers = [
+        "1", //Backup Selected Directories
+        "1", //the quick-pick directory
+        "", //don't review the largest directories
+        "", //no exclusion patterns
+        "", //full backup
+        "", //no advanced options
+        "", //gzip
+        "", //default level
+        "", //no encryption
+        &archive.display().to_string(),
+        "", //read the archive back
+        "n", //no restore script
+        "", //press Enter to continue
+        "5", //Restore Backup
+        "", //use the last backup
+        "restored",
+        "n", //don't preserve ownership
+        "n", //no advanced options
+        "", //press Enter to continue
+        "7", //Verify Backup
+        "", //use the last backup
+        "", //against the last restore
+        "", //press Enter to continue
+        "9", //Exit
+    ];
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_linux_backup"))
+        .current_dir(root)
+        .env("HOME", root)
+        .env("TERM", "dumb")
+        .env("LINUX_BACKUP_CONFIG_DIR", root.join("config"))
+        .env("LINUX_BACKUP_STATE_DIR", root.join("state"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all((answers.join("\n") + "\n").as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "{}\n{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout.matches("Use the last backup (out.tar.gz)?").count(), 2, "{}", stdout);
+    assert!(stdout.contains("Directory the backup was restored to (leave empty to check the archive itself) [restored]"), "{}", stdout);
+    assert!(!stdout.contains("Enter path to backup file"), "{}", stdout);
+    assert!(!stdout.contains("Files that failed verification"), "{}", stdout);
+    let restored = root.join("restored").join(root.strip_prefix("/").unwrap()).join("src/a.txt");
+    assert_eq!(fs::read_to_string(restored).unwrap(), "hello");
+}
+
+//running out of answers ends the run instead of asking forever
+#[test]
+fn closed_stdin_is_an_error() {
+    let temp = tempfile::tempdir().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_linux_backup"))
+        .current_dir(temp.path())
+        .env("HOME", temp.path())
+        .env("TERM", "dumb")
+        .env("LINUX_BACKUP_CONFIG_DIR", temp.path().join("config"))
+        .env("LINUX_BACKUP_STATE_DIR", temp.path().join("state"))
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no more input"));
+}
+
+//a piped passphrase is read like any answer but never written back out
+#[test]
+fn piped_passphrases_are_not_echoed() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src/a.txt"), "hello").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_linux_backup"))
+        .current_dir(root)
+        .env("HOME", root)
+        .env("TERM", "dumb")
+        .env("LINUX_BACKUP_CONFIG_DIR", root.join("config"))
+        .env("LINUX_BACKUP_STATE_DIR", root.join("state"))
+        .args(["selective", "--dirs", "src", "-o", "out.tar.gz", "--encrypt-builtin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"hunter2secret\nhunter2secret\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "{}\n{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("Backup completed!"));
+    assert!(!stdout.contains("hunter2secret"), "{}", stdout);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("hunter2secret"));
+}
@@ -0,0 +1,122 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn linux_backup(root: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_linux_backup"))
+        .args(args)
+        .current_dir(root)
+        .env("HOME", root)
+        .env("TERM", "dumb")
+        .env("LINUX_BACKUP_CONFIG_DIR", root.join("config"))
+        .env("LINUX_BACKUP_STATE_DIR", root.join("state"))
+        .output()
+        .unwrap()
+}
+
+fn set_portability(root: &Path, level: &str) {
+    fs::create_dir_all(root.join("config")).unwrap();
+    fs::write(root.join("config/config.toml"), format!("portability = \"{}\"\n", level)).unwrap();
+}
+
+//long names, hard links, symlinks, empty files and directories, names that aren't ASCII
+fn feature_rich_tree(src: &Path) {
+    let deep = src.join("a".repeat(90)).join("b".repeat(90)).join("c".repeat(90));
+    fs::create_dir_all(&deep).unwrap();
+    fs::write(deep.join(format!("{}.txt", "d".repeat(120))), "deep down").unwrap();
+    fs::create_dir_all(src.join("empty dir")).unwrap();
+    fs::write(src.join("empty"), "").unwrap();
+    fs::write(src.join("Grüße – ünïcödé.txt"), "hallo").unwrap();
+    fs::write(src.join("big.bin"), (0..400_000u32).flat_map(|i| i.to_le_bytes()).collect::<Vec<u8>>()).unwrap();
+    fs::hard_link(src.join("big.bin"), src.join("big-link.bin")).unwrap();
+    std::os::unix::fs::symlink("Grüße – ünïcödé.txt", src.join("link")).unwrap();
+    std::os::unix::fs::symlink("missing", src.join("dangling")).unwrap();
+}
+
+//every entry below `src` is in the extracted copy with the same type, content and mode
+fn assert_same_tree(src: &Path, copy: &Path) {
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(src.join(&relative)).unwrap() {
+            let relative = relative.join(entry.unwrap().file_name());
+            let (original, extracted) = (src.join(&relative), copy.join(&relative));
+            let metadata = original.symlink_metadata().unwrap();
+            let restored = extracted.symlink_metadata().unwrap_or_else(|_| panic!("{} is missing", extracted.display()));
+            assert_eq!(metadata.file_type(), restored.file_type(), "{}", relative.display());
+            assert_eq!(metadata.mode(), restored.mode(), "{}", relative.display());
+            if metadata.is_symlink() {
+                assert_eq!(fs::read_link(&original).unwrap(), fs::read_link(&extracted).unwrap());
+            } else if metadata.is_dir() {
+                pending.push(relative);
+            } else {
+                assert_eq!(fs::read(&original).unwrap(), fs::read(&extracted).unwrap(), "{}", relative.display());
+            }
+        }
+    }
+}
+
+//strict-tar archives extract completely with the system tar, whatever the compression
+#[test]
+fn strict_archives_extract_with_plain_tar() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    let src = root.join("src");
+    feature_rich_tree(&src);
+    set_portability(root, "strict-tar");
+
+    for (compression, extension, extra) in [("gzip", "tar.gz", &["--threads", "4"][..]), ("zstd", "tar.zst", &["--reproducible"][..]), ("xz", "tar.xz", &[][..])] {
+        let archive = root.join(format!("out.{}", extension));
+        let mut args = vec!["selective", "--dirs", src.to_str().unwrap(), "--output", archive.to_str().unwrap(), "--compression", compression, "--reset-metadata"];
+        args.extend(extra);
+        let output = linux_backup(root, &args);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+        let target = root.join(format!("extracted-{}", compression));
+        fs::create_dir_all(&target).unwrap();
+        let tar = Command::new("tar").arg("-xf").arg(&archive).arg("-C").arg(&target).output().unwrap();
+        assert!(tar.status.success(), "{}", String::from_utf8_lossy(&tar.stderr));
+        assert!(!target.join("README.linux_backup.txt").exists());
+
+        let copy = target.join(src.strip_prefix("/").unwrap());
+        assert_same_tree(&src, &copy);
+        assert_eq!(fs::metadata(copy.join("big.bin")).unwrap().ino(), fs::metadata(copy.join("big-link.bin")).unwrap().ino());
+
+        let info = linux_backup(root, &["info", "--file", archive.to_str().unwrap()]);
+        assert!(String::from_utf8_lossy(&info.stdout).contains("Portability: strict-tar"));
+    }
+
+    //the built-in cipher is only readable by linux_backup, refused before the passphrase is asked for
+    let refused = linux_backup(root, &["selective", "--dirs", src.to_str().unwrap(), "--output", "enc.tar.gz", "--encrypt-builtin"]);
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("strict-tar"));
+    assert!(!root.join("enc.tar.gz.lbt").exists());
+}
+
+//extended archives carry a README for plain tar users, restores leave it out of the target
+#[test]
+fn extended_archives_explain_themselves() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = temp.path();
+    let src = root.join("src");
+    feature_rich_tree(&src);
+    let archive = root.join("out.tar.gz");
+
+    let output = linux_backup(root, &["selective", "--dirs", src.to_str().unwrap(), "--output", archive.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let listing = Command::new("tar").arg("-tzf").arg(&archive).output().unwrap();
+    let listing = String::from_utf8_lossy(&listing.stdout);
+    assert_eq!(listing.lines().nth(1), Some("README.linux_backup.txt"));
+    let readme = Command::new("tar").arg("-xzOf").arg(&archive).arg("README.linux_backup.txt").output().unwrap();
+    assert!(String::from_utf8_lossy(&readme.stdout).contains("--xattrs"));
+
+    let info = linux_backup(root, &["info", "--file", archive.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&info.stdout).contains("Portability: extended"));
+
+    let target = root.join("restored");
+    let restore = linux_backup(root, &["restore", "--file", archive.to_str().unwrap(), "--target", target.to_str().unwrap()]);
+    assert!(restore.status.success(), "{}", String::from_utf8_lossy(&restore.stderr));
+    assert!(!target.join("README.linux_backup.txt").exists());
+    assert_same_tree(&src, &target.join(src.strip_prefix("/").unwrap()));
+}